@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf, sync::Arc};
+
+use tokio::io::{ErrorKind, Result};
+
+use crate::{
+    dictionary::Dictionary,
+    record::{MemValue, Value},
+    sstable_set::TableScan,
+};
+
+/// Power-of-two histogram of lengths in bytes.
+#[derive(Debug, Default, Clone)]
+pub struct Histogram {
+    /// Maps each bucket's inclusive upper bound to the number of samples in it.
+    pub buckets: BTreeMap<usize, u64>,
+    pub count: u64,
+    pub sum: u64,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Histogram {
+    pub fn record(&mut self, len: usize) {
+        let bound = len.max(1).next_power_of_two();
+        *self.buckets.entry(bound).or_default() += 1;
+        self.min = if self.count == 0 { len } else { self.min.min(len) };
+        self.max = self.max.max(len);
+        self.count += 1;
+        self.sum += len as u64;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  count={} min={} max={} mean={:.1}",
+            self.count,
+            self.min,
+            self.max,
+            self.mean()
+        )?;
+        for (bound, count) in &self.buckets {
+            writeln!(f, "  <= {bound:>8}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Distribution of key lengths, value lengths and value types across the
+/// memtable and every SSTable.
+///
+/// Every stored record is considered, including tombstones and versions
+/// shadowed by newer writes, since that is what determines on-disk layout.
+#[derive(Debug, Default, Clone)]
+pub struct KeyspaceReport {
+    /// Number of records inspected.
+    pub sampled: u64,
+    /// Number of records seen, inspected or not.
+    pub total: u64,
+    pub key_len: Histogram,
+    pub value_len: Histogram,
+    /// Number of records per value type (`str`, `int64`, `float64`, `tombstone`).
+    pub types: BTreeMap<&'static str, u64>,
+    /// Number of records carrying an expiration time.
+    pub expiring: u64,
+}
+
+impl KeyspaceReport {
+    fn record(&mut self, key: &str, value: &MemValue) {
+        self.key_len.record(key.len());
+        self.value_len.record(value.len());
+        *self.types.entry(type_name(value)).or_default() += 1;
+        if value.expires_at().is_some() {
+            self.expiring += 1;
+        }
+        self.sampled += 1;
+    }
+}
+
+impl fmt::Display for KeyspaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "records: {} sampled of {}", self.sampled, self.total)?;
+        writeln!(f, "key length:")?;
+        write!(f, "{}", self.key_len)?;
+        writeln!(f, "value length:")?;
+        write!(f, "{}", self.value_len)?;
+        writeln!(f, "types:")?;
+        for (name, count) in &self.types {
+            writeln!(f, "  {name}: {count}")?;
+        }
+        writeln!(f, "expiring: {}", self.expiring)
+    }
+}
+
+fn type_name(value: &MemValue) -> &'static str {
+    match value {
+        MemValue::Value(value) | MemValue::Expiring { value, .. } => match value {
+            Value::Str(_) => "str",
+            Value::Int64(_) => "int64",
+            Value::Float64(_) => "float64",
+            Value::Bytes(_) => "bytes",
+            Value::Set(_) => "set",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        },
+        MemValue::Tombstone => "tombstone",
+    }
+}
+
+/// Incrementally builds a `KeyspaceReport`, inspecting one record out of every
+/// `every` (`1` inspects all of them).
+pub struct Analyzer {
+    every: u64,
+    report: KeyspaceReport,
+}
+
+impl Analyzer {
+    pub fn new(every: usize) -> Self {
+        Self {
+            every: every.max(1) as u64,
+            report: KeyspaceReport::default(),
+        }
+    }
+
+    pub fn visit(&mut self, key: &str, value: &MemValue) {
+        if self.report.total.is_multiple_of(self.every) {
+            self.report.record(key, value);
+        }
+        self.report.total += 1;
+    }
+
+    /// Visits every record of the given data files, whose values are
+    /// compressed with `dictionary`. Files removed in the meantime (e.g. by a
+    /// concurrent compaction) are skipped.
+    pub async fn visit_files(
+        &mut self,
+        paths: &[PathBuf],
+        dictionary: Option<Arc<Dictionary>>,
+    ) -> Result<()> {
+        for path in paths {
+            let mut scan = match TableScan::open(path).await {
+                Ok(scan) => scan.with_dictionary(dictionary.clone()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    log::warn!("Skipping {}: file no longer exists.", path.display());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            while let Some(record) = scan.next().await? {
+                self.visit(&record.key, &record.value);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> KeyspaceReport {
+        self.report
+    }
+}
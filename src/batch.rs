@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use crate::Value;
+
+/// Writes applied together by [`crate::Controller::write`]: readers see
+/// either none or all of them.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Set {
+        key: String,
+        value: Value,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: String, value: Value) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl: None,
+        });
+        self
+    }
+
+    /// Sets `key` to `value` for the given time-to-live, counted from when
+    /// the batch is written.
+    pub fn set_ex(&mut self, key: String, value: Value, ttl: Duration) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: String) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns `true` if the batch holds writes other than deletes.
+    pub(crate) fn has_sets(&self) -> bool {
+        self.ops.iter().any(|op| matches!(op, BatchOp::Set { .. }))
+    }
+}
@@ -0,0 +1,88 @@
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Order of keys in scans, see `Config::collation`.
+///
+/// Keys are stored under a sort key whose byte order is the collation order,
+/// so the memtable, sparse indexes and compaction, which all compare stored
+/// keys as bytes, agree on it without knowing the collation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Collation {
+    /// Byte order of the UTF-8 keys; keys are stored as they are.
+    #[default]
+    Binary,
+    /// Order of the lowercased keys, then byte order among keys differing
+    /// only in case. Keys are stored as their lowercase form without NUL
+    /// characters, a NUL, then the key itself.
+    CaseInsensitive,
+}
+
+impl Collation {
+    pub fn is_binary(&self) -> bool {
+        *self == Collation::Binary
+    }
+
+    /// Returns the key `key` is stored under.
+    pub fn encode<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            Collation::Binary => Cow::Borrowed(key),
+            Collation::CaseInsensitive => {
+                let folded = key.to_lowercase().replace('\0', "");
+                Cow::Owned(format!("{folded}\0{key}"))
+            }
+        }
+    }
+
+    /// Returns the stored key that sorts right before the stored keys of
+    /// `key` and of every key that collates equal to it, to bound scans with.
+    pub fn bound<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            Collation::Binary => Cow::Borrowed(key),
+            Collation::CaseInsensitive => Cow::Owned(key.to_lowercase().replace('\0', "")),
+        }
+    }
+
+    /// Returns whether `key` starts with `prefix` under this collation. The
+    /// keys that do sort next to one another.
+    pub fn has_prefix(&self, key: &str, prefix: &str) -> bool {
+        match self {
+            Collation::Binary => key.starts_with(prefix),
+            Collation::CaseInsensitive => self.bound(key).starts_with(&*self.bound(prefix)),
+        }
+    }
+
+    /// Reverses [`Collation::encode`].
+    pub fn decode<'a>(&self, stored: &'a str) -> &'a str {
+        match self {
+            Collation::Binary => stored,
+            Collation::CaseInsensitive => stored.split_once('\0').map_or(stored, |(_, key)| key),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Collation::Binary => "binary",
+            Collation::CaseInsensitive => "case_insensitive",
+        }
+    }
+}
+
+impl fmt::Display for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Collation {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "binary" => Ok(Collation::Binary),
+            "case_insensitive" => Ok(Collation::CaseInsensitive),
+            _ => Err(()),
+        }
+    }
+}
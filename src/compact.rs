@@ -1,123 +1,299 @@
-use std::{collections::BinaryHeap, path::Path};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use tokio::{
     fs::File,
-    io::{AsyncWrite, BufReader, Result},
+    io::{AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter, Result, SeekFrom},
 };
 
 use crate::{
-    record::{MemValue, Record},
-    sparse_index::SparseIndex,
-    sstable_set::SSTableSet,
+    collation::Collation,
+    dictionary::Dictionary,
+    format::{self, Format},
+    memtable::{MemEntry, MemTable},
+    merge::{MergeIterator, Source},
+    rate_limit::RateLimiter,
+    record::{FrameEncoder, MemValue, Record},
+    retention::{self, RetentionRule},
+    sparse_index::{self, SparseIndex},
+    sstable_set::{SSTable, SSTableSet, TableScan},
 };
 
-#[derive(Debug)]
-struct HeapEntry {
-    key: String,
-    priority: usize,
-    value: MemValue,
+/// How and where compaction writes its output tables.
+pub struct Output<'a> {
+    pub index_stride: usize,
+    pub format: Format,
+    pub dictionary: Option<&'a Dictionary>,
+    /// Size at which a new output table is started (`0` for a single table).
+    pub target_bytes: u64,
+    /// Returns the data and index paths of the `n`-th output table.
+    pub part_paths: &'a (dyn Fn(usize) -> (PathBuf, PathBuf) + Sync),
+    /// Limits the rate at which inputs are read and outputs written.
+    pub limiter: &'a RateLimiter,
+    /// Drops tombstones and expired records. Only safe when no table older
+    /// than the inputs is left out, as they hide older versions of their keys.
+    pub drop_deleted: bool,
+    /// Deletes the keys past their retention, see `Config::retention`.
+    pub retention: &'a [RetentionRule],
+    /// Collation of the stored keys, decoded before retention rules are
+    /// matched against them.
+    pub collation: Collation,
 }
 
-pub async fn compact_sstable_set<W>(
-    sstable_set: &mut SSTableSet,
-    output: &mut W,
-    data_dir: &Path,
-    index_stride: usize,
-) -> Result<SparseIndex>
-where
-    W: AsyncWrite + Unpin,
-{
-    let mut readers = Vec::new();
-    let mut heap = BinaryHeap::new();
-    let mut index = SparseIndex::new();
-    let mut offset = 0u64;
-    let mut i = 0;
+/// What a compaction would do if it ran now, estimated without writing
+/// anything; see [`crate::Controller::compaction_plan`].
+#[derive(Debug, Clone, Default)]
+pub struct CompactionPlan {
+    /// Why the compaction policy would start a compaction now, if it would.
+    pub reason: Option<String>,
+    /// Data files that would be merged, newest first. Empty if there are too
+    /// few sorted runs for a compaction to do anything.
+    pub inputs: Vec<PathBuf>,
+    /// Size of the data and index files of the inputs, in bytes.
+    pub input_bytes: u64,
+    /// Estimated size of the output data files, in bytes. Records are
+    /// sampled block by block, and output values are assumed to compress
+    /// as well as the inputs did.
+    pub estimated_output_bytes: u64,
+    /// Estimated number of output tables, given
+    /// `Config::compaction_target_file_bytes`.
+    pub estimated_output_tables: usize,
+    /// Estimated disk space freed once the inputs are deleted, in bytes.
+    pub estimated_reclaimed_bytes: u64,
+}
 
-    let inputs: Vec<_> = sstable_set
-        .tables
-        .iter()
-        .map(|t| data_dir.join(&t.data_path))
-        .collect();
-
-    for (i, path) in inputs.iter().enumerate() {
-        let file = File::open(path).await?;
-        let mut reader = BufReader::new(file);
-        if let Ok(record) = Record::read_from(&mut reader).await {
-            heap.push(HeapEntry {
-                key: record.key,
-                value: record.value,
-                priority: i,
-            });
+impl fmt::Display for CompactionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reason {
+            Some(reason) => writeln!(f, "compaction due: {reason}")?,
+            None => writeln!(f, "compaction not due")?,
+        }
+        writeln!(f, "inputs: {} tables, {} bytes", self.inputs.len(), self.input_bytes)?;
+        for path in &self.inputs {
+            writeln!(f, "  {}", path.display())?;
         }
-        readers.push(reader);
+        writeln!(
+            f,
+            "estimated output: {} tables, {} bytes",
+            self.estimated_output_tables, self.estimated_output_bytes
+        )?;
+        writeln!(f, "estimated reclaimed: {} bytes", self.estimated_reclaimed_bytes)
     }
+}
 
-    while let Some(entry) = heap.pop() {
-        // Peek and check if the next element shares the key with the current one.
-        // In case they do, discard it (as `HeapEntries` are sorted by `key`, `priority`),
-        // and replace it with the next element from the same log file.
-        while let Some(next) = heap.peek() {
-            if next.key != entry.key {
-                break;
-            }
-            let next = heap.pop().unwrap();
-            // When no record is found the log is consumed.
-            if let Ok(record) = Record::read_from(&mut readers[next.priority]).await {
-                heap.push(HeapEntry {
-                    key: record.key,
-                    value: record.value,
-                    priority: next.priority,
-                });
-            }
+/// Merges `tables`, newest first, into new tables of about
+/// `output.target_bytes` each, dropping overwritten records and, if
+/// `output.drop_deleted`, deleted and expired ones. Returns the index of
+/// every output table in key order; no table is written if no record
+/// survives.
+pub async fn merge_tables(
+    tables: &[SSTable],
+    dictionary: Option<Arc<Dictionary>>,
+    data_dir: &Path,
+    now: u64,
+    output: Output<'_>,
+) -> Result<Vec<SparseIndex>> {
+    let mut sources = Vec::new();
+    for table in tables {
+        let scan = TableScan::open(&data_dir.join(&table.data_path))
+            .await?
+            .with_dictionary(dictionary.clone());
+        sources.push(Source::Table(Box::new(scan)));
+    }
+    let mut merge = MergeIterator::with_limiter(sources, Some(output.limiter)).await?;
+    let mut outputs = OutputTables::new(output, now);
+    while let Some(record) = merge.next().await? {
+        outputs.write(record).await?;
+    }
+    outputs.finish().await
+}
+
+/// Writes the records of `memtable` into new tables the way
+/// [`merge_tables`] writes its outputs, for a flush with no older table
+/// left to merge them with later.
+pub async fn write_memtable(
+    memtable: &MemTable,
+    now: u64,
+    output: Output<'_>,
+) -> Result<Vec<SparseIndex>> {
+    let mut outputs = OutputTables::new(output, now);
+    for (key, MemEntry { seq, value }) in memtable.iter() {
+        let record = Record {
+            key: key.clone(),
+            value: value.clone(),
+            seq: *seq,
+        };
+        outputs.write(record).await?;
+    }
+    outputs.finish().await
+}
+
+/// Output tables written in key order, each started once the previous one
+/// reaches `output.target_bytes`.
+struct OutputTables<'a> {
+    output: Output<'a>,
+    now: u64,
+    current: Option<OutputTable>,
+    finished: Vec<SparseIndex>,
+    /// Keys deleted for being past their retention.
+    past_retention: u64,
+}
+
+impl<'a> OutputTables<'a> {
+    fn new(output: Output<'a>, now: u64) -> Self {
+        Self {
+            output,
+            now,
+            current: None,
+            finished: Vec::new(),
+            past_retention: 0,
         }
+    }
 
-        if !matches!(entry.value, MemValue::Tombstone) {
-            let record = Record {
-                key: entry.key,
-                value: entry.value,
-            };
-            // Save offset before writing data
-            if i % index_stride == 0 {
-                index.insert(record.key.clone(), offset);
+    /// Writes `record`, unless `output.drop_deleted` and it is deleted or
+    /// expired. Keys past their retention are deleted: dropped along with
+    /// deleted records, written as tombstones otherwise.
+    async fn write(&mut self, mut record: Record) -> Result<()> {
+        let key = self.output.collation.decode(&record.key);
+        if !matches!(record.value, MemValue::Tombstone)
+            && retention::expires(self.output.retention, key, self.now)
+        {
+            record.value = MemValue::Tombstone;
+            self.past_retention += 1;
+        }
+        let deleted =
+            matches!(record.value, MemValue::Tombstone) || record.value.is_expired(self.now);
+        if deleted && self.output.drop_deleted {
+            return Ok(());
+        }
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let paths = (self.output.part_paths)(self.finished.len());
+                self.current.insert(OutputTable::create(paths).await?)
             }
-
-            offset += record.write_to(output).await?;
-            i += 1;
+        };
+        current.write(record, &self.output).await?;
+        if self.output.target_bytes > 0 && current.offset() >= self.output.target_bytes {
+            let table = self.current.take().unwrap();
+            self.finished.push(table.finish(self.output.format).await?);
         }
+        Ok(())
+    }
 
-        // Refill from the file that provided the last inserted key
-        let reader = &mut readers[entry.priority];
-        if let Ok(record) = Record::read_from(reader).await {
-            heap.push(HeapEntry {
-                key: record.key,
-                value: record.value,
-                priority: entry.priority,
-            });
+    /// Finishes the table being written, returning the index of every
+    /// table; none is written if no record was.
+    async fn finish(mut self) -> Result<Vec<SparseIndex>> {
+        if self.past_retention > 0 {
+            log::info!(
+                keys = self.past_retention;
+                "Deleted {} keys past their retention.",
+                self.past_retention
+            );
         }
+        if let Some(table) = self.current.take() {
+            self.finished.push(table.finish(self.output.format).await?);
+        }
+        Ok(self.finished)
     }
+}
 
-    Ok(index)
+/// An output table being written.
+pub(crate) struct OutputTable {
+    data: BufWriter<File>,
+    encoder: FrameEncoder,
+    index_path: PathBuf,
+    index: SparseIndex,
+    records: usize,
 }
 
-impl PartialEq for HeapEntry {
-    fn eq(&self, other: &Self) -> bool {
-        self.key.eq(&other.key) && self.priority.eq(&other.priority)
+impl OutputTable {
+    pub(crate) async fn create((data_path, index_path): (PathBuf, PathBuf)) -> Result<Self> {
+        log::info!("Output log file: {}", data_path.display());
+        Ok(Self {
+            data: BufWriter::new(File::create(&data_path).await?),
+            encoder: FrameEncoder::new(),
+            index_path,
+            index: SparseIndex::new(),
+            records: 0,
+        })
     }
-}
 
-impl Eq for HeapEntry {}
+    /// Returns the number of bytes of records written so far.
+    pub(crate) fn offset(&self) -> u64 {
+        self.encoder.offset()
+    }
 
-impl PartialOrd for HeapEntry {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    pub(crate) async fn write(&mut self, record: Record, output: &Output<'_>) -> Result<()> {
+        // Indexed records are restart points.
+        let restart = self.records.is_multiple_of(output.index_stride);
+        let (offset, len) = self
+            .encoder
+            .push(&mut self.data, &record, restart, output.format, output.dictionary)
+            .await?;
+        if restart {
+            self.index.insert(record.key, offset);
+        }
+        output.limiter.consume(len).await;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Returns the key of the last record written, if any.
+    pub(crate) fn last_key(&self) -> Option<&str> {
+        (self.records > 0).then_some(self.encoder.last_key())
+    }
+
+    /// Ends the data file and writes the index, syncing both: they must be
+    /// durable before the manifest refers to them.
+    pub(crate) async fn finish(mut self, format: Format) -> Result<SparseIndex> {
+        let data_len = self.encoder.finish(&mut self.data).await?;
+        format::write_footer(&mut self.data, format).await?;
+        self.data.flush().await?;
+        self.data.get_mut().sync_all().await?;
+
+        let mut index_file = BufWriter::new(File::create(&self.index_path).await?);
+        sparse_index::write_to(&self.index, data_len, &mut index_file).await?;
+        index_file.flush().await?;
+        index_file.get_mut().sync_all().await?;
+        Ok(self.index)
     }
 }
 
-impl Ord for HeapEntry {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.key
-            .cmp(&other.key)
-            .reverse()
-            .then_with(|| self.priority.cmp(&other.priority).reverse())
+/// Returns the serialized values of up to `max_samples` records spread evenly
+/// over every table, to train a dictionary with. Only restart points are
+/// read, so every sample takes a single seek.
+pub async fn sample_values(
+    sstable_set: &SSTableSet,
+    data_dir: &Path,
+    max_samples: usize,
+) -> Result<Vec<Vec<u8>>> {
+    let mut restarts = 0;
+    for table in &sstable_set.tables {
+        restarts += table.index().await?.len();
+    }
+    let step = restarts.div_ceil(max_samples.max(1)).max(1);
+    let mut samples = Vec::new();
+    let mut i = 0;
+
+    for table in &sstable_set.tables {
+        let mut file = BufReader::new(File::open(data_dir.join(&table.data_path)).await?);
+        let contents = table.contents().await?;
+        for &offset in contents.index.values() {
+            i += 1;
+            if i % step != 0 {
+                continue;
+            }
+            file.seek(SeekFrom::Start(offset)).await?;
+            let dictionary = sstable_set.dictionary.as_deref();
+            let record = Record::read_from(&mut file, contents.layout.format, "", dictionary).await?;
+            if !matches!(record.value, MemValue::Tombstone) {
+                samples.push(record.value.serialize());
+            }
+        }
     }
+    Ok(samples)
 }
@@ -1,11 +1,150 @@
-use std::path::PathBuf;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    collation::Collation, events::EventClasses, format::Format, layout::Layout,
+    retention::RetentionRule, sstable_set::CorruptTablePolicy,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub data_dir: PathBuf,
     pub sparse_stride: usize,
+    /// Bytes of records between consecutive entries of a table's sparse
+    /// index, bounding what a lookup reads (`0` uses `sparse_stride` for
+    /// every table). The stride of each table is chosen from the average
+    /// size of its records and recorded in the MANIFEST.
+    pub sparse_scan_bytes: u64,
     pub memtable_capacity: usize,
     pub create_if_missing: bool,
+    /// Size in bytes of the records held by the memtable (see
+    /// `MemValue::encoded_len`) above which a background flush is scheduled.
+    pub flush_threshold: usize,
+    /// Appends every write to the write-ahead log in `data_dir` before the
+    /// memtable takes it, so that writes not yet flushed outlive a crash.
+    /// The log is replayed on open and emptied by every flush; one left by
+    /// a run with it enabled is still replayed, then flushed, when disabled.
+    pub wal: bool,
+    /// Syncs the write-ahead log after every write, so that writes outlive
+    /// a power loss too rather than only the process crashing, at the cost
+    /// of a disk sync per write.
+    pub wal_sync: bool,
+    /// Directory every flush first copies the write-ahead log to, as a
+    /// segment named after the sequence number of its first write, for
+    /// `DatabaseImpl::restore_to` to replay over a backup. A flush fails,
+    /// keeping the memtable and the log, if the copy does. Bulk loads and
+    /// ingested tables aren't logged, so they are only restored as part of
+    /// a backup taken after them. Requires `wal`.
+    pub wal_archive_dir: Option<PathBuf>,
+    /// Number of sorted runs at which a compaction runs after a background
+    /// flush (`0` disables automatic compaction). Every flushed table is a run,
+    /// and so are the tables written by the last compaction, together.
+    pub compaction_trigger: usize,
+    /// Age in milliseconds of the oldest flushed table at which a compaction
+    /// runs, however few sorted runs there are (`0` disables).
+    pub compaction_max_table_age_ms: u64,
+    /// How often a background job checks whether a compaction is due, in
+    /// milliseconds (`0` leaves it to the checks following flushes).
+    pub compaction_check_interval_ms: u64,
+    /// Number of flushed tables from which every write is delayed, longer
+    /// the more tables there are (`0` disables).
+    pub write_slowdown_tables: usize,
+    /// Number of flushed tables at which writes stall until a compaction
+    /// merges them (`0` disables).
+    pub write_stop_tables: usize,
+    /// Operations slower than this are logged, in milliseconds (`0` disables).
+    pub slow_log_threshold_ms: u64,
+    /// Longest a `get`, `scan` or `set` may wait before failing with a
+    /// timeout, in milliseconds (`0` disables).
+    pub operation_timeout_ms: u64,
+    /// How often the background task looks for expired keys, in milliseconds.
+    pub ttl_sweep_interval_ms: u64,
+    /// How often the checksums of every table are verified in the
+    /// background, in milliseconds (`0` disables).
+    pub scrub_interval_ms: u64,
+    /// Fraction of the bytes of the tables verified every hour by the
+    /// incremental scrubber, a slice a minute, each carrying on where the
+    /// last one stopped: `1` goes over every table once an hour, `0.1` once
+    /// every ten hours (`0` disables). Unlike `scrub_interval_ms`, the reads
+    /// are spread out rather than done all at once.
+    pub scrub_fraction_per_hour: f64,
+    /// Quarantines the tables the scrubbers find corrupt, moving them to
+    /// `corrupt/` as `corrupt_tables = "quarantine"` does on open, rather than
+    /// only logging them. The keys they held revert to older versions, if any.
+    pub scrub_quarantine: bool,
+    /// How often a snapshot of the stats is logged, in milliseconds (`0`
+    /// disables).
+    pub stats_interval_ms: u64,
+    /// Which keyspace events are published to subscribers and hooks.
+    pub notify_events: EventClasses,
+    /// Maximum number of table files open at once (`0` for no limit): kept
+    /// open between point reads, and opened by scans, flushes, compactions,
+    /// backups and the scrubbers, which wait for room. An operation needing
+    /// more files than that, such as a compaction of more tables, runs
+    /// alone. Indexes read lazily and the write-ahead log aren't counted.
+    pub max_open_files: usize,
+    /// Size of the data files above which writes are rejected, in bytes
+    /// (`0` disables the limit).
+    pub max_disk_bytes: u64,
+    /// Starts a compaction when a write is rejected for exceeding `max_disk_bytes`.
+    pub compact_on_disk_full: bool,
+    /// Byte budget of the records of live keys in cache mode: once exceeded, the
+    /// least recently used keys are evicted (`0` disables cache mode).
+    pub cache_max_bytes: u64,
+    /// Record framing of newly written tables: `3` (varint lengths, sequence
+    /// numbers and shared key prefixes), `2` (the same without shared
+    /// prefixes) or `1`, readable by releases that predate v2.
+    pub table_format: u32,
+    /// Size of the dictionary trained over sampled values at every compaction,
+    /// in bytes. Values of new tables that it makes shorter are stored
+    /// compressed (`0` disables compression; requires `table_format` 2 or later).
+    pub dictionary_max_bytes: usize,
+    /// Number of tables a read probes concurrently when the key isn't in the
+    /// memtable (`1` probes them one after the other).
+    pub read_parallelism: usize,
+    /// Size in bytes of the reads a range scan switches to once it is found
+    /// to read a table sequentially, rather than reading a few kilobytes at
+    /// a time (`0` keeps reads small).
+    pub scan_readahead_bytes: usize,
+    /// Size in bytes at which compaction starts a new output table (`0`
+    /// writes a single table).
+    pub compaction_target_file_bytes: u64,
+    /// Combined rate at which flushes and compactions may read and write
+    /// table files, in bytes per second (`0` for no limit).
+    pub maintenance_bytes_per_sec: u64,
+    /// Order of keys in scans: `binary` or `case_insensitive`. Fixed once the
+    /// database holds tables, as it decides how keys are stored.
+    pub collation: Collation,
+    /// What to do with tables that fail to load when the database is opened:
+    /// `fail` to open, `skip` them, or `quarantine` them to `corrupt/`.
+    pub corrupt_tables: CorruptTablePolicy,
+    /// Reads the index of every table when the database is opened. Otherwise
+    /// indexes are read on first use, so that opening doesn't slow down with
+    /// the number of tables; `corrupt_tables` then doesn't apply, and a table
+    /// that fails to load fails the reads that need it. Expiration times and,
+    /// in cache mode, key sizes are still loaded by scanning every table.
+    pub load_indexes_eagerly: bool,
+    /// How long files replaced by a compaction are kept in `trash/` before
+    /// being deleted, in milliseconds (`0` deletes them right away). They
+    /// are only deleted once MANIFEST is found readable and complete.
+    pub trash_retention_ms: u64,
+    /// Extensions of table files and how they are spread over subdirectories
+    /// of `data_dir`, so that large databases don't keep thousands of files
+    /// in one directory. Applies to tables written from then on; existing
+    /// ones stay where they are.
+    pub layout: Layout,
+    /// Rules deleting keys whose timestamp is past the retention of their
+    /// prefix, applied by compactions: until a compaction rewrites them,
+    /// such keys can still be read. Set in the configuration file only, as
+    /// `[[retention]]` tables, and reloaded from it with
+    /// `Controller::reload_retention`.
+    pub retention: Vec<RetentionRule>,
 }
 
 impl Default for Config {
@@ -13,8 +152,217 @@ impl Default for Config {
         Self {
             data_dir: PathBuf::from("./data"),
             sparse_stride: 50,
+            sparse_scan_bytes: 0,
             memtable_capacity: 1000,
             create_if_missing: true,
+            flush_threshold: 50000,
+            wal: true,
+            wal_sync: false,
+            wal_archive_dir: None,
+            compaction_trigger: 0,
+            compaction_max_table_age_ms: 0,
+            compaction_check_interval_ms: 60_000,
+            write_slowdown_tables: 0,
+            write_stop_tables: 0,
+            slow_log_threshold_ms: 0,
+            operation_timeout_ms: 0,
+            ttl_sweep_interval_ms: 1000,
+            scrub_interval_ms: 0,
+            scrub_fraction_per_hour: 0.0,
+            scrub_quarantine: false,
+            stats_interval_ms: 0,
+            notify_events: EventClasses::NONE,
+            max_open_files: 256,
+            max_disk_bytes: 0,
+            compact_on_disk_full: false,
+            cache_max_bytes: 0,
+            table_format: 3,
+            dictionary_max_bytes: 0,
+            read_parallelism: 4,
+            scan_readahead_bytes: 256 * 1024,
+            compaction_target_file_bytes: 64 * 1024 * 1024,
+            maintenance_bytes_per_sec: 0,
+            collation: Collation::Binary,
+            corrupt_tables: CorruptTablePolicy::Fail,
+            load_indexes_eagerly: true,
+            trash_retention_ms: 3_600_000,
+            layout: Layout::default(),
+            retention: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads a configuration from a TOML file; missing fields keep their defaults.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Unable to parse {}: {e}", path.display()),
+            )
+        })
+    }
+
+    /// Fails if fields are out of range or contradict each other. Whether
+    /// they suit the database they open is checked against its MANIFEST, see
+    /// `Manifest::apply_config`.
+    pub fn validate(&self) -> Result<()> {
+        Format::from_version(self.table_format, 0)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        if self.dictionary_max_bytes > 0 && self.table_format < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "dictionary_max_bytes requires table_format 2 or later",
+            ));
+        }
+        if self.wal_archive_dir.is_some() && !self.wal {
+            return Err(Error::new(ErrorKind::InvalidInput, "wal_archive_dir requires wal"));
+        }
+        if !(self.scrub_fraction_per_hour >= 0.0 && self.scrub_fraction_per_hour.is_finite()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "scrub_fraction_per_hour must be a non-negative number",
+            ));
         }
+        self.layout.validate()
+    }
+
+    /// Overrides fields with the `LOGDB_*` environment variables that are set:
+    /// `LOGDB_DATA_DIR`, `LOGDB_SPARSE_STRIDE`, `LOGDB_SPARSE_SCAN_BYTES`,
+    /// `LOGDB_MEMTABLE_CAPACITY`,
+    /// `LOGDB_CREATE_IF_MISSING`, `LOGDB_FLUSH_THRESHOLD`, `LOGDB_WAL`, `LOGDB_WAL_SYNC`,
+    /// `LOGDB_WAL_ARCHIVE_DIR` (empty to disable), `LOGDB_COMPACTION_TRIGGER`,
+    /// `LOGDB_COMPACTION_MAX_TABLE_AGE_MS`, `LOGDB_COMPACTION_CHECK_INTERVAL_MS`,
+    /// `LOGDB_WRITE_SLOWDOWN_TABLES`, `LOGDB_WRITE_STOP_TABLES`,
+    /// `LOGDB_SLOW_LOG_THRESHOLD_MS`, `LOGDB_OPERATION_TIMEOUT_MS`,
+    /// `LOGDB_TTL_SWEEP_INTERVAL_MS`,
+    /// `LOGDB_SCRUB_INTERVAL_MS`, `LOGDB_SCRUB_FRACTION_PER_HOUR`, `LOGDB_SCRUB_QUARANTINE`,
+    /// `LOGDB_STATS_INTERVAL_MS`, `LOGDB_NOTIFY_EVENTS`, `LOGDB_MAX_OPEN_FILES`, `LOGDB_MAX_DISK_BYTES`,
+    /// `LOGDB_COMPACT_ON_DISK_FULL`, `LOGDB_CACHE_MAX_BYTES`, `LOGDB_TABLE_FORMAT`,
+    /// `LOGDB_DICTIONARY_MAX_BYTES`, `LOGDB_READ_PARALLELISM`, `LOGDB_SCAN_READAHEAD_BYTES`,
+    /// `LOGDB_COMPACTION_TARGET_FILE_BYTES`, `LOGDB_MAINTENANCE_BYTES_PER_SEC`,
+    /// `LOGDB_COLLATION`, `LOGDB_CORRUPT_TABLES`, `LOGDB_LOAD_INDEXES_EAGERLY`,
+    /// `LOGDB_TRASH_RETENTION_MS`, `LOGDB_DATA_EXTENSION`,
+    /// `LOGDB_INDEX_EXTENSION` and `LOGDB_TABLES_PER_DIR`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.apply_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Same as [`Config::apply_env`], reading variables through `lookup`.
+    pub fn apply_vars(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(data_dir) = lookup("LOGDB_DATA_DIR") {
+            self.data_dir = data_dir.into();
+        }
+        override_from(&lookup, "LOGDB_SPARSE_STRIDE", &mut self.sparse_stride)?;
+        override_from(&lookup, "LOGDB_SPARSE_SCAN_BYTES", &mut self.sparse_scan_bytes)?;
+        override_from(&lookup, "LOGDB_MEMTABLE_CAPACITY", &mut self.memtable_capacity)?;
+        override_from(&lookup, "LOGDB_CREATE_IF_MISSING", &mut self.create_if_missing)?;
+        override_from(&lookup, "LOGDB_FLUSH_THRESHOLD", &mut self.flush_threshold)?;
+        override_from(&lookup, "LOGDB_WAL", &mut self.wal)?;
+        override_from(&lookup, "LOGDB_WAL_SYNC", &mut self.wal_sync)?;
+        if let Some(dir) = lookup("LOGDB_WAL_ARCHIVE_DIR") {
+            self.wal_archive_dir = (!dir.is_empty()).then(|| dir.into());
+        }
+        override_from(&lookup, "LOGDB_COMPACTION_TRIGGER", &mut self.compaction_trigger)?;
+        override_from(
+            &lookup,
+            "LOGDB_COMPACTION_MAX_TABLE_AGE_MS",
+            &mut self.compaction_max_table_age_ms,
+        )?;
+        override_from(
+            &lookup,
+            "LOGDB_COMPACTION_CHECK_INTERVAL_MS",
+            &mut self.compaction_check_interval_ms,
+        )?;
+        override_from(
+            &lookup,
+            "LOGDB_WRITE_SLOWDOWN_TABLES",
+            &mut self.write_slowdown_tables,
+        )?;
+        override_from(&lookup, "LOGDB_WRITE_STOP_TABLES", &mut self.write_stop_tables)?;
+        override_from(
+            &lookup,
+            "LOGDB_SLOW_LOG_THRESHOLD_MS",
+            &mut self.slow_log_threshold_ms,
+        )?;
+        override_from(
+            &lookup,
+            "LOGDB_OPERATION_TIMEOUT_MS",
+            &mut self.operation_timeout_ms,
+        )?;
+        override_from(
+            &lookup,
+            "LOGDB_TTL_SWEEP_INTERVAL_MS",
+            &mut self.ttl_sweep_interval_ms,
+        )?;
+        override_from(&lookup, "LOGDB_SCRUB_INTERVAL_MS", &mut self.scrub_interval_ms)?;
+        override_from(
+            &lookup,
+            "LOGDB_SCRUB_FRACTION_PER_HOUR",
+            &mut self.scrub_fraction_per_hour,
+        )?;
+        override_from(&lookup, "LOGDB_SCRUB_QUARANTINE", &mut self.scrub_quarantine)?;
+        override_from(&lookup, "LOGDB_STATS_INTERVAL_MS", &mut self.stats_interval_ms)?;
+        override_from(&lookup, "LOGDB_NOTIFY_EVENTS", &mut self.notify_events)?;
+        override_from(&lookup, "LOGDB_MAX_OPEN_FILES", &mut self.max_open_files)?;
+        override_from(&lookup, "LOGDB_MAX_DISK_BYTES", &mut self.max_disk_bytes)?;
+        override_from(
+            &lookup,
+            "LOGDB_COMPACT_ON_DISK_FULL",
+            &mut self.compact_on_disk_full,
+        )?;
+        override_from(&lookup, "LOGDB_CACHE_MAX_BYTES", &mut self.cache_max_bytes)?;
+        override_from(&lookup, "LOGDB_TABLE_FORMAT", &mut self.table_format)?;
+        override_from(
+            &lookup,
+            "LOGDB_DICTIONARY_MAX_BYTES",
+            &mut self.dictionary_max_bytes,
+        )?;
+        override_from(&lookup, "LOGDB_READ_PARALLELISM", &mut self.read_parallelism)?;
+        override_from(
+            &lookup,
+            "LOGDB_SCAN_READAHEAD_BYTES",
+            &mut self.scan_readahead_bytes,
+        )?;
+        override_from(
+            &lookup,
+            "LOGDB_COMPACTION_TARGET_FILE_BYTES",
+            &mut self.compaction_target_file_bytes,
+        )?;
+        override_from(
+            &lookup,
+            "LOGDB_MAINTENANCE_BYTES_PER_SEC",
+            &mut self.maintenance_bytes_per_sec,
+        )?;
+        override_from(&lookup, "LOGDB_COLLATION", &mut self.collation)?;
+        override_from(&lookup, "LOGDB_CORRUPT_TABLES", &mut self.corrupt_tables)?;
+        override_from(
+            &lookup,
+            "LOGDB_LOAD_INDEXES_EAGERLY",
+            &mut self.load_indexes_eagerly,
+        )?;
+        override_from(&lookup, "LOGDB_TRASH_RETENTION_MS", &mut self.trash_retention_ms)?;
+        override_from(&lookup, "LOGDB_DATA_EXTENSION", &mut self.layout.data_extension)?;
+        override_from(&lookup, "LOGDB_INDEX_EXTENSION", &mut self.layout.index_extension)?;
+        override_from(&lookup, "LOGDB_TABLES_PER_DIR", &mut self.layout.tables_per_dir)?;
+        Ok(())
+    }
+}
+
+/// Parses the variable `name`, if set, into `target`.
+fn override_from<T: FromStr>(
+    lookup: impl Fn(&str) -> Option<String>,
+    name: &str,
+    target: &mut T,
+) -> Result<()> {
+    if let Some(raw) = lookup(name) {
+        *target = raw.trim().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid value for {name}: {raw:?}"),
+            )
+        })?;
     }
+    Ok(())
 }
@@ -1,16 +1,115 @@
-use std::{io::Result, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use tokio::{sync::{Mutex, RwLock}, task::JoinSet};
+use tokio::{
+    sync::{Mutex, RwLock, broadcast, oneshot, watch},
+    task::JoinSet,
+};
 
-use crate::{Database, DatabaseAdmin, DatabaseImpl, Value};
+use crate::{
+    CacheLoader, ChecksumReport, Clock, CompactionPlan, Database, DatabaseAdmin, DatabaseImpl, KeyTtl,
+    KeyspaceReport, Loaded, ManifestRewrite, MemValue, OrderedStream, RetentionRule, ScanPage,
+    Stats, TableSummary, Value, WriteBatch,
+    analyze::Analyzer,
+    batch::BatchOp,
+    events::{EventFilter, EventKind, FilteredEvents, KeyspaceEvent, Notifier},
+    key_lock::KeyLocks,
+    keyenc,
+    options::{ConfigFile, RuntimeOptions},
+    request, schedule,
+    script::{ScriptContext, ScriptEngine},
+    trash,
+    verify::{self, ScrubCursor},
+};
 
 pub struct Controller {
     db: Arc<RwLock<DatabaseImpl>>,
-    flush_threshold: usize,
+    options: Arc<RuntimeOptions>,
+    clock: Arc<dyn Clock>,
+    config_file: Option<ConfigFile>,
     workers: Mutex<JoinSet<()>>,
     is_shutdown: AtomicBool,
+    shutdown_tx: watch::Sender<bool>,
+    notifier: Notifier,
+    flush_status: Arc<FlushStatus>,
+    /// Set while a compaction started by [`Controller::check_disk_quota`] runs.
+    compacting_for_space: Arc<AtomicBool>,
+    write_throttle: WriteThrottle,
+    script_engine: Option<Arc<dyn ScriptEngine>>,
+    cache_loader: Option<Arc<dyn CacheLoader>>,
+    cache_loads: AtomicU64,
+    key_locks: KeyLocks,
 }
 
+/// Resolves once the flush requested with [`Controller::flush_async`] has
+/// committed, or with the error that made it fail.
+#[derive(Debug)]
+pub struct FlushHandle(oneshot::Receiver<Result<()>>);
+
+impl Future for FlushHandle {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.unwrap_or_else(|_| Err(Error::other("Flush task was cancelled"))))
+    }
+}
+
+/// Failures of flushes run outside of a caller's request.
+///
+/// A flush can fail after draining the memtable, losing its writes, so the
+/// first failure poisons the controller and further writes are refused.
+#[derive(Debug, Default)]
+struct FlushStatus {
+    failures: AtomicU64,
+    last_error: std::sync::Mutex<Option<String>>,
+}
+
+impl FlushStatus {
+    fn record(&self, result: &Result<()>) {
+        if let Err(e) = result {
+            log::error!(error:% = e; "Background flush failed: {e}");
+            self.failures.fetch_add(1, Ordering::SeqCst);
+            *self.last_error.lock().unwrap() = Some(e.to_string());
+        }
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.failures.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Writes held back by [`Controller::throttle_writes`].
+#[derive(Debug, Default)]
+struct WriteThrottle {
+    slowdowns: AtomicU64,
+    stalls: AtomicU64,
+    /// Set while writes are delayed, to warn once when it starts.
+    slowing: AtomicBool,
+}
+
+/// Delay of a write at `write_slowdown_tables`, added again for every
+/// further flushed table.
+const SLOWDOWN_STEP: Duration = Duration::from_millis(1);
+
+/// Longest the trash goes unchecked for files due for deletion.
+const TRASH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the incremental scrubber checks a slice of the tables, see
+/// `Config::scrub_fraction_per_hour`.
+const SCRUB_SLICE_INTERVAL: Duration = Duration::from_secs(60);
+
 impl Drop for Controller {
     fn drop(&mut self) {
         if !self.is_shutdown.load(Ordering::SeqCst) {
@@ -21,22 +120,195 @@ impl Drop for Controller {
 
 impl Controller {
     pub fn new(inner: DatabaseImpl, flush_threshold: usize) -> Controller {
+        let options = Arc::new(RuntimeOptions::new(&inner.config));
+        options
+            .set("flush_threshold", &flush_threshold.to_string())
+            .expect("flush_threshold is a valid option");
+        let notifier = Notifier::new(inner.config.notify_events);
+        let clock = inner.clock().clone();
+        let [compaction_check_interval, scrub_interval, stats_interval] = [
+            inner.config.compaction_check_interval_ms,
+            inner.config.scrub_interval_ms,
+            inner.config.stats_interval_ms,
+        ]
+        .map(Duration::from_millis);
+        let scrub_slice_interval = match inner.config.scrub_fraction_per_hour > 0.0 {
+            true => SCRUB_SLICE_INTERVAL,
+            false => Duration::ZERO,
+        };
+        let trash_retention = inner.config.trash_retention_ms;
+        let trash_check_interval = match trash_retention {
+            0 => TRASH_CHECK_INTERVAL,
+            retention => Duration::from_millis(retention).min(TRASH_CHECK_INTERVAL),
+        };
+        let data_dir = inner.config.data_dir.clone();
         let db: Arc<RwLock<DatabaseImpl>> = Arc::new(RwLock::new(inner));
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        // Maintenance jobs, each cloning what it needs into every run.
+        let mut workers = JoinSet::new();
+        let sweep_interval = {
+            let options = options.clone();
+            move || Duration::from_millis(options.ttl_sweep_interval_ms())
+        };
+        let sweep = {
+            let (db, clock, notifier) = (db.clone(), clock.clone(), notifier.clone());
+            move || sweep_expired(db.clone(), clock.clone(), notifier.clone())
+        };
+        let compaction_check = {
+            let (db, options, clock) = (db.clone(), options.clone(), clock.clone());
+            move || check_compaction(db.clone(), options.clone(), clock.clone())
+        };
+        let scrub = {
+            let db = db.clone();
+            move || scrub(db.clone())
+        };
+        let scrub_slice = {
+            let (db, cursor) = (db.clone(), Arc::new(Mutex::new(None)));
+            move || scrub_slice(db.clone(), cursor.clone())
+        };
+        let stats_snapshot = {
+            let db = db.clone();
+            move || log_stats(db.clone())
+        };
+        workers.spawn(schedule::every(shutdown_rx.clone(), sweep_interval, sweep));
+        workers.spawn(schedule::every(
+            shutdown_rx.clone(),
+            move || compaction_check_interval,
+            compaction_check,
+        ));
+        workers.spawn(schedule::every(shutdown_rx.clone(), move || scrub_interval, scrub));
+        workers.spawn(schedule::every(
+            shutdown_rx.clone(),
+            move || scrub_slice_interval,
+            scrub_slice,
+        ));
+        let trash_purge = {
+            let (db, clock) = (db.clone(), clock.clone());
+            move || purge_trash(db.clone(), data_dir.clone(), clock.clone(), trash_retention)
+        };
+        workers.spawn(schedule::every(shutdown_rx.clone(), move || stats_interval, stats_snapshot));
+        workers.spawn(schedule::every(
+            shutdown_rx,
+            move || trash_check_interval,
+            trash_purge,
+        ));
 
         Controller {
             db,
-            flush_threshold,
-            workers: Mutex::new(JoinSet::new()),
+            options,
+            clock,
+            config_file: None,
+            workers: Mutex::new(workers),
             is_shutdown: AtomicBool::new(false),
+            shutdown_tx,
+            notifier,
+            flush_status: Arc::default(),
+            compacting_for_space: Arc::default(),
+            write_throttle: WriteThrottle::default(),
+            script_engine: None,
+            cache_loader: None,
+            cache_loads: AtomicU64::new(0),
+            key_locks: KeyLocks::default(),
         }
     }
 
+    /// Runs `job` every `interval` in a worker, like the built-in maintenance
+    /// jobs, until shutdown. A job running when shutdown starts is waited for.
+    pub async fn schedule<F, Fut>(&self, interval: Duration, job: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown_rx = self.shutdown_tx.subscribe();
+        self.workers
+            .lock()
+            .await
+            .spawn(schedule::every(shutdown_rx, move || interval, job));
+    }
+
+    /// Persists options changed with [`Controller::set_option`] to `file`.
+    pub fn with_config_file(mut self, file: ConfigFile) -> Self {
+        self.config_file = Some(file);
+        self
+    }
+
+    /// Runs the scripts passed to [`Controller::eval`] with `engine`.
+    pub fn with_script_engine(mut self, engine: Arc<dyn ScriptEngine>) -> Self {
+        self.script_engine = Some(engine);
+        self
+    }
+
+    /// Makes [`Controller::get`] fetch keys missing from the database with
+    /// `loader`, storing the values it returns.
+    pub fn with_cache_loader(mut self, loader: Arc<dyn CacheLoader>) -> Self {
+        self.cache_loader = Some(loader);
+        self
+    }
+
+    /// Runs `script` on the registered script engine, holding the write lock
+    /// so that it reads and writes atomically.
+    pub async fn eval(&self, script: &str, args: &[String]) -> Result<Vec<Value>> {
+        let Some(engine) = &self.script_engine else {
+            return Err(Error::new(ErrorKind::Unsupported, "No script engine is configured"));
+        };
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let now = self.clock.now_millis();
+        let mut db = self.db.write().await;
+        self.check_disk_quota(&db).await?;
+        let result = engine
+            .eval(script, args, ScriptContext::new(&mut db, &self.notifier, now))
+            .await;
+        self.evict_if_needed(&mut db);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("eval", started);
+        result
+    }
+
+    /// Changes a hot-tunable option (see [`crate::OPTION_NAMES`]) without a
+    /// restart, writing it back to the config file if one is set.
+    pub async fn set_option(&self, name: &str, value: &str) -> Result<()> {
+        self.options.set(name, value)?;
+        log::info!("Option {name} set to {value}.");
+
+        if let Some(file) = &self.config_file {
+            let value = self.options.get(name).expect("option was just set");
+            file.persist(name, value).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the current value of a hot-tunable option.
+    pub fn option(&self, name: &str) -> Option<u64> {
+        self.options.get(name)
+    }
+
+    /// Returns the rules of `Config::retention` in force.
+    pub async fn retention(&self) -> Vec<RetentionRule> {
+        self.db.read().await.config.retention.clone()
+    }
+
+    /// Rereads the retention rules from the configuration file, applying
+    /// them from the next compaction on, and returns them.
+    pub async fn reload_retention(&self) -> Result<Vec<RetentionRule>> {
+        let Some(file) = &self.config_file else {
+            return Err(Error::new(ErrorKind::Unsupported, "No configuration file to reload from"));
+        };
+        let rules = file.retention().await?;
+        self.db.write().await.config.retention = rules.clone();
+        log::info!(rules = rules.len(); "Reloaded {} retention rules.", rules.len());
+        Ok(rules)
+    }
+
     pub async fn shutdown(&self) -> Result<()> {
         // Check if controller is already shut down.
         if self.is_shutdown.swap(true, Ordering::SeqCst) {
             log::warn!("Double shutdown attempt.");
             return Ok(())
         }
+        let _ = self.shutdown_tx.send(true);
 
         let mut workers = self.workers.lock().await;
         let len = workers.len();
@@ -53,42 +325,1230 @@ impl Controller {
 
         let mut db = self.db.write().await;
 
-        if db.memtable.len() > 0 {
+        if !db.memtable.is_empty() {
             db.flush().await?;
         }
+        db.save_totals().await?;
 
         Ok(())
     }
 
+    pub fn is_shutdown(&self) -> bool {
+        self.is_shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` once a background flush has failed. Writes are refused
+    /// from then on; see [`Stats::last_flush_error`] for the cause.
+    pub fn is_poisoned(&self) -> bool {
+        self.flush_status.is_poisoned()
+    }
+
+    /// Returns `true` if the controller is not shut down and the database lock
+    /// can be taken within `timeout`, i.e. nothing is stuck holding it.
+    pub async fn is_live(&self, timeout: Duration) -> bool {
+        !self.is_shutdown() && tokio::time::timeout(timeout, self.db.read()).await.is_ok()
+    }
+
     pub async fn get(&self, key: &str) -> Result<Option<Value>> {
-        self.db.read().await.get(&key).await
+        self.get_within(key, self.operation_timeout()).await
+    }
+
+    /// Same as [`Controller::get`], failing with [`ErrorKind::TimedOut`] if
+    /// it doesn't complete within `timeout`, including the time taken by the
+    /// cache loader on a miss.
+    pub async fn get_within(&self, key: &str, timeout: Option<Duration>) -> Result<Option<Value>> {
+        let started = Instant::now();
+        let value = within(timeout, "get", async {
+            let value = self.db.read().await.get(key).await?;
+            match (value, &self.cache_loader) {
+                (None, Some(loader)) => self.load(loader.as_ref(), key).await,
+                (value, _) => Ok(value),
+            }
+        })
+        .await;
+        self.log_if_slow("get", started);
+        value
+    }
+
+    /// Returns the value of every key of `keys`, in order, under a single
+    /// read lock.
+    pub async fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<Value>>> {
+        self.multi_get_within(keys, self.operation_timeout()).await
+    }
+
+    /// Same as [`Controller::multi_get`], failing with [`ErrorKind::TimedOut`]
+    /// if it doesn't complete within `timeout`.
+    pub async fn multi_get_within(
+        &self,
+        keys: &[&str],
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Option<Value>>> {
+        let started = Instant::now();
+        let values = within(timeout, "mget", async {
+            let mut values = Vec::with_capacity(keys.len());
+            {
+                let db = self.db.read().await;
+                for key in keys {
+                    values.push(db.get(key).await?);
+                }
+            }
+            if let Some(loader) = &self.cache_loader {
+                for (key, value) in keys.iter().zip(&mut values) {
+                    if value.is_none() {
+                        *value = self.load(loader.as_ref(), key).await?;
+                    }
+                }
+            }
+            Ok(values)
+        })
+        .await;
+        self.log_if_slow("mget", started);
+        values
+    }
+
+    /// Returns up to `limit` keys from `start` to `end` (exclusive) along
+    /// with their values; see [`DatabaseImpl::scan`].
+    pub async fn scan(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>> {
+        self.scan_within(start, end, limit, self.operation_timeout()).await
+    }
+
+    /// Same as [`Controller::scan`], failing with [`ErrorKind::TimedOut`] if
+    /// it doesn't complete within `timeout`.
+    pub async fn scan_within(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<(String, Value)>> {
+        let started = Instant::now();
+        let entries = within(timeout, "scan", async {
+            self.db.read().await.scan(start, end, limit).await
+        })
+        .await;
+        self.log_if_slow("scan", started);
+        entries
+    }
+
+    /// Returns a page of up to `limit` entries of the range `start` to `end`
+    /// (exclusive), after `after`, the [`ScanPage::next`] of the previous
+    /// page; see [`DatabaseImpl::scan_page`]. Writes may run between pages,
+    /// so unlike a single scan the pages aren't a consistent snapshot.
+    pub async fn scan_page(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        let started = Instant::now();
+        let page = within(self.operation_timeout(), "scan", async {
+            self.db.read().await.scan_page(start, end, after, limit).await
+        })
+        .await;
+        self.log_if_slow("scan", started);
+        page
+    }
+
+    /// Returns the entries of the range `start` to `end` (exclusive) as an
+    /// [`OrderedStream`], read a page at a time with
+    /// [`Controller::scan_page`], and so with the same consistency.
+    pub async fn scan_stream(&self, start: &str, end: Option<&str>) -> OrderedStream<'_> {
+        let collation = self.db.read().await.sstable_set.collation;
+        OrderedStream::new(self, start, end, collation)
+    }
+
+    /// Returns the entries whose keys start with `prefix`, as compared in
+    /// the collation of the database.
+    pub async fn scan_prefix(&self, prefix: &str) -> OrderedStream<'_> {
+        let collation = self.db.read().await.sstable_set.collation;
+        // Only bytewise prefixes have a known end; otherwise the stream stops
+        // at the first key past them.
+        let end = if collation.is_binary() { keyenc::prefix_end(prefix) } else { None };
+        OrderedStream::new(self, prefix, end.as_deref(), collation).take_while_prefix(prefix)
     }
 
     pub async fn set(&self, key: String, value: Value) -> Result<()> {
+        self.set_within(key, value, None, self.operation_timeout()).await
+    }
+
+    /// Sets `key` to `value`, for `ttl` if given, failing with
+    /// [`ErrorKind::TimedOut`] if the write can't start within `timeout`.
+    ///
+    /// Only the wait for the database lock is bounded: once the write is
+    /// under way it completes, so a timed out `set` never leaves it half
+    /// applied.
+    pub async fn set_within(
+        &self,
+        key: String,
+        value: Value,
+        ttl: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.write_value(key, value, ttl, timeout, false).await?;
+        Ok(())
+    }
+
+    /// Sets `key` to `value` as [`Controller::set`] does, returning a token
+    /// that [`Controller::delete_ephemeral`] deletes it with, e.g. once the
+    /// client that set it goes away. The token stands for this write only:
+    /// any later write of the key, even of the same value, invalidates it.
+    ///
+    /// Tokens are kept in memory, so a restart invalidates them all.
+    pub async fn set_ephemeral(&self, key: String, value: Value) -> Result<u64> {
+        self.set_ephemeral_within(key, value, self.operation_timeout()).await
+    }
+
+    /// Same as [`Controller::set_ephemeral`], failing with
+    /// [`ErrorKind::TimedOut`] as [`Controller::set_within`] does.
+    pub async fn set_ephemeral_within(
+        &self,
+        key: String,
+        value: Value,
+        timeout: Option<Duration>,
+    ) -> Result<u64> {
+        let token = self.write_value(key, value, None, timeout, true).await?;
+        Ok(token.expect("ephemeral writes are owned"))
+    }
+
+    /// Deletes `key` if it still holds the write [`Controller::set_ephemeral`]
+    /// returned `token` for. Returns whether the key was deleted.
+    pub async fn delete_ephemeral(&self, key: &str, token: u64) -> Result<bool> {
+        self.check_writable()?;
+        let started = Instant::now();
         let mut db = self.db.write().await;
-        db.set(key, value).await?;
+        if !db.owns_ephemeral(key, token) {
+            return Ok(false);
+        }
+        let event_key = self.notifier.wants(EventKind::Delete).then(|| key.to_string());
+        db.delete(key.to_string()).await?;
+        self.notifier.publish(EventKind::Delete, event_key);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("delete_ephemeral", started);
+        Ok(true)
+    }
 
-        if db.current_size > self.flush_threshold {
-            let db_clone = self.db.clone();
-            self.workers.lock().await.spawn(async move {
-                let _ = db_clone.write().await.flush().await;
-            });
+    /// Writes `key` for [`Controller::set_within`], returning the token of
+    /// the write if `ephemeral`.
+    async fn write_value(
+        &self,
+        key: String,
+        value: Value,
+        ttl: Option<Duration>,
+        timeout: Option<Duration>,
+        ephemeral: bool,
+    ) -> Result<Option<u64>> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let event = self.notifier.wants(EventKind::Set).then(|| (key.clone(), value.clone()));
+        let owner = ephemeral.then(|| key.clone());
+        let mut db = within(timeout, "set", async { Ok(self.db.write().await) }).await?;
+        self.check_disk_quota(&db).await?;
+        match ttl {
+            Some(ttl) => {
+                let expires_at = self.clock.now_millis() + ttl.as_millis() as u64;
+                db.set_with_expiry(key, value, expires_at).await?;
+            }
+            None => db.set(key, value).await?,
+        }
+        let token = owner.map(|key| db.own_ephemeral(&key));
+        // The write is applied: being cancelled from here on at worst skips
+        // scheduling a flush, which the next write does.
+        self.notifier.publish_set(event);
+        self.evict_if_needed(&mut db);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("set", started);
+
+        Ok(token)
+    }
+
+    /// Sets `key` to `value` for the given time-to-live.
+    pub async fn set_ex(&self, key: String, value: Value, ttl: Duration) -> Result<()> {
+        self.set_within(key, value, Some(ttl), self.operation_timeout()).await
+    }
+
+    /// Applies every write of `batch` under a single lock, so that readers
+    /// see either none or all of them, and as a single write of the
+    /// write-ahead log, so that a crash keeps either none or all of them.
+    /// Fails, writing nothing, if any of them is refused.
+    pub async fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let now = self.clock.now_millis();
+        let mut db = self.db.write().await;
+        if batch.has_sets() {
+            self.check_disk_quota(&db).await?;
+        }
+        // Published once the whole batch is written.
+        let events: Vec<_> = batch
+            .ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value, .. } => {
+                    let set = self.notifier.wants(EventKind::Set);
+                    (set.then(|| (key.clone(), value.clone())), None)
+                }
+                BatchOp::Delete { key } => {
+                    (None, self.notifier.wants(EventKind::Delete).then(|| key.clone()))
+                }
+            })
+            .collect();
+        db.write_batch(batch.ops, now)?;
+        for (set, deleted) in events {
+            self.notifier.publish_set(set);
+            self.notifier.publish(EventKind::Delete, deleted);
         }
+        self.evict_if_needed(&mut db);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("write", started);
 
         Ok(())
     }
 
+    /// Adds `delta` to the integer stored at `key`, starting from `0` if the
+    /// key doesn't exist, and returns the result.
+    pub async fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        self.update(key, |value| {
+            let current = match value {
+                None => 0,
+                Some(Value::Int64(n)) => n,
+                Some(_) => return Err(wrong_type("an integer")),
+            };
+            let n = current
+                .checked_add(delta)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Increment would overflow"))?;
+            Ok((Some(Value::Int64(n)), n))
+        })
+        .await
+    }
+
+    /// Takes the advisory lock on `key` for `ttl`, waiting up to `wait` for
+    /// it to be released or to expire. Returns the token to
+    /// [`Controller::unlock`] it with, or `None` if it is still held after
+    /// `wait`. Locks only exclude each other: clients coordinating updates to
+    /// `key` take it first, but writes to it are never held back.
+    pub async fn lock(&self, key: &str, ttl: Duration, wait: Duration) -> Option<u64> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let mut released = std::pin::pin!(self.key_locks.released());
+            released.as_mut().enable();
+            let now = self.clock.now_millis();
+            let expires_at = now.saturating_add(ttl.as_millis() as u64);
+            let held_until = match self.key_locks.try_lock(key, now, expires_at) {
+                Ok(token) => return Some(token),
+                Err(held_until) => held_until,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            let expiry = Duration::from_millis(held_until.saturating_sub(now));
+            let _ = tokio::time::timeout(remaining.min(expiry), released).await;
+        }
+    }
+
+    /// Releases the lock on `key` taken with `token`. Returns `false` if it
+    /// isn't held with that token, such as after it expired.
+    pub fn unlock(&self, key: &str, token: u64) -> bool {
+        self.key_locks.unlock(key, token, self.clock.now_millis())
+    }
+
+    /// Adds `members` to the set stored at `key`, creating it if needed.
+    /// Returns the number of members that weren't in the set yet.
+    pub async fn sadd(&self, key: &str, members: Vec<String>) -> Result<usize> {
+        self.update(key, |value| {
+            let mut set = match value {
+                None => BTreeSet::new(),
+                Some(Value::Set(set)) => set,
+                Some(_) => return Err(wrong_type("a set")),
+            };
+            let added = members.into_iter().filter(|m| set.insert(m.clone())).count();
+            Ok((Some(Value::Set(set)), added))
+        })
+        .await
+    }
+
+    /// Removes `members` from the set stored at `key`, deleting the key once
+    /// the set is empty. Returns the number of members removed.
+    pub async fn srem(&self, key: &str, members: &[String]) -> Result<usize> {
+        self.update(key, |value| {
+            let mut set = match value {
+                None => return Ok((None, 0)),
+                Some(Value::Set(set)) => set,
+                Some(_) => return Err(wrong_type("a set")),
+            };
+            let removed = members.iter().filter(|m| set.remove(*m)).count();
+            Ok(((!set.is_empty()).then_some(Value::Set(set)), removed))
+        })
+        .await
+    }
+
+    /// Returns the members of the set stored at `key`, in order.
+    pub async fn smembers(&self, key: &str) -> Result<BTreeSet<String>> {
+        match self.get(key).await? {
+            None => Ok(BTreeSet::new()),
+            Some(Value::Set(set)) => Ok(set),
+            Some(_) => Err(wrong_type("a set")),
+        }
+    }
+
+    /// Pushes `items` one after the other to the front of the list stored
+    /// at `key`, creating it if needed, so that the last item ends up first.
+    /// Returns the length of the list.
+    pub async fn lpush(&self, key: &str, items: Vec<String>) -> Result<usize> {
+        self.push(key, items, VecDeque::push_front).await
+    }
+
+    /// Appends `items` to the list stored at `key`, creating it if needed.
+    /// Returns the length of the list.
+    pub async fn rpush(&self, key: &str, items: Vec<String>) -> Result<usize> {
+        self.push(key, items, VecDeque::push_back).await
+    }
+
+    async fn push(
+        &self,
+        key: &str,
+        items: Vec<String>,
+        push: fn(&mut VecDeque<String>, String),
+    ) -> Result<usize> {
+        self.update(key, |value| {
+            let mut list = match value {
+                None => VecDeque::new(),
+                Some(Value::List(list)) => list,
+                Some(_) => return Err(wrong_type("a list")),
+            };
+            for item in items {
+                push(&mut list, item);
+            }
+            let len = list.len();
+            Ok((Some(Value::List(list)), len))
+        })
+        .await
+    }
+
+    /// Removes and returns the first item of the list stored at `key`,
+    /// deleting the key once the list is empty.
+    pub async fn lpop(&self, key: &str) -> Result<Option<String>> {
+        self.update(key, |value| {
+            let mut list = match value {
+                None => return Ok((None, None)),
+                Some(Value::List(list)) => list,
+                Some(_) => return Err(wrong_type("a list")),
+            };
+            let item = list.pop_front();
+            Ok(((!list.is_empty()).then_some(Value::List(list)), item))
+        })
+        .await
+    }
+
+    /// Returns the items of the list stored at `key` from `start` to `stop`,
+    /// both included. Negative positions count from the end of the list,
+    /// `-1` being the last item.
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>> {
+        let list = match self.get(key).await? {
+            None => return Ok(Vec::new()),
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(wrong_type("a list")),
+        };
+        let len = list.len() as i64;
+        let resolve = |i: i64| if i < 0 { len + i } else { i };
+        let start = resolve(start).max(0);
+        let stop = resolve(stop).min(len - 1);
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        Ok(list
+            .into_iter()
+            .skip(start as usize)
+            .take((stop - start + 1) as usize)
+            .collect())
+    }
+
+    /// Sets `fields` of the map stored at `key`, creating it if needed.
+    /// Returns the number of fields that didn't exist yet.
+    pub async fn hset(&self, key: &str, fields: Vec<(String, String)>) -> Result<usize> {
+        self.update(key, |value| {
+            let mut map = match value {
+                None => BTreeMap::new(),
+                Some(Value::Map(map)) => map,
+                Some(_) => return Err(wrong_type("a map")),
+            };
+            let added = fields
+                .into_iter()
+                .filter(|(field, value)| map.insert(field.clone(), value.clone()).is_none())
+                .count();
+            Ok((Some(Value::Map(map)), added))
+        })
+        .await
+    }
+
+    /// Returns the value of `field` in the map stored at `key`.
+    pub async fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        Ok(self.hgetall(key).await?.remove(field))
+    }
+
+    /// Removes `fields` from the map stored at `key`, deleting the key once
+    /// the map is empty. Returns the number of fields removed.
+    pub async fn hdel(&self, key: &str, fields: &[String]) -> Result<usize> {
+        self.update(key, |value| {
+            let mut map = match value {
+                None => return Ok((None, 0)),
+                Some(Value::Map(map)) => map,
+                Some(_) => return Err(wrong_type("a map")),
+            };
+            let removed = fields.iter().filter(|field| map.remove(*field).is_some()).count();
+            Ok(((!map.is_empty()).then_some(Value::Map(map)), removed))
+        })
+        .await
+    }
+
+    /// Returns every field of the map stored at `key`.
+    pub async fn hgetall(&self, key: &str) -> Result<BTreeMap<String, String>> {
+        match self.get(key).await? {
+            None => Ok(BTreeMap::new()),
+            Some(Value::Map(map)) => Ok(map),
+            Some(_) => Err(wrong_type("a map")),
+        }
+    }
+
+    /// Fetches `key` with `loader` after a miss and stores it. A value that
+    /// can't be stored, e.g. with the disk quota reached, is still returned.
+    async fn load(&self, loader: &dyn CacheLoader, key: &str) -> Result<Option<Value>> {
+        let Some(loaded) = loader.load(key).await? else {
+            return Ok(None);
+        };
+        self.cache_loads.fetch_add(1, Ordering::Relaxed);
+        let value = loaded.value.clone();
+        match self.store_loaded(key, loaded).await {
+            Ok(current) => Ok(Some(current)),
+            Err(e) => {
+                log::warn!(error:% = e; "Unable to store the loaded value of {key}: {e}");
+                Ok(Some(value))
+            }
+        }
+    }
+
+    /// Stores `loaded` as the value of `key`, unless the key was written
+    /// since the miss. Returns the value the key holds afterwards.
+    async fn store_loaded(&self, key: &str, loaded: Loaded) -> Result<Value> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let now = self.clock.now_millis();
+        let mut db = self.db.write().await;
+        let current = db.get_entry(key).await?.filter(|entry| !entry.is_expired(now));
+        if let Some(current) = current.and_then(MemValue::into_value) {
+            return Ok(current);
+        }
+        self.check_disk_quota(&db).await?;
+        let event = self
+            .notifier
+            .wants(EventKind::Set)
+            .then(|| (key.to_string(), loaded.value.clone()));
+        match loaded.ttl {
+            Some(ttl) => {
+                let expires_at = now + ttl.as_millis() as u64;
+                db.set_with_expiry(key.to_string(), loaded.value.clone(), expires_at).await?
+            }
+            None => db.set(key.to_string(), loaded.value.clone()).await?,
+        }
+        self.notifier.publish_set(event);
+        self.evict_if_needed(&mut db);
+        self.schedule_flush_if_needed(&db).await;
+        Ok(loaded.value)
+    }
+
+    /// Replaces the value of `key` with the one `update` computes from it
+    /// (`None` deletes the key), under the write lock so that concurrent
+    /// updates aren't lost. The key's expiration time is kept.
+    async fn update<T>(
+        &self,
+        key: &str,
+        update: impl FnOnce(Option<Value>) -> Result<(Option<Value>, T)>,
+    ) -> Result<T> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let now = self.clock.now_millis();
+        let mut db = self.db.write().await;
+        let entry = db.get_entry(key).await?.filter(|entry| !entry.is_expired(now));
+        let expires_at = entry.as_ref().and_then(MemValue::expires_at);
+        let current = entry.and_then(MemValue::into_value);
+        let existed = current.is_some();
+
+        let (value, result) = update(current)?;
+        match value {
+            Some(value) => {
+                self.check_disk_quota(&db).await?;
+                let event =
+                    self.notifier.wants(EventKind::Set).then(|| (key.to_string(), value.clone()));
+                match expires_at {
+                    Some(expires_at) => db.set_with_expiry(key.to_string(), value, expires_at).await?,
+                    None => db.set(key.to_string(), value).await?,
+                }
+                self.notifier.publish_set(event);
+            }
+            None if existed => {
+                let event_key = self.notifier.wants(EventKind::Delete).then(|| key.to_string());
+                db.delete(key.to_string()).await?;
+                self.notifier.publish(EventKind::Delete, event_key);
+            }
+            None => {}
+        }
+        self.evict_if_needed(&mut db);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("update", started);
+
+        Ok(result)
+    }
+
+    /// Returns the length in bytes of the string or byte value of `key`.
+    pub async fn get_len(&self, key: &str) -> Result<Option<usize>> {
+        match self.get(key).await? {
+            None => Ok(None),
+            Some(value) => Ok(Some(value_bytes(&value)?.len())),
+        }
+    }
+
+    /// Returns up to `len` bytes of the string or byte value of `key`, from
+    /// `offset` on; none past the end of the value. Slices of strings may cut
+    /// characters in two.
+    pub async fn get_range_of_value(
+        &self,
+        key: &str,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(value) = self.get(key).await? else {
+            return Ok(None);
+        };
+        let bytes = value_bytes(&value)?;
+        let start = offset.min(bytes.len());
+        let end = start.saturating_add(len).min(bytes.len());
+        Ok(Some(bytes[start..end].to_vec()))
+    }
+
+    /// Returns the remaining time-to-live of `key`.
+    pub async fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        self.db.read().await.ttl(key, self.clock.now_millis()).await
+    }
+
+    /// Removes the expiration time of `key`, returning whether it had one.
+    pub async fn persist(&self, key: &str) -> Result<bool> {
+        self.db.write().await.persist(key, self.clock.now_millis()).await
+    }
+
     pub async fn delete(&self, key: String) -> Result<()> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let event_key = self.notifier.wants(EventKind::Delete).then(|| key.clone());
         let mut db = self.db.write().await;
         db.delete(key).await?;
+        self.notifier.publish(EventKind::Delete, event_key);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("delete", started);
+
+        Ok(())
+    }
+
+    /// Sets `key` to `value`, for `ttl` if given, only if it holds
+    /// `expected` (`None`: only if it doesn't exist). The comparison and the
+    /// write happen under the write lock, so no write can come in between.
+    /// Returns whether the key was set.
+    pub async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        let written = self.compare_and_set_with(key, expected, |_| value, ttl).await?;
+        Ok(written.is_some())
+    }
+
+    /// Same as [`Controller::compare_and_set`], computing the value from the
+    /// sequence number of the write, which is returned if the key was set.
+    pub(crate) async fn compare_and_set_with(
+        &self,
+        key: &str,
+        expected: Option<&Value>,
+        value: impl FnOnce(u64) -> Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<u64>> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let now = self.clock.now_millis();
+        let mut db = self.db.write().await;
+        let current = db.get_entry(key).await?.filter(|entry| !entry.is_expired(now));
+        if current.and_then(MemValue::into_value).as_ref() != expected {
+            return Ok(None);
+        }
+        self.check_disk_quota(&db).await?;
+        let seq = db.sstable_set.last_record_seq + 1;
+        let value = value(seq);
+        let event = self.notifier.wants(EventKind::Set).then(|| (key.to_string(), value.clone()));
+        match ttl {
+            Some(ttl) => {
+                let expires_at = now + ttl.as_millis() as u64;
+                db.set_with_expiry(key.to_string(), value, expires_at).await?;
+            }
+            None => db.set(key.to_string(), value).await?,
+        }
+        self.notifier.publish_set(event);
+        self.evict_if_needed(&mut db);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("compare_and_set", started);
+        Ok(Some(seq))
+    }
+
+    /// Deletes `key` only if it holds `expected`, e.g. to remove a lease
+    /// key only while it still holds the caller's token. The comparison and
+    /// the delete happen under the write lock, so no write can come in
+    /// between. Returns whether the key was deleted.
+    pub async fn delete_if(&self, key: &str, expected: &Value) -> Result<bool> {
+        self.check_writable()?;
+        self.throttle_writes().await?;
+        let started = Instant::now();
+        let now = self.clock.now_millis();
+        let mut db = self.db.write().await;
+        let current = db.get_entry(key).await?.filter(|entry| !entry.is_expired(now));
+        if current.and_then(MemValue::into_value).as_ref() != Some(expected) {
+            return Ok(false);
+        }
+        let event_key = self.notifier.wants(EventKind::Delete).then(|| key.to_string());
+        db.delete(key.to_string()).await?;
+        self.notifier.publish(EventKind::Delete, event_key);
+        self.schedule_flush_if_needed(&db).await;
+        self.log_if_slow("delete_if", started);
+        Ok(true)
+    }
+
+    /// Flushes the memtable to a new SSTable, if it holds anything.
+    pub async fn flush(&self) -> Result<()> {
+        let mut db = self.db.write().await;
+        if db.memtable.is_empty() {
+            return Ok(());
+        }
+        db.flush().await
+    }
+
+    /// Starts a background flush of the memtable, returning a handle that
+    /// resolves once the new SSTable is committed.
+    pub async fn flush_async(&self) -> FlushHandle {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.spawn_flush(Some(done_tx)).await;
+        FlushHandle(done_rx)
+    }
+
+    /// Merges every SSTable into one.
+    pub async fn compact(&self) -> Result<()> {
+        self.db.write().await.compact().await
+    }
+
+    /// Merges the SSTables whose data files are numbered `numbers`, e.g. the
+    /// two newest small ones; see [`DatabaseImpl::compact_tables`].
+    pub async fn compact_tables(&self, numbers: &[u64]) -> Result<()> {
+        self.db.write().await.compact_tables(numbers).await
+    }
+
+    /// Reports which tables a compaction would merge if it ran now, whether
+    /// the compaction policy would start one, and estimates of the output
+    /// size and reclaimed disk space, without compacting anything; see
+    /// [`DatabaseImpl::compaction_plan`].
+    pub async fn compaction_plan(&self) -> Result<CompactionPlan> {
+        let db = self.db.read().await;
+        let mut plan = db.compaction_plan().await?;
+        plan.reason = compaction_reason(&db, &self.options, self.clock.now_millis());
+        Ok(plan)
+    }
 
-        if db.current_size > self.flush_threshold {
+    /// Rewrites the MANIFEST; see [`DatabaseImpl::rewrite_manifest`].
+    pub async fn rewrite_manifest(&self) -> Result<ManifestRewrite> {
+        self.db.write().await.rewrite_manifest().await
+    }
+
+    pub async fn dump(&self) -> Result<()> {
+        self.db.read().await.dump().await
+    }
+
+    pub async fn stats(&self) -> Stats {
+        let mut stats = self.db.read().await.stats();
+        stats.flush_failures = self.flush_status.failures.load(Ordering::SeqCst);
+        stats.last_flush_error = self.flush_status.last_error.lock().unwrap().clone();
+        stats.write_slowdowns = self.write_throttle.slowdowns.load(Ordering::SeqCst);
+        stats.write_stalls = self.write_throttle.stalls.load(Ordering::SeqCst);
+        stats.cache_loads = self.cache_loads.load(Ordering::Relaxed);
+        stats
+    }
+
+    /// Lists the tables; see [`DatabaseImpl::tables`].
+    pub async fn tables(&self) -> Result<Vec<TableSummary>> {
+        self.db.read().await.tables().await
+    }
+
+    /// Flushes the memtable and copies the database into `dest`.
+    ///
+    /// Writers are blocked only while flushing; the tables are then copied
+    /// without holding the lock, held so that compactions leave their
+    /// files in place until the copy is done.
+    pub async fn backup(&self, dest: &Path) -> Result<()> {
+        let source = {
+            let mut db = self.db.write().await;
+            if !db.memtable.is_empty() {
+                db.flush().await?;
+            }
+            db.backup_source()
+        };
+        source.copy_to(dest).await
+    }
+
+    /// Writes `entries`, sorted by key, straight to a new SSTable; see
+    /// [`DatabaseImpl::bulk_load`]. Other writes wait until the load is
+    /// done, and no keyspace events are published for the loaded keys.
+    pub async fn bulk_load<S>(&self, entries: S) -> Result<u64>
+    where
+        S: futures::Stream<Item = (String, Value)> + Unpin,
+    {
+        self.check_writable()?;
+        let started = Instant::now();
+        let mut db = self.db.write().await;
+        self.check_disk_quota(&db).await?;
+        let records = db.bulk_load(entries).await?;
+        self.evict_if_needed(&mut db);
+        self.log_if_slow("bulk_load", started);
+        Ok(records)
+    }
+
+    /// Adds an externally built SSTable (`.db` file plus its `.idx`) as the
+    /// newest table. See [`DatabaseImpl::ingest_sstable`].
+    pub async fn ingest_sstable(&self, path: &Path) -> Result<u64> {
+        self.db.write().await.ingest_sstable(path).await
+    }
+
+    /// Returns up to `n` live keys drawn at random without scanning the
+    /// database; see [`DatabaseImpl::sample_keys`].
+    pub async fn sample_keys(&self, n: usize) -> Result<Vec<String>> {
+        self.db.read().await.sample_keys(n).await
+    }
+
+    /// Reports key length, value length and type distributions, inspecting one
+    /// record out of every `every` (`1` scans everything).
+    ///
+    /// Only the memtable is read under the database lock; data files are
+    /// scanned afterwards so writes are not blocked for the whole scan.
+    pub async fn analyze(&self, every: usize) -> Result<KeyspaceReport> {
+        let mut analyzer = Analyzer::new(every);
+        let (data_files, dictionary, files, _version) = {
+            let db = self.db.read().await;
+            for (key, entry) in &db.memtable {
+                analyzer.visit(key, &entry.value);
+            }
+            let dictionary = db.sstable_set.dictionary.clone();
+            (db.data_files(), dictionary, db.files.clone(), db.current_version())
+        };
+        let _files = files.reserve(1).await;
+        analyzer.visit_files(&data_files, dictionary).await?;
+
+        Ok(analyzer.finish())
+    }
+
+    /// Checks the checksum of every record in every SSTable, reading at most
+    /// `bytes_per_sec` (unlimited if `None`).
+    ///
+    /// The database stays online: the lock is only held to list the tables,
+    /// whose files are held until they are verified.
+    pub async fn verify_checksums(&self, bytes_per_sec: Option<u64>) -> Result<ChecksumReport> {
+        let (data_files, files, _version) = {
+            let db = self.db.read().await;
+            (db.data_files(), db.files.clone(), db.current_version())
+        };
+        let _files = files.reserve(1).await;
+        verify::verify_files(&data_files, bytes_per_sec).await
+    }
+
+    /// Subscribes to keyspace events of the classes enabled in
+    /// `Config::notify_events`.
+    pub fn subscribe(&self) -> broadcast::Receiver<KeyspaceEvent> {
+        self.notifier.subscribe()
+    }
+
+    /// Same as [`Controller::subscribe`], receiving only the events that
+    /// pass `filter`.
+    pub fn subscribe_filtered(&self, filter: EventFilter) -> FilteredEvents {
+        FilteredEvents::new(self.notifier.subscribe(), filter)
+    }
+
+    /// Registers a hook invoked for every published keyspace event until shutdown.
+    pub async fn add_hook<F>(&self, hook: F)
+    where
+        F: Fn(KeyspaceEvent) + Send + 'static,
+    {
+        let mut events = self.notifier.subscribe();
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        self.workers.lock().await.spawn(async move {
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => hook(event),
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            log::warn!("Event hook lagged behind, {n} events dropped.")
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    async fn schedule_flush_if_needed(&self, db: &DatabaseImpl) {
+        if db.current_size > self.options.flush_threshold() {
+            self.spawn_flush(None).await;
+        }
+    }
+
+    /// Flushes the memtable in a worker, reporting the outcome to `done`, then
+    /// compacts if the compaction trigger or maximum table age is reached.
+    async fn spawn_flush(&self, done: Option<oneshot::Sender<Result<()>>>) {
+        let db_clone = self.db.clone();
+        let options = self.options.clone();
+        let clock = self.clock.clone();
+        let status = self.flush_status.clone();
+        self.workers.lock().await.spawn(async move {
+            let mut db = db_clone.write().await;
+            let result = if db.memtable.is_empty() {
+                Ok(())
+            } else {
+                db.flush().await
+            };
+            status.record(&result);
+            let flushed = result.is_ok();
+            if let Some(done) = done {
+                let _ = done.send(result);
+            }
+            if !flushed {
+                return;
+            }
+
+            if let Some(reason) = compaction_reason(&db, &options, clock.now_millis()) {
+                log::info!("{reason}");
+                if let Err(e) = db.compact().await {
+                    log::warn!(error:% = e; "Automatic compaction failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Rejects writes once the data files exceed `max_disk_bytes`, starting a
+    /// compaction to reclaim space if `compact_on_disk_full` is set. Deletes
+    /// are still accepted so that space can be freed.
+    async fn check_disk_quota(&self, db: &DatabaseImpl) -> Result<()> {
+        let limit = self.options.max_disk_bytes();
+        let usage = db.disk_usage();
+        if limit == 0 || usage < limit {
+            return Ok(());
+        }
+
+        if db.config.compact_on_disk_full
+            && db.sstable_set.sorted_runs() > 1
+            && !self.compacting_for_space.swap(true, Ordering::SeqCst)
+        {
+            log::warn!("Disk usage limit reached, compacting to reclaim space.");
             let db_clone = self.db.clone();
+            let compacting = self.compacting_for_space.clone();
             self.workers.lock().await.spawn(async move {
-                let _ = db_clone.write().await.flush().await;
+                if let Err(e) = db_clone.write().await.compact().await {
+                    log::warn!(error:% = e; "Compaction to reclaim space failed: {:?}", e);
+                }
+                compacting.store(false, Ordering::SeqCst);
             });
         }
+        Err(Error::new(
+            ErrorKind::StorageFull,
+            format!("Disk usage limit reached: {usage} of {limit} bytes used"),
+        ))
+    }
 
+    /// In cache mode, evicts least recently used keys once the byte budget
+    /// is exceeded.
+    fn evict_if_needed(&self, db: &mut DatabaseImpl) {
+        let evicted = match db.evict_over_budget() {
+            Ok(evicted) => evicted,
+            Err(e) => {
+                log::error!(error:% = e; "Unable to evict keys: {e}");
+                return;
+            }
+        };
+        if evicted.is_empty() {
+            return;
+        }
+        log::debug!("Evicted {} keys.", evicted.len());
+        if self.notifier.wants(EventKind::Evict) {
+            for key in evicted {
+                self.notifier.publish(EventKind::Evict, Some(key));
+            }
+        }
+    }
+
+    /// Delays writes once flushed tables reach `write_slowdown_tables`, one
+    /// step more for every further table, so that compactions keep up. At
+    /// `write_stop_tables` the write stalls while it compacts them itself.
+    async fn throttle_writes(&self) -> Result<()> {
+        let slowdown = self.options.write_slowdown_tables();
+        let stop = self.options.write_stop_tables();
+        if slowdown == 0 && stop == 0 {
+            return Ok(());
+        }
+        let throttle = &self.write_throttle;
+        let flushed = self.db.read().await.sstable_set.flushed_tables();
+
+        if stop > 0 && flushed >= stop {
+            throttle.stalls.fetch_add(1, Ordering::SeqCst);
+            let mut db = self.db.write().await;
+            // Checked again: a concurrent writer may have compacted already.
+            let flushed = db.sstable_set.flushed_tables();
+            if flushed >= stop {
+                log::warn!("{flushed} flushed tables reached the write stop limit, compacting.");
+                db.compact().await?;
+            }
+        } else if slowdown > 0 && flushed >= slowdown {
+            throttle.slowdowns.fetch_add(1, Ordering::SeqCst);
+            if !throttle.slowing.swap(true, Ordering::SeqCst) {
+                log::warn!("{flushed} flushed tables reached the write slowdown limit.");
+            }
+            tokio::time::sleep(SLOWDOWN_STEP * (flushed - slowdown + 1) as u32).await;
+        } else if throttle.slowing.swap(false, Ordering::SeqCst) {
+            log::info!("Writes are no longer delayed.");
+        }
         Ok(())
     }
+
+    fn check_writable(&self) -> Result<()> {
+        if !self.flush_status.is_poisoned() {
+            return Ok(());
+        }
+        let cause = self.flush_status.last_error.lock().unwrap().clone();
+        Err(Error::other(format!(
+            "Writes refused after a failed background flush: {}",
+            cause.unwrap_or_default()
+        )))
+    }
+
+    /// Returns the timeout of operations without one of their own, set by
+    /// `operation_timeout_ms`.
+    pub fn operation_timeout(&self) -> Option<Duration> {
+        let timeout = self.options.operation_timeout_ms();
+        (timeout > 0).then(|| Duration::from_millis(timeout))
+    }
+
+    fn log_if_slow(&self, operation: &str, started: Instant) {
+        let threshold = self.options.slow_log_threshold_ms();
+        let elapsed = started.elapsed();
+        if threshold > 0 && elapsed >= Duration::from_millis(threshold) {
+            match request::current() {
+                Some(request_id) => log::warn!(
+                    operation,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    request_id;
+                    "Slow {operation}: took {elapsed:?} (request {request_id})"
+                ),
+                None => log::warn!(
+                    operation,
+                    elapsed_ms = elapsed.as_millis() as u64;
+                    "Slow {operation}: took {elapsed:?}"
+                ),
+            }
+        }
+    }
+}
+
+/// Runs `operation`, failing with [`ErrorKind::TimedOut`] if a `timeout` is
+/// given and elapses first.
+async fn within<T>(
+    timeout: Option<Duration>,
+    name: &str,
+    operation: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return operation.await;
+    };
+    tokio::time::timeout(timeout, operation).await.unwrap_or_else(|_| {
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("{name} timed out after {timeout:?}"),
+        ))
+    })
+}
+
+/// Returns the bytes of a string or byte value.
+fn value_bytes(value: &Value) -> Result<&[u8]> {
+    match value {
+        Value::Str(s) => Ok(s.as_bytes()),
+        Value::Bytes(bytes) => Ok(bytes),
+        _ => Err(wrong_type("a string")),
+    }
+}
+
+/// Error for an operation on a key holding a value of another type.
+fn wrong_type(expected: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("WRONGTYPE the key doesn't hold {expected}"),
+    )
+}
+
+/// Deletes keys whose expiration time has passed.
+async fn sweep_expired(db: Arc<RwLock<DatabaseImpl>>, clock: Arc<dyn Clock>, notifier: Notifier) {
+    let now = clock.now_millis();
+    let due = db
+        .read()
+        .await
+        .next_expiration()
+        .is_some_and(|deadline| deadline <= now);
+    if !due {
+        return;
+    }
+
+    match db.write().await.expire_keys(now).await {
+        Ok(expired) => {
+            log::info!("Expired {} keys.", expired.len());
+            if notifier.wants(EventKind::Expire) {
+                for key in expired {
+                    notifier.publish(EventKind::Expire, Some(key));
+                }
+            }
+        }
+        Err(e) => log::warn!(error:% = e; "Failed to expire keys: {:?}", e),
+    }
+}
+
+/// Returns why the database is due for a compaction, if the compaction
+/// trigger or the maximum table age is reached.
+fn compaction_reason(db: &DatabaseImpl, options: &RuntimeOptions, now: u64) -> Option<String> {
+    let runs = db.sstable_set.sorted_runs();
+    let trigger = options.compaction_trigger();
+    if trigger > 0 && runs >= trigger {
+        return Some(format!("{trigger} sorted runs reached the compaction trigger."));
+    }
+    let max_age = options.compaction_max_table_age_ms();
+    let age = db.sstable_set.oldest_flushed_age(now)?;
+    (max_age > 0 && age >= max_age && runs > 1)
+        .then(|| format!("A table {age} ms old reached the maximum table age."))
+}
+
+/// Compacts if the compaction trigger or the maximum table age is reached.
+/// Besides following flushes, this catches tables aging past
+/// `compaction_max_table_age_ms` while no write comes in.
+async fn check_compaction(
+    db: Arc<RwLock<DatabaseImpl>>,
+    options: Arc<RuntimeOptions>,
+    clock: Arc<dyn Clock>,
+) {
+    let due = compaction_reason(&*db.read().await, &options, clock.now_millis());
+    if due.is_none() {
+        return;
+    }
+
+    let mut db = db.write().await;
+    // Checked again: a flush may have compacted in the meantime.
+    if let Some(reason) = compaction_reason(&db, &options, clock.now_millis()) {
+        log::info!("{reason}");
+        if let Err(e) = db.compact().await {
+            log::warn!(error:% = e; "Automatic compaction failed: {:?}", e);
+        }
+    }
+}
+
+/// Moves the files replaced by a compaction that readers have released to
+/// the trash, and deletes those that have been in it for `retention_ms`.
+async fn purge_trash(
+    db: Arc<RwLock<DatabaseImpl>>,
+    data_dir: PathBuf,
+    clock: Arc<dyn Clock>,
+    retention_ms: u64,
+) {
+    let released = db.read().await.has_released_files();
+    if released && let Err(e) = db.write().await.discard_released().await
+    {
+        log::warn!(error:% = e; "Unable to move released files to the trash: {e}");
+    }
+    match trash::purge(&data_dir, clock.now_millis(), retention_ms).await {
+        Ok(0) => {}
+        Ok(purged) => log::info!(purged; "Deleted {purged} files from the trash."),
+        Err(e) => log::warn!(error:% = e; "Unable to purge the trash: {e}"),
+    }
+}
+
+/// Checks the checksums of every table, at the maintenance IO rate.
+async fn scrub(db: Arc<RwLock<DatabaseImpl>>) {
+    let (data_files, bytes_per_sec, files, _version) = {
+        let db = db.read().await;
+        let bytes_per_sec = db.config.maintenance_bytes_per_sec;
+        (db.data_files(), bytes_per_sec, db.files.clone(), db.current_version())
+    };
+    let bytes_per_sec = (bytes_per_sec > 0).then_some(bytes_per_sec);
+    let verified = {
+        let _files = files.reserve(1).await;
+        verify::verify_files(&data_files, bytes_per_sec).await
+    };
+    match verified {
+        Ok(report) if report.is_ok() => log::info!("Scrub: {}", report.to_string().trim_end()),
+        Ok(report) => {
+            log::error!("Scrub found corrupt records: {report}");
+            quarantine_corrupt(&db, &report).await;
+        }
+        Err(e) => log::warn!(error:% = e; "Scrub failed: {:?}", e),
+    }
+}
+
+/// Checks the checksums of the next slice of the tables, sized for
+/// `Config::scrub_fraction_per_hour` of them to be checked every hour.
+async fn scrub_slice(db: Arc<RwLock<DatabaseImpl>>, cursor: Arc<Mutex<Option<ScrubCursor>>>) {
+    let (data_files, budget, bytes_per_sec, files, _version) = {
+        let db = db.read().await;
+        let slices_per_hour = (3600 / SCRUB_SLICE_INTERVAL.as_secs()) as f64;
+        let fraction = db.config.scrub_fraction_per_hour / slices_per_hour;
+        let budget = (db.disk_usage() as f64 * fraction).ceil() as u64;
+        let bytes_per_sec = db.config.maintenance_bytes_per_sec;
+        (db.data_files(), budget, bytes_per_sec, db.files.clone(), db.current_version())
+    };
+    let bytes_per_sec = (bytes_per_sec > 0).then_some(bytes_per_sec);
+    let mut cursor = cursor.lock().await;
+    let verified = {
+        let _files = files.reserve(1).await;
+        verify::verify_slice(&data_files, &mut cursor, budget, bytes_per_sec).await
+    };
+    match verified {
+        Ok(report) if report.is_ok() => log::debug!("Scrub: {}", report.to_string().trim_end()),
+        Ok(report) => {
+            log::error!("Scrub found corrupt records: {report}");
+            quarantine_corrupt(&db, &report).await;
+        }
+        Err(e) => log::warn!(error:% = e; "Scrub failed: {:?}", e),
+    }
+}
+
+/// Quarantines the tables `report` found corrupt, if `Config::scrub_quarantine`.
+async fn quarantine_corrupt(db: &RwLock<DatabaseImpl>, report: &ChecksumReport) {
+    let mut db = db.write().await;
+    if !db.config.scrub_quarantine {
+        return;
+    }
+    let tables = report.corrupt.iter().map(|block| block.table.clone()).collect();
+    if let Err(e) = db.quarantine_tables(&tables).await {
+        log::error!(error:% = e; "Unable to quarantine corrupt tables: {e}");
+    }
+}
+
+/// Logs a snapshot of the stats.
+async fn log_stats(db: Arc<RwLock<DatabaseImpl>>) {
+    log::info!("Stats snapshot:\n{}", db.read().await.stats());
 }
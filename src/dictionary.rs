@@ -0,0 +1,232 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use tokio::io::{Error, ErrorKind, Result};
+
+use crate::format;
+
+/// Shortest run of bytes worth encoding as a back reference.
+const MIN_MATCH: usize = 4;
+/// Length of the substrings counted when training.
+const GRAM_LEN: usize = 8;
+/// Largest value `decompress` produces, so corrupt input can't exhaust memory,
+/// and so the longest `compress` compresses: longer values are stored as is.
+const MAX_DECOMPRESSED_LEN: usize = 1 << 20;
+
+/// Bytes common to many values, used as a shared history when compressing
+/// values that are too small to compress well on their own.
+///
+/// Compressed values are a sequence of
+/// `[literal_len (varint)][literal bytes][match_len - 4 (varint)][distance (varint)]`
+/// ending with a literal run. A match copies `match_len` bytes starting
+/// `distance` bytes back in the dictionary followed by the output so far.
+#[derive(Debug)]
+pub struct Dictionary {
+    /// Increases every time a new dictionary is trained.
+    pub id: u64,
+    bytes: Vec<u8>,
+    /// Last position of every `MIN_MATCH`-byte string in `bytes`.
+    positions: HashMap<[u8; MIN_MATCH], usize>,
+}
+
+impl Dictionary {
+    pub fn new(id: u64, bytes: Vec<u8>) -> Self {
+        let positions = bytes
+            .windows(MIN_MATCH)
+            .enumerate()
+            .map(|(i, gram)| (gram.try_into().unwrap(), i))
+            .collect();
+        Self {
+            id,
+            bytes,
+            positions,
+        }
+    }
+
+    /// Name of the file the dictionary is stored in, inside the data directory.
+    pub fn file_name(&self) -> String {
+        format!("{:0>5}.dict", self.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Reads a dictionary written by [`Dictionary::write`]:
+    /// `[id (u64)][crc32 (u32)][bytes]`.
+    pub async fn load(path: &Path) -> Result<Self> {
+        let contents = tokio::fs::read(path).await?;
+        if contents.len() < 12 {
+            return Err(Error::new(ErrorKind::InvalidData, "Dictionary file is truncated"));
+        }
+        let id = u64::from_be_bytes(contents[..8].try_into().unwrap());
+        let crc = u32::from_be_bytes(contents[8..12].try_into().unwrap());
+        let bytes = contents[12..].to_vec();
+        if crc32fast::hash(&bytes) != crc {
+            return Err(Error::new(ErrorKind::InvalidData, "Dictionary checksum mismatch"));
+        }
+        Ok(Self::new(id, bytes))
+    }
+
+    /// Stores the dictionary in `data_dir`, under [`Dictionary::file_name`].
+    pub async fn write(&self, data_dir: &Path) -> Result<()> {
+        let mut contents = Vec::with_capacity(12 + self.bytes.len());
+        contents.extend(self.id.to_be_bytes());
+        contents.extend(crc32fast::hash(&self.bytes).to_be_bytes());
+        contents.extend(&self.bytes);
+
+        let path = data_dir.join(self.file_name());
+        let part = data_dir.join(format!("{}.part", self.file_name()));
+        tokio::fs::write(&part, contents).await?;
+        tokio::fs::rename(&part, &path).await
+    }
+
+    /// Compresses `input`, returning `None` if that wouldn't make it shorter
+    /// or it is too long to be decompressed again.
+    pub fn compress(&self, input: &[u8]) -> Option<Vec<u8>> {
+        if input.len() > MAX_DECOMPRESSED_LEN {
+            return None;
+        }
+        let dict_len = self.bytes.len();
+        let mut out = Vec::with_capacity(input.len());
+        // Last position of every `MIN_MATCH`-byte string seen in `input`.
+        let mut recent = HashMap::new();
+        let mut literal_start = 0;
+        let mut i = 0;
+
+        while i + MIN_MATCH <= input.len() {
+            let gram: [u8; MIN_MATCH] = input[i..i + MIN_MATCH].try_into().unwrap();
+            let best = [
+                recent.get(&gram).map(|&pos| dict_len + pos),
+                self.positions.get(&gram).copied(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(|start| (start, self.match_len(input, start, i)))
+            .max_by_key(|&(_, len)| len);
+            recent.insert(gram, i);
+
+            match best {
+                Some((start, len)) if len >= MIN_MATCH => {
+                    format::put_varint(&mut out, (i - literal_start) as u64);
+                    out.extend(&input[literal_start..i]);
+                    format::put_varint(&mut out, (len - MIN_MATCH) as u64);
+                    format::put_varint(&mut out, (dict_len + i - start) as u64);
+                    i += len;
+                    literal_start = i;
+                }
+                _ => i += 1,
+            }
+        }
+        format::put_varint(&mut out, (input.len() - literal_start) as u64);
+        out.extend(&input[literal_start..]);
+
+        (out.len() < input.len()).then_some(out)
+    }
+
+    /// Returns how many bytes of `input` starting at `at` match the window
+    /// (the dictionary followed by `input`) starting at `start`.
+    fn match_len(&self, input: &[u8], start: usize, at: usize) -> usize {
+        let dict_len = self.bytes.len();
+        (0..input.len() - at)
+            .take_while(|&len| {
+                let pos = start + len;
+                let byte = if pos < dict_len {
+                    self.bytes[pos]
+                } else {
+                    input[pos - dict_len]
+                };
+                byte == input[at + len]
+            })
+            .count()
+    }
+
+    /// Reverses [`Dictionary::compress`].
+    pub fn decompress(&self, mut input: &[u8]) -> Result<Vec<u8>> {
+        let corrupt = || Error::new(ErrorKind::InvalidData, "Corrupt compressed value");
+        let dict_len = self.bytes.len();
+        let mut out = Vec::new();
+
+        loop {
+            let literals = format::get_varint(&mut input)? as usize;
+            if literals > input.len() || out.len() + literals > MAX_DECOMPRESSED_LEN {
+                return Err(corrupt());
+            }
+            out.extend(&input[..literals]);
+            input = &input[literals..];
+            if input.is_empty() {
+                return Ok(out);
+            }
+
+            let len = format::get_varint(&mut input)? as usize + MIN_MATCH;
+            let distance = format::get_varint(&mut input)? as usize;
+            let window = dict_len + out.len();
+            if distance == 0 || distance > window || out.len() + len > MAX_DECOMPRESSED_LEN {
+                return Err(corrupt());
+            }
+            for pos in window - distance..window - distance + len {
+                let byte = if pos < dict_len {
+                    self.bytes[pos]
+                } else {
+                    out[pos - dict_len]
+                };
+                out.push(byte);
+            }
+        }
+    }
+}
+
+/// Builds dictionary contents of at most `max_len` bytes out of the samples
+/// sharing the most substrings with other samples. Returns an empty vector
+/// if the samples have nothing in common.
+pub fn train(samples: &[Vec<u8>], max_len: usize) -> Vec<u8> {
+    let mut counts: HashMap<&[u8], u64> = HashMap::new();
+    for sample in samples {
+        let grams: HashSet<_> = sample.windows(GRAM_LEN).collect();
+        for gram in grams {
+            *counts.entry(gram).or_default() += 1;
+        }
+    }
+
+    // A sample scores for every substring it shares with other samples.
+    let mut scored: Vec<_> = samples
+        .iter()
+        .map(|sample| {
+            let score: u64 = sample.windows(GRAM_LEN).map(|gram| counts[gram] - 1).sum();
+            (score, sample)
+        })
+        .filter(|&(score, _)| score > 0)
+        .collect();
+    scored.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+
+    let mut covered = HashSet::new();
+    let mut chosen = Vec::new();
+    let mut len = 0;
+    for (_, sample) in scored {
+        if len >= max_len {
+            break;
+        }
+        // Skip samples mostly made of substrings already in the dictionary.
+        let grams = sample.windows(GRAM_LEN).count();
+        let new = sample
+            .windows(GRAM_LEN)
+            .filter(|gram| !covered.contains(gram))
+            .count();
+        if new * 2 < grams {
+            continue;
+        }
+        covered.extend(sample.windows(GRAM_LEN));
+        let take = sample.len().min(max_len - len);
+        chosen.push(&sample[..take]);
+        len += take;
+    }
+
+    // The most useful samples go last, closest to the values referencing them.
+    chosen.into_iter().rev().flatten().copied().collect()
+}
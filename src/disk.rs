@@ -0,0 +1,61 @@
+use std::{io::Result, path::Path};
+
+/// Returns the number of bytes available to unprivileged users on the
+/// filesystem containing `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<u64> {
+    use std::{ffi::CString, io::Error, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` points to
+    // writable memory large enough for a `statvfs`.
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: `statvfs` returned successfully, so `stat` is initialized.
+    let stat = unsafe { stat.assume_init() };
+    // Field widths differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Free disk space is only available on Unix",
+    ))
+}
+
+/// Makes the creation, renaming and removal of entries of `dir` durable.
+#[cfg(unix)]
+pub async fn sync_dir(dir: &Path) -> Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await
+}
+
+#[cfg(not(unix))]
+pub async fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Tells the OS that `file` will be read sequentially from `offset`, so that
+/// it reads further ahead. Only a hint: failures are ignored.
+#[cfg(target_os = "linux")]
+pub fn advise_sequential(file: &tokio::fs::File, offset: u64) {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is owned by `file`, which outlives the call.
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            0,
+            libc::POSIX_FADV_SEQUENTIAL,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_sequential(_file: &tokio::fs::File, _offset: u64) {}
@@ -0,0 +1,410 @@
+use std::{collections::HashSet, fmt, path::Path, sync::Arc};
+
+use tokio::{
+    fs::File,
+    io::{BufReader, ErrorKind, Result},
+};
+
+use crate::{
+    Manifest,
+    dictionary::Dictionary,
+    disk,
+    layout,
+    lock::{self, LockStatus},
+    recovery,
+    sparse_index::{self, IndexCorruption},
+    sstable_set::TableScan,
+    version,
+};
+
+/// Below this many free bytes the doctor warns regardless of database size.
+const MIN_FREE_SPACE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+    /// What the operator should do about it, if anything.
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DoctorReport {
+    pub findings: Vec<Finding>,
+}
+
+impl DoctorReport {
+    /// Returns `true` if no finding prevents opening the database.
+    pub fn is_healthy(&self) -> bool {
+        self.findings.iter().all(|f| f.severity < Severity::Error)
+    }
+
+    fn info(&mut self, message: impl Into<String>) {
+        self.push(Severity::Info, message.into(), None);
+    }
+
+    fn warn(&mut self, message: impl Into<String>, hint: impl Into<String>) {
+        self.push(Severity::Warning, message.into(), Some(hint.into()));
+    }
+
+    fn error(&mut self, message: impl Into<String>, hint: impl Into<String>) {
+        self.push(Severity::Error, message.into(), Some(hint.into()));
+    }
+
+    fn push(&mut self, severity: Severity, message: String, hint: Option<String>) {
+        self.findings.push(Finding {
+            severity,
+            message,
+            hint,
+        });
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(f, "[{}] {}", finding.severity, finding.message)?;
+            if let Some(hint) = &finding.hint {
+                writeln!(f, "    -> {hint}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Inspects `data_dir` without opening the database.
+///
+/// Checks the manifest against the files on disk, version compatibility, the
+/// directory lock, free disk space, and whether any table file is truncated,
+/// corrupt or out of order.
+pub async fn doctor(data_dir: &Path) -> Result<DoctorReport> {
+    let mut report = DoctorReport::default();
+
+    if tokio::fs::metadata(data_dir).await.is_err() {
+        report.error(
+            format!("Data directory {} does not exist", data_dir.display()),
+            "Create it, or start the server with `create_if_missing = true`.",
+        );
+        return Ok(report);
+    }
+
+    match lock::status(data_dir)? {
+        LockStatus::Held => report.error(
+            "Data directory is locked by a running instance",
+            "Stop the other instance before opening the database read-write.",
+        ),
+        LockStatus::Free | LockStatus::Missing => report.info("Data directory is not locked"),
+    }
+
+    let total_size = check_files(&mut report, data_dir, false).await?;
+
+    match disk::available_space(data_dir) {
+        Ok(free) if free < MIN_FREE_SPACE.max(total_size) => report.warn(
+            format!("Only {free} bytes of free disk space left"),
+            "Compaction may need up to the current database size in free space.",
+        ),
+        Ok(free) => report.info(format!("{free} bytes of free disk space")),
+        Err(e) => report.warn(
+            format!("Unable to determine free disk space: {e}"),
+            "Check free space manually.",
+        ),
+    }
+
+    Ok(report)
+}
+
+/// Checks the backup in `backup_dir`: its manifest, and the checksums and key
+/// order of every table it lists. Only `backup_dir` is read, so this can run
+/// next to a live instance.
+pub async fn verify_backup(backup_dir: &Path) -> Result<DoctorReport> {
+    let mut report = DoctorReport::default();
+
+    if tokio::fs::metadata(backup_dir).await.is_err() {
+        report.error(
+            format!("Backup directory {} does not exist", backup_dir.display()),
+            "Check the path of the backup.",
+        );
+        return Ok(report);
+    }
+    check_files(&mut report, backup_dir, true).await?;
+    Ok(report)
+}
+
+/// Checks the manifest of `data_dir` and the files it lists or that lie
+/// around, returning the size of the referenced data files. A missing
+/// manifest is an error in a `backup`, which can't be opened without it.
+async fn check_files(report: &mut DoctorReport, data_dir: &Path, backup: bool) -> Result<u64> {
+    let manifest_path = data_dir.join("MANIFEST");
+    let manifest = match tokio::fs::read_to_string(&manifest_path).await {
+        Ok(contents) => match toml::from_str::<Manifest>(&contents) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                report.error(
+                    format!("Unable to parse MANIFEST: {e}"),
+                    "Restore MANIFEST from a backup, or rebuild it with `repair`.",
+                );
+                None
+            }
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound && backup => {
+            report.error(
+                "No MANIFEST file found",
+                "The backup is incomplete; take a new one.",
+            );
+            None
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            report.warn(
+                "No MANIFEST file found",
+                "A new empty database will be created on open; any existing table files will be ignored.",
+            );
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut referenced = HashSet::new();
+    let mut total_size = 0u64;
+
+    if let Some(manifest) = &manifest {
+        if manifest.version == version::VERSION {
+            report.info(format!("MANIFEST version {} is supported", manifest.version));
+        } else {
+            report.error(
+                format!(
+                    "MANIFEST version {} is not supported by this build ({})",
+                    manifest.version,
+                    version::VERSION
+                ),
+                "Open the data directory with a matching version of the server.",
+            );
+        }
+
+        let mut dictionary = None;
+        if let Some(path) = &manifest.dictionary {
+            referenced.insert(path.clone());
+            match Dictionary::load(&data_dir.join(path)).await {
+                Ok(loaded) => {
+                    report.info(format!(
+                        "Dictionary {} holds {} bytes",
+                        path.display(),
+                        loaded.len()
+                    ));
+                    dictionary = Some(Arc::new(loaded));
+                }
+                Err(e) => report.error(
+                    format!("Unable to read dictionary {}: {e}", path.display()),
+                    "Compressed values can't be read; restore the dictionary from a backup.",
+                ),
+            }
+        }
+
+        let mut generations = HashSet::new();
+        for entry in &manifest.sstables {
+            referenced.insert(entry.data_path.clone());
+            referenced.insert(entry.index_path.clone());
+            match entry.generation {
+                Some(generation) if !generations.insert(generation) => report.error(
+                    format!(
+                        "{} shares generation {generation} with another table",
+                        entry.data_path.display()
+                    ),
+                    "Which table holds the newest version of a key is undefined; restore MANIFEST from a backup.",
+                ),
+                _ => {}
+            }
+            total_size += check_table(
+                report,
+                &data_dir.join(&entry.data_path),
+                &data_dir.join(&entry.index_path),
+                dictionary.clone(),
+            )
+            .await?;
+        }
+        if !manifest.obsolete.is_empty() {
+            referenced.extend(manifest.obsolete.iter().cloned());
+            report.warn(
+                format!(
+                    "{} files replaced by a compaction are still listed in MANIFEST",
+                    manifest.obsolete.len()
+                ),
+                "Readers still pinned them, or the compaction was interrupted after committing; they are moved to the trash on the next open.",
+            );
+        }
+        let legacy = manifest
+            .sstables
+            .iter()
+            .filter(|entry| entry.generation.is_none())
+            .count();
+        if legacy > 0 {
+            report.warn(
+                format!("{legacy} tables have no generation"),
+                "Tables are ordered as listed in MANIFEST until the next flush or compaction rewrites it.",
+            );
+        }
+        report.info(format!(
+            "{} tables referenced by MANIFEST, {} bytes",
+            manifest.sstables.len(),
+            total_size
+        ));
+    }
+
+    for name in layout::list_files(data_dir).await? {
+        let Some(name_str) = name.to_str() else {
+            continue;
+        };
+        if name_str.ends_with(".part") {
+            report.warn(
+                format!("Leftover partial file {name_str}"),
+                "An interrupted write left it behind; it is deleted on the next open.",
+            );
+        } else if let Some(manifest) = &manifest
+            && recovery::is_leftover(manifest, &name)
+        {
+            report.warn(
+                format!("{name_str} is not referenced by MANIFEST"),
+                "An interrupted write left it behind; it is deleted on the next open.",
+            );
+        } else if let Some(manifest) = &manifest
+            && (manifest.layout.is_data_file(&name)
+                || manifest.layout.is_index_file(&name)
+                || name_str.ends_with(".dict"))
+            && !referenced.contains(&name)
+        {
+            report.warn(
+                format!("{name_str} is not referenced by MANIFEST"),
+                "It is ignored on open; move it out of the data directory once no longer needed.",
+            );
+        }
+    }
+
+    Ok(total_size)
+}
+
+/// Checks one table's data and index files, returning the data file size.
+async fn check_table(
+    report: &mut DoctorReport,
+    data_path: &Path,
+    index_path: &Path,
+    dictionary: Option<Arc<Dictionary>>,
+) -> Result<u64> {
+    let data_len = match tokio::fs::metadata(data_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            report.error(
+                format!("Missing data file {}", data_path.display()),
+                "Restore the file from a backup, or remove its entry from MANIFEST.",
+            );
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let mut scan = match TableScan::open(data_path).await {
+        Ok(scan) => scan.with_dictionary(dictionary),
+        Err(e) => {
+            report.error(
+                format!("Unable to read {}: {e}", data_path.display()),
+                "Restore the data file from a backup.",
+            );
+            return Ok(data_len);
+        }
+    };
+    let records_len = scan.layout().data_len;
+
+    let index = match File::open(index_path).await {
+        Ok(file) => sparse_index::read_from(BufReader::new(file), records_len).await,
+        Err(e) => Err(e),
+    };
+    match index {
+        Ok(index) if index.is_empty() => report.warn(
+            format!("Empty index file {}", index_path.display()),
+            "It is rebuilt from the data file on open.",
+        ),
+        Err(e) if e.kind() == ErrorKind::NotFound => report.warn(
+            format!("Missing index file {}", index_path.display()),
+            "It is rebuilt from the data file on open.",
+        ),
+        Ok(_) => {}
+        Err(e) => match sparse_index::corruption(&e) {
+            Some(
+                corruption @ (IndexCorruption::LengthMismatch { .. }
+                | IndexCorruption::PastEnd { .. }),
+            ) => report.error(
+                format!(
+                    "Index {} doesn't match {}: {corruption}",
+                    index_path.display(),
+                    data_path.display()
+                ),
+                "The data file is truncated; restore it from a backup.",
+            ),
+            Some(corruption) => report.warn(
+                format!("Corrupt index file {}: {corruption}", index_path.display()),
+                "It is rebuilt from the data file on open.",
+            ),
+            None => report.error(
+                format!("Unable to read index {}: {e}", index_path.display()),
+                "Restore the index from a backup, or rebuild it with `repair`.",
+            ),
+        },
+    }
+
+    let mut last_key = None::<String>;
+    loop {
+        let offset = scan.offset();
+        match scan.next().await {
+            Ok(Some(record)) => {
+                if last_key.as_ref().is_some_and(|last| *last >= record.key) {
+                    report.error(
+                        format!(
+                            "Keys out of order in {} at offset {offset}",
+                            data_path.display()
+                        ),
+                        "Lookups may miss keys of this table; restore it from a backup.",
+                    );
+                    return Ok(data_len);
+                }
+                last_key = Some(record.key);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                report.error(
+                    format!(
+                        "Corrupt record in {} at offset {offset}: {e}",
+                        data_path.display()
+                    ),
+                    "Restore the data file from a backup.",
+                );
+                return Ok(data_len);
+            }
+        }
+    }
+    let offset = scan.offset();
+    if offset != records_len {
+        report.error(
+            format!(
+                "{} is truncated: last complete record ends at {offset} of {records_len} bytes",
+                data_path.display()
+            ),
+            "Restore the data file from a backup.",
+        );
+    }
+
+    Ok(data_len)
+}
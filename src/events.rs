@@ -0,0 +1,291 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{Value, ValueType};
+
+/// Capacity of the keyspace event channel. Subscribers lagging further behind
+/// than this miss events.
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Set,
+    Delete,
+    /// The key's expiration time passed and the TTL sweeper deleted it, see
+    /// `Config::ttl_sweep_interval_ms`. Named `expire`, also accepted as
+    /// `expired`.
+    Expire,
+    /// The key was evicted to stay within `Config::cache_max_bytes`.
+    Evict,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Set => "set",
+            EventKind::Delete => "delete",
+            EventKind::Expire => "expire",
+            EventKind::Evict => "evict",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<EventKind> {
+        match name {
+            "set" => Some(EventKind::Set),
+            "delete" => Some(EventKind::Delete),
+            "expire" | "expired" => Some(EventKind::Expire),
+            "evict" => Some(EventKind::Evict),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyspaceEvent {
+    pub kind: EventKind,
+    pub key: String,
+    /// New value of the key for `set` events, `None` for the others.
+    pub value: Option<Value>,
+}
+
+/// Selects the keyspace events a subscriber receives, see
+/// `Controller::subscribe_filtered`. Events are matched as they're received,
+/// so those left out never reach the subscriber, e.g. a network client.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    /// Kinds of events received, all of them if empty.
+    pub kinds: Vec<EventKind>,
+    /// Pattern the keys must match: `*` matches any characters, `?` any one
+    /// character, and `\` matches the character following it.
+    pub key_pattern: Option<String>,
+    /// Conditions on the new value of `set` events. Other events carry no
+    /// value, and pass as long as their kind and key do.
+    pub value: ValueFilter,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &KeyspaceEvent) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && self.key_pattern.as_deref().is_none_or(|pattern| glob_matches(pattern, &event.key))
+            && event.value.as_ref().is_none_or(|value| self.value.matches(value))
+    }
+}
+
+/// Conditions a value must meet, each left out if `None`.
+#[derive(Clone, Debug, Default)]
+pub struct ValueFilter {
+    pub value_type: Option<ValueType>,
+    /// Smallest value accepted. Integers and floats compare with each other,
+    /// strings and bytes with values of their own type; values that don't
+    /// compare with a bound never pass it.
+    pub min: Option<Value>,
+    /// Largest value accepted, compared as `min` is.
+    pub max: Option<Value>,
+}
+
+impl ValueFilter {
+    pub fn matches(&self, value: &Value) -> bool {
+        self.value_type.is_none_or(|value_type| value.value_type() == value_type)
+            && self.min.as_ref().is_none_or(|min| compare(value, min).is_some_and(Ordering::is_ge))
+            && self.max.as_ref().is_none_or(|max| compare(value, max).is_some_and(Ordering::is_le))
+    }
+}
+
+/// Orders `a` and `b`, if they're both numbers or of the same ordered type.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Int64(a), Value::Int64(b)) => Some(a.cmp(b)),
+        (Value::Int64(a), Value::Float64(b)) => (*a as f64).partial_cmp(b),
+        (Value::Float64(a), Value::Int64(b)) => a.partial_cmp(&(*b as f64)),
+        (Value::Float64(a), Value::Float64(b)) => a.partial_cmp(b),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        (Value::Bytes(a), Value::Bytes(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Returns whether `key` matches `pattern`, see [`EventFilter::key_pattern`].
+fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // The pattern past the last `*` seen, and the key from where it would
+    // resume should the rest fail to match: one more character taken by the
+    // `*`.
+    let mut retry = None;
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                retry = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('\\') => (pattern.get(p + 1) == Some(&key[k])).then_some(2),
+            Some(&c) => (c == key[k]).then_some(1),
+            None => None,
+        };
+        match (step, retry) {
+            (Some(step), _) => {
+                p += step;
+                k += 1;
+            }
+            (None, Some((after_star, from))) => {
+                p = after_star;
+                k = from + 1;
+                retry = Some((after_star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Selects which kinds of keyspace events are published.
+///
+/// Events of a disabled class are never constructed, so leaving notifications
+/// off (the default) costs nothing on the write path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventClasses {
+    pub set: bool,
+    pub delete: bool,
+    pub expire: bool,
+    pub evict: bool,
+}
+
+impl EventClasses {
+    pub const NONE: EventClasses = EventClasses {
+        set: false,
+        delete: false,
+        expire: false,
+        evict: false,
+    };
+
+    pub const ALL: EventClasses = EventClasses {
+        set: true,
+        delete: true,
+        expire: true,
+        evict: true,
+    };
+
+    pub fn contains(&self, kind: EventKind) -> bool {
+        match kind {
+            EventKind::Set => self.set,
+            EventKind::Delete => self.delete,
+            EventKind::Expire => self.expire,
+            EventKind::Evict => self.evict,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+}
+
+/// Parses `all`, `none`, or a comma-separated list of event kinds.
+impl FromStr for EventClasses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "all" => return Ok(Self::ALL),
+            "none" | "" => return Ok(Self::NONE),
+            _ => {}
+        }
+
+        let mut classes = Self::NONE;
+        for name in s.split(',') {
+            match EventKind::parse(name.trim()) {
+                Some(EventKind::Set) => classes.set = true,
+                Some(EventKind::Delete) => classes.delete = true,
+                Some(EventKind::Expire) => classes.expire = true,
+                Some(EventKind::Evict) => classes.evict = true,
+                None => return Err(format!("Unknown event kind {name:?}")),
+            }
+        }
+        Ok(classes)
+    }
+}
+
+/// Publishing side of the keyspace event channel.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    tx: broadcast::Sender<KeyspaceEvent>,
+    classes: EventClasses,
+}
+
+impl Notifier {
+    pub fn new(classes: EventClasses) -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { tx, classes }
+    }
+
+    /// Returns `true` if an event of this kind would reach at least one subscriber.
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.classes.contains(kind) && self.tx.receiver_count() > 0
+    }
+
+    /// Publishes an event for `key`; `None` means the caller skipped building it
+    /// because [`Notifier::wants`] returned `false`.
+    pub fn publish(&self, kind: EventKind, key: Option<String>) {
+        if let Some(key) = key {
+            let _ = self.tx.send(KeyspaceEvent {
+                kind,
+                key,
+                value: None,
+            });
+        }
+    }
+
+    /// Publishes a `set` event for a key and its new value, skipped as with
+    /// [`Notifier::publish`].
+    pub fn publish_set(&self, set: Option<(String, Value)>) {
+        if let Some((key, value)) = set {
+            let _ = self.tx.send(KeyspaceEvent {
+                kind: EventKind::Set,
+                key,
+                value: Some(value),
+            });
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<KeyspaceEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// Keyspace events passing an [`EventFilter`], as returned by
+/// `Controller::subscribe_filtered`.
+#[derive(Debug)]
+pub struct FilteredEvents {
+    events: broadcast::Receiver<KeyspaceEvent>,
+    filter: EventFilter,
+}
+
+impl FilteredEvents {
+    pub fn new(events: broadcast::Receiver<KeyspaceEvent>, filter: EventFilter) -> Self {
+        Self { events, filter }
+    }
+
+    /// Receives the next event passing the filter, as
+    /// [`broadcast::Receiver::recv`] does. Lagging behind reports the number
+    /// of events missed, filtered or not.
+    pub async fn recv(&mut self) -> Result<KeyspaceEvent, RecvError> {
+        loop {
+            let event = self.events.recv().await?;
+            if self.filter.matches(&event) {
+                return Ok(event);
+            }
+        }
+    }
+}
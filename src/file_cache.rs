@@ -0,0 +1,167 @@
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, Result},
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+};
+
+/// Keeps data files open between reads, and reserves descriptors for the
+/// table files opened outside of it, never holding more than a fixed number
+/// of descriptors at once.
+///
+/// When the limit is reached the least recently used idle file is closed; if
+/// every file is in use, readers wait for one to be returned instead of
+/// failing with `EMFILE`.
+#[derive(Debug)]
+pub struct FileCache {
+    limit: usize,
+    permits: Arc<Semaphore>,
+    /// Idle files, least recently used first.
+    idle: Mutex<VecDeque<CachedFile>>,
+    /// Signalled whenever a file is returned, as idle files keep their permit.
+    returned: Notify,
+}
+
+#[derive(Debug)]
+struct CachedFile {
+    path: PathBuf,
+    file: File,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Descriptors reserved with [`FileCache::reserve`], given back when dropped.
+#[derive(Debug)]
+pub struct FilePermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// A file checked out of a [`FileCache`], returned to it when dropped. Its
+/// position is unspecified, so callers seek before reading.
+#[derive(Debug)]
+pub struct CachedHandle<'a> {
+    cache: &'a FileCache,
+    entry: Option<CachedFile>,
+}
+
+impl FileCache {
+    /// Creates a cache holding at most `limit` open files (`0` for no limit).
+    pub fn new(limit: usize) -> Self {
+        let limit = if limit == 0 { Semaphore::MAX_PERMITS } else { limit };
+        Self {
+            limit,
+            permits: Arc::new(Semaphore::new(limit)),
+            idle: Mutex::new(VecDeque::new()),
+            returned: Notify::new(),
+        }
+    }
+
+    /// Returns an open handle on `path`, reusing an idle one if possible.
+    pub async fn open(&self, path: &Path) -> Result<CachedHandle<'_>> {
+        let permit = loop {
+            let cached = {
+                let mut idle = self.idle.lock().unwrap();
+                idle.iter()
+                    .rposition(|entry| entry.path == path)
+                    .and_then(|i| idle.remove(i))
+            };
+            if let Some(mut entry) = cached {
+                // A reader cancelled mid-read returns the file with the read
+                // still in flight; wait for it so the next seek succeeds.
+                entry.file.flush().await?;
+                return Ok(self.handle(entry));
+            }
+
+            if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+                break permit;
+            }
+            // Closing an idle file frees a permit; with none idle, wait for a
+            // permit or for a file to be returned.
+            if self.idle.lock().unwrap().pop_front().is_some() {
+                continue;
+            }
+            tokio::select! {
+                permit = self.permits.clone().acquire_owned() => {
+                    break permit.expect("the semaphore is never closed");
+                }
+                _ = self.returned.notified() => {}
+            }
+        };
+        let file = File::open(path).await?;
+        Ok(self.handle(CachedFile {
+            path: path.to_path_buf(),
+            file,
+            _permit: permit,
+        }))
+    }
+
+    /// Reserves `files` descriptors for files opened outside the cache, such
+    /// as the tables of a scan or the inputs and outputs of a compaction,
+    /// closing idle files to make room. They are reserved all at once, so
+    /// that operations waiting for descriptors hold none in the meantime and
+    /// can't wait on each other. An operation needing more files than the
+    /// limit reserves all of them, running alone rather than not at all.
+    pub async fn reserve(&self, files: usize) -> FilePermit {
+        let files = files.min(self.limit) as u32;
+        let permit = loop {
+            if let Ok(permit) = self.permits.clone().try_acquire_many_owned(files) {
+                break permit;
+            }
+            if self.idle.lock().unwrap().pop_front().is_some() {
+                continue;
+            }
+            tokio::select! {
+                permit = self.permits.clone().acquire_many_owned(files) => {
+                    break permit.expect("the semaphore is never closed");
+                }
+                _ = self.returned.notified() => {}
+            }
+        };
+        FilePermit { _permit: permit }
+    }
+
+    /// Closes every idle file, e.g. after the files they refer to were replaced.
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Returns the number of files currently open, idle or in use.
+    pub fn open_files(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    fn handle(&self, entry: CachedFile) -> CachedHandle<'_> {
+        CachedHandle {
+            cache: self,
+            entry: Some(entry),
+        }
+    }
+}
+
+impl Deref for CachedHandle<'_> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.entry.as_ref().expect("present until dropped").file
+    }
+}
+
+impl DerefMut for CachedHandle<'_> {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.entry.as_mut().expect("present until dropped").file
+    }
+}
+
+impl Drop for CachedHandle<'_> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.cache.idle.lock().unwrap().push_back(entry);
+            self.cache.returned.notify_one();
+        }
+    }
+}
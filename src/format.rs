@@ -0,0 +1,145 @@
+use std::io::SeekFrom;
+
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind,
+    Result,
+};
+
+/// Length of the footer ending v2 and later data files:
+/// `[base_seq (u64)][format_version (u32)][magic]`.
+pub const FOOTER_LEN: u64 = 8 + 4 + 8;
+const MAGIC: &[u8; 8] = b"LOGDBSST";
+
+/// How the records of a data file are framed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Fixed-width lengths and no sequence numbers. Files have no footer.
+    V1,
+    /// Varint lengths and per-record sequence numbers, stored relative to
+    /// `base_seq`, the smallest sequence number in the file.
+    V2 { base_seq: u64 },
+    /// Like v2, with each key stored as the length of the prefix it shares
+    /// with the previous key plus the remaining suffix. Records pointed at by
+    /// the sparse index share nothing, so lookups can start there.
+    V3 { base_seq: u64 },
+}
+
+impl Format {
+    pub fn version(&self) -> u32 {
+        match self {
+            Format::V1 => 1,
+            Format::V2 { .. } => 2,
+            Format::V3 { .. } => 3,
+        }
+    }
+
+    /// Returns the smallest sequence number stored in the file (`0` for v1).
+    pub fn base_seq(&self) -> u64 {
+        match self {
+            Format::V1 => 0,
+            Format::V2 { base_seq } | Format::V3 { base_seq } => *base_seq,
+        }
+    }
+
+    /// Returns the format with the given version number.
+    pub fn from_version(version: u32, base_seq: u64) -> Result<Self> {
+        match version {
+            1 => Ok(Format::V1),
+            2 => Ok(Format::V2 { base_seq }),
+            3 => Ok(Format::V3 { base_seq }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported table format version {version}"),
+            )),
+        }
+    }
+}
+
+/// Framing of a data file and the length of its records, footer excluded.
+#[derive(Clone, Copy, Debug)]
+pub struct TableLayout {
+    pub format: Format,
+    pub data_len: u64,
+}
+
+/// Reads the footer of a data file, if it has one, and rewinds it to the
+/// first record. Files without a footer are v1.
+pub async fn read_layout<R: AsyncRead + AsyncSeek + Unpin>(file: &mut R) -> Result<TableLayout> {
+    let len = file.seek(SeekFrom::End(0)).await?;
+    let mut layout = TableLayout {
+        format: Format::V1,
+        data_len: len,
+    };
+
+    if len >= FOOTER_LEN {
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64))).await?;
+        file.read_exact(&mut footer).await?;
+        if &footer[12..] == MAGIC {
+            let base_seq = u64::from_be_bytes(footer[..8].try_into().unwrap());
+            let version = u32::from_be_bytes(footer[8..12].try_into().unwrap());
+            layout = TableLayout {
+                format: Format::from_version(version, base_seq)?,
+                data_len: len - FOOTER_LEN,
+            };
+        }
+    }
+
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(layout)
+}
+
+/// Ends a data file written in `format`, returning the number of bytes written.
+pub async fn write_footer<W: AsyncWrite + Unpin>(writer: &mut W, format: Format) -> Result<u64> {
+    if format == Format::V1 {
+        return Ok(0);
+    }
+    writer.write_all(&format.base_seq().to_be_bytes()).await?;
+    writer.write_all(&format.version().to_be_bytes()).await?;
+    writer.write_all(MAGIC).await?;
+    Ok(FOOTER_LEN)
+}
+
+/// Appends `value` as a LEB128 varint.
+pub fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Returns the number of bytes `put_varint` uses for `value`.
+pub fn varint_len(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).div_ceil(7).max(1)
+}
+
+/// Reads a LEB128 varint from the start of `input`, advancing past it.
+pub fn get_varint(input: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Truncated varint"))?;
+        *input = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Varint is too long"))
+}
+
+/// Reads a LEB128 varint, appending its raw bytes to `raw`.
+pub async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R, raw: &mut Vec<u8>) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = reader.read_u8().await?;
+        raw.push(byte);
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Varint is too long"))
+}
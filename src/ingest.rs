@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use tokio::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Result},
+};
+
+use crate::{
+    format::TableLayout,
+    sparse_index::{self, SparseIndex},
+    sstable_set::TableScan,
+};
+
+/// An externally built SSTable that passed [`validate`].
+#[derive(Debug)]
+pub struct IngestedTable {
+    pub index: SparseIndex,
+    pub layout: TableLayout,
+    pub records: u64,
+    /// Smallest and largest key in the table.
+    pub first_key: String,
+    pub last_key: String,
+    /// Keys whose expiration time changes once the table is ingested, with
+    /// their new expiration time (`None` for values without one and tombstones).
+    pub expirations: Vec<(String, Option<u64>)>,
+}
+
+/// Checks that `data_path` holds checksummed records in strictly increasing
+/// key order and that every entry of the sparse index at `index_path` points
+/// at the record with the same key, which must be a restart point.
+///
+/// `tracked` tells whether a key currently has an expiration time, so that
+/// only the keys affecting the TTL index are collected.
+pub async fn validate(
+    data_path: &Path,
+    index_path: &Path,
+    tracked: impl Fn(&str) -> bool,
+) -> Result<IngestedTable> {
+    let mut scan = TableScan::open(data_path).await?;
+    let layout = scan.layout();
+    let index = sparse_index::read_from(BufReader::new(File::open(index_path).await?), layout.data_len)
+        .await?;
+    if index.is_empty() {
+        return Err(invalid(index_path, "index can't be empty"));
+    }
+
+    let mut entries = index.iter().peekable();
+    let mut expirations = Vec::new();
+    let mut records = 0;
+    let mut first_key = None;
+    let mut last_key: Option<String> = None;
+
+    loop {
+        let offset = scan.offset();
+        let Some(record) = scan
+            .next()
+            .await
+            .map_err(|e| invalid(data_path, &format!("record at offset {offset}: {e}")))?
+        else {
+            break;
+        };
+        if let Some(previous) = &last_key
+            && *previous >= record.key
+        {
+            return Err(invalid(
+                data_path,
+                &format!("key {:?} at offset {offset} is out of order", record.key),
+            ));
+        }
+        if let Some(&(key, &entry_offset)) = entries.peek()
+            && *key <= record.key
+        {
+            if *key != record.key || entry_offset != offset || !scan.at_restart() {
+                return Err(invalid(
+                    index_path,
+                    &format!("entry {key:?} doesn't point at its record"),
+                ));
+            }
+            entries.next();
+        }
+
+        let expires_at = record.value.expires_at();
+        if expires_at.is_some() || tracked(&record.key) {
+            expirations.push((record.key.clone(), expires_at));
+        }
+        records += 1;
+        first_key.get_or_insert_with(|| record.key.clone());
+        last_key = Some(record.key);
+    }
+
+    if let Some((key, _)) = entries.next() {
+        return Err(invalid(
+            index_path,
+            &format!("entry {key:?} doesn't point at a record"),
+        ));
+    }
+    let (Some(first_key), Some(last_key)) = (first_key, last_key) else {
+        return Err(invalid(data_path, "table has no records"));
+    };
+
+    Ok(IngestedTable {
+        index,
+        layout,
+        records,
+        first_key,
+        last_key,
+        expirations,
+    })
+}
+
+fn invalid(path: &Path, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Unable to ingest {}: {reason}", path.display()),
+    )
+}
@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::sync::{Notify, futures::Notified};
+
+/// Advisory locks on keys, see [`crate::Controller::lock`]. They only
+/// exclude each other: writes to a locked key go through.
+#[derive(Debug, Default)]
+pub(crate) struct KeyLocks {
+    held: Mutex<HashMap<String, Held>>,
+    next_token: AtomicU64,
+    /// Notified whenever a lock is released.
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct Held {
+    token: u64,
+    /// Time the lock is released at if not unlocked before, in milliseconds
+    /// since the Unix epoch.
+    expires_at: u64,
+}
+
+impl KeyLocks {
+    /// Takes the lock on `key` until `expires_at`, unless it is held at
+    /// `now`. Returns the token releasing it, or else when the lock held
+    /// expires.
+    pub(crate) fn try_lock(
+        &self,
+        key: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> std::result::Result<u64, u64> {
+        let mut held = self.held.lock().unwrap();
+        held.retain(|_, lock| lock.expires_at > now);
+        if let Some(lock) = held.get(key) {
+            return Err(lock.expires_at);
+        }
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed) + 1;
+        held.insert(key.to_string(), Held { token, expires_at });
+        Ok(token)
+    }
+
+    /// Releases the lock on `key` taken with `token`. Returns `false` if it
+    /// isn't held with that token at `now`, such as after it expired.
+    pub(crate) fn unlock(&self, key: &str, token: u64, now: u64) -> bool {
+        let mut held = self.held.lock().unwrap();
+        match held.get(key) {
+            Some(lock) if lock.token == token && lock.expires_at > now => {
+                held.remove(key);
+                self.released.notify_waiters();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Resolves once a lock is released, when enabled before checking it.
+    pub(crate) fn released(&self) -> Notified<'_> {
+        self.released.notified()
+    }
+}
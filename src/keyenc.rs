@@ -0,0 +1,292 @@
+//! Order-preserving encodings of composite keys, such as a tenant and a
+//! timestamp, so that keys sort like the tuples they are built from and a
+//! scan over a prefix of the components returns exactly the keys sharing it.
+//!
+//! Components are appended to a [`KeyEncoder`] and read back in the same
+//! order with a [`KeyDecoder`]:
+//!
+//! - strings are followed by a NUL and `\u{1}`, a NUL inside them being
+//!   written as a NUL and `\u{2}`, so that a string sorts before any longer
+//!   string it is a prefix of;
+//! - integers and timestamps (milliseconds since the UNIX epoch) are written
+//!   as 20 decimal digits, wide enough for any `u64`; signed integers are
+//!   first offset by `2^63`, so that negative ones sort first.
+//!
+//! The encoded keys are meant for the binary collation, under which keys
+//! sort by their bytes; see `Config::collation`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{Error, ErrorKind, Result};
+
+/// Digits of an encoded `u64`, enough for `u64::MAX`.
+const U64_DIGITS: usize = 20;
+/// Flipped in signed integers, mapping `i64::MIN..=i64::MAX` onto
+/// `0..=u64::MAX` in order.
+const SIGN_BIT: u64 = 1 << 63;
+/// Ends a string component.
+const STRING_END: &str = "\0\u{1}";
+/// Stands for a NUL inside a string component.
+const ESCAPED_NUL: &str = "\0\u{2}";
+
+/// Builds a composite key a component at a time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyEncoder {
+    key: String,
+}
+
+impl KeyEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `s`.
+    pub fn str(mut self, s: &str) -> Self {
+        self.key.push_str(&s.replace('\0', ESCAPED_NUL));
+        self.key.push_str(STRING_END);
+        self
+    }
+
+    /// Appends `n`.
+    pub fn u64(mut self, n: u64) -> Self {
+        self.key.push_str(&format!("{n:0U64_DIGITS$}"));
+        self
+    }
+
+    /// Appends `n`.
+    pub fn i64(self, n: i64) -> Self {
+        self.u64((n as u64) ^ SIGN_BIT)
+    }
+
+    /// Appends `time` to the millisecond. Times before the UNIX epoch are
+    /// encoded as the epoch.
+    pub fn timestamp(self, time: SystemTime) -> Self {
+        let millis = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+        self.u64(u64::try_from(millis).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the key built so far, which is also the prefix shared by every
+    /// key with these first components; see [`prefix_end`].
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    pub fn finish(self) -> String {
+        self.key
+    }
+}
+
+/// Reads the components of a key built with a [`KeyEncoder`], in the order
+/// they were appended.
+#[derive(Debug, Clone)]
+pub struct KeyDecoder<'a> {
+    rest: &'a str,
+}
+
+impl<'a> KeyDecoder<'a> {
+    pub fn new(key: &'a str) -> Self {
+        Self { rest: key }
+    }
+
+    /// Reads a component appended with [`KeyEncoder::str`].
+    pub fn str(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            let Some(nul) = self.rest.find('\0') else {
+                return Err(invalid("unterminated string component"));
+            };
+            s.push_str(&self.rest[..nul]);
+            let escape = &self.rest[nul..];
+            if let Some(rest) = escape.strip_prefix(STRING_END) {
+                self.rest = rest;
+                return Ok(s);
+            }
+            let Some(rest) = escape.strip_prefix(ESCAPED_NUL) else {
+                return Err(invalid("invalid escape in string component"));
+            };
+            s.push('\0');
+            self.rest = rest;
+        }
+    }
+
+    /// Reads a component appended with [`KeyEncoder::u64`].
+    pub fn u64(&mut self) -> Result<u64> {
+        let digits = self.rest.get(..U64_DIGITS).ok_or_else(|| invalid("truncated integer"))?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid("invalid integer component"));
+        }
+        let n = digits.parse().map_err(|_| invalid("integer component out of range"))?;
+        self.rest = &self.rest[U64_DIGITS..];
+        Ok(n)
+    }
+
+    /// Reads a component appended with [`KeyEncoder::i64`].
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok((self.u64()? ^ SIGN_BIT) as i64)
+    }
+
+    /// Reads a component appended with [`KeyEncoder::timestamp`].
+    pub fn timestamp(&mut self) -> Result<SystemTime> {
+        Ok(UNIX_EPOCH + Duration::from_millis(self.u64()?))
+    }
+
+    /// Returns whether every component has been read.
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    /// Returns what is left of the key.
+    pub fn rest(&self) -> &'a str {
+        self.rest
+    }
+}
+
+/// Returns the smallest key greater than every key starting with `prefix`,
+/// to end a scan of them, or `None` if there is none and the scan must be
+/// left unbounded.
+pub fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_string();
+    while let Some(last) = end.pop() {
+        // The character following `last`, skipping the surrogate range.
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid composite key: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Components of the keys compared in the tests below.
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    enum Component {
+        Str(String),
+        I64(i64),
+    }
+
+    fn encode(components: &[Component]) -> String {
+        components
+            .iter()
+            .fold(KeyEncoder::new(), |key, component| match component {
+                Component::Str(s) => key.str(s),
+                Component::I64(n) => key.i64(*n),
+            })
+            .finish()
+    }
+
+    fn strings() -> Vec<String> {
+        ["", "\0", "\0\0", "\0a", "\u{1}", "\u{2}", "a", "a\0", "a\0b", "a\u{1}", "ab", "b", "é"]
+            .map(str::to_string)
+            .to_vec()
+    }
+
+    fn integers() -> Vec<i64> {
+        vec![i64::MIN, i64::MIN + 1, -1_000_000, -10, -9, -1, 0, 1, 9, 10, 1_000_000, i64::MAX]
+    }
+
+    /// Asserts that sorting `tuples` and sorting their keys give the same
+    /// order, and that every key decodes to its tuple.
+    fn assert_order_preserved(mut tuples: Vec<Vec<Component>>) {
+        tuples.sort();
+        tuples.dedup();
+        let keys: Vec<String> = tuples.iter().map(|tuple| encode(tuple)).collect();
+        for (i, pair) in keys.windows(2).enumerate() {
+            assert!(
+                pair[0] < pair[1],
+                "{:?} sorts before {:?} but its key doesn't",
+                tuples[i],
+                tuples[i + 1]
+            );
+        }
+        for (tuple, key) in tuples.iter().zip(&keys) {
+            let mut decoder = KeyDecoder::new(key);
+            for component in tuple {
+                let decoded = match component {
+                    Component::Str(_) => Component::Str(decoder.str().unwrap()),
+                    Component::I64(_) => Component::I64(decoder.i64().unwrap()),
+                };
+                assert_eq!(&decoded, component);
+            }
+            assert!(decoder.is_empty());
+        }
+    }
+
+    #[test]
+    fn strings_sort_like_their_keys() {
+        assert_order_preserved(strings().into_iter().map(|s| vec![Component::Str(s)]).collect());
+    }
+
+    #[test]
+    fn integers_sort_like_their_keys_across_signs() {
+        assert_order_preserved(integers().into_iter().map(|n| vec![Component::I64(n)]).collect());
+        let unsigned = [0, 1, 9, 10, 99, 100, u64::MAX - 1, u64::MAX];
+        let keys: Vec<String> =
+            unsigned.iter().map(|&n| KeyEncoder::new().u64(n).finish()).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        for (n, key) in unsigned.iter().zip(&keys) {
+            assert_eq!(KeyDecoder::new(key).u64().unwrap(), *n);
+        }
+    }
+
+    #[test]
+    fn tuples_sort_like_their_keys() {
+        let mut tuples = Vec::new();
+        for first in strings() {
+            for second in integers() {
+                for third in strings() {
+                    tuples.push(vec![
+                        Component::Str(first.clone()),
+                        Component::I64(second),
+                        Component::Str(third),
+                    ]);
+                }
+            }
+        }
+        assert_order_preserved(tuples);
+    }
+
+    #[test]
+    fn prefixes_bound_exactly_the_keys_sharing_them() {
+        for tenant in strings() {
+            let prefix = KeyEncoder::new().str(&tenant);
+            let end = prefix_end(prefix.as_str()).unwrap();
+            for other in strings() {
+                for n in integers() {
+                    let key = KeyEncoder::new().str(&other).i64(n).finish();
+                    let inside = key.as_str() >= prefix.as_str() && key < end;
+                    assert_eq!(inside, other == tenant, "{other:?} under {tenant:?}");
+                }
+            }
+        }
+        assert_eq!(prefix_end(""), None);
+        assert_eq!(prefix_end("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(prefix_end("\u{D7FF}").as_deref(), Some("\u{E000}"));
+    }
+
+    #[test]
+    fn timestamps_round_trip_to_the_millisecond() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let key = KeyEncoder::new().timestamp(time + Duration::from_micros(456)).finish();
+        assert_eq!(KeyDecoder::new(&key).timestamp().unwrap(), time);
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        let key = KeyEncoder::new().timestamp(before_epoch).finish();
+        assert_eq!(KeyDecoder::new(&key).timestamp().unwrap(), UNIX_EPOCH);
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert!(KeyDecoder::new("abc").str().is_err());
+        assert!(KeyDecoder::new("a\0\u{3}").str().is_err());
+        assert!(KeyDecoder::new("123").u64().is_err());
+        assert!(KeyDecoder::new("0000000000000000000x").u64().is_err());
+        assert!(KeyDecoder::new("99999999999999999999").u64().is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{Error, ErrorKind, Result};
+
+use crate::disk;
+
+/// Where new tables are stored in the data directory and how their files are
+/// named, see `Config::layout`. Recorded in the MANIFEST, which also lists
+/// the path of every table: changing it only affects tables written
+/// afterwards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Layout {
+    /// Extension of data files.
+    pub data_extension: String,
+    /// Extension of index files.
+    pub index_extension: String,
+    /// Number of consecutive file numbers whose tables share a subdirectory,
+    /// named after the first of them (`0` keeps every table in the data
+    /// directory itself).
+    pub tables_per_dir: usize,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            data_extension: "db".to_string(),
+            index_extension: "idx".to_string(),
+            tables_per_dir: 0,
+        }
+    }
+}
+
+impl Layout {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fails unless the extensions are distinct, made of ASCII letters,
+    /// digits and underscores, and not used by other files of the data
+    /// directory.
+    pub fn validate(&self) -> Result<()> {
+        for extension in [&self.data_extension, &self.index_extension] {
+            let valid = !extension.is_empty()
+                && extension
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                && !matches!(extension.as_str(), "part" | "dict");
+            if !valid {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid table file extension {extension:?}"),
+                ));
+            }
+        }
+        if self.data_extension == self.index_extension {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Data and index files need different extensions",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the paths of the data and index files of the table numbered
+    /// `number`, relative to the data directory.
+    pub fn table_paths(&self, number: usize) -> (String, String) {
+        let dir = match self.tables_per_dir {
+            0 => String::new(),
+            n => format!("{:0>5}/", number / n * n),
+        };
+        (
+            format!("{dir}{number:0>5}.{}", self.data_extension),
+            format!("{dir}{number:0>5}.{}", self.index_extension),
+        )
+    }
+
+    pub fn is_data_file(&self, path: &Path) -> bool {
+        path.extension().and_then(|ext| ext.to_str()) == Some(self.data_extension.as_str())
+    }
+
+    pub fn is_index_file(&self, path: &Path) -> bool {
+        path.extension().and_then(|ext| ext.to_str()) == Some(self.index_extension.as_str())
+    }
+
+    /// Returns the path of the index file of the data file `data_path`.
+    pub fn index_path(&self, data_path: &Path) -> PathBuf {
+        data_path.with_extension(&self.index_extension)
+    }
+}
+
+/// Returns `true` if `name` is that of a subdirectory of the data directory
+/// holding tables, whatever `Layout::tables_per_dir` was when it was created.
+fn is_table_dir(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Returns the files of `data_dir` and of its table subdirectories, relative
+/// to `data_dir`.
+pub async fn list_files(data_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let mut entries = tokio::fs::read_dir(data_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = PathBuf::from(entry.file_name());
+        if !entry.file_type().await?.is_dir() {
+            files.push(name);
+        } else if name.to_str().is_some_and(is_table_dir) {
+            dirs.push(name);
+        }
+    }
+    for dir in dirs {
+        let mut entries = tokio::fs::read_dir(data_dir.join(&dir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                files.push(dir.join(entry.file_name()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the path of the `.part` file the table file `path` is written to
+/// before [`install`] moves it into place. It is kept in `data_dir` itself,
+/// so that subdirectories are only created for complete tables.
+pub fn part_path(data_dir: &Path, path: &str) -> PathBuf {
+    let name = Path::new(path).file_name().unwrap_or(path.as_ref());
+    let mut part = name.to_os_string();
+    part.push(".part");
+    data_dir.join(part)
+}
+
+/// Renames the `.part` file `part` to the table file `path` of `data_dir`,
+/// creating the subdirectory it goes in if needed.
+pub async fn install(data_dir: &Path, part: &Path, path: &str) -> Result<()> {
+    if let Some(dir) = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let dir = data_dir.join(dir);
+        if tokio::fs::metadata(&dir).await.is_err() {
+            tokio::fs::create_dir_all(&dir).await?;
+            disk::sync_dir(data_dir).await?;
+        }
+    }
+    tokio::fs::rename(part, data_dir.join(path)).await
+}
+
+/// Makes the files renamed into the subdirectory of the table file `path`
+/// durable. Files of the data directory itself are made durable by
+/// committing the MANIFEST.
+pub async fn sync_table_dir(data_dir: &Path, path: &str) -> Result<()> {
+    match Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => disk::sync_dir(&data_dir.join(dir)).await,
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use tokio::io::Result;
+
+use crate::{Controller, Value};
+
+/// A lease on a key, held by whoever acquired it until it is released or
+/// its time-to-live runs out, for simple coordination between processes
+/// sharing the database.
+///
+/// Unlike [`Controller::lock`], a lease is stored in the database: the key
+/// holds the lease's fencing token as an integer for as long as it's held,
+/// so it can be read like any other key and outlives a restart once
+/// flushed. Fencing tokens are the sequence number of the write acquiring
+/// the lease and so increase with every acquisition. A resource guarded by
+/// the lease can turn away requests carrying a lower token than the last
+/// it has seen, from a holder whose lease ran out without it noticing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub key: String,
+    pub token: u64,
+}
+
+impl Lease {
+    /// Acquires the lease on `key` for `ttl`, unless it is held. Returns
+    /// `None` if it is, or if the key holds anything else.
+    pub async fn acquire(database: &Controller, key: &str, ttl: Duration) -> Result<Option<Self>> {
+        let token = database
+            .compare_and_set_with(key, None, |seq| Value::Int64(seq as i64), Some(ttl))
+            .await?;
+        Ok(token.map(|token| Self {
+            key: key.to_string(),
+            token,
+        }))
+    }
+
+    /// Extends the lease to `ttl` from now, keeping its token. Returns
+    /// whether it was still held; if not, it has to be acquired again.
+    pub async fn renew(&self, database: &Controller, ttl: Duration) -> Result<bool> {
+        let value = self.value();
+        database.compare_and_set(&self.key, Some(&value), value.clone(), Some(ttl)).await
+    }
+
+    /// Releases the lease, returning whether it was still held.
+    pub async fn release(&self, database: &Controller) -> Result<bool> {
+        database.delete_if(&self.key, &self.value()).await
+    }
+
+    /// Value of the key while the lease is held.
+    fn value(&self) -> Value {
+        Value::Int64(self.token as i64)
+    }
+}
@@ -1,29 +1,120 @@
-use futures::future::try_join_all;
-use log;
-use memtable::MemTable;
-use record::MemValue;
-use sstable_set::{SSTable, SSTableSet};
-use std::{collections::BTreeMap, path::Path};
+use futures::{StreamExt, future::try_join_all, stream};
+use format::{Format, TableLayout};
+use memtable::{MemEntry, MemTable};
+use sparse_index::SparseIndex;
+use sstable_set::{SSTable, SSTableSet, TableScan};
+use file_cache::FileCache;
+use lru::LruTracker;
+use merge::{MergeIterator, Source};
+use rate_limit::RateLimiter;
+use ttl::TtlIndex;
+use version::{Version, VersionSet};
+use wal::Wal;
+use batch::BatchOp;
+pub use ttl::KeyTtl;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::{Bound, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     fs::File,
     io::{AsyncWriteExt, BufReader, BufWriter, Error, Result},
-    join,
 };
 
+mod analyze;
+mod batch;
+mod clock;
+mod collation;
 mod compact;
 mod config;
 mod controller;
+mod dictionary;
+mod disk;
+mod doctor;
+mod events;
+mod file_cache;
+mod format;
+mod ingest;
+pub mod keyenc;
+mod key_lock;
+mod layout;
+mod lease;
+mod loader;
+mod lock;
+mod lru;
 mod manifest;
+mod merge;
+mod options;
 mod memtable;
+mod rate_limit;
+mod reader;
+mod script;
+pub mod server;
 mod record;
+mod recovery;
+mod repair;
+mod request;
+mod restore;
+mod retention;
+mod sample;
+mod scan;
+mod schedule;
 mod sparse_index;
 mod sstable_set;
+mod stats;
+#[cfg(feature = "testing")]
+mod testing;
+mod trash;
+mod ttl;
+mod verify;
 mod version;
+mod wal;
 
-pub use controller::Controller;
+pub use batch::WriteBatch;
+pub use controller::{Controller, FlushHandle};
+pub use dictionary::Dictionary;
+pub use analyze::{Histogram, KeyspaceReport};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use collation::Collation;
+pub use compact::CompactionPlan;
 pub use config::Config;
-pub use manifest::Manifest;
-pub use record::Value;
+pub use layout::Layout;
+pub use lease::Lease;
+pub use doctor::{DoctorReport, Finding, Severity, doctor, verify_backup};
+pub use events::{
+    EventClasses, EventFilter, EventKind, FilteredEvents, KeyspaceEvent, ValueFilter,
+};
+pub use loader::{CacheLoader, Loaded};
+pub use manifest::{Manifest, ManifestRewrite};
+pub use merge::ScanPage;
+pub use options::{ConfigFile, OPTION_NAMES};
+pub use reader::{SSTableReader, TableProperties};
+pub use record::{MemValue, Record, Value, ValueType};
+pub use repair::{RepairReport, RepairedTable};
+pub use retention::RetentionRule;
+pub use scan::OrderedStream;
+pub use script::{ScriptContext, ScriptEngine};
+pub use sparse_index::{IndexCorruption, ScanRange};
+pub use sstable_set::CorruptTablePolicy;
+pub use stats::{Stats, TableSummary, Totals};
+#[cfg(feature = "testing")]
+pub use testing::{TempDatabase, TempDir, str_value};
+pub use verify::{ChecksumReport, CorruptBlock};
+pub use wal::WalCipher;
+
+/// Number of values sampled to train a compression dictionary.
+const DICTIONARY_SAMPLES: usize = 1024;
+
+/// Keys drawn per key asked of [`DatabaseImpl::sample_keys`] before giving up
+/// on finding enough live ones.
+const SAMPLE_ATTEMPTS: usize = 4;
+
+/// Blocks of every table read by [`DatabaseImpl::compaction_plan`] to
+/// estimate how much of it survives a compaction.
+const PLAN_SAMPLE_BLOCKS: usize = 16;
 
 #[derive(Debug)]
 pub struct DatabaseImpl {
@@ -31,6 +122,36 @@ pub struct DatabaseImpl {
     sstable_set: SSTableSet,
     config: Config,
     current_size: usize,
+    ttl_index: TtlIndex,
+    clock: Arc<dyn Clock>,
+    /// Data files kept open for point reads, and the budget of descriptors
+    /// that every other read or write of the tables reserves from.
+    files: Arc<FileCache>,
+    /// Size of the data and index files of every table, in bytes.
+    disk_bytes: u64,
+    /// Versions of the set of tables held by readers outside the database
+    /// lock, see [`DatabaseImpl::current_version`].
+    versions: VersionSet,
+    /// Files replaced by a compaction while readers held them, moved to the
+    /// trash once released.
+    deferred: Vec<PathBuf>,
+    /// Key sizes and recency, tracked only in cache mode.
+    lru: Option<std::sync::Mutex<LruTracker>>,
+    evictions: u64,
+    /// Compactions refused for lack of free disk space, see
+    /// [`DatabaseImpl::check_compaction_space`].
+    compactions_refused: u64,
+    /// Counters kept across restarts, saved by [`DatabaseImpl::save_totals`].
+    totals: Totals,
+    /// Limits the IO of flushes and compactions.
+    maintenance_io: RateLimiter,
+    /// Log of the writes held by the memtable, if `Config::wal` is enabled.
+    wal: Option<Wal>,
+    /// Stored keys last set by [`Controller::set_ephemeral`], with the
+    /// sequence number of that write. Any other write of a key drops it.
+    ephemeral: HashMap<String, u64>,
+    /// Advisory lock on `data_dir`, released when the database is dropped.
+    _lock: std::fs::File,
 }
 
 pub trait Database {
@@ -47,19 +168,840 @@ pub trait DatabaseAdmin {
 
 impl DatabaseImpl {
     pub async fn build(config: Config) -> Result<Self> {
-        let manifest =
+        Self::build_with_wal_cipher(config, None).await
+    }
+
+    /// Same as [`DatabaseImpl::build`], sealing the frames of the
+    /// write-ahead log with `cipher`, with a key of its own, and opening
+    /// those left by an earlier run with it. Tables aren't encrypted. A log
+    /// holding sealed frames fails the open without a cipher that opens them.
+    pub async fn build_with_wal_cipher(
+        config: Config,
+        cipher: Option<Arc<dyn WalCipher>>,
+    ) -> Result<Self> {
+        config.validate()?;
+        let lock = lock::acquire(&config.data_dir)?;
+        let mut manifest =
             Self::get_or_create_manifest(&config.data_dir, config.create_if_missing).await?;
+        let now = SystemClock.now_millis();
+        recovery::recover(&config.data_dir, &mut manifest, now).await?;
+        if let Err(e) = trash::purge(&config.data_dir, now, config.trash_retention_ms).await {
+            log::warn!(error:% = e; "Unable to purge the trash: {e}");
+        }
+        if manifest.apply_config(&config)? {
+            manifest::commit(&manifest, &config.data_dir).await?;
+        }
         log::info!("Using configuration:\n{:#?}", manifest);
-        let sstable_set = SSTableSet::build(&manifest, Some(&config.data_dir)).await?;
+        let sstable_set = SSTableSet::build(
+            &manifest,
+            Some(&config.data_dir),
+            config.corrupt_tables,
+            config.sparse_stride,
+            config.load_indexes_eagerly,
+        )
+        .await?;
+        if sstable_set.tables.len() + sstable_set.skipped.len() < manifest.sstables.len() {
+            // Tables were quarantined.
+            manifest::commit(&Manifest::new(&sstable_set), &config.data_dir).await?;
+        }
+        let ttl_index = ttl::load(&sstable_set, &config.data_dir).await?;
+        if !ttl_index.is_empty() {
+            log::info!("Tracking {} keys with an expiration time.", ttl_index.len());
+        }
+
+        let disk_bytes = Self::table_bytes(&sstable_set.tables, &config.data_dir).await?;
+        let lru = if config.cache_max_bytes > 0 {
+            let tracker = lru::load(&sstable_set, &config.data_dir).await?;
+            log::info!(
+                "Cache mode: {} keys, {} of {} bytes.",
+                tracker.len(),
+                tracker.total_bytes(),
+                config.cache_max_bytes
+            );
+            Some(std::sync::Mutex::new(tracker))
+        } else {
+            None
+        };
 
-        Ok(Self {
+        let versions = VersionSet::new(&sstable_set);
+        let totals = Totals::load(&config.data_dir).await?;
+        let (wal, logged) = Wal::open(&config.data_dir, config.wal_sync, cipher).await?;
+        let mut database = Self {
+            files: Arc::new(FileCache::new(config.max_open_files)),
+            disk_bytes,
+            lru,
+            evictions: 0,
+            compactions_refused: 0,
+            totals,
+            maintenance_io: RateLimiter::new(config.maintenance_bytes_per_sec),
+            wal: Some(wal),
+            ephemeral: HashMap::new(),
             config,
             sstable_set,
             memtable: BTreeMap::new(),
             current_size: 0,
+            ttl_index,
+            clock: Arc::new(SystemClock),
+            versions,
+            deferred: Vec::new(),
+            _lock: lock,
+        };
+        if !logged.is_empty() {
+            log::info!("Replaying {} writes from the write-ahead log.", logged.len());
+            database.replay(logged);
+        }
+        if !database.config.wal {
+            // Left by a run with the log enabled: flushed and removed, as
+            // nothing would empty it.
+            if !database.memtable.is_empty() {
+                database.flush().await?;
+            }
+            database.wal = None;
+            Wal::remove(&database.config.data_dir).await?;
+        }
+        Ok(database)
+    }
+
+    /// Applies the records of the write-ahead log to the memtable and the
+    /// key trackers, as the writes that logged them did.
+    fn replay(&mut self, records: Vec<Record>) {
+        for record in records {
+            let key = self.sstable_set.collation.decode(&record.key).to_string();
+            self.track_write(&key, &record.value);
+            self.apply_entry(record);
+        }
+    }
+
+    /// Rebuilds the indexes and MANIFEST of the database in `data_dir` from
+    /// its data files; see [`repair::repair`]. The database must not be open.
+    pub async fn repair(data_dir: &Path) -> Result<RepairReport> {
+        repair::repair(data_dir).await
+    }
+
+    /// Restores into `data_dir` a backup taken from `backup_dir` and the
+    /// writes archived to `archive_dir` after it, up to sequence number
+    /// `sequence`; see [`restore::restore_to`]. Returns the sequence number
+    /// of the last write restored. Segments sealed by a [`WalCipher`] need
+    /// `cipher`, which also seals the restored log.
+    pub async fn restore_to(
+        backup_dir: &Path,
+        archive_dir: &Path,
+        data_dir: &Path,
+        sequence: u64,
+        cipher: Option<Arc<dyn WalCipher>>,
+    ) -> Result<u64> {
+        restore::restore_to(backup_dir, archive_dir, data_dir, sequence, cipher).await
+    }
+
+    /// Replaces the clock used for expiration times.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Sets `key` to `value`, making it invisible once `expires_at` (milliseconds
+    /// since the Unix epoch) has passed.
+    pub async fn set_with_expiry(&mut self, key: String, value: Value, expires_at: u64) -> Result<()> {
+        self.write_entries(vec![(key, MemValue::Expiring { value, expires_at })])
+    }
+
+    /// Applies `ops` as a single write, with the expiration times of their
+    /// time-to-live counted from `now`: logged as one frame of the
+    /// write-ahead log, so that a crash keeps either all or none of them.
+    /// Fails, writing nothing, if any of them is refused.
+    pub(crate) fn write_batch(&mut self, ops: Vec<BatchOp>, now: u64) -> Result<()> {
+        let entries = ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value, ttl: None } => (key, MemValue::Value(value)),
+                BatchOp::Set { key, value, ttl: Some(ttl) } => {
+                    let expires_at = now + ttl.as_millis() as u64;
+                    (key, MemValue::Expiring { value, expires_at })
+                }
+                BatchOp::Delete { key } => (key, MemValue::Tombstone),
+            })
+            .collect();
+        self.write_entries(entries)
+    }
+
+    /// Returns the time-to-live of `key` as of `now`.
+    pub async fn ttl(&self, key: &str, now: u64) -> Result<KeyTtl> {
+        let entry = self
+            .get_entry(key)
+            .await?
+            .filter(|entry| !entry.is_expired(now));
+
+        Ok(match entry {
+            None | Some(MemValue::Tombstone) => KeyTtl::NotFound,
+            Some(MemValue::Value(_)) => KeyTtl::NoExpiry,
+            Some(MemValue::Expiring { expires_at, .. }) => {
+                KeyTtl::Remaining(Duration::from_millis(expires_at - now))
+            }
         })
     }
 
+    /// Removes the expiration time from `key`. Returns `false` if the key does
+    /// not exist or has no expiration time.
+    pub async fn persist(&mut self, key: &str, now: u64) -> Result<bool> {
+        if self.ttl_index.get(key).is_none() {
+            return Ok(false);
+        }
+
+        match self.get_entry(key).await? {
+            Some(MemValue::Expiring { value, expires_at }) if expires_at > now => {
+                self.set(key.to_string(), value).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns up to `limit` live keys and their values, in collation order,
+    /// starting at `start` and ending before `end` (if given).
+    pub async fn scan(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>> {
+        let collation = self.sstable_set.collation;
+        let start = Bound::Included(collation.bound(start).into_owned());
+        let entries = self.scan_stored(start, end, limit).await?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| (collation.decode(&key).to_string(), value))
+            .collect())
+    }
+
+    /// Same as [`DatabaseImpl::scan`], carrying on from `after`, the
+    /// [`ScanPage::next`] of the previous page, if given.
+    pub async fn scan_page(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        let collation = self.sstable_set.collation;
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_string()),
+            None => Bound::Included(collation.bound(start).into_owned()),
+        };
+        let entries = self.scan_stored(start, end, limit).await?;
+        let next = match entries.last() {
+            Some((key, _)) if entries.len() == limit => Some(key.clone()),
+            _ => None,
+        };
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (collation.decode(&key).to_string(), value))
+            .collect();
+        Ok(ScanPage { entries, next })
+    }
+
+    /// Returns up to `limit` live records from `start`, a stored key, to
+    /// before `end`, under their stored keys.
+    async fn scan_stored(
+        &self,
+        start: Bound<String>,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>> {
+        let now = self.clock.now_millis();
+        let end = end.map(|end| self.sstable_set.collation.bound(end));
+        let end = end.as_deref();
+        let from = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+            Bound::Unbounded => String::new(),
+        };
+        let bounds = (
+            start.clone(),
+            end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_string())),
+        );
+        let mut sources = vec![Source::memtable(&self.memtable, bounds)];
+        let _files = self.files.reserve(self.sstable_set.tables.len()).await;
+        for table in &self.sstable_set.tables {
+            let mut scan = TableScan::open(&self.config.data_dir.join(&table.data_path))
+                .await?
+                .with_dictionary(self.sstable_set.dictionary.clone())
+                .with_readahead(self.config.scan_readahead_bytes);
+            // Start from the last restart point at or before `from`.
+            if let Some((_, &offset)) = table.index().await?.range(..=from.clone()).next_back() {
+                scan.seek(offset).await?;
+            }
+            sources.push(Source::Table(Box::new(scan)));
+        }
+
+        let mut merge = MergeIterator::new(sources).await?;
+        let mut entries = Vec::new();
+        while entries.len() < limit
+            && let Some(record) = merge.next().await?
+        {
+            let before_start = match &start {
+                Bound::Included(start) => &record.key < start,
+                Bound::Excluded(after) => &record.key <= after,
+                Bound::Unbounded => false,
+            };
+            if before_start {
+                continue;
+            }
+            if end.is_some_and(|end| record.key.as_str() >= end) {
+                break;
+            }
+            if record.value.is_expired(now) {
+                continue;
+            }
+            if let Some(value) = record.value.into_value() {
+                entries.push((record.key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let now = self.clock.now_millis();
+        Stats {
+            memtable_entries: self.memtable.len(),
+            memtable_bytes: self.current_size,
+            tables: self.sstable_set.tables.len(),
+            skipped_tables: self.sstable_set.skipped.len(),
+            last_sequence: self.sstable_set.last_sequence,
+            last_record_seq: self.sstable_set.last_record_seq,
+            expiring_keys: self.ttl_index.len(),
+            cache_bytes: self.lru.as_ref().map_or(0, |lru| lru.lock().unwrap().total_bytes()),
+            evictions: self.evictions,
+            compactions_refused: self.compactions_refused,
+            disk_bytes: self.disk_bytes,
+            open_files: self.files.open_files(),
+            table_ages_ms: self
+                .sstable_set
+                .tables
+                .iter()
+                .map(|table| now.saturating_sub(table.created_at))
+                .collect(),
+            totals: self.totals,
+            ..Stats::default()
+        }
+    }
+
+    /// Lists the tables, newest first, with the size of their files.
+    pub async fn tables(&self) -> Result<Vec<TableSummary>> {
+        let now = self.clock.now_millis();
+        let mut tables = Vec::with_capacity(self.sstable_set.tables.len());
+        for table in &self.sstable_set.tables {
+            let data_dir = &self.config.data_dir;
+            let index_bytes = match tokio::fs::metadata(data_dir.join(&table.index_path)).await {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+            tables.push(TableSummary {
+                data_path: table.data_path.clone(),
+                generation: table.generation,
+                compacted: table.compacted,
+                age_ms: now.saturating_sub(table.created_at),
+                data_bytes: tokio::fs::metadata(data_dir.join(&table.data_path)).await?.len(),
+                index_bytes,
+            });
+        }
+        Ok(tables)
+    }
+
+    /// Copies every SSTable and a matching MANIFEST into `dest`, which must not
+    /// already contain a database. The memtable is not included; flush first.
+    pub async fn backup(&self, dest: &Path) -> Result<()> {
+        self.backup_source().copy_to(dest).await
+    }
+
+    /// Captures the files [`DatabaseImpl::backup`] copies, held so that
+    /// they can be copied without holding the database lock.
+    pub(crate) fn backup_source(&self) -> BackupSource {
+        BackupSource {
+            data_dir: self.config.data_dir.clone(),
+            files: self.files.clone(),
+            manifest: Manifest::new(&self.sstable_set),
+            version: self.current_version(),
+        }
+    }
+
+    /// Returns the current version of the set of tables: a compaction
+    /// replacing its files leaves them in place until every clone of it is
+    /// dropped, for readers that let go of the database lock.
+    pub(crate) fn current_version(&self) -> Arc<Version> {
+        self.versions.current()
+    }
+
+    /// Returns the files referenced by the current set of tables or by
+    /// versions of it readers still hold, relative to the data directory.
+    pub fn live_files(&self) -> BTreeSet<PathBuf> {
+        self.versions.live_files()
+    }
+
+    /// Returns `true` if files replaced by a compaction are no longer held
+    /// by readers and wait to be moved to the trash.
+    pub fn has_released_files(&self) -> bool {
+        let live = self.live_files();
+        self.deferred.iter().any(|path| !live.contains(path))
+    }
+
+    /// Moves the files replaced by a compaction that no reader holds anymore
+    /// to the trash, returning how many.
+    pub async fn discard_released(&mut self) -> Result<usize> {
+        let live = self.live_files();
+        let (pinned, released): (Vec<_>, Vec<_>) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition(|path| live.contains(path));
+        self.deferred = pinned;
+        if released.is_empty() {
+            return Ok(0);
+        }
+        log::info!("Moving files released by readers to the trash: {:?}", released);
+        let now = self.clock.now_millis();
+        let data_dir = &self.config.data_dir;
+        let moved = try_join_all(released.iter().map(|path| trash::discard(data_dir, path, now))).await;
+        if let Err(e) = moved {
+            self.deferred.extend(released);
+            return Err(e);
+        }
+        self.commit_manifest(Manifest::new(&self.sstable_set)).await?;
+        Ok(released.len())
+    }
+
+    /// Drops the tables whose data files are named `names` and moves their
+    /// files to the quarantine directory, as
+    /// [`CorruptTablePolicy::Quarantine`] does on open, returning how many.
+    /// The keys they held revert to their versions in older tables, if any.
+    pub async fn quarantine_tables(&mut self, names: &BTreeSet<String>) -> Result<usize> {
+        let is_named = |table: &SSTable| {
+            Path::new(&table.data_path)
+                .file_name()
+                .is_some_and(|name| names.contains(&*name.to_string_lossy()))
+        };
+        let (quarantined, kept): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.sstable_set.tables).into_iter().partition(is_named);
+        self.sstable_set.tables = kept;
+        if quarantined.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = self.commit_manifest(Manifest::new(&self.sstable_set)).await {
+            self.sstable_set.tables.extend(quarantined);
+            self.sstable_set.tables.sort_by_key(|table| std::cmp::Reverse(table.generation));
+            return Err(e);
+        }
+        self.files.clear();
+        for table in &quarantined {
+            log::error!("Quarantining table {}, which failed its checksums.", table.data_path);
+            let paths = [Path::new(&table.data_path), Path::new(&table.index_path)];
+            sstable_set::quarantine(&self.config.data_dir, paths).await?;
+        }
+        self.refresh_disk_usage().await?;
+        Ok(quarantined.len())
+    }
+
+    /// Adds the externally built SSTable at `data_path` (with its index next
+    /// to it, as `.idx`) as the newest table, returning the number of records
+    /// it holds. The memtable is flushed first, so the ingested records take
+    /// precedence over every earlier write.
+    pub async fn ingest_sstable(&mut self, data_path: &Path) -> Result<u64> {
+        if !self.sstable_set.collation.is_binary() {
+            return Err(Error::new(
+                tokio::io::ErrorKind::Unsupported,
+                "Tables can only be ingested with the binary collation",
+            ));
+        }
+        let index_path = data_path.with_extension("idx");
+        log::info!("Validating {}...", data_path.display());
+        let table = {
+            let _files = self.files.reserve(2).await;
+            ingest::validate(data_path, &index_path, |key| self.ttl_index.get(key).is_some())
+                .await?
+        };
+        log::info!(
+            "Ingesting {} records ({:?}..={:?}).",
+            table.records,
+            table.first_key,
+            table.last_key
+        );
+
+        if !self.memtable.is_empty() {
+            self.flush().await?;
+        }
+        // Overwritten by the ingested records, under the binary collation.
+        self.ephemeral.retain(|key, _| *key < table.first_key || *key > table.last_key);
+
+        let next_sequence = self.sstable_set.last_sequence + 1;
+        let (target_data, target_index) = self.sstable_set.layout.table_paths(next_sequence);
+        let data_dir = &self.config.data_dir;
+        for (source, target) in [(data_path, &target_data), (&index_path, &target_index)] {
+            let part = layout::part_path(data_dir, target);
+            let _files = self.files.reserve(2).await;
+            tokio::fs::copy(source, &part).await?;
+            layout::install(data_dir, &part, target).await?;
+        }
+        layout::sync_table_dir(data_dir, &target_data).await?;
+
+        self.sstable_set.tables.insert(
+            0,
+            SSTable::new(
+                target_data,
+                target_index,
+                table.index,
+                table.layout,
+                next_sequence,
+                false,
+                self.clock.now_millis(),
+            ),
+        );
+        self.sstable_set.last_sequence = next_sequence;
+        for (key, expires_at) in table.expirations {
+            match expires_at {
+                Some(expires_at) => self.ttl_index.insert(key, expires_at),
+                None => self.ttl_index.remove(&key),
+            }
+        }
+
+        self.commit_manifest(Manifest::new(&self.sstable_set)).await?;
+        self.refresh_disk_usage().await?;
+        if self.lru.is_some() {
+            let _files = self.files.reserve(1).await;
+            let tracker = lru::load(&self.sstable_set, &self.config.data_dir).await?;
+            self.lru = Some(std::sync::Mutex::new(tracker));
+        }
+        Ok(table.records)
+    }
+
+    /// Writes `entries`, sorted by key without duplicates, straight to a new
+    /// table added as the newest one, skipping the memtable. Returns the
+    /// number of records written. The memtable is flushed first, so the
+    /// loaded values take precedence over every earlier write; they all
+    /// share one sequence number, as if written by a single batch.
+    ///
+    /// An entry out of order fails the load before anything is committed.
+    /// Keys must be in the order of the collation.
+    pub async fn bulk_load<S>(&mut self, mut entries: S) -> Result<u64>
+    where
+        S: futures::Stream<Item = (String, Value)> + Unpin,
+    {
+        if !self.memtable.is_empty() {
+            self.flush().await?;
+        }
+
+        let sequence = self.sstable_set.last_sequence + 1;
+        let (data_path, index_path) = self.sstable_set.layout.table_paths(sequence);
+        let data_dir = self.config.data_dir.clone();
+        let part_paths = |_: usize| {
+            (
+                layout::part_path(&data_dir, &data_path),
+                layout::part_path(&data_dir, &index_path),
+            )
+        };
+        let seq = self.sstable_set.last_record_seq + 1;
+        let format = self.table_format(seq);
+        let dictionary = self.write_dictionary();
+        let unlimited = RateLimiter::new(0);
+        let output = compact::Output {
+            index_stride: self.config.sparse_stride,
+            format,
+            dictionary: dictionary.as_deref(),
+            target_bytes: 0,
+            part_paths: &part_paths,
+            limiter: &unlimited,
+            drop_deleted: false,
+            retention: &[],
+            collation: self.sstable_set.collation,
+        };
+
+        log::info!(table = data_path.as_str(); "Bulk loading into {data_path}...");
+        let files = self.files.reserve(2).await;
+        // Applied once the table is committed.
+        let mut ephemeral = Vec::new();
+        let mut expiring = Vec::new();
+        let mut sizes = Vec::new();
+        let written = async {
+            let mut table = compact::OutputTable::create(part_paths(0)).await?;
+            let mut records = 0;
+            while let Some((key, value)) = entries.next().await {
+                let stored = self.sstable_set.collation.encode(&key).into_owned();
+                if table.last_key().is_some_and(|last| *last >= *stored) {
+                    return Err(Error::new(
+                        tokio::io::ErrorKind::InvalidInput,
+                        format!("Bulk loaded key {key:?} is not after the previous one"),
+                    ));
+                }
+                let value = MemValue::Value(value);
+                record::check_lengths(&stored, value.len(), format)?;
+                if self.ephemeral.contains_key(&stored) {
+                    ephemeral.push(stored.clone());
+                }
+                if self.ttl_index.get(&key).is_some() {
+                    expiring.push(key.clone());
+                }
+                if self.lru.is_some() {
+                    let size = lru::entry_size(&key, &value, format);
+                    sizes.push((key, size));
+                }
+                table.write(Record { key: stored, value, seq }, &output).await?;
+                records += 1;
+            }
+            if records == 0 {
+                return Ok(None);
+            }
+            Ok(Some((table.finish(format).await?, records)))
+        }
+        .await;
+        drop(files);
+        let (index, records) = match written {
+            Ok(Some(written)) => written,
+            Ok(None) => {
+                Self::remove_parts(&part_paths).await;
+                return Ok(0);
+            }
+            Err(e) => {
+                Self::remove_parts(&part_paths).await;
+                return Err(e);
+            }
+        };
+        let (data_part, index_part) = part_paths(0);
+        layout::install(&data_dir, &data_part, &data_path).await?;
+        layout::install(&data_dir, &index_part, &index_path).await?;
+        layout::sync_table_dir(&data_dir, &data_path).await?;
+
+        let layout = self.table_layout(&data_path).await?;
+        self.totals.bytes_written += layout.data_len;
+        self.sstable_set.tables.insert(
+            0,
+            SSTable::new(
+                data_path,
+                index_path,
+                index,
+                layout,
+                sequence,
+                false,
+                self.clock.now_millis(),
+            )
+            .with_stride(self.config.sparse_stride),
+        );
+        self.sstable_set.last_sequence = sequence;
+        self.sstable_set.last_record_seq = seq;
+        self.commit_manifest(Manifest::new(&self.sstable_set)).await?;
+        self.totals.keys_written += records;
+        log::info!(records; "Bulk loaded {records} records.");
+
+        for key in ephemeral {
+            self.ephemeral.remove(&key);
+        }
+        for key in expiring {
+            self.ttl_index.remove(&key);
+        }
+        if let Some(lru) = &self.lru {
+            let tick = self.lru_tick();
+            let mut lru = lru.lock().unwrap();
+            for (key, size) in sizes {
+                lru.insert(key, size, tick);
+            }
+        }
+        self.refresh_disk_usage().await?;
+        Ok(records)
+    }
+
+    /// Returns up to `n` distinct live keys drawn at random, reading a block
+    /// of a table per key rather than scanning them; see
+    /// [`sample::KeySampler`]. Fewer keys are returned if too many of those
+    /// drawn turn out to be overwritten, deleted or expired.
+    pub async fn sample_keys(&self, n: usize) -> Result<Vec<String>> {
+        let mut sampler = sample::KeySampler::new(
+            &self.memtable,
+            &self.sstable_set,
+            &self.config.data_dir,
+            self.config.sparse_stride,
+            &self.files,
+        )
+        .await?;
+        let now = self.clock.now_millis();
+        let mut keys = BTreeSet::new();
+        for _ in 0..n.saturating_mul(SAMPLE_ATTEMPTS) {
+            if keys.len() == n {
+                break;
+            }
+            let Some(stored) = sampler.draw().await? else {
+                break;
+            };
+            let key = self.sstable_set.collation.decode(&stored);
+            if keys.contains(key) {
+                continue;
+            }
+            let live = self.get_entry(key).await?.is_some_and(|entry| {
+                !entry.is_expired(now) && !matches!(entry, MemValue::Tombstone)
+            });
+            if live {
+                keys.insert(key.to_string());
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Estimates what [`DatabaseAdmin::compact`] would write if it ran now,
+    /// without writing anything. The policy deciding whether it is due is
+    /// the controller's, so `reason` is left empty.
+    ///
+    /// A few blocks spread over every table are read; a sampled record
+    /// survives unless it is a tombstone, has expired, or a newer table
+    /// holds its key. Every table is assumed to keep the share of its data
+    /// bytes that survived in its sample.
+    pub async fn compaction_plan(&self) -> Result<compact::CompactionPlan> {
+        let mut plan = compact::CompactionPlan::default();
+        if self.sstable_set.sorted_runs() < 2 {
+            return Ok(plan);
+        }
+        let now = self.clock.now_millis();
+        let mut output_bytes = 0;
+        for (i, table) in self.sstable_set.tables.iter().enumerate() {
+            plan.inputs.push(self.config.data_dir.join(&table.data_path));
+            let data_len = table.layout().await?.data_len;
+            let (sampled, survived) = self.sample_survivors(i, now).await?;
+            if sampled > 0 {
+                output_bytes += (data_len as u128 * survived as u128 / sampled as u128) as u64;
+            }
+        }
+        plan.input_bytes = Self::table_bytes(&self.sstable_set.tables, &self.config.data_dir).await?;
+        plan.estimated_output_bytes = output_bytes;
+        plan.estimated_output_tables = match self.config.compaction_target_file_bytes {
+            _ if output_bytes == 0 => 0,
+            0 => 1,
+            target => output_bytes.div_ceil(target) as usize,
+        };
+        plan.estimated_reclaimed_bytes = plan.input_bytes.saturating_sub(output_bytes);
+        Ok(plan)
+    }
+
+    /// Reads up to `PLAN_SAMPLE_BLOCKS` blocks of the `i`-th table, returning
+    /// the bytes of records read and of those a compaction at `now` keeps.
+    async fn sample_survivors(&self, i: usize, now: u64) -> Result<(u64, u64)> {
+        let table = &self.sstable_set.tables[i];
+        let contents = table.contents().await?;
+        let offsets: Vec<u64> = contents.index.values().copied().collect();
+        let step = offsets.len().div_ceil(PLAN_SAMPLE_BLOCKS).max(1);
+        let mut sampled = 0;
+        // Live records and their size, probed once the table is closed:
+        // probes take descriptors of their own.
+        let mut live = Vec::new();
+        {
+            let _files = self.files.reserve(1).await;
+            let mut scan = TableScan::open(&self.config.data_dir.join(&table.data_path))
+                .await?
+                .with_dictionary(self.sstable_set.dictionary.clone());
+            for n in (0..offsets.len()).step_by(step) {
+                let end = offsets.get(n + 1).copied().unwrap_or(contents.layout.data_len);
+                scan.seek(offsets[n]).await?;
+                let mut start = scan.offset();
+                while start < end
+                    && let Some(record) = scan.next().await?
+                {
+                    let len = scan.offset() - start;
+                    start = scan.offset();
+                    sampled += len;
+                    let value = &record.value;
+                    if !matches!(value, MemValue::Tombstone) && !value.is_expired(now) {
+                        live.push((record.key, len));
+                    }
+                }
+            }
+        }
+
+        let mut survived = 0;
+        for (key, len) in live {
+            let mut shadowed = false;
+            for newer in &self.sstable_set.tables[..i] {
+                if self.probe(newer, &key).await?.is_some() {
+                    shadowed = true;
+                    break;
+                }
+            }
+            if !shadowed {
+                survived += len;
+            }
+        }
+        Ok((sampled, survived))
+    }
+
+    /// Returns the size of the data and index files, in bytes.
+    pub fn disk_usage(&self) -> u64 {
+        self.disk_bytes
+    }
+
+    async fn table_bytes(tables: &[SSTable], data_dir: &Path) -> Result<u64> {
+        let mut total = 0;
+        for table in tables {
+            for path in [&table.data_path, &table.index_path] {
+                match tokio::fs::metadata(data_dir.join(path)).await {
+                    Ok(metadata) => total += metadata.len(),
+                    // Not rebuilt yet, see `Config::load_indexes_eagerly`.
+                    Err(e) if e.kind() == tokio::io::ErrorKind::NotFound && path == &table.index_path => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// Fails with [`tokio::io::ErrorKind::StorageFull`] if the filesystem of
+    /// the data directory has less free space than a compaction of `inputs`
+    /// may write: as much as their files take, should it drop nothing.
+    /// Refusing up front beats running out of space halfway through the
+    /// outputs.
+    async fn check_compaction_space(&mut self, inputs: RangeInclusive<usize>) -> Result<()> {
+        let free = match disk::available_space(&self.config.data_dir) {
+            Ok(free) => free,
+            Err(e) if e.kind() == tokio::io::ErrorKind::Unsupported => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let inputs = &self.sstable_set.tables[inputs];
+        let needed = Self::table_bytes(inputs, &self.config.data_dir).await?;
+        if free >= needed {
+            return Ok(());
+        }
+        self.compactions_refused += 1;
+        Err(Error::new(
+            tokio::io::ErrorKind::StorageFull,
+            format!(
+                "Not enough free disk space to compact: up to {needed} bytes needed, \
+                 {free} available"
+            ),
+        ))
+    }
+
+    async fn refresh_disk_usage(&mut self) -> Result<()> {
+        self.disk_bytes = Self::table_bytes(&self.sstable_set.tables, &self.config.data_dir).await?;
+        Ok(())
+    }
+
+    /// Returns the paths of every data file, newest first.
+    fn data_files(&self) -> Vec<PathBuf> {
+        self.sstable_set
+            .tables
+            .iter()
+            .map(|table| self.config.data_dir.join(&table.data_path))
+            .collect()
+    }
+
+    /// Returns the earliest expiration time among the tracked keys.
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.ttl_index.next_deadline()
+    }
+
+    /// Deletes every key whose expiration time is at or before `now`, returning
+    /// the deleted keys.
+    pub async fn expire_keys(&mut self, now: u64) -> Result<Vec<String>> {
+        let expired = self.ttl_index.pop_expired(now);
+        let tombstones = expired.iter().map(|key| (key.clone(), MemValue::Tombstone)).collect();
+        self.write_entries(tombstones)?;
+        Ok(expired)
+    }
+
     async fn get_or_create_manifest(data_dir: &Path, create_if_missing: bool) -> Result<Manifest> {
         let manifest_path = Self::get_manifest_path(data_dir);
         let manifest_exists = tokio::fs::metadata(&manifest_path).await.is_ok();
@@ -87,16 +1029,18 @@ impl DatabaseImpl {
         let manifest = Manifest {
             sstables: Vec::new(),
             last_sequence: 0,
+            last_record_seq: 0,
+            dictionary: None,
+            obsolete: Vec::new(),
+            collation: Collation::Binary,
+            table_format: None,
+            layout: Layout::default(),
             version: version::VERSION.to_string(),
         };
         let manifest_path = Self::get_manifest_path(data_dir);
 
         log::info!("Creating manifest file: {}...", &manifest_path);
-        manifest::write_manifest(
-            &manifest,
-            &mut BufWriter::new(File::create(&manifest_path).await?),
-        )
-        .await?;
+        manifest::commit(&manifest, data_dir).await?;
         log::info!("Done.");
 
         Ok(manifest)
@@ -110,164 +1054,864 @@ impl DatabaseImpl {
             .unwrap()
     }
 
-    fn remove_key_size(&mut self, key: &str) {
-        if let Some(old) = self.memtable.get(key) {
-            self.current_size -= key.len() + old.len()
+    /// Returns the newest stored entry for `key`, including tombstones and
+    /// expired values.
+    ///
+    /// Up to `Config::read_parallelism` tables are probed at once; results
+    /// are still considered newest table first, and probes of older tables
+    /// are dropped as soon as a newer one has the key.
+    async fn get_entry(&self, key: &str) -> Result<Option<MemValue>> {
+        let key = &*self.sstable_set.collation.encode(key);
+        if let Some(inner) = self.memtable.get(key) {
+            return Ok(Some(inner.value.clone()));
+        }
+
+        let probes: Vec<_> = self
+            .sstable_set
+            .tables
+            .iter()
+            .map(|table| self.probe(table, key))
+            .collect();
+        let mut probes = stream::iter(probes).buffered(self.config.read_parallelism.max(1));
+        while let Some(result) = probes.next().await {
+            if let Some(inner) = result? {
+                return Ok(Some(inner));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Looks `key` up in a single table.
+    async fn probe(&self, table: &SSTable, key: &str) -> Result<Option<MemValue>> {
+        let contents = table.contents().await?;
+        let range = sparse_index::bounds(&contents.index, key, contents.layout.data_len);
+        let mut handle = self.files.open(&self.config.data_dir.join(&table.data_path)).await?;
+        let mut file = BufReader::new(&mut *handle);
+
+        let dictionary = self.sstable_set.dictionary.as_deref();
+        sstable_set::seek_and_read(&mut file, key, range, contents.layout, dictionary).await
+    }
+
+    /// Deletes least recently used keys until the cache-mode budget is met,
+    /// returning the evicted keys. Does nothing outside of cache mode.
+    pub fn evict_over_budget(&mut self) -> Result<Vec<String>> {
+        let budget = self.config.cache_max_bytes;
+        let mut evicted = Vec::new();
+        let Some(lru) = &self.lru else {
+            return Ok(evicted);
+        };
+        {
+            let mut lru = lru.lock().unwrap();
+            while lru.total_bytes() > budget {
+                let Some(key) = lru.pop_lru() else { break };
+                evicted.push(key);
+            }
         }
+        let tombstones = evicted.iter().map(|key| (key.clone(), MemValue::Tombstone)).collect();
+        self.write_entries(tombstones)?;
+        self.evictions += evicted.len() as u64;
+        Ok(evicted)
     }
-}
 
-impl Database for DatabaseImpl {
-    async fn get(&self, key: &str) -> Result<Option<Value>> {
-        if let Some(inner) = self.memtable.get(key) {
-            return Ok(inner.clone().to_value());
+    /// Current tick of the coarse clock recording key use in cache mode.
+    fn lru_tick(&self) -> u64 {
+        self.clock.now_millis() / 1000
+    }
+
+    fn track(&self, key: &str, value: &MemValue) {
+        if let Some(lru) = &self.lru {
+            let tick = self.lru_tick();
+            lru.lock()
+                .unwrap()
+                .insert(key.to_string(), lru::entry_size(key, value, self.table_format(0)), tick);
+        }
+    }
+
+    fn untrack(&self, key: &str) {
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Updates the expiration times and, in cache mode, the sizes tracked
+    /// for `key` once it is written `value`.
+    fn track_write(&mut self, key: &str, value: &MemValue) {
+        match value {
+            MemValue::Expiring { expires_at, .. } => {
+                self.ttl_index.insert(key.to_string(), *expires_at)
+            }
+            _ => self.ttl_index.remove(key),
+        }
+        match value {
+            MemValue::Tombstone => self.untrack(key),
+            value => self.track(key, value),
+        }
+    }
+
+    /// Fails with `InvalidInput` if the record of a value serialized to
+    /// `value_len` bytes under `key` would be too long to read back from a
+    /// table, see [`record::check_lengths`].
+    pub(crate) fn check_write(&self, key: &str, value_len: usize) -> Result<()> {
+        let key = self.sstable_set.collation.encode(key);
+        record::check_lengths(&key, value_len, self.table_format(0))
+    }
+
+    /// Inserts `entries` into the memtable, in order, under the next
+    /// sequence numbers and the stored forms of their keys, once appended
+    /// to the write-ahead log as one frame. Fails, leaving the memtable and
+    /// the key trackers as they were, if a value is too long to store or the
+    /// append fails.
+    fn write_entries(&mut self, entries: Vec<(String, MemValue)>) -> Result<()> {
+        for (key, value) in &entries {
+            if !matches!(value, MemValue::Tombstone) {
+                self.check_write(key, value.len())?;
+            }
+        }
+        let first_seq = self.sstable_set.last_record_seq + 1;
+        let (keys, records): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .zip(first_seq..)
+            .map(|((key, value), seq)| {
+                let stored = self.sstable_set.collation.encode(&key).into_owned();
+                (key, Record { key: stored, value, seq })
+            })
+            .unzip();
+        if let Some(wal) = &mut self.wal {
+            wal.append(&records)?;
+        }
+        for (key, record) in keys.into_iter().zip(records) {
+            self.track_write(&key, &record.value);
+            self.ephemeral.remove(&record.key);
+            self.apply_entry(record);
+            self.totals.keys_written += 1;
+        }
+        Ok(())
+    }
+
+    /// Inserts `record`, under its stored key and sequence number, into the
+    /// memtable, accounting for the size of the record it will be flushed as.
+    fn apply_entry(&mut self, record: Record) {
+        let Record { key, value, seq } = record;
+        let format = self.table_format(0);
+        if let Some(old) = self.memtable.get(&key) {
+            self.current_size -= old.value.encoded_len(&key, format);
+        }
+        self.current_size += value.encoded_len(&key, format);
+        self.sstable_set.last_record_seq = self.sstable_set.last_record_seq.max(seq);
+        self.memtable.insert(key, MemEntry { seq, value });
+    }
+
+    /// Records that the last write, of `key`, was made by
+    /// [`Controller::set_ephemeral`], returning its sequence number.
+    pub(crate) fn own_ephemeral(&mut self, key: &str) -> u64 {
+        let seq = self.sstable_set.last_record_seq;
+        let key = self.sstable_set.collation.encode(key).into_owned();
+        self.ephemeral.insert(key, seq);
+        seq
+    }
+
+    /// Returns whether `key` still holds the write of sequence number `seq`
+    /// recorded by [`DatabaseImpl::own_ephemeral`].
+    pub(crate) fn owns_ephemeral(&self, key: &str, seq: u64) -> bool {
+        let key = self.sstable_set.collation.encode(key);
+        self.ephemeral.get(&*key) == Some(&seq)
+    }
+
+    /// Empties the write-ahead log, once the memtable is flushed.
+    fn truncate_wal(&mut self) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the format new tables are written in, given the smallest
+    /// sequence number they will hold.
+    fn table_format(&self, base_seq: u64) -> Format {
+        Format::from_version(self.config.table_format, base_seq)
+            .expect("table_format is validated when the database is built")
+    }
+
+    /// Returns the dictionary new tables compress their values with, if
+    /// compression is enabled and a dictionary has been trained.
+    fn write_dictionary(&self) -> Option<Arc<Dictionary>> {
+        self.sstable_set
+            .dictionary
+            .clone()
+            .filter(|_| self.config.dictionary_max_bytes > 0)
+    }
+
+    /// Returns the stride of a table written from the memtable, see
+    /// `Config::sparse_scan_bytes`.
+    fn memtable_stride(&self) -> usize {
+        let record_bytes = self.current_size.checked_div(self.memtable.len()).unwrap_or(0);
+        sparse_index::adaptive_stride(
+            self.config.sparse_stride,
+            self.config.sparse_scan_bytes,
+            record_bytes as u64,
+        )
+    }
+
+    /// Returns the stride of a table merging `tables`, whose average record
+    /// size is estimated from their length and the records their indexes
+    /// cover.
+    async fn merged_stride(&self, tables: &[SSTable]) -> Result<usize> {
+        let (stride, scan_bytes) = (self.config.sparse_stride, self.config.sparse_scan_bytes);
+        if scan_bytes == 0 {
+            return Ok(stride);
+        }
+        let (mut bytes, mut records) = (0, 0);
+        for table in tables {
+            let contents = table.contents().await?;
+            bytes += contents.layout.data_len;
+            records += (contents.index.len() * table.stride.unwrap_or(stride)) as u64;
+        }
+        let record_bytes = bytes.checked_div(records).unwrap_or(0);
+        Ok(sparse_index::adaptive_stride(stride, scan_bytes, record_bytes))
+    }
+
+    /// Trains a dictionary over values sampled from every table and stores it
+    /// in the data directory under the file number `id`. Returns `None` if
+    /// the values have nothing in common.
+    async fn train_dictionary(&self, id: usize) -> Result<Option<Arc<Dictionary>>> {
+        let samples = {
+            let _files = self.files.reserve(1).await;
+            compact::sample_values(&self.sstable_set, &self.config.data_dir, DICTIONARY_SAMPLES)
+                .await?
+        };
+        let bytes = dictionary::train(&samples, self.config.dictionary_max_bytes);
+        if bytes.is_empty() {
+            log::info!("No dictionary trained from {} sampled values.", samples.len());
+            return Ok(None);
+        }
+
+        let dictionary = Dictionary::new(id as u64, bytes);
+        log::info!(
+            "Trained a {} byte dictionary from {} sampled values: {}",
+            dictionary.len(),
+            samples.len(),
+            dictionary.file_name()
+        );
+        dictionary.write(&self.config.data_dir).await?;
+        Ok(Some(Arc::new(dictionary)))
+    }
+
+    /// Returns the format of the tables a compaction writes and, if
+    /// compression is enabled, the dictionary trained for them, stored under
+    /// the file number `dictionary_id`.
+    async fn compaction_output(
+        &self,
+        dictionary_id: usize,
+    ) -> Result<(Format, Option<Arc<Dictionary>>)> {
+        let mut base_seq = None::<u64>;
+        for table in &self.sstable_set.tables {
+            let table_seq = table.layout().await?.format.base_seq();
+            base_seq = Some(base_seq.map_or(table_seq, |seq| seq.min(table_seq)));
+        }
+        let format = self.table_format(base_seq.unwrap_or(0));
+        let dictionary = if self.config.dictionary_max_bytes > 0 && format != Format::V1 {
+            self.train_dictionary(dictionary_id).await?
+        } else {
+            None
+        };
+        Ok((format, dictionary))
+    }
+
+    /// Writes the merge of every table to the `.part` files `part_paths`
+    /// names, in `format` and compressed with `dictionary`, indexing every
+    /// `stride`-th record, syncing them. Returns the index of every output
+    /// table.
+    async fn compact_into(
+        &mut self,
+        format: Format,
+        dictionary: Option<&Dictionary>,
+        stride: usize,
+        part_paths: &(dyn Fn(usize) -> (PathBuf, PathBuf) + Sync),
+    ) -> Result<Vec<SparseIndex>> {
+        log::info!(inputs = self.sstable_set.tables.len(); "Starting log compaction.");
+        log::info!("Input log files: {:#?}", self.data_files());
+        let output = compact::Output {
+            index_stride: stride,
+            format,
+            dictionary,
+            target_bytes: self.config.compaction_target_file_bytes,
+            part_paths,
+            limiter: &self.maintenance_io,
+            drop_deleted: true,
+            retention: &self.config.retention,
+            collation: self.sstable_set.collation,
+        };
+        // The inputs, and the data and index file of the output.
+        let _files = self.files.reserve(self.sstable_set.tables.len() + 2).await;
+        let indexes = compact::merge_tables(
+            &self.sstable_set.tables,
+            self.sstable_set.dictionary.clone(),
+            &self.config.data_dir,
+            self.clock.now_millis(),
+            output,
+        )
+        .await?;
+        log::info!(
+            outputs = indexes.len();
+            "Finished log compaction: {} output tables.",
+            indexes.len()
+        );
+        Ok(indexes)
+    }
+
+    /// Flushes the memtable of a database without tables the way a
+    /// compaction would write it: into tables of about
+    /// `Config::compaction_target_file_bytes`, leaving out tombstones and
+    /// expired records since no older table is left for them to hide. An
+    /// initial bulk load thus isn't written again by the first compaction
+    /// just to be split and cleaned up. Otherwise as [`DatabaseAdmin::flush`].
+    async fn flush_compacted(&mut self) -> Result<()> {
+        let data_dir = self.config.data_dir.clone();
+        let sequence = self.sstable_set.last_sequence + 1;
+        let file_layout = self.sstable_set.layout.clone();
+        let table_paths = |n: usize| file_layout.table_paths(sequence + n);
+        let part_paths = |n: usize| {
+            let (data_path, index_path) = table_paths(n);
+            (
+                layout::part_path(&data_dir, &data_path),
+                layout::part_path(&data_dir, &index_path),
+            )
+        };
+
+        log::info!(
+            entries = self.memtable.len();
+            "Flushing memtable to compacted tables ({} entries)...",
+            self.memtable.len(),
+        );
+        let format = self.table_format(memtable::min_seq(&self.memtable));
+        let dictionary = self.write_dictionary();
+        let stride = self.memtable_stride();
+        let output = compact::Output {
+            index_stride: stride,
+            format,
+            dictionary: dictionary.as_deref(),
+            target_bytes: self.config.compaction_target_file_bytes,
+            part_paths: &part_paths,
+            limiter: &self.maintenance_io,
+            drop_deleted: true,
+            retention: &self.config.retention,
+            collation: self.sstable_set.collation,
+        };
+        let now = self.clock.now_millis();
+        let files = self.files.reserve(2).await;
+        let written = compact::write_memtable(&self.memtable, now, output).await;
+        drop(files);
+        let indexes = match written {
+            Ok(indexes) => indexes,
+            Err(e) => {
+                Self::remove_parts(&part_paths).await;
+                return Err(e);
+            }
+        };
+
+        let mut outputs = Vec::with_capacity(indexes.len());
+        for (n, index) in indexes.into_iter().enumerate() {
+            let (data_path, index_path) = table_paths(n);
+            let (data_part, index_part) = part_paths(n);
+            layout::install(&data_dir, &data_part, &data_path).await?;
+            layout::install(&data_dir, &index_part, &index_path).await?;
+            layout::sync_table_dir(&data_dir, &data_path).await?;
+            let layout = self.table_layout(&data_path).await?;
+            self.totals.bytes_written += layout.data_len;
+            outputs.push(
+                SSTable::new(
+                    data_path,
+                    index_path,
+                    index,
+                    layout,
+                    sequence + n,
+                    true,
+                    self.clock.now_millis(),
+                )
+                .with_stride(stride),
+            );
+        }
+        log::info!(outputs = outputs.len(); "Done: {} tables.", outputs.len());
+
+        outputs.reverse();
+        self.sstable_set.last_sequence += outputs.len();
+        self.sstable_set.tables = outputs;
+        self.commit_manifest(Manifest::new(&self.sstable_set)).await?;
+        self.memtable.clear();
+        self.truncate_wal()?;
+        self.current_size = 0;
+        self.refresh_disk_usage().await
+    }
+
+    /// Merges the tables whose data files are numbered `numbers` into one,
+    /// e.g. the two newest small ones, leaving the others alone. They must
+    /// be adjacent in recency, so that the output takes their place among the
+    /// others. Tombstones and expired records are only dropped if the oldest
+    /// table is among them; otherwise they still hide older versions of their
+    /// keys. The output is written, committed and the inputs moved to the
+    /// trash as by a full compaction.
+    pub async fn compact_tables(&mut self, numbers: &[u64]) -> Result<()> {
+        let invalid = |message: String| Error::new(tokio::io::ErrorKind::InvalidInput, message);
+        let mut positions = Vec::with_capacity(numbers.len());
+        for &number in numbers {
+            let position = self
+                .sstable_set
+                .tables
+                .iter()
+                .position(|table| {
+                    recovery::file_number(Path::new(&table.data_path)) == Some(number)
+                })
+                .ok_or_else(|| invalid(format!("No table numbered {number}")))?;
+            positions.push(position);
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        if positions.len() < 2 {
+            return Err(invalid("At least two tables are needed".to_string()));
+        }
+        let (first, last) = (positions[0], positions[positions.len() - 1]);
+        if last - first + 1 != positions.len() {
+            return Err(invalid(format!(
+                "Tables must be adjacent in recency, {} tables lie between them",
+                last - first + 1 - positions.len()
+            )));
+        }
+        let inputs = first..=last;
+        self.check_compaction_space(inputs.clone()).await?;
+        let drop_deleted =
+            last == self.sstable_set.tables.len() - 1 && self.sstable_set.skipped.is_empty();
+
+        let mut base_seq = u64::MAX;
+        for table in &self.sstable_set.tables[inputs.clone()] {
+            base_seq = base_seq.min(table.layout().await?.format.base_seq());
+        }
+        let format = self.table_format(base_seq);
+        let dictionary = self.write_dictionary();
+        let stride = self.merged_stride(&self.sstable_set.tables[inputs.clone()]).await?;
+        let number = self.sstable_set.last_sequence + 1;
+        let (data_path, index_path) = self.sstable_set.layout.table_paths(number);
+        let data_dir = self.config.data_dir.clone();
+        let part_paths = |_: usize| {
+            (
+                layout::part_path(&data_dir, &data_path),
+                layout::part_path(&data_dir, &index_path),
+            )
+        };
+
+        log::info!(
+            inputs = positions.len();
+            "Compacting {:?} into {data_path}...",
+            self.sstable_set.tables[inputs.clone()]
+                .iter()
+                .map(|table| table.data_path.as_str())
+                .collect::<Vec<_>>()
+        );
+        let output = compact::Output {
+            index_stride: stride,
+            format,
+            dictionary: dictionary.as_deref(),
+            target_bytes: 0,
+            part_paths: &part_paths,
+            limiter: &self.maintenance_io,
+            drop_deleted,
+            retention: &self.config.retention,
+            collation: self.sstable_set.collation,
+        };
+        // The inputs, and the data and index file of the output.
+        let files = self.files.reserve(inputs.clone().count() + 2).await;
+        let result = compact::merge_tables(
+            &self.sstable_set.tables[inputs.clone()],
+            self.sstable_set.dictionary.clone(),
+            &data_dir,
+            self.clock.now_millis(),
+            output,
+        )
+        .await;
+        drop(files);
+        let indexes = match result {
+            Ok(indexes) => indexes,
+            Err(e) => {
+                Self::remove_parts(&part_paths).await;
+                return Err(e);
+            }
+        };
+
+        // The output takes the generation of the newest input, keeping the
+        // set ordered by decreasing generation.
+        let generation = self.sstable_set.tables[first].generation;
+        let mut outputs = Vec::new();
+        if let Some(index) = indexes.into_iter().next() {
+            let (data_part, index_part) = part_paths(0);
+            layout::install(&data_dir, &data_part, &data_path).await?;
+            layout::install(&data_dir, &index_part, &index_path).await?;
+            layout::sync_table_dir(&data_dir, &data_path).await?;
+            let layout = self.table_layout(&data_path).await?;
+            self.totals.bytes_written += layout.data_len;
+            outputs.push(
+                SSTable::new(
+                    data_path,
+                    index_path,
+                    index,
+                    layout,
+                    generation,
+                    false,
+                    self.clock.now_millis(),
+                )
+                .with_stride(stride),
+            );
+            disk::sync_dir(&data_dir).await?;
+            self.sstable_set.last_sequence = number;
         }
+        log::info!(outputs = outputs.len(); "Finished compacting tables.");
+
+        let obsolete: Vec<PathBuf> = self
+            .sstable_set
+            .tables
+            .splice(inputs, outputs)
+            .flat_map(|table| [table.data_path, table.index_path])
+            .map(PathBuf::from)
+            .collect();
+        self.totals.compactions += 1;
+        self.retire(obsolete).await
+    }
+
+    /// Commits the set of tables with the files a compaction replaced listed
+    /// as obsolete, then moves those no reader holds to the trash.
+    async fn retire(&mut self, obsolete: Vec<PathBuf>) -> Result<()> {
+        let data_dir = self.config.data_dir.clone();
+        let mut manifest = Manifest::new(&self.sstable_set);
+        manifest.obsolete = obsolete.clone();
+        self.commit_manifest(manifest).await?;
 
-        for SSTable {
-            index, data_path, ..
-        } in &self.sstable_set.tables
+        self.files.clear();
+        let live = self.live_files();
+        let (pinned, released): (Vec<_>, Vec<_>) =
+            obsolete.into_iter().partition(|path| live.contains(path));
+        if !pinned.is_empty() {
+            log::info!("Keeping input files held by readers: {:?}", pinned);
+        }
+        log::info!("Moving input files to the trash: {:?}", released);
+        let now = self.clock.now_millis();
+        try_join_all(
+            released
+                .iter()
+                .map(|path| trash::discard(&data_dir, path, now)),
+        )
+        .await?;
+        self.deferred.extend(pinned);
+        self.commit_manifest(Manifest::new(&self.sstable_set)).await?;
+        self.refresh_disk_usage().await?;
+        if self.config.trash_retention_ms == 0
+            && let Err(e) = trash::purge(&data_dir, now, 0).await
         {
-            let range = sparse_index::bounds(&index, key);
-            let mut file = BufReader::new(File::open(&self.config.data_dir.join(data_path)).await?);
+            log::warn!(error:% = e; "Unable to purge the trash: {e}");
+        }
+        Ok(())
+    }
 
-            if let Some(inner) = sstable_set::seek_and_read(&mut file, key, range).await? {
-                return Ok(inner.to_value());
+    /// Removes the `.part` files `part_paths` names, left by a failed
+    /// compaction or bulk load.
+    async fn remove_parts(part_paths: &(dyn Fn(usize) -> (PathBuf, PathBuf) + Sync)) {
+        for n in 0.. {
+            let (data_part, index_part) = part_paths(n);
+            let data_removed = tokio::fs::remove_file(data_part).await.is_ok();
+            let index_removed = tokio::fs::remove_file(index_part).await.is_ok();
+            if !data_removed && !index_removed {
+                break;
             }
         }
-        Ok(None)
     }
 
-    async fn set(&mut self, key: String, value: Value) -> Result<()> {
-        self.remove_key_size(&key);
-        self.current_size += key.len() + value.len();
-        self.memtable.insert(key, MemValue::Value(value));
+    /// Atomically replaces the MANIFEST with `manifest`.
+    ///
+    /// Files replaced by a compaction that readers still hold are added to
+    /// `Manifest::obsolete`, so that they are cleaned up on the next open
+    /// should the database stop before they are released.
+    async fn commit_manifest(&self, mut manifest: Manifest) -> Result<()> {
+        for path in &self.deferred {
+            if !manifest.obsolete.contains(path) {
+                manifest.obsolete.push(path.clone());
+            }
+        }
+        let manifest_path = Self::get_manifest_path(&self.config.data_dir);
+        log::info!("Writing manifest file: {}...", &manifest_path);
+        manifest::commit(&manifest, &self.config.data_dir).await?;
+        self.versions.install(Version::new(&self.sstable_set));
+        log::info!("Done.");
         Ok(())
     }
 
-    async fn delete(&mut self, key: String) -> Result<()> {
-        self.remove_key_size(&key);
-        self.memtable.insert(key, MemValue::Tombstone);
+    /// Rewrites the MANIFEST as a fresh snapshot of the tables the database
+    /// holds, checked by reading it back before it replaces the old one; see
+    /// [`manifest::commit_verified`]. Tables are left as they are.
+    pub async fn rewrite_manifest(&mut self) -> Result<ManifestRewrite> {
+        let data_dir = self.config.data_dir.clone();
+        let old_bytes = tokio::fs::metadata(data_dir.join("MANIFEST")).await?.len();
+        let mut manifest = Manifest::new(&self.sstable_set);
+        manifest.obsolete = self.deferred.clone();
+        log::info!("Rewriting manifest file: {}...", Self::get_manifest_path(&data_dir));
+        let new_bytes = manifest::commit_verified(&manifest, &data_dir).await?;
+        log::info!("Done.");
+        Ok(ManifestRewrite {
+            tables: manifest.sstables.len(),
+            old_bytes,
+            new_bytes,
+        })
+    }
+
+    /// Saves the counters kept across restarts to the data directory; see
+    /// [`Totals`].
+    pub async fn save_totals(&self) -> Result<()> {
+        self.totals.save(&self.config.data_dir).await
+    }
+
+    async fn table_layout(&self, data_path: &str) -> Result<TableLayout> {
+        let _files = self.files.reserve(1).await;
+        format::read_layout(&mut File::open(self.config.data_dir.join(data_path)).await?).await
+    }
+}
+
+/// What a backup copies: the version of the set of tables current when it
+/// started, held until it is done, and the MANIFEST listing them.
+pub(crate) struct BackupSource {
+    data_dir: PathBuf,
+    files: Arc<FileCache>,
+    manifest: Manifest,
+    version: Arc<Version>,
+}
+
+impl BackupSource {
+    /// Copies the files into `dest`, which must not hold a database yet.
+    pub(crate) async fn copy_to(self, dest: &Path) -> Result<()> {
+        let dest_manifest = DatabaseImpl::get_manifest_path(dest);
+        if tokio::fs::metadata(&dest_manifest).await.is_ok() {
+            return Err(Error::new(
+                tokio::io::ErrorKind::AlreadyExists,
+                format!("{} already contains a database", dest.display()),
+            ));
+        }
+        tokio::fs::create_dir_all(dest).await?;
+
+        log::info!("Backing up {} tables to {}...", self.manifest.sstables.len(), dest.display());
+        for path in self.version.files() {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dest.join(dir)).await?;
+            }
+            let _files = self.files.reserve(2).await;
+            tokio::fs::copy(self.data_dir.join(path), dest.join(path)).await?;
+        }
+        manifest::write_manifest(
+            &self.manifest,
+            &mut BufWriter::new(File::create(&dest_manifest).await?),
+        )
+        .await?;
+        log::info!("Done.");
         Ok(())
     }
 }
 
+impl Database for DatabaseImpl {
+    async fn get(&self, key: &str) -> Result<Option<Value>> {
+        let now = self.clock.now_millis();
+        let value = self
+            .get_entry(key)
+            .await?
+            .filter(|entry| !entry.is_expired(now))
+            .and_then(MemValue::into_value);
+        if value.is_some()
+            && let Some(lru) = &self.lru
+        {
+            lru.lock().unwrap().touch(key, self.lru_tick());
+        }
+        Ok(value)
+    }
+
+    async fn set(&mut self, key: String, value: Value) -> Result<()> {
+        self.write_entries(vec![(key, MemValue::Value(value))])
+    }
+
+    async fn delete(&mut self, key: String) -> Result<()> {
+        self.write_entries(vec![(key, MemValue::Tombstone)])
+    }
+}
+
 impl DatabaseAdmin for DatabaseImpl {
+    /// Writes the memtable to a new table. The table is written to `.part`
+    /// files, synced and renamed before anything in memory changes, so a
+    /// failed or cancelled flush leaves at most files that the next flush
+    /// overwrites or the next open deletes. The table is then added to the
+    /// set and the manifest committed; only once that succeeds is the
+    /// memtable cleared. Should the commit fail or be cancelled, the
+    /// memtable keeps records the new table holds too, which reads resolve
+    /// the same way and the next flush writes again. The write-ahead log is
+    /// emptied along with the memtable, once copied to
+    /// `Config::wal_archive_dir` if set. The first flush of a
+    /// database is written as compacted tables, see
+    /// [`DatabaseImpl::flush_compacted`].
     async fn flush(&mut self) -> Result<()> {
+        if let (Some(wal), Some(archive_dir)) = (&self.wal, &self.config.wal_archive_dir) {
+            wal.archive(archive_dir).await?;
+        }
+        if self.sstable_set.tables.is_empty() && self.sstable_set.skipped.is_empty() {
+            return self.flush_compacted().await;
+        }
         let next_sequence = self.sstable_set.last_sequence + 1;
-        let data_path = format!("{:0>5}.db", next_sequence);
-        let index_path = format!("{:0>5}.idx", next_sequence);
-        let mut data_writer =
-            BufWriter::new(File::create(self.config.data_dir.join(&data_path)).await?);
-        let mut index_writer =
-            BufWriter::new(File::create(self.config.data_dir.join(&index_path)).await?);
+        let (data_path, index_path) = self.sstable_set.layout.table_paths(next_sequence);
+        let data_dir = self.config.data_dir.clone();
+        let data_part = layout::part_path(&data_dir, &data_path);
+        let index_part = layout::part_path(&data_dir, &index_path);
+        let files = self.files.reserve(2).await;
+        let mut data_writer = BufWriter::new(File::create(&data_part).await?);
+        let mut index_writer = BufWriter::new(File::create(&index_part).await?);
 
         log::info!(
+            table = data_path.as_str(), entries = self.memtable.len();
             "Flushing memtable to {} ({} entries)...",
             data_path,
             self.memtable.len(),
         );
-        let index = memtable::flush_to(
-            &mut self.memtable,
+        let format = self.table_format(memtable::min_seq(&self.memtable));
+        let dictionary = self.write_dictionary();
+        let stride = self.memtable_stride();
+        let (index, data_len) = memtable::flush_to(
+            &self.memtable,
             &mut data_writer,
-            self.config.sparse_stride,
+            stride,
+            format,
+            dictionary.as_deref(),
+            &self.maintenance_io,
         )
         .await?;
 
         log::info!("Writing index to {}...", index_path);
-        sparse_index::write_to(&index, &mut index_writer).await?;
+        sparse_index::write_to(&index, data_len, &mut index_writer).await?;
         let (data_res, index_res) =
             futures::future::join(data_writer.flush(), index_writer.flush()).await;
         data_res?;
         index_res?;
-        log::info!("Done.");
+        data_writer.get_ref().sync_all().await?;
+        index_writer.get_ref().sync_all().await?;
+        drop((data_writer, index_writer, files));
+        layout::install(&data_dir, &data_part, &data_path).await?;
+        layout::install(&data_dir, &index_part, &index_path).await?;
+        layout::sync_table_dir(&data_dir, &data_path).await?;
+        log::info!(table = data_path.as_str(); "Done.");
 
+        let layout = self.table_layout(&data_path).await?;
+        self.totals.bytes_written += layout.data_len;
         self.sstable_set.tables.insert(
             0,
-            SSTable {
-                index,
+            SSTable::new(
                 data_path,
                 index_path,
-            },
+                index,
+                layout,
+                next_sequence,
+                false,
+                self.clock.now_millis(),
+            )
+            .with_stride(stride),
         );
         self.sstable_set.last_sequence = next_sequence;
 
-        let manifest_path = Self::get_manifest_path(&self.config.data_dir);
-        log::info!("Writing manifest file: {}...", &manifest_path);
-        manifest::write_manifest(
-            &Manifest::new(&self.sstable_set),
-            &mut BufWriter::new(File::create(&manifest_path).await?),
-        )
-        .await?;
-        log::info!("Done.");
-        Ok(())
+        self.commit_manifest(Manifest::new(&self.sstable_set)).await?;
+        self.memtable.clear();
+        self.truncate_wal()?;
+        self.current_size = 0;
+        self.refresh_disk_usage().await
     }
 
+    /// Merges every table into tables of about
+    /// `Config::compaction_target_file_bytes` each. The outputs are written
+    /// under new sequence numbers and synced before the manifest is
+    /// atomically switched over to them; the inputs are only moved to the
+    /// trash afterwards, see `Config::trash_retention_ms`. A crash at any
+    /// point leaves either the inputs or the outputs referenced by the
+    /// manifest, and the others are cleaned up on the next open.
     async fn compact(&mut self) -> Result<()> {
-        if self.sstable_set.tables.len() < 2 {
+        if self.sstable_set.sorted_runs() < 2 {
             return Ok(());
         }
+        self.check_compaction_space(0..=self.sstable_set.tables.len() - 1).await?;
+
+        let data_dir = self.config.data_dir.clone();
+        // A trained dictionary takes the first free file number and the
+        // outputs the following ones: numbers are never reused.
+        let next_number = self.sstable_set.last_sequence + 1;
+        let (format, dictionary) = self.compaction_output(next_number).await?;
+        let sequence = next_number + usize::from(dictionary.is_some());
+        let stride = self.merged_stride(&self.sstable_set.tables).await?;
+        let file_layout = self.sstable_set.layout.clone();
+        let table_paths = |n: usize| file_layout.table_paths(sequence + n);
+        let part_paths = |n: usize| {
+            let (data_path, index_path) = table_paths(n);
+            (
+                layout::part_path(&data_dir, &data_path),
+                layout::part_path(&data_dir, &index_path),
+            )
+        };
+
+        let indexes = match self.compact_into(format, dictionary.as_deref(), stride, &part_paths).await {
+            Ok(indexes) => indexes,
+            Err(e) => {
+                if let Some(dictionary) = &dictionary {
+                    let _ = tokio::fs::remove_file(data_dir.join(dictionary.file_name())).await;
+                }
+                Self::remove_parts(&part_paths).await;
+                return Err(e);
+            }
+        };
+
+        let mut outputs = Vec::with_capacity(indexes.len());
+        for (n, index) in indexes.into_iter().enumerate() {
+            let (data_path, index_path) = table_paths(n);
+            let (data_part, index_part) = part_paths(n);
+            layout::install(&data_dir, &data_part, &data_path).await?;
+            layout::install(&data_dir, &index_part, &index_path).await?;
+            layout::sync_table_dir(&data_dir, &data_path).await?;
+            let layout = self.table_layout(&data_path).await?;
+            self.totals.bytes_written += layout.data_len;
+            outputs.push(
+                SSTable::new(
+                    data_path,
+                    index_path,
+                    index,
+                    layout,
+                    sequence + n,
+                    true,
+                    self.clock.now_millis(),
+                )
+                .with_stride(stride),
+            );
+        }
+        disk::sync_dir(&data_dir).await?;
 
-        let data_path_part = self.config.data_dir.join("compact.db.part");
-        let idx_path_part = self.config.data_dir.join("compact.idx.part");
-        let final_data_path = self.config.data_dir.join("00001.db");
-        let final_idx_path = self.config.data_dir.join("00001.idx");
+        // Outputs hold disjoint key ranges, so their relative order doesn't
+        // matter; keep the set ordered by decreasing generation anyway.
+        outputs.reverse();
+        self.sstable_set.last_sequence = sequence + outputs.len() - 1;
+        let inputs = std::mem::replace(&mut self.sstable_set.tables, outputs);
+        let old_dictionary = std::mem::replace(&mut self.sstable_set.dictionary, dictionary);
+        // With no record left, nothing was compressed with the new dictionary.
+        let unused_dictionary = if self.sstable_set.tables.is_empty() {
+            self.sstable_set.dictionary.take()
+        } else {
+            None
+        };
 
-        let data_files: Vec<_> = self
-            .sstable_set
-            .tables
+        let obsolete: Vec<PathBuf> = inputs
             .iter()
-            .map(|x| self.config.data_dir.join(&x.data_path))
+            .flat_map(|table| [&table.data_path, &table.index_path])
+            .map(PathBuf::from)
+            .chain(
+                old_dictionary
+                    .into_iter()
+                    .chain(unused_dictionary)
+                    .map(|dictionary| dictionary.file_name().into()),
+            )
             .collect();
-        let index_files: Vec<_> = self
-            .sstable_set
-            .tables
-            .iter()
-            .map(|x| self.config.data_dir.join(&x.index_path))
-            .collect();
-        let mut output = File::create(&data_path_part).await?;
-        let mut output_idx = File::create(&idx_path_part).await?;
-
-        log::info!("Starting log compaction.");
-        log::info!("Input log files: {:#?}", data_files,);
-        log::info!("Output log file: {}", data_path_part.to_str().unwrap());
-        let index = compact::compact_sstable_set(
-            &mut self.sstable_set,
-            &mut output,
-            &self.config.data_dir,
-            self.config.sparse_stride,
-        )
-        .await?;
-        sparse_index::write_to(&index, &mut output_idx).await?;
-        log::info!("Finished log compaction.");
-
-        log::info!("Deleting input files: {:?}", data_files);
-        let _ = try_join_all(
-            data_files
-                .into_iter()
-                .chain(index_files)
-                .map(tokio::fs::remove_file),
-        )
-        .await?;
-        let _ = join!(
-            tokio::fs::rename(data_path_part, final_data_path),
-            tokio::fs::rename(idx_path_part, final_idx_path),
-        );
-
-        self.sstable_set.tables.clear();
-        self.sstable_set.tables.push(SSTable {
-            index,
-            index_path: "00001.idx".to_string(),
-            data_path: "00001.db".to_string(),
-        });
-        self.sstable_set.last_sequence = 1;
-
-        let manifest_path = DatabaseImpl::get_manifest_path(&self.config.data_dir);
-        log::info!("Updating manifest file: {}...", &manifest_path);
-        manifest::write_manifest(
-            &Manifest::new(&self.sstable_set),
-            &mut BufWriter::new(File::create(&manifest_path).await?),
-        )
-        .await
+        self.totals.compactions += 1;
+        self.retire(obsolete).await
     }
 
     async fn dump(&self) -> Result<()> {
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::io::Result;
+
+use crate::Value;
+
+/// Fetches the values of keys missing from the database from an upstream
+/// source, making [`crate::Controller::get`] read through to it. No loader is
+/// built in: embedders register one with
+/// [`crate::Controller::with_cache_loader`].
+pub trait CacheLoader: Send + Sync {
+    /// Returns the upstream value of `key`, or `None` if it has none. No
+    /// database lock is held meanwhile, so concurrent misses of the same key
+    /// may each call it.
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Loaded>>>;
+}
+
+/// A value fetched by a [`CacheLoader`], stored before it is returned.
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub value: Value,
+    /// How long the stored value lives, or `None` to keep it until it is
+    /// overwritten, deleted or evicted.
+    pub ttl: Option<Duration>,
+}
+
+impl Loaded {
+    pub fn new(value: Value) -> Self {
+        Self { value, ttl: None }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+}
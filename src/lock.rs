@@ -0,0 +1,55 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+pub const LOCK_FILE: &str = "LOCK";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStatus {
+    /// No `LOCK` file exists; the directory was never opened.
+    Missing,
+    /// The `LOCK` file exists and nobody holds it.
+    Free,
+    /// Another handle (possibly in this process) holds the lock.
+    Held,
+}
+
+/// Takes an exclusive advisory lock on `data_dir`, held until the returned
+/// file is dropped.
+pub fn acquire(data_dir: &Path) -> Result<File> {
+    let path = data_dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Error::new(
+            ErrorKind::WouldBlock,
+            format!(
+                "Data directory {} is in use by another process",
+                data_dir.display()
+            ),
+        )),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Reports whether `data_dir` is currently locked, without keeping the lock.
+pub fn status(data_dir: &Path) -> Result<LockStatus> {
+    let file = match File::open(data_dir.join(LOCK_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(LockStatus::Missing),
+        Err(e) => return Err(e),
+    };
+
+    match file.try_lock_shared() {
+        Ok(()) => Ok(LockStatus::Free),
+        Err(TryLockError::WouldBlock) => Ok(LockStatus::Held),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
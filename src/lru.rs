@@ -0,0 +1,108 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
+};
+
+use tokio::io::Result;
+
+use crate::{
+    format::Format,
+    record::MemValue,
+    sstable_set::{SSTableSet, TableScan},
+};
+
+/// Size and approximate recency of every live key, used in cache mode to pick
+/// the keys to evict.
+///
+/// Recency is tracked with a coarse clock (see [`LruTracker::touch`]), so keys
+/// used within the same tick are evicted in key order rather than strictly by
+/// last use.
+#[derive(Debug, Default)]
+pub struct LruTracker {
+    by_age: BTreeSet<(u64, String)>,
+    entries: HashMap<String, (u64, u64)>,
+    total_bytes: u64,
+}
+
+impl LruTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the total size of the tracked keys and values, in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Records that `key` has `size` bytes and was written at `tick`.
+    pub fn insert(&mut self, key: String, size: u64, tick: u64) {
+        self.remove(&key);
+        self.total_bytes += size;
+        self.by_age.insert((tick, key.clone()));
+        self.entries.insert(key, (tick, size));
+    }
+
+    /// Marks `key`, if tracked, as used at `tick`.
+    pub fn touch(&mut self, key: &str, tick: u64) {
+        let Some((last_used, _)) = self.entries.get_mut(key) else {
+            return;
+        };
+        if *last_used >= tick {
+            return;
+        }
+        self.by_age.remove(&(*last_used, key.to_string()));
+        self.by_age.insert((tick, key.to_string()));
+        *last_used = tick;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some((last_used, size)) = self.entries.remove(key) {
+            self.by_age.remove(&(last_used, key.to_string()));
+            self.total_bytes -= size;
+        }
+    }
+
+    /// Removes and returns the least recently used key.
+    pub fn pop_lru(&mut self) -> Option<String> {
+        let (_, key) = self.by_age.pop_first()?;
+        if let Some((_, size)) = self.entries.remove(&key) {
+            self.total_bytes -= size;
+        }
+        Some(key)
+    }
+}
+
+/// Returns the size `value` is accounted for under `key`: that of its record
+/// in `format`.
+pub fn entry_size(key: &str, value: &MemValue, format: Format) -> u64 {
+    value.encoded_len(key, format) as u64
+}
+
+/// Rebuilds an `LruTracker` by scanning every SSTable, newest first. Every
+/// loaded key counts as used at tick `0`, i.e. before anything used since.
+pub async fn load(sstable_set: &SSTableSet, data_dir: &Path) -> Result<LruTracker> {
+    let mut tracker = LruTracker::new();
+    let mut seen = HashSet::new();
+
+    for table in &sstable_set.tables {
+        let mut scan = TableScan::open(&data_dir.join(&table.data_path))
+            .await?
+            .with_dictionary(sstable_set.dictionary.clone());
+        let format = scan.layout().format;
+        while let Some(record) = scan.next().await? {
+            if !seen.insert(record.key.clone()) {
+                continue;
+            }
+            if !matches!(record.value, MemValue::Tombstone) {
+                let size = entry_size(&record.key, &record.value, format);
+                tracker.insert(sstable_set.collation.decode(&record.key).to_string(), size, 0);
+            }
+        }
+    }
+
+    Ok(tracker)
+}
@@ -1,191 +1,322 @@
-use std::sync::Arc;
-
-use core::net::SocketAddr;
-use tokio::{
-    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Error, Result},
-    net::{TcpListener, TcpStream},
-    sync::watch::{self, Receiver},
-    task::JoinSet,
-};
+use std::{collections::BTreeMap, io::Write, path::PathBuf, str::FromStr, sync::Arc};
+
+use tokio::io::{BufReader, Error, ErrorKind, Result};
 
-use my_database::{Config, Controller, DatabaseImpl, Value};
+use log::kv::{self, Key, VisitSource};
+use serde::{Deserialize, Serialize};
+
+use my_database::{
+    Config, ConfigFile, Controller, DatabaseImpl, EventClasses,
+    server::{DEFAULT_DATABASE, Databases, Server, ServerConfig},
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
-    let database = Controller::new(
-        DatabaseImpl::build(Config {
-            data_dir: "data".into(),
-            sparse_stride: 20,
-            memtable_capacity: 1000,
-            create_if_missing: true,
-        })
-        .await?,
-        50000
-    );
-
-    let db = Arc::new(database);
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+    let subcommand = args
+        .first()
+        .filter(|arg| matches!(arg.as_str(), "doctor" | "repair"))
+        .cloned();
+    if subcommand.is_some() {
+        args.remove(0);
+    }
+    let settings = Settings::load(args)?;
+    init_logger(settings.server.log_format);
 
-    let listener = TcpListener::bind("127.0.0.1:2345").await?;
+    match subcommand.as_deref() {
+        Some("doctor") => {
+            let report = my_database::doctor(&settings.database.data_dir).await?;
+            print!("{report}");
+            std::process::exit(if report.is_healthy() { 0 } else { 1 });
+        }
+        Some("repair") => {
+            let report = DatabaseImpl::repair(&settings.database.data_dir).await?;
+            print!("{report}");
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    // Bound before recovery so that `/readyz` can report it.
+    let mut server = Server::bind(settings.server.listeners).await?;
 
-    let db_clone = db.clone();
-    let listener_handle = tokio::spawn(async move {
-        let _ = accept_connections(listener, &db_clone, shutdown_rx).await;
-    });
+    let open = |config: Config, section: String| {
+        let config_path = settings.config_path.clone();
+        async move {
+            log::info!("Opening database in {}...", config.data_dir.display());
+            let flush_threshold = config.flush_threshold;
+            let mut database = Controller::new(DatabaseImpl::build(config).await?, flush_threshold);
+            if let Some(path) = config_path {
+                database = database.with_config_file(ConfigFile {
+                    path,
+                    section: Some(section),
+                });
+            }
+            Ok::<_, Error>(Arc::new(database))
+        }
+    };
+    let mut databases = Databases::new(open(settings.database, "database".to_string()).await?);
+    for (name, config) in settings.databases {
+        let database = open(config, format!("databases.{name}")).await?;
+        databases = databases.with(name, database);
+    }
+    server.serve(databases.clone());
 
     let stdin = BufReader::new(tokio::io::stdin());
     let mut stdout = tokio::io::stdout();
-    repl(&db, stdin, &mut stdout).await?;
-
-    let _ = shutdown_tx.send(());
+    server.console(stdin, &mut stdout).await?;
 
-    listener_handle.await?;
-    log::info!("Closed network socket.");
-    db.shutdown().await?;
+    server.shutdown().await?;
+    for (_, database) in databases.iter() {
+        database.shutdown().await?;
+    }
 
     Ok(())
 }
 
-async fn accept_connections(
-    listener: TcpListener,
-    db: &Arc<Controller>,
-    shutdown_rx: Receiver<()>,
-) -> Result<()> {
-    let mut shutdown_rx_main = shutdown_rx.clone();
-    let mut connections = JoinSet::new();
-
-    tokio::select! {
-        Ok::<_, Error>(()) = async {
-            loop {
-                let (socket, conn) = listener.accept().await?;
-
-                let db = db.clone();
-                let mut shutdown_rx_task = shutdown_rx.clone();
-                connections.spawn(async move {
-                    tokio::select! {
-                        _ = handle_connection(socket, conn, &db) => {},
-                        _ = shutdown_rx_task.changed() => {
-                            log::info!("Socket {}:{} shutdown requested", conn.ip(), conn.port());
-                        }
-                    }
-                });
-            }
-        } => {
-            Ok(())
-        },
-        _ = shutdown_rx_main.changed() => {
-            log::info!("Socket shutdown requested.");
-
-            while let Some(res) = connections.join_next().await {
-                if let Err(e) = res {
-                    log::warn!("Connection handler exited with error: {:?}", e)
-                }
-            }
+/// Complete server configuration. Built from the defaults below, then the TOML
+/// file given by `--config`, then command-line flags, then `LOGDB_*`
+/// environment variables, each layer overriding the previous one.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Settings {
+    database: Config,
+    /// Further databases served by name from separate data directories,
+    /// selected on a connection with `select <name>`; `database` is named
+    /// `default`. Fields they leave out take the library defaults, and
+    /// `LOGDB_*` variables don't apply to them.
+    databases: BTreeMap<String, Config>,
+    server: ServerSettings,
+    /// File given by `--config`, where `config set` persists changes.
+    #[serde(skip)]
+    config_path: Option<PathBuf>,
+}
 
-            Ok(())
-        },
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            database: Config {
+                data_dir: "data".into(),
+                sparse_stride: 20,
+                notify_events: EventClasses::ALL,
+                ..Config::default()
+            },
+            databases: BTreeMap::new(),
+            server: ServerSettings::default(),
+            config_path: None,
+        }
     }
 }
 
-async fn handle_connection(socket: TcpStream, addr: SocketAddr, database: &Controller) -> Result<()> {
-    let (read, mut write) = tokio::io::split(socket);
-    let read = BufReader::new(read);
-    log::info!("Client connection from {}:{}", addr.ip(), addr.port());
-    repl(database, read, &mut write).await?;
-    log::info!("Closed connection from {}:{}", addr.ip(), addr.port());
-    Ok::<_, Error>(())
-}
+impl Settings {
+    fn load(args: Vec<String>) -> Result<Self> {
+        let mut settings = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path = args.get(i + 1).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "Missing value for --config")
+                })?;
+                let contents = std::fs::read_to_string(path)?;
+                let mut settings: Settings = toml::from_str(&contents).map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("Unable to parse {path}: {e}"))
+                })?;
+                settings.config_path = Some(path.into());
+                settings
+            }
+            None => Settings::default(),
+        };
+        // TOML has no null, so listeners are disabled in the file with "none" too.
+        let listeners = &mut settings.server.listeners;
+        listeners.admin_bind = listeners.admin_bind.take().and_then(optional);
+        listeners.http_bind = listeners.http_bind.take().and_then(optional);
 
-async fn repl<R, W>(database: &Controller, input: R, output: &mut W) -> Result<()>
-where
-    R: AsyncBufRead + Unpin,
-    W: AsyncWrite + Unpin,
-{
-    let mut lines = input.lines();
-
-    loop {
-        output.write_all(b"> ").await?;
-        output.flush().await?;
-
-        if let Some(line) = lines.next_line().await? {
-            let line = line.trim();
-            if line == "exit" {
-                output.write_all(b"bye.\n").await?;
-                break;
+        settings.apply_args(args.into_iter())?;
+        settings.database.apply_env()?;
+        settings.server.apply_env()?;
+        if settings.databases.contains_key(DEFAULT_DATABASE) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("`{DEFAULT_DATABASE}` names [database], choose another name"),
+            ));
+        }
+        Ok(settings)
+    }
+
+    /// Applies `--data-dir <path>`, `--bind <addr[,addr...]>`, `--admin-bind <addr|none>`,
+    /// `--admin-token <token>`, `--http-bind <addr|none>`,
+    /// `--unix-socket <path|none>` and `--log-format <text|json>`.
+    fn apply_args(&mut self, mut args: impl Iterator<Item = String>) -> Result<()> {
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("Missing value for {flag}"))
+            })?;
+            match flag.as_str() {
+                "--config" => {}
+                "--data-dir" => self.database.data_dir = value.into(),
+                "--bind" => self.server.listeners.bind = addresses(&value),
+                "--admin-bind" => self.server.listeners.admin_bind = optional(value),
+                "--admin-token" => self.server.listeners.admin_token = Some(value),
+                "--http-bind" => self.server.listeners.http_bind = optional(value),
+                "--unix-socket" => {
+                    self.server.listeners.unix_socket = optional(value).map(Into::into)
+                }
+                "--log-format" => self.server.log_format = value.parse()?,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Unknown option {flag}"),
+                    ));
+                }
             }
-            parse(line, &database, output).await?;
-        } else {
-            break;
         }
+        Ok(())
     }
-    Ok(())
 }
 
-async fn parse<W: AsyncWrite + Unpin>(command: &str, database: &Controller, output: &mut W) -> Result<()> {
-    let args: Vec<_> = command.split_whitespace().collect();
-
-    match args.get(0) {
-        Some(&"get") => {
-            let value = database
-                .get(args.get(1).unwrap())
-                .await?
-                .map(|x| match x {
-                    Value::Str(s) => s,
-                    Value::Int64(i) => format!("i:{}", i.to_string()),
-                    Value::Float64(f) => format!("f:{}", f.to_string()),
-                })
-                .unwrap_or("(none)".to_string())
-                + "\n";
-
-            output.write_all(value.as_bytes()).await?;
-            output.flush().await
+/// Splits a comma separated list of addresses.
+fn addresses(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Maps `none` to `None`, for settings that can be disabled.
+fn optional(value: String) -> Option<String> {
+    (value != "none").then_some(value)
+}
+
+
+/// Network front-end settings.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ServerSettings {
+    #[serde(flatten)]
+    listeners: ServerConfig,
+    log_format: LogFormat,
+}
+
+impl ServerSettings {
+    /// Overrides fields with `LOGDB_BIND`, `LOGDB_ADMIN_BIND`, `LOGDB_ADMIN_TOKEN`,
+    /// `LOGDB_HTTP_BIND`, `LOGDB_UNIX_SOCKET` and `LOGDB_LOG_FORMAT`, when set.
+    fn apply_env(&mut self) -> Result<()> {
+        let var = |name| std::env::var(name).ok();
+        let listeners = &mut self.listeners;
+        if let Some(bind) = var("LOGDB_BIND") {
+            listeners.bind = addresses(&bind);
+        }
+        if let Some(admin_bind) = var("LOGDB_ADMIN_BIND") {
+            listeners.admin_bind = optional(admin_bind);
+        }
+        if let Some(admin_token) = var("LOGDB_ADMIN_TOKEN") {
+            listeners.admin_token = Some(admin_token);
         }
-        Some(&"set") => {
-            database
-                .set(
-                    args.get(1).unwrap().to_string(),
-                    parse_value(args.get(2).unwrap()),
-                )
-                .await
+        if let Some(http_bind) = var("LOGDB_HTTP_BIND") {
+            listeners.http_bind = optional(http_bind);
         }
-        Some(&"delete") => {
-            database
-                .delete(args.get(1).unwrap().to_string())
-                .await
+        if let Some(unix_socket) = var("LOGDB_UNIX_SOCKET") {
+            listeners.unix_socket = optional(unix_socket).map(Into::into);
         }
-        // Some(&"compact") => database.compact().await,
-        // Some(&"flush") => database.flush().await,
-        // Some(&"dump") => database.dump().await,
-        Some(&"words") => load_words_into_db(database).await,
-        _ => Ok(()),
+        if let Some(log_format) = var("LOGDB_LOG_FORMAT") {
+            self.log_format = log_format.parse()?;
+        }
+        Ok(())
     }
 }
 
-fn parse_value(input: &str) -> Value {
-    if let Some(rest) = input.strip_prefix("i:") {
-        if let Ok(num) = rest.parse::<i64>() {
-            return Value::Int64(num);
-        }
-    } else if let Some(rest) = input.strip_prefix("f:") {
-        if let Ok(num) = rest.parse::<f64>() {
-            return Value::Float64(num);
+/// How log records are written to stderr.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LogFormat {
+    /// `env_logger`'s human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per record, see [`write_json_record`].
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown log format {name:?}, expected text or json"),
+            )),
         }
     }
-    Value::Str(input.to_string())
 }
 
-async fn load_words_into_db(database: &Controller) -> Result<()> {
-    let content = tokio::fs::read_to_string("words.txt").await?;
-    let lines: Vec<_> = content.lines().map(|line| line.to_string()).collect();
+/// Installs the logger, filtered by `RUST_LOG` as usual.
+fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(write_json_record);
+    }
+    builder.init();
+}
+
+/// Writes `record` as a JSON object on a line of its own, with its `ts`,
+/// `level`, `target` and `message`, followed by its key-values as fields.
+/// Numbers and booleans are kept as such; any other value is a string.
+fn write_json_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> Result<()> {
+    let mut line = format!(
+        "{{\"ts\":{},\"level\":{},\"target\":{},\"message\":{}",
+        json_string(&buf.timestamp_millis().to_string()),
+        json_string(record.level().as_str()),
+        json_string(record.target()),
+        json_string(&record.args().to_string()),
+    );
+    let mut fields = JsonFields(&mut line);
+    let _ = record.key_values().visit(&mut fields);
+    line.push('}');
+    writeln!(buf, "{line}")
+}
+
+/// Appends the key-values of a record to a JSON object.
+struct JsonFields<'a>(&'a mut String);
 
-    for line in lines {
-        // let strip = Value::Str(line.chars().take(3).collect());
-        let reversed = Value::Str(line.chars().rev().collect());
-        database.set(line, reversed).await?;
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: kv::Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.to_string()
+        } else if let Some(n) = value.to_i64() {
+            n.to_string()
+        } else if let Some(x) = value.to_f64().filter(|x| x.is_finite()) {
+            x.to_string()
+        } else if let Some(b) = value.to_bool() {
+            b.to_string()
+        } else {
+            json_string(&value.to_string())
+        };
+        self.0.push_str(&format!(",{}:{value}", json_string(key.as_str())));
+        Ok(())
     }
-    
-    Ok(())
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
@@ -1,21 +1,69 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use tokio::io::{AsyncWrite, AsyncWriteExt, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt, Error, ErrorKind, Result};
 
+use crate::Config;
+use crate::collation::Collation;
+use crate::disk;
+use crate::layout::Layout;
 use crate::sstable_set::SSTableSet;
 use crate::version;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
     pub version: String,
+    /// Highest file number allocated to a table or dictionary. Numbers are
+    /// never reused, so a file name always refers to the same contents.
     pub last_sequence: usize,
+    /// Sequence number of the most recent flushed write.
+    #[serde(default)]
+    pub last_record_seq: u64,
+    /// Dictionary compressed values are decompressed with, if any.
+    #[serde(default)]
+    pub dictionary: Option<PathBuf>,
+    /// Files replaced by a committed compaction that may not have been
+    /// moved to the trash yet; they are moved when the database is opened.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub obsolete: Vec<PathBuf>,
+    /// Collation keys are stored with, see `Config::collation`.
+    #[serde(default, skip_serializing_if = "Collation::is_binary")]
+    pub collation: Collation,
+    /// Newest `Config::table_format` the database was opened with: tables may
+    /// have been written in it, which releases reading only older formats
+    /// can't open. Missing from manifests written before it was recorded.
+    #[serde(default)]
+    pub table_format: Option<u32>,
+    /// Layout tables are written in, see `Config::layout`. Tables written
+    /// in an earlier one keep their paths.
+    #[serde(default, skip_serializing_if = "Layout::is_default")]
+    pub layout: Layout,
     pub sstables: Vec<SSTableEntry>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SSTableEntry {
     pub data_path: PathBuf,
     pub index_path: PathBuf,
+    /// Recency of the table: tables with a higher generation hold newer
+    /// versions of their keys. Missing from manifests written before
+    /// generations were recorded, whose tables are listed newest first.
+    #[serde(default)]
+    pub generation: Option<usize>,
+    /// Written by the last compaction. Such tables hold disjoint key ranges.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compacted: bool,
+    /// Time the table was written at, in milliseconds since the Unix epoch.
+    /// Missing from manifests written before creation times were recorded,
+    /// whose tables are dated by the modification time of their data file.
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    /// Number of records per entry of the table's index, chosen when it was
+    /// written, see `Config::sparse_scan_bytes`. Missing for tables written
+    /// before strides were recorded and ingested ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stride: Option<usize>,
 }
 
 impl Manifest {
@@ -26,13 +74,73 @@ impl Manifest {
             .map(|table| SSTableEntry {
                 data_path: table.data_path.clone().into(),
                 index_path: table.index_path.clone().into(),
+                generation: Some(table.generation),
+                compacted: table.compacted,
+                created_at: Some(table.created_at),
+                stride: table.stride,
             })
+            .chain(sstable_set.skipped.iter().cloned())
             .collect();
         Self {
             version: version::VERSION.to_owned(),
             sstables,
             last_sequence: sstable_set.last_sequence,
+            last_record_seq: sstable_set.last_record_seq,
+            dictionary: sstable_set
+                .dictionary
+                .as_ref()
+                .map(|dictionary| dictionary.file_name().into()),
+            obsolete: Vec::new(),
+            collation: sstable_set.collation,
+            table_format: sstable_set.table_format,
+            layout: sstable_set.layout.clone(),
+        }
+    }
+
+    /// Records the options of `config` that decide how tables are stored,
+    /// returning `true` if anything changed and the MANIFEST must be
+    /// committed before a table is written. Fails if `config` conflicts with
+    /// the tables the database holds: keys stored with another collation, or
+    /// an older `table_format` than some tables were written in.
+    ///
+    /// Compression isn't checked: tables name the dictionary they were
+    /// compressed with, which stays in use until a compaction rewrites them.
+    pub fn apply_config(&mut self, config: &Config) -> Result<bool> {
+        let conflict = |message: String| Error::new(ErrorKind::InvalidInput, message);
+        let mut changed = false;
+        if self.sstables.is_empty() {
+            changed |= self.collation != config.collation;
+            self.collation = config.collation;
+        } else if self.collation != config.collation {
+            return Err(conflict(format!(
+                "The database stores keys with the {} collation, not {}",
+                self.collation, config.collation
+            )));
+        }
+
+        match self.table_format {
+            Some(newest) if newest > config.table_format && !self.sstables.is_empty() => {
+                return Err(conflict(format!(
+                    "The database holds tables in format {newest}, newer than table_format {}; \
+                     releases reading only that format couldn't open it",
+                    config.table_format
+                )));
+            }
+            Some(newest) if newest == config.table_format => {}
+            _ => {
+                self.table_format = Some(config.table_format);
+                changed = true;
+            }
+        }
+
+        if self.layout != config.layout {
+            // Recorded before any table is written in it, so that recovery
+            // recognizes what an interrupted write leaves behind.
+            log::info!("Writing new tables in {:?}.", config.layout);
+            self.layout = config.layout.clone();
+            changed = true;
         }
+        Ok(changed)
     }
 }
 
@@ -50,3 +158,82 @@ pub async fn write_manifest<W: AsyncWrite + Unpin>(
     writer.write_all(serialized.as_bytes()).await?;
     writer.flush().await
 }
+
+/// Atomically replaces the MANIFEST in `data_dir`: the new contents are
+/// written and synced to `MANIFEST.part`, which is then renamed over it.
+pub async fn commit(manifest: &Manifest, data_dir: &Path) -> Result<()> {
+    let part = write_part(manifest, data_dir).await?;
+    install(&part, data_dir).await
+}
+
+/// Like [`commit`], but reads the new MANIFEST back before it replaces the
+/// old one, failing unless it parses to `manifest` and the data files and
+/// dictionary it refers to exist. Returns its size in bytes.
+pub async fn commit_verified(manifest: &Manifest, data_dir: &Path) -> Result<u64> {
+    let part = write_part(manifest, data_dir).await?;
+    match verify(&part, manifest, data_dir).await {
+        Ok(len) => {
+            install(&part, data_dir).await?;
+            Ok(len)
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&part).await;
+            Err(e)
+        }
+    }
+}
+
+async fn write_part(manifest: &Manifest, data_dir: &Path) -> Result<PathBuf> {
+    let part = data_dir.join("MANIFEST.part");
+    let mut file = File::create(&part).await?;
+    write_manifest(manifest, &mut file).await?;
+    file.sync_all().await?;
+    Ok(part)
+}
+
+async fn install(part: &Path, data_dir: &Path) -> Result<()> {
+    tokio::fs::rename(part, data_dir.join("MANIFEST")).await?;
+    disk::sync_dir(data_dir).await
+}
+
+/// Checks the MANIFEST written to `part`, returning its size.
+async fn verify(part: &Path, manifest: &Manifest, data_dir: &Path) -> Result<u64> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    let contents = tokio::fs::read_to_string(part).await?;
+    let read = toml::from_str::<Manifest>(&contents)
+        .map_err(|e| invalid(format!("The new MANIFEST doesn't parse: {e}")))?;
+    if toml::to_string(&read).ok() != toml::to_string(manifest).ok() {
+        return Err(invalid("The new MANIFEST doesn't read back as written".to_string()));
+    }
+    let referenced = read.sstables.iter().map(|entry| &entry.data_path).chain(&read.dictionary);
+    for path in referenced {
+        if tokio::fs::metadata(data_dir.join(path)).await.is_err() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("The new MANIFEST refers to the missing {}", path.display()),
+            ));
+        }
+    }
+    Ok(contents.len() as u64)
+}
+
+/// Outcome of [`crate::DatabaseImpl::rewrite_manifest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestRewrite {
+    /// Number of tables the new MANIFEST lists.
+    pub tables: usize,
+    /// Size of the MANIFEST before the rewrite, in bytes.
+    pub old_bytes: u64,
+    /// Size of the new MANIFEST, in bytes.
+    pub new_bytes: u64,
+}
+
+impl fmt::Display for ManifestRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "manifest rewritten: {} tables, {} -> {} bytes",
+            self.tables, self.old_bytes, self.new_bytes
+        )
+    }
+}
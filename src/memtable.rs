@@ -3,16 +3,30 @@ use std::collections::BTreeMap;
 use tokio::io::{AsyncWrite, Result};
 
 use crate::{
-    record::{MemValue, Record},
+    dictionary::Dictionary,
+    format::{self, Format},
+    rate_limit::RateLimiter,
+    record::{FrameEncoder, MemValue, Record},
     sparse_index::SparseIndex,
 };
 
-pub type MemTable = BTreeMap<String, MemValue>;
+pub type MemTable = BTreeMap<String, MemEntry>;
+
+/// A memtable value along with the sequence number of the write that produced it.
+#[derive(Clone, Debug)]
+pub struct MemEntry {
+    pub seq: u64,
+    pub value: MemValue,
+}
+
+/// Returns the smallest sequence number in the memtable.
+pub fn min_seq(memtable: &MemTable) -> u64 {
+    memtable.values().map(|entry| entry.seq).min().unwrap_or(0)
+}
 
 /// Serializes the current contents of the memtable to the given writer.
 ///
-/// This function consumes all key-value pairs in the provided `memtable` and
-/// writes them to the `writer` in a compact binary format. As it writes,
+/// This function writes all key-value pairs in the provided `memtable` to the `writer` in a compact binary format. As it writes,
 /// it also constructs a `SparseIndex` that maps a subset of keys to their
 /// corresponding byte offsets in the output, enabling efficient lookup.
 ///
@@ -21,35 +35,48 @@ pub type MemTable = BTreeMap<String, MemValue>;
 ///
 /// # Arguments
 ///
-/// * `memtable` - The in-memory table of records to flush. Will be emptied after the operation.
+/// * `memtable` - The in-memory table of records to flush. It is left as is,
+///   to be cleared once the new table is committed.
 /// * `writer` - The output stream to which the records are written.
 /// * `index_stride` - How often to index a record (e.g., 1 = every record, 4 = every 4th record).
+/// * `format` - How records are framed; a v2 footer is written after them.
+/// * `dictionary` - Dictionary to compress values with, if any.
+/// * `limiter` - Limits the rate at which records are written.
 ///
 /// # Returns
 ///
-/// A `SparseIndex` containing the offset of every `index_stride`-th record written.
+/// A `SparseIndex` containing the offset of every `index_stride`-th record
+/// written, and the length of the records, footer excluded.
 ///
 /// # Errors
 ///
 /// Returns an error if writing to the output stream fails.
 pub async fn flush_to<W: AsyncWrite + Unpin>(
-    memtable: &mut MemTable,
+    memtable: &MemTable,
     writer: &mut W,
     index_stride: usize,
-) -> Result<SparseIndex> {
+    format: Format,
+    dictionary: Option<&Dictionary>,
+    limiter: &RateLimiter,
+) -> Result<(SparseIndex, u64)> {
     let mut index = SparseIndex::new();
-    let mut offset: u64 = 0;
-
-    let entries = std::mem::take(memtable);
-    for (i, (key, value)) in entries.into_iter().enumerate() {
-        let record = Record { key, value };
-        let len = record.write_to(writer).await?;
+    let mut encoder = FrameEncoder::new();
 
-        if i % index_stride == 0 {
+    for (i, (key, MemEntry { seq, value })) in memtable.iter().enumerate() {
+        let record = Record {
+            key: key.clone(),
+            value: value.clone(),
+            seq: *seq,
+        };
+        // Indexed records are restart points, encoded without the previous key.
+        let restart = i % index_stride == 0;
+        let (offset, len) = encoder.push(writer, &record, restart, format, dictionary).await?;
+        if restart {
             index.insert(record.key, offset);
         }
-
-        offset += len;
+        limiter.consume(len).await;
     }
-    Ok(index)
+    let data_len = encoder.finish(writer).await?;
+    format::write_footer(writer, format).await?;
+    Ok((index, data_len))
 }
@@ -0,0 +1,147 @@
+use std::{collections::BinaryHeap, ops::RangeBounds, vec};
+
+use tokio::io::Result;
+
+use crate::{
+    Value, memtable::MemTable, rate_limit::RateLimiter, record::Record, sstable_set::TableScan,
+};
+
+/// Part of a range read page by page with `Controller::scan_page`, which
+/// doesn't hold the database lock between pages.
+#[derive(Debug, Clone, Default)]
+pub struct ScanPage {
+    pub entries: Vec<(String, Value)>,
+    /// Where the next page starts, to pass back as is; `None` once the range
+    /// is exhausted.
+    pub next: Option<String>,
+}
+
+/// Records in key order, one of the inputs of a [`MergeIterator`].
+#[derive(Debug)]
+pub enum Source {
+    /// Records copied out of a memtable.
+    Memtable(vec::IntoIter<Record>),
+    /// Records read from a data file.
+    Table(Box<TableScan>),
+}
+
+impl Source {
+    /// Copies the records of `memtable` whose key is within `range`.
+    pub fn memtable<R: RangeBounds<String>>(memtable: &MemTable, range: R) -> Self {
+        let records: Vec<_> = memtable
+            .range(range)
+            .map(|(key, entry)| Record {
+                key: key.clone(),
+                value: entry.value.clone(),
+                seq: entry.seq,
+            })
+            .collect();
+        Source::Memtable(records.into_iter())
+    }
+
+    async fn next(&mut self, limiter: Option<&RateLimiter>) -> Result<Option<Record>> {
+        match self {
+            Source::Memtable(records) => Ok(records.next()),
+            Source::Table(scan) => {
+                let start = scan.offset();
+                let record = scan.next().await;
+                if let Some(limiter) = limiter {
+                    limiter.consume(scan.offset() - start).await;
+                }
+                record
+            }
+        }
+    }
+}
+
+/// Merges sources of records in key order, returning a single record per
+/// key: where several sources hold a key, the one listed first wins, so
+/// sources must be listed newest first. Tombstones and expired values are
+/// returned like any other record, for the caller to filter.
+#[derive(Debug)]
+pub struct MergeIterator<'a> {
+    sources: Vec<Source>,
+    heap: BinaryHeap<HeapEntry>,
+    /// Charged for every byte read from tables, if set.
+    limiter: Option<&'a RateLimiter>,
+}
+
+impl<'a> MergeIterator<'a> {
+    pub async fn new(sources: Vec<Source>) -> Result<Self> {
+        Self::with_limiter(sources, None).await
+    }
+
+    /// Like [`MergeIterator::new`], charging the bytes read from tables to
+    /// `limiter`.
+    pub async fn with_limiter(
+        sources: Vec<Source>,
+        limiter: Option<&'a RateLimiter>,
+    ) -> Result<Self> {
+        let mut merge = Self {
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            limiter,
+        };
+        for source in 0..merge.sources.len() {
+            merge.refill(source).await?;
+        }
+        Ok(merge)
+    }
+
+    /// Returns the newest record of the next key, or `None` once every
+    /// source is consumed.
+    pub async fn next(&mut self) -> Result<Option<Record>> {
+        let Some(entry) = self.heap.pop() else {
+            return Ok(None);
+        };
+        // Older versions of the key are ordered right after it: discard them,
+        // replacing each with the next record of the same source.
+        while let Some(next) = self.heap.peek()
+            && next.record.key == entry.record.key
+        {
+            let source = self.heap.pop().unwrap().source;
+            self.refill(source).await?;
+        }
+        self.refill(entry.source).await?;
+        Ok(Some(entry.record))
+    }
+
+    /// Pushes the next record of `source` onto the heap, if any.
+    async fn refill(&mut self, source: usize) -> Result<()> {
+        if let Some(record) = self.sources[source].next(self.limiter).await? {
+            self.heap.push(HeapEntry { record, source });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct HeapEntry {
+    record: Record,
+    /// Position of the source the record was read from; lower is newer.
+    source: usize,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.record.key.eq(&other.record.key) && self.source.eq(&other.source)
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.record
+            .key
+            .cmp(&other.record.key)
+            .reverse()
+            .then_with(|| self.source.cmp(&other.source).reverse())
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use toml_edit::DocumentMut;
+
+use crate::{Config, RetentionRule};
+
+/// Settings that can be changed while the database is running.
+#[derive(Debug)]
+pub struct RuntimeOptions {
+    flush_threshold: AtomicUsize,
+    compaction_trigger: AtomicUsize,
+    compaction_max_table_age_ms: AtomicU64,
+    write_slowdown_tables: AtomicUsize,
+    write_stop_tables: AtomicUsize,
+    slow_log_threshold_ms: AtomicU64,
+    operation_timeout_ms: AtomicU64,
+    ttl_sweep_interval_ms: AtomicU64,
+    max_disk_bytes: AtomicU64,
+}
+
+/// Names accepted by [`RuntimeOptions::set`].
+pub const OPTION_NAMES: &[&str] = &[
+    "flush_threshold",
+    "compaction_trigger",
+    "compaction_max_table_age_ms",
+    "write_slowdown_tables",
+    "write_stop_tables",
+    "slow_log_threshold_ms",
+    "operation_timeout_ms",
+    "ttl_sweep_interval_ms",
+    "max_disk_bytes",
+];
+
+impl RuntimeOptions {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            flush_threshold: AtomicUsize::new(config.flush_threshold),
+            compaction_trigger: AtomicUsize::new(config.compaction_trigger),
+            compaction_max_table_age_ms: AtomicU64::new(config.compaction_max_table_age_ms),
+            write_slowdown_tables: AtomicUsize::new(config.write_slowdown_tables),
+            write_stop_tables: AtomicUsize::new(config.write_stop_tables),
+            slow_log_threshold_ms: AtomicU64::new(config.slow_log_threshold_ms),
+            operation_timeout_ms: AtomicU64::new(config.operation_timeout_ms),
+            ttl_sweep_interval_ms: AtomicU64::new(config.ttl_sweep_interval_ms),
+            max_disk_bytes: AtomicU64::new(config.max_disk_bytes),
+        }
+    }
+
+    pub fn flush_threshold(&self) -> usize {
+        self.flush_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn compaction_trigger(&self) -> usize {
+        self.compaction_trigger.load(Ordering::Relaxed)
+    }
+
+    pub fn compaction_max_table_age_ms(&self) -> u64 {
+        self.compaction_max_table_age_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn write_slowdown_tables(&self) -> usize {
+        self.write_slowdown_tables.load(Ordering::Relaxed)
+    }
+
+    pub fn write_stop_tables(&self) -> usize {
+        self.write_stop_tables.load(Ordering::Relaxed)
+    }
+
+    pub fn slow_log_threshold_ms(&self) -> u64 {
+        self.slow_log_threshold_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn operation_timeout_ms(&self) -> u64 {
+        self.operation_timeout_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn ttl_sweep_interval_ms(&self) -> u64 {
+        self.ttl_sweep_interval_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn max_disk_bytes(&self) -> u64 {
+        self.max_disk_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Parses `value` and applies it to the option called `name`.
+    pub fn set(&self, name: &str, value: &str) -> Result<()> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid value for {name}: {value:?}"),
+            )
+        };
+        match name {
+            "flush_threshold" => self
+                .flush_threshold
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "compaction_trigger" => self
+                .compaction_trigger
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "compaction_max_table_age_ms" => self
+                .compaction_max_table_age_ms
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "write_slowdown_tables" => self
+                .write_slowdown_tables
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "write_stop_tables" => self
+                .write_stop_tables
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "slow_log_threshold_ms" => self
+                .slow_log_threshold_ms
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "operation_timeout_ms" => self
+                .operation_timeout_ms
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "ttl_sweep_interval_ms" => {
+                let interval: u64 = value.parse().map_err(|_| invalid())?;
+                if interval == 0 {
+                    return Err(invalid());
+                }
+                self.ttl_sweep_interval_ms.store(interval, Ordering::Relaxed)
+            }
+            "max_disk_bytes" => self
+                .max_disk_bytes
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Unknown or read-only option {name}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the current value of the option called `name`.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        match name {
+            "flush_threshold" => Some(self.flush_threshold() as u64),
+            "compaction_trigger" => Some(self.compaction_trigger() as u64),
+            "compaction_max_table_age_ms" => Some(self.compaction_max_table_age_ms()),
+            "write_slowdown_tables" => Some(self.write_slowdown_tables() as u64),
+            "write_stop_tables" => Some(self.write_stop_tables() as u64),
+            "slow_log_threshold_ms" => Some(self.slow_log_threshold_ms()),
+            "operation_timeout_ms" => Some(self.operation_timeout_ms()),
+            "ttl_sweep_interval_ms" => Some(self.ttl_sweep_interval_ms()),
+            "max_disk_bytes" => Some(self.max_disk_bytes()),
+            _ => None,
+        }
+    }
+}
+
+/// Where changed options are written back to.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: std::path::PathBuf,
+    /// TOML table holding the database options, as a dotted path such as
+    /// `databases.analytics` for a nested one, or `None` for the top level.
+    pub section: Option<String>,
+}
+
+impl ConfigFile {
+    /// Rewrites `name = value` in the file, keeping every other entry as well
+    /// as comments and formatting.
+    pub async fn persist(&self, name: &str, value: u64) -> Result<()> {
+        let mut document = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents.parse::<DocumentMut>().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unable to parse {}: {e}", self.path.display()),
+                )
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => DocumentMut::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut table = document.as_table_mut();
+        for section in self.section.iter().flat_map(|section| section.split('.')) {
+            table = table
+                .entry(section)
+                .or_insert(toml_edit::table())
+                .as_table_mut()
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("`{section}` in {} is not a table", self.path.display()),
+                    )
+                })?;
+        }
+        table[name] = toml_edit::value(value as i64);
+
+        write_atomically(&self.path, document.to_string().as_bytes()).await
+    }
+}
+
+impl ConfigFile {
+    /// Reads the `retention` rules of the section, none if it has no
+    /// `retention` entry.
+    pub async fn retention(&self) -> Result<Vec<RetentionRule>> {
+        let invalid = |message: String| {
+            Error::new(ErrorKind::InvalidData, format!("{}: {message}", self.path.display()))
+        };
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        let mut table: toml::Table =
+            toml::from_str(&contents).map_err(|e| invalid(format!("unable to parse: {e}")))?;
+        for section in self.section.iter().flat_map(|section| section.split('.')) {
+            table = match table.remove(section) {
+                Some(toml::Value::Table(table)) => table,
+                Some(_) => return Err(invalid(format!("`{section}` is not a table"))),
+                None => return Ok(Vec::new()),
+            };
+        }
+        match table.remove("retention") {
+            Some(rules) => rules.try_into().map_err(|e| invalid(format!("invalid retention: {e}"))),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Writes `contents` to a temporary sibling of `path` and renames it into place.
+async fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
@@ -0,0 +1,47 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// How far ahead of the configured rate IO may run, so that short pauses
+/// between reads aren't lost.
+const BURST: Duration = Duration::from_millis(100);
+
+/// Limits the rate of IO shared by every caller to `bytes_per_sec`.
+///
+/// Callers report the bytes they read or write and are delayed until the
+/// limiter has caught up with them.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// `0` for no limit.
+    bytes_per_sec: u64,
+    /// Time at which the bytes consumed so far are paid for.
+    paid_until: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Returns a limiter for `bytes_per_sec`, or one that never waits if it is `0`.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            paid_until: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `bytes` of IO, waiting as long as needed to stay under
+    /// the rate.
+    pub async fn consume(&self, bytes: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let now = Instant::now();
+        let paid_until = {
+            let mut paid_until = self.paid_until.lock().unwrap();
+            *paid_until = (*paid_until).max(now) + cost;
+            *paid_until
+        };
+        if paid_until > now + BURST {
+            tokio::time::sleep_until(paid_until - BURST).await;
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::{Stream, stream};
+use tokio::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Result},
+};
+
+use crate::{
+    dictionary::Dictionary,
+    format::{self, TableLayout},
+    record::{MemValue, Record},
+    sparse_index::{self, ScanRange, SparseIndex},
+    sstable_set::{self, TableScan},
+};
+
+/// Read-only access to a single `.db`/`.idx` pair, outside of a database.
+#[derive(Debug)]
+pub struct SSTableReader {
+    data_path: PathBuf,
+    index_path: PathBuf,
+    index: SparseIndex,
+    layout: TableLayout,
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableProperties {
+    /// Version of the record framing, see `Config::table_format`.
+    pub format_version: u32,
+    pub data_bytes: u64,
+    pub index_bytes: u64,
+    pub index_entries: usize,
+    /// Smallest key in the table (the first entry of the index).
+    pub first_key: String,
+}
+
+impl SSTableReader {
+    /// Opens the table at `data_path`, reading its sparse index from the file
+    /// with the same name and an `.idx` extension.
+    pub async fn open(data_path: impl AsRef<Path>) -> Result<Self> {
+        let data_path = data_path.as_ref().to_path_buf();
+        let index_path = data_path.with_extension("idx");
+        let layout = format::read_layout(&mut File::open(&data_path).await?).await?;
+        let index = sparse_index::read_from(
+            BufReader::new(File::open(&index_path).await?),
+            layout.data_len,
+        )
+        .await?;
+        if index.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Index can't be empty"));
+        }
+        Ok(Self {
+            data_path,
+            index_path,
+            index,
+            layout,
+            dictionary: None,
+        })
+    }
+
+    /// Loads the dictionary compressed values are read with, such as the
+    /// `.dict` file named by the MANIFEST of the table's database.
+    pub async fn load_dictionary(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.dictionary = Some(Arc::new(Dictionary::load(path.as_ref()).await?));
+        Ok(())
+    }
+
+    pub fn data_path(&self) -> &Path {
+        &self.data_path
+    }
+
+    pub fn index(&self) -> &SparseIndex {
+        &self.index
+    }
+
+    /// Returns where the record of `key` is in the data file, if the table
+    /// holds it: the range is always within the records of the file.
+    pub fn locate(&self, key: &str) -> ScanRange {
+        sparse_index::bounds(&self.index, key, self.layout.data_len)
+    }
+
+    /// Looks up `key`, returning tombstones and expired values as stored.
+    pub async fn get(&self, key: &str) -> Result<Option<MemValue>> {
+        let range = self.locate(key);
+        let mut file = BufReader::new(File::open(&self.data_path).await?);
+        let dictionary = self.dictionary.as_deref();
+        sstable_set::seek_and_read(&mut file, key, range, self.layout, dictionary).await
+    }
+
+    /// Returns every record in key order. The stream ends after the first
+    /// error, such as a checksum mismatch.
+    pub async fn records(&self) -> Result<impl Stream<Item = Result<Record>> + use<>> {
+        let scan = TableScan::open(&self.data_path)
+            .await?
+            .with_dictionary(self.dictionary.clone());
+        Ok(stream::unfold(Some(scan), |scan| async move {
+            let mut scan = scan?;
+            match scan.next().await {
+                Ok(Some(record)) => Some((Ok(record), Some(scan))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        }))
+    }
+
+    pub async fn properties(&self) -> Result<TableProperties> {
+        let data_bytes = tokio::fs::metadata(&self.data_path).await?.len();
+        let index_bytes = tokio::fs::metadata(&self.index_path).await?.len();
+        let first_key = self.index.keys().next().cloned().unwrap_or_default();
+        Ok(TableProperties {
+            format_version: self.layout.format.version(),
+            data_bytes,
+            index_bytes,
+            index_entries: self.index.len(),
+            first_key,
+        })
+    }
+}
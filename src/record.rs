@@ -1,13 +1,21 @@
 use std::{
     cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     io::{Error, ErrorKind, Result},
 };
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::{
+    dictionary::Dictionary,
+    format::{self, Format},
+};
+
 #[derive(Clone, Debug)]
 pub struct Record {
     pub key: String,
     pub value: MemValue,
+    /// Sequence number of the write that produced this record (`0` in v1 tables).
+    pub seq: u64,
 }
 
 impl PartialEq for Record {
@@ -30,117 +38,681 @@ impl Ord for Record {
     }
 }
 
-impl Record {
-    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<u64> {
-        let key_bytes = self.key.as_bytes();
-        let val_bytes = self.value.serialize();
-        let tag = self.value.type_tag();
+/// Size of the fixed v1 record header: key length, value length, type tag and checksum.
+pub const HEADER_LEN: usize = 2 + 2 + 1 + 4;
 
-        let key_len = key_bytes.len() as u16;
-        let val_len = val_bytes.len() as u16;
+/// Longest key a record holds, in bytes: v1 stores its length in two bytes,
+/// and later formats keep the limit.
+pub const MAX_KEY_LEN: usize = u16::MAX as usize;
 
-        let mut offset = 0;
-        offset += writer.write(&key_len.to_be_bytes()).await? as u64;
-        offset += writer.write(&val_len.to_be_bytes()).await? as u64;
-        offset += writer.write(&[tag]).await? as u64;
-        offset += writer.write(key_bytes).await? as u64;
-        offset += writer.write(&val_bytes).await? as u64;
+/// Longest serialized value a v2 or v3 record holds, in bytes (v1 stores its
+/// length in two bytes). Readers reject longer ones as corrupt rather than
+/// allocating whatever a damaged length says.
+pub const MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+
+/// Fails with `InvalidInput` unless a record of `format` holds `key` and a
+/// value serialized to `value_len` bytes, so that nothing is written that
+/// can't be read back.
+pub fn check_lengths(key: &str, value_len: usize, format: Format) -> Result<()> {
+    let max_value_len = match format {
+        Format::V1 => u16::MAX as usize,
+        Format::V2 { .. } | Format::V3 { .. } => MAX_VALUE_LEN,
+    };
+    if key.len() > MAX_KEY_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Key of {} bytes is longer than the limit of {MAX_KEY_LEN}", key.len()),
+        ));
+    }
+    if value_len > max_value_len {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Value of {value_len} bytes is longer than the limit of {max_value_len}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Outcome of checking one record frame with [`Record::verify_next`].
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCheck {
+    /// Total length of the frame in bytes.
+    pub len: u64,
+    pub checksum_ok: bool,
+}
+
+/// Decoded record header.
+#[derive(Debug, Clone)]
+pub struct Header {
+    /// Number of leading key bytes shared with the previous record (v3).
+    pub shared: usize,
+    /// Number of key bytes stored in the frame, after the shared prefix.
+    pub key_len: usize,
+    pub val_len: usize,
+    pub tag: u8,
+    pub seq: u64,
+    crc: u32,
+    /// Header bytes covered by the checksum.
+    covered: Vec<u8>,
+}
+
+impl Header {
+    /// Returns the length of the header in bytes.
+    pub fn len(&self) -> usize {
+        4 + self.covered.len()
+    }
+
+    /// Returns the length of the whole record frame in bytes.
+    pub fn frame_len(&self) -> u64 {
+        (self.len() + self.key_len + self.val_len) as u64
+    }
+
+    /// Checks the checksum against the stored key bytes and the value.
+    pub fn checksum_ok(&self, key: &[u8], value: &[u8]) -> bool {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.covered);
+        hasher.update(key);
+        hasher.update(value);
+        hasher.finalize() == self.crc
+    }
+}
+
+impl Record {
+    /// Returns the number of bytes `write_to` produces for this record.
+    pub fn encoded_len(&self, format: Format, prev_key: &str, dictionary: Option<&Dictionary>) -> u64 {
+        self.encode_into(&mut Vec::new(), format, prev_key, dictionary)
+    }
 
-        Ok(offset)
+    /// v1 layout:
+    /// [key_len (u16)][val_len (u16)][tag (u8)][crc32 (u32)][key bytes][value bytes]
+    ///
+    /// v2 layout:
+    /// [crc32 (u32)][key_len (varint)][val_len << 4 | tag code (varint)][seq - base_seq (varint)][key bytes][value bytes]
+    ///
+    /// v3 layout, storing only the part of the key not shared with `prev_key`:
+    /// [crc32 (u32)][shared (varint)][suffix_len (varint)][val_len << 4 | tag code (varint)][seq - base_seq (varint)][suffix bytes][value bytes]
+    ///
+    /// The checksum covers every other header field, the stored key bytes and
+    /// the value. Pass an empty `prev_key` to start a restart point, which can
+    /// be decoded without the records before it.
+    ///
+    /// In v2 and later, values that `dictionary` makes shorter are stored
+    /// compressed, flagged in their type tag.
+    pub async fn write_to<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        format: Format,
+        prev_key: &str,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<u64> {
+        let mut frame = Vec::new();
+        let len = self.encode_into(&mut frame, format, prev_key, dictionary);
+        writer.write_all(&frame).await?;
+        Ok(len)
     }
 
-    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
-        let mut len_buf = [0u8; 2];
+    /// Appends the frame [`Record::write_to`] writes to `frame`, returning its
+    /// length, so that many records can be written at once.
+    pub fn encode_into(
+        &self,
+        frame: &mut Vec<u8>,
+        format: Format,
+        prev_key: &str,
+        dictionary: Option<&Dictionary>,
+    ) -> u64 {
+        let mut key_bytes = self.key.as_bytes();
+        let mut val_bytes = self.value.serialize();
+        let mut tag = self.value.type_tag();
+        if format != Format::V1
+            && let Some(compressed) = dictionary.and_then(|dict| dict.compress(&val_bytes))
+        {
+            val_bytes = compressed;
+            tag |= COMPRESSED_FLAG;
+        }
+        let meta = (val_bytes.len() as u64) << 4 | tag_code(tag);
 
-        // Read key length
-        reader.read_exact(&mut len_buf).await?;
-        let key_len = u16::from_be_bytes(len_buf) as usize;
+        let mut covered = Vec::with_capacity(HEADER_LEN);
+        match format {
+            Format::V1 => {
+                covered.extend((key_bytes.len() as u16).to_be_bytes());
+                covered.extend((val_bytes.len() as u16).to_be_bytes());
+                covered.push(tag);
+            }
+            Format::V2 { base_seq } => {
+                format::put_varint(&mut covered, key_bytes.len() as u64);
+                format::put_varint(&mut covered, meta);
+                format::put_varint(&mut covered, self.seq.saturating_sub(base_seq));
+            }
+            Format::V3 { base_seq } => {
+                let shared = key_bytes
+                    .iter()
+                    .zip(prev_key.as_bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                key_bytes = &key_bytes[shared..];
+                format::put_varint(&mut covered, shared as u64);
+                format::put_varint(&mut covered, key_bytes.len() as u64);
+                format::put_varint(&mut covered, meta);
+                format::put_varint(&mut covered, self.seq.saturating_sub(base_seq));
+            }
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&covered);
+        hasher.update(key_bytes);
+        hasher.update(&val_bytes);
+        let crc = hasher.finalize().to_be_bytes();
 
-        // Read value length
-        reader.read_exact(&mut len_buf).await?;
-        let val_len = u16::from_be_bytes(len_buf) as usize;
+        let start = frame.len();
+        frame.reserve(4 + covered.len() + key_bytes.len() + val_bytes.len());
+        match format {
+            Format::V1 => {
+                frame.extend(&covered);
+                frame.extend(crc);
+            }
+            Format::V2 { .. } | Format::V3 { .. } => {
+                frame.extend(crc);
+                frame.extend(&covered);
+            }
+        }
+        frame.extend(key_bytes);
+        frame.extend(&val_bytes);
+        (frame.len() - start) as u64
+    }
 
-        // Read value tag
-        let mut tag_buf = [0u8; 1];
-        reader.read_exact(&mut tag_buf).await?;
-        let tag = tag_buf[0];
+    /// Reads the record following the one with key `prev_key` (empty at a
+    /// restart point). Compressed values need the table's `dictionary`.
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        format: Format,
+        prev_key: &str,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<Self> {
+        Ok(Self::read_framed(reader, format, prev_key, dictionary).await?.0)
+    }
+
+    /// Same as [`Record::read_from`], also returning the record's header.
+    pub async fn read_framed<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        format: Format,
+        prev_key: &str,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<(Self, Header)> {
+        let header = read_header(reader, format).await?;
+        if header.shared > prev_key.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Shared key prefix is longer than the previous key",
+            ));
+        }
 
         // Read key
-        let mut key_buf = vec![0u8; key_len];
+        let mut key_buf = vec![0u8; header.key_len];
         reader.read_exact(&mut key_buf).await?;
-        let key = String::from_utf8(key_buf)
-            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UTF-8 in key"))?;
 
         // Read value
-        let mut val_buf = vec![0u8; val_len];
+        let mut val_buf = vec![0u8; header.val_len];
         reader.read_exact(&mut val_buf).await?;
 
+        if !header.checksum_ok(&key_buf, &val_buf) {
+            return Err(checksum_mismatch());
+        }
+
+        key_buf.splice(0..0, prev_key.as_bytes()[..header.shared].iter().copied());
+        let key = String::from_utf8(key_buf)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UTF-8 in key"))?;
+
         // Deserialize value from tag + bytes
-        let value = MemValue::deserialize(tag, &val_buf)?;
+        let value = decode_value(header.tag, &val_buf, dictionary)?;
 
-        Ok(Record { key, value })
+        let seq = header.seq;
+        Ok((Record { key, value, seq }, header))
+    }
+
+    /// Reads the next record frame and checks its checksum without decoding it.
+    /// Returns `None` at the end of the input.
+    pub async fn verify_next<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        format: Format,
+    ) -> Result<Option<FrameCheck>> {
+        let header = match read_header(reader, format).await {
+            Ok(header) => header,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut key_buf = vec![0u8; header.key_len];
+        reader.read_exact(&mut key_buf).await?;
+        let mut val_buf = vec![0u8; header.val_len];
+        reader.read_exact(&mut val_buf).await?;
+
+        Ok(Some(FrameCheck {
+            len: header.frame_len(),
+            checksum_ok: header.checksum_ok(&key_buf, &val_buf),
+        }))
+    }
+}
+
+/// Size at which a [`FrameEncoder`] writes out the records it has encoded.
+const WRITE_BLOCK_BYTES: usize = 256 * 1024;
+
+/// Encodes the records of a data file into blocks of frames, written out
+/// whole with `write_all`, so that a short write can't leave a frame cut
+/// short. Offsets and lengths come from the encoded frames, so they match
+/// the file as long as every record goes through the encoder.
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    block: Vec<u8>,
+    /// Offset of the start of `block` in the data file.
+    written: u64,
+    last_key: String,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of bytes of records encoded so far.
+    pub fn offset(&self) -> u64 {
+        self.written + self.block.len() as u64
+    }
+
+    /// Returns the key of the last record encoded (empty before the first).
+    pub fn last_key(&self) -> &str {
+        &self.last_key
+    }
+
+    /// Encodes `record`, without the previous key if `restart`, writing the
+    /// block to `writer` once it is full. Returns the offset and the length
+    /// of the record's frame.
+    pub async fn push<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        record: &Record,
+        restart: bool,
+        format: Format,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<(u64, u64)> {
+        let offset = self.offset();
+        let prev_key = if restart { "" } else { &self.last_key };
+        let len = record.encode_into(&mut self.block, format, prev_key, dictionary);
+        self.last_key.clone_from(&record.key);
+        if self.block.len() >= WRITE_BLOCK_BYTES {
+            writer.write_all(&self.block).await?;
+            self.written += self.block.len() as u64;
+            self.block.clear();
+        }
+        Ok((offset, len))
+    }
+
+    /// Writes out the records still buffered, returning the length of all
+    /// the records written.
+    pub async fn finish<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.block).await?;
+        self.written += self.block.len() as u64;
+        self.block.clear();
+        Ok(self.written)
+    }
+}
+
+/// Reads a record header framed in `format`.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R, format: Format) -> Result<Header> {
+    let base_seq = match format {
+        Format::V1 => {
+            let mut header = [0u8; HEADER_LEN];
+            reader.read_exact(&mut header).await?;
+
+            return Ok(Header {
+                shared: 0,
+                key_len: u16::from_be_bytes([header[0], header[1]]) as usize,
+                val_len: u16::from_be_bytes([header[2], header[3]]) as usize,
+                tag: header[4],
+                seq: 0,
+                crc: u32::from_be_bytes([header[5], header[6], header[7], header[8]]),
+                covered: header[..5].to_vec(),
+            });
+        }
+        Format::V2 { base_seq } | Format::V3 { base_seq } => base_seq,
+    };
+
+    let crc = reader.read_u32().await?;
+    let mut covered = Vec::with_capacity(8);
+    let shared = match format {
+        Format::V3 { .. } => format::read_varint(reader, &mut covered).await?,
+        _ => 0,
+    };
+    let key_len = format::read_varint(reader, &mut covered).await?;
+    let meta = format::read_varint(reader, &mut covered).await?;
+    let seq = format::read_varint(reader, &mut covered).await?;
+    if shared + key_len > MAX_KEY_LEN as u64 || meta >> 4 > MAX_VALUE_LEN as u64 {
+        return Err(Error::new(ErrorKind::InvalidData, "Record lengths out of range"));
+    }
+
+    Ok(Header {
+        shared: shared as usize,
+        key_len: key_len as usize,
+        val_len: (meta >> 4) as usize,
+        tag: code_tag(meta & 0xf),
+        seq: base_seq.saturating_add(seq),
+        crc,
+        covered,
+    })
+}
+
+/// Deserializes a stored value, decompressing it first if its tag says so.
+pub fn decode_value(tag: u8, bytes: &[u8], dictionary: Option<&Dictionary>) -> Result<MemValue> {
+    if tag == TOMBSTONE_TAG || tag & COMPRESSED_FLAG == 0 {
+        return MemValue::deserialize(tag, bytes);
+    }
+    let dictionary = dictionary.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "Value is compressed with a dictionary that isn't loaded",
+        )
+    })?;
+    MemValue::deserialize(tag & !COMPRESSED_FLAG, &dictionary.decompress(bytes)?)
+}
+
+pub fn checksum_mismatch() -> Error {
+    Error::new(ErrorKind::InvalidData, "Record checksum mismatch")
+}
+
+/// Packs a type tag into the 4 bits v2 stores it in: the value type in the
+/// low two bits, then the compression and expiry flags.
+fn tag_code(tag: u8) -> u64 {
+    if tag == TOMBSTONE_TAG {
+        return 0xf;
+    }
+    let mut code = (tag & 0x3) as u64;
+    if tag & COMPRESSED_FLAG != 0 {
+        code |= 0x4;
+    }
+    if tag & EXPIRY_FLAG != 0 {
+        code |= 0x8;
+    }
+    code
+}
+
+fn code_tag(code: u64) -> u8 {
+    if code == 0xf {
+        return TOMBSTONE_TAG;
+    }
+    let mut tag = (code & 0x3) as u8;
+    if code & 0x4 != 0 {
+        tag |= COMPRESSED_FLAG;
     }
+    if code & 0x8 != 0 {
+        tag |= EXPIRY_FLAG;
+    }
+    tag
 }
 
+/// Type tag bit marking a value that carries an expiration time.
+const EXPIRY_FLAG: u8 = 0x80;
+/// Type tag bit marking a value compressed with the table's dictionary.
+const COMPRESSED_FLAG: u8 = 0x40;
+const TOMBSTONE_TAG: u8 = 255;
+
 #[derive(Clone, Debug)]
 pub enum MemValue {
     Value(Value),
+    /// A value that stops being visible at `expires_at` (milliseconds since the Unix epoch).
+    Expiring { value: Value, expires_at: u64 },
     Tombstone,
 }
 
 impl MemValue {
-    /// Returns the length of this `MemValue` in bytes.
+    /// Returns the length of the serialized value in bytes, without the
+    /// record header or key; see [`MemValue::encoded_len`].
     pub fn len(&self) -> usize {
         match self {
             Self::Value(value) => value.len(),
+            Self::Expiring { value, .. } => value.len() + 8,
             Self::Tombstone => 0
         }
     }
 
-    pub fn to_value(self) -> Option<Value> {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the size of the record storing this value under `key` in
+    /// `format`, header included, as written at a restart point without
+    /// compression. The sequence number is counted as a single byte, its size
+    /// when it is less than 128 past the table's base one.
+    pub fn encoded_len(&self, key: &str, format: Format) -> usize {
+        let val_len = self.len();
+        let lengths = format::varint_len(key.len() as u64) + format::varint_len((val_len as u64) << 4);
+        let header = match format {
+            Format::V1 => HEADER_LEN,
+            // crc32, lengths and sequence number.
+            Format::V2 { .. } => 4 + lengths + 1,
+            // crc32, shared prefix length (`0` at restart points), lengths and
+            // sequence number.
+            Format::V3 { .. } => 4 + 1 + lengths + 1,
+        };
+        header + key.len() + val_len
+    }
+
+    pub fn into_value(self) -> Option<Value> {
         match self {
             MemValue::Tombstone => None,
-            MemValue::Value(value) => Some(value),
+            MemValue::Value(value) | MemValue::Expiring { value, .. } => Some(value),
         }
     }
 
+    /// Returns the expiration time of this value, if it has one.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            MemValue::Expiring { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if this value has an expiration time at or before `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at().is_some_and(|expires_at| expires_at <= now)
+    }
+
     pub fn type_tag(&self) -> u8 {
         match self {
-            MemValue::Value(Value::Str(_)) => 0,
-            MemValue::Value(Value::Int64(_)) => 1,
-            MemValue::Value(Value::Float64(_)) => 2,
-            MemValue::Tombstone => 255,
+            MemValue::Value(value) => value.type_tag(),
+            MemValue::Expiring { value, .. } => value.type_tag() | EXPIRY_FLAG,
+            MemValue::Tombstone => TOMBSTONE_TAG,
         }
     }
 
+    /// Expiring values are prefixed by their expiration time as a big-endian `u64`.
     pub fn serialize(&self) -> Vec<u8> {
         match self {
-            MemValue::Value(Value::Str(s)) => s.as_bytes().to_vec(),
-            MemValue::Value(Value::Int64(i)) => i.to_be_bytes().to_vec(),
-            MemValue::Value(Value::Float64(f)) => f.to_be_bytes().to_vec(),
+            MemValue::Value(value) => value.serialize(),
+            MemValue::Expiring { value, expires_at } => {
+                let mut bytes = expires_at.to_be_bytes().to_vec();
+                bytes.extend(value.serialize());
+                bytes
+            }
             MemValue::Tombstone => vec![],
         }
     }
 
     pub fn deserialize(tag: u8, bytes: &[u8]) -> Result<Self> {
+        match tag {
+            TOMBSTONE_TAG => Ok(MemValue::Tombstone),
+            tag if tag & EXPIRY_FLAG != 0 && bytes.len() >= 8 => {
+                let (deadline, rest) = bytes.split_at(8);
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(deadline);
+                Ok(MemValue::Expiring {
+                    value: Value::deserialize(tag & !EXPIRY_FLAG, rest)?,
+                    expires_at: u64::from_be_bytes(buf),
+                })
+            }
+            tag => Ok(MemValue::Value(Value::deserialize(tag, bytes)?)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int64(i64),
+    Float64(f64),
+    /// Arbitrary bytes, such as values that aren't valid UTF-8.
+    Bytes(Vec<u8>),
+    Set(BTreeSet<String>),
+    List(VecDeque<String>),
+    /// Fields and their values.
+    Map(BTreeMap<String, String>),
+}
+
+/// Type of a [`Value`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    Str,
+    Int64,
+    Float64,
+    Bytes,
+    Set,
+    List,
+    Map,
+}
+
+impl ValueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValueType::Str => "str",
+            ValueType::Int64 => "int64",
+            ValueType::Float64 => "float64",
+            ValueType::Bytes => "bytes",
+            ValueType::Set => "set",
+            ValueType::List => "list",
+            ValueType::Map => "map",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ValueType> {
+        match name {
+            "str" => Some(ValueType::Str),
+            "int64" => Some(ValueType::Int64),
+            "float64" => Some(ValueType::Float64),
+            "bytes" => Some(ValueType::Bytes),
+            "set" => Some(ValueType::Set),
+            "list" => Some(ValueType::List),
+            "map" => Some(ValueType::Map),
+            _ => None,
+        }
+    }
+}
+
+/// Type tag of values whose serialized form starts with a byte giving their
+/// actual type, as the type code of v2 tables only has room for four types.
+const EXTENDED_TAG: u8 = 3;
+const BYTES_TYPE: u8 = 0;
+const SET_TYPE: u8 = 1;
+const LIST_TYPE: u8 = 2;
+const MAP_TYPE: u8 = 3;
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Str(_) => ValueType::Str,
+            Value::Int64(_) => ValueType::Int64,
+            Value::Float64(_) => ValueType::Float64,
+            Value::Bytes(_) => ValueType::Bytes,
+            Value::Set(_) => ValueType::Set,
+            Value::List(_) => ValueType::List,
+            Value::Map(_) => ValueType::Map,
+        }
+    }
+
+    /// Returns the length of this `Value` in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Value::Str(value) => value.len(),
+            Value::Int64(_) => 8,
+            Value::Float64(_) => 8,
+            Value::Bytes(value) => 1 + value.len(),
+            Value::Set(members) => 1 + strings_len(members.len(), members.iter()),
+            Value::List(items) => 1 + strings_len(items.len(), items.iter()),
+            Value::Map(fields) => 1 + strings_len(fields.len() * 2, map_strings(fields)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn type_tag(&self) -> u8 {
+        match self {
+            Value::Str(_) => 0,
+            Value::Int64(_) => 1,
+            Value::Float64(_) => 2,
+            Value::Bytes(_) | Value::Set(_) | Value::List(_) | Value::Map(_) => EXTENDED_TAG,
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        match self {
+            Value::Str(s) => s.as_bytes().to_vec(),
+            Value::Int64(i) => i.to_be_bytes().to_vec(),
+            Value::Float64(f) => f.to_be_bytes().to_vec(),
+            Value::Bytes(bytes) => {
+                let mut out = vec![BYTES_TYPE];
+                out.extend(bytes);
+                out
+            }
+            Value::Set(members) => {
+                let mut out = vec![SET_TYPE];
+                put_strings(&mut out, members.len(), members.iter());
+                out
+            }
+            Value::List(items) => {
+                let mut out = vec![LIST_TYPE];
+                put_strings(&mut out, items.len(), items.iter());
+                out
+            }
+            Value::Map(fields) => {
+                let mut out = vec![MAP_TYPE];
+                put_strings(&mut out, fields.len() * 2, map_strings(fields));
+                out
+            }
+        }
+    }
+
+    fn deserialize(tag: u8, bytes: &[u8]) -> Result<Self> {
         match tag {
             0 => {
                 let parsed = String::from_utf8(bytes.to_vec()).map_err(|_| {
                     Error::new(ErrorKind::InvalidData, "Unable to deserialize record")
                 })?;
-                Ok(MemValue::Value(Value::Str(parsed)))
+                Ok(Value::Str(parsed))
             }
             1 if bytes.len() == 8 => {
                 let mut buf = [0u8; 8];
                 buf.copy_from_slice(bytes);
-                Ok(MemValue::Value(Value::Int64(i64::from_be_bytes(buf))))
+                Ok(Value::Int64(i64::from_be_bytes(buf)))
             }
             2 if bytes.len() == 8 => {
                 let mut buf = [0u8; 8];
                 buf.copy_from_slice(bytes);
-                Ok(MemValue::Value(Value::Float64(f64::from_be_bytes(buf))))
+                Ok(Value::Float64(f64::from_be_bytes(buf)))
             }
-            255 => Ok(MemValue::Tombstone),
+            EXTENDED_TAG => match bytes.split_first() {
+                Some((&BYTES_TYPE, rest)) => Ok(Value::Bytes(rest.to_vec())),
+                Some((&SET_TYPE, mut rest)) => Ok(Value::Set(get_strings(&mut rest)?.collect())),
+                Some((&LIST_TYPE, mut rest)) => Ok(Value::List(get_strings(&mut rest)?.collect())),
+                Some((&MAP_TYPE, mut rest)) => {
+                    let mut strings = get_strings(&mut rest)?;
+                    let mut fields = BTreeMap::new();
+                    while let Some(field) = strings.next() {
+                        let value = strings.next().ok_or_else(|| {
+                            Error::new(ErrorKind::InvalidData, "Map field without a value")
+                        })?;
+                        fields.insert(field, value);
+                    }
+                    Ok(Value::Map(fields))
+                }
+                _ => Err(Error::new(ErrorKind::InvalidData, "Unknown value type")),
+            },
             _ => Err(Error::new(
                 ErrorKind::InvalidData,
                 "Unable to deserialize record",
@@ -149,20 +721,44 @@ impl MemValue {
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum Value {
-    Str(String),
-    Int64(i64),
-    Float64(f64),
+/// Returns the length of `count` strings written by [`put_strings`].
+fn strings_len<'a>(count: usize, strings: impl Iterator<Item = &'a String>) -> usize {
+    let contents: usize = strings
+        .map(|s| format::varint_len(s.len() as u64) + s.len())
+        .sum();
+    format::varint_len(count as u64) + contents
 }
 
-impl Value {
-    /// Returns the length of this `Value` in bytes.
-    pub fn len(&self) -> usize {
-        match self {
-            Value::Str(value) => value.len(),
-            Value::Int64(_) => 8,
-            Value::Float64(_) => 8,
+/// Returns the fields of a map interleaved with their values.
+fn map_strings(fields: &BTreeMap<String, String>) -> impl Iterator<Item = &String> {
+    fields.iter().flat_map(|(field, value)| [field, value])
+}
+
+/// Writes `[count (varint)]` followed by `[len (varint)][bytes]` for every string.
+fn put_strings<'a>(out: &mut Vec<u8>, count: usize, strings: impl Iterator<Item = &'a String>) {
+    format::put_varint(out, count as u64);
+    for s in strings {
+        format::put_varint(out, s.len() as u64);
+        out.extend(s.as_bytes());
+    }
+}
+
+/// Reads the strings written by [`put_strings`], which must span all of `input`.
+fn get_strings(input: &mut &[u8]) -> Result<impl Iterator<Item = String>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Unable to deserialize record");
+    let count = format::get_varint(input)?;
+    let mut strings = Vec::new();
+    for _ in 0..count {
+        let len = format::get_varint(input)? as usize;
+        if len > input.len() {
+            return Err(invalid());
         }
+        let (s, rest) = input.split_at(len);
+        strings.push(String::from_utf8(s.to_vec()).map_err(|_| invalid())?);
+        *input = rest;
+    }
+    if !input.is_empty() {
+        return Err(invalid());
     }
+    Ok(strings.into_iter())
 }
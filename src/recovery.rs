@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use tokio::io::Result;
+
+use crate::{
+    layout,
+    manifest::{self, Manifest},
+    trash,
+};
+
+/// Deletes what an interrupted flush, ingest or compaction left in
+/// `data_dir` and its table subdirectories, before its tables are loaded:
+///
+/// - `.part` files, which the MANIFEST never refers to;
+/// - tables numbered above `Manifest::last_sequence` and dictionaries newer
+///   than the MANIFEST's, written by an operation that never committed;
+/// - the files listed in `Manifest::obsolete`, replaced by a compaction that
+///   committed but didn't get to move them to the trash. They are moved
+///   there as of `now`, and the MANIFEST is rewritten without them.
+pub async fn recover(data_dir: &Path, manifest: &mut Manifest, now: u64) -> Result<()> {
+    for name in layout::list_files(data_dir).await? {
+        if is_leftover(manifest, &name) {
+            log::warn!("Deleting {}, left over by an interrupted write.", name.display());
+            tokio::fs::remove_file(data_dir.join(&name)).await?;
+        }
+    }
+
+    if manifest.obsolete.is_empty() {
+        return Ok(());
+    }
+    for path in manifest.obsolete.drain(..) {
+        log::warn!("Moving {} to the trash, replaced by a compaction.", path.display());
+        trash::discard(data_dir, &path, now).await?;
+    }
+    manifest::commit(manifest, data_dir).await
+}
+
+/// Returns `true` if the file `name`, relative to the data directory of
+/// `manifest`, was written by an operation that never committed.
+pub fn is_leftover(manifest: &Manifest, name: &Path) -> bool {
+    let referenced = manifest
+        .sstables
+        .iter()
+        .any(|entry| entry.data_path == name || entry.index_path == name)
+        || manifest.dictionary.as_deref() == Some(name);
+    let newer_than = |newest: u64| !referenced && file_number(name).is_some_and(|n| n > newest);
+
+    let layout = &manifest.layout;
+    match name.extension().and_then(|ext| ext.to_str()) {
+        Some("part") => true,
+        _ if layout.is_data_file(name) || layout.is_index_file(name) => {
+            newer_than(manifest.last_sequence as u64)
+        }
+        Some("dict") => {
+            let current = manifest.dictionary.as_deref().and_then(file_number);
+            newer_than(current.unwrap_or(0))
+        }
+        _ => false,
+    }
+}
+
+/// Returns the number a table or dictionary file is named after.
+pub(crate) fn file_number(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
@@ -0,0 +1,315 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter, Error, ErrorKind, Result},
+};
+
+use crate::{
+    Config, Manifest,
+    collation::Collation,
+    dictionary::Dictionary,
+    disk,
+    format::{self, Format, TableLayout},
+    layout::{self, Layout},
+    lock,
+    manifest::{self, SSTableEntry},
+    recovery,
+    sparse_index::{self, SparseIndex},
+    sstable_set::{self, QUARANTINE_DIR, TableScan},
+    version,
+};
+
+/// A data file [`repair`] kept.
+#[derive(Debug, Clone)]
+pub struct RepairedTable {
+    pub data_path: PathBuf,
+    pub records: u64,
+    /// Bytes after the last readable record, cut from the table. The
+    /// original file is kept in `corrupt/`.
+    pub dropped_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    pub tables: Vec<RepairedTable>,
+    /// Data files without a readable record, moved to `corrupt/`.
+    pub discarded: Vec<PathBuf>,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let records: u64 = self.tables.iter().map(|table| table.records).sum();
+        writeln!(f, "repaired {} tables, {} records", self.tables.len(), records)?;
+        for table in self.tables.iter().filter(|table| table.dropped_bytes > 0) {
+            writeln!(
+                f,
+                "  {}: dropped {} unreadable bytes",
+                table.data_path.display(),
+                table.dropped_bytes
+            )?;
+        }
+        for path in &self.discarded {
+            writeln!(f, "  {}: no readable record, discarded", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the index of every data file in `data_dir` and a MANIFEST
+/// listing them, for when either is lost or corrupt. The database must not
+/// be open.
+///
+/// Files are scanned up to their first unreadable record; anything after it
+/// is cut, keeping the original in `corrupt/`. A file that lost its footer is
+/// read in whichever format gets furthest into it. Tables are ordered by
+/// their file number, as they were numbered in the order they were written.
+/// The dictionary, collation and layout are taken from the old MANIFEST if it
+/// can still be read; otherwise the newest dictionary is used, keys are
+/// assumed to be binary and table files to have the default extensions.
+pub async fn repair(data_dir: &Path) -> Result<RepairReport> {
+    let _lock = lock::acquire(data_dir)?;
+    let old_manifest = match tokio::fs::read_to_string(data_dir.join("MANIFEST")).await {
+        Ok(contents) => toml::from_str::<Manifest>(&contents).ok(),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let mut data_files = Vec::new();
+    let mut dictionaries = Vec::new();
+    let mut last_sequence = old_manifest.as_ref().map_or(0, |m| m.last_sequence as u64);
+    let layout = old_manifest.as_ref().map_or_else(Layout::default, |m| m.layout.clone());
+    for name in layout::list_files(data_dir).await? {
+        let Some(number) = recovery::file_number(&name) else {
+            continue;
+        };
+        if layout.is_data_file(&name) {
+            last_sequence = last_sequence.max(number);
+            data_files.push((number, name));
+        } else if layout.is_index_file(&name) {
+            last_sequence = last_sequence.max(number);
+        } else if name.extension().is_some_and(|ext| ext == "dict") {
+            last_sequence = last_sequence.max(number);
+            dictionaries.push((number, name));
+        }
+    }
+    data_files.sort();
+    dictionaries.sort();
+
+    let dictionary_path = match old_manifest.as_ref().and_then(|m| m.dictionary.clone()) {
+        Some(path) => Some(path),
+        None => dictionaries.pop().map(|(_, path)| path),
+    };
+    let dictionary = match &dictionary_path {
+        Some(path) => Some(Arc::new(Dictionary::load(&data_dir.join(path)).await?)),
+        None => None,
+    };
+
+    let stride = Config::default().sparse_stride;
+    let mut report = RepairReport::default();
+    let mut sstables = Vec::new();
+    let mut last_record_seq = 0;
+    let mut table_format = None;
+    for (number, name) in data_files {
+        log::info!("Repairing {}...", name.display());
+        let path = data_dir.join(&name);
+        let created_at = sstable_set::modified_millis(&path).await?;
+        let table = best_scan(&path, dictionary.clone(), stride).await?;
+        if table.records == 0 {
+            log::warn!("No readable record in {}, discarding it.", name.display());
+            move_to_quarantine(data_dir, &name).await?;
+            report.discarded.push(name);
+            continue;
+        }
+
+        let dropped_bytes = table.file_len - table.readable - table.footer_len;
+        if dropped_bytes > 0 || (table.footer_len == 0 && table.layout.format != Format::V1) {
+            log::warn!("Cutting {dropped_bytes} unreadable bytes from {}.", name.display());
+            rewrite_prefix(data_dir, &name, &table).await?;
+        }
+
+        let index_path = layout.index_path(&name);
+        write_index(&table.index, table.readable, &data_dir.join(&index_path)).await?;
+
+        last_record_seq = last_record_seq.max(table.max_seq);
+        table_format = table_format.max(Some(table.layout.format.version()));
+        report.tables.push(RepairedTable {
+            data_path: name.clone(),
+            records: table.records,
+            dropped_bytes,
+        });
+        sstables.push(SSTableEntry {
+            data_path: name,
+            index_path,
+            generation: Some(number as usize),
+            compacted: false,
+            created_at: Some(created_at),
+            stride: Some((table.records as usize).div_ceil(table.index.len().max(1))),
+        });
+    }
+    disk::sync_dir(data_dir).await?;
+
+    sstables.reverse();
+    let manifest = Manifest {
+        version: version::VERSION.to_owned(),
+        last_sequence: last_sequence as usize,
+        last_record_seq,
+        dictionary: dictionary_path,
+        obsolete: Vec::new(),
+        collation: old_manifest.map_or(Collation::Binary, |m| m.collation),
+        table_format,
+        layout,
+        sstables,
+    };
+    manifest::commit(&manifest, data_dir).await?;
+    Ok(report)
+}
+
+/// Rebuilds the sparse index of the data file at `data_path`, indexing
+/// restart points at least `stride` records apart, and writes it to
+/// `index_path`. Fails if a record can't be read.
+pub(crate) async fn rebuild_index(
+    data_path: &Path,
+    index_path: &Path,
+    dictionary: Option<Arc<Dictionary>>,
+    stride: usize,
+) -> Result<SparseIndex> {
+    let layout = format::read_layout(&mut File::open(data_path).await?).await?;
+    let table = scan(data_path, layout, dictionary, stride).await?;
+    if table.readable < layout.data_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Unreadable record at offset {} of {}",
+                table.readable,
+                data_path.display()
+            ),
+        ));
+    }
+    write_index(&table.index, layout.data_len, index_path).await?;
+    Ok(table.index)
+}
+
+/// Writes `index`, of a data file whose records take `data_len` bytes, to
+/// `path` through a synced `.part` file.
+async fn write_index(index: &SparseIndex, data_len: u64, path: &Path) -> Result<()> {
+    let part = PathBuf::from(format!("{}.part", path.display()));
+    let mut writer = BufWriter::new(File::create(&part).await?);
+    sparse_index::write_to(index, data_len, &mut writer).await?;
+    writer.get_ref().sync_all().await?;
+    tokio::fs::rename(&part, path).await
+}
+
+/// What a scan of a data file in a given layout could read.
+struct ScannedTable {
+    layout: TableLayout,
+    file_len: u64,
+    footer_len: u64,
+    /// Length of the readable records.
+    readable: u64,
+    records: u64,
+    max_seq: u64,
+    index: SparseIndex,
+}
+
+/// Scans the data file at `path` in the layout its footer gives or, without
+/// one, in every format, keeping the scan that reads the most.
+async fn best_scan(
+    path: &Path,
+    dictionary: Option<Arc<Dictionary>>,
+    stride: usize,
+) -> Result<ScannedTable> {
+    let mut file = File::open(path).await?;
+    let file_len = file.metadata().await?.len();
+    let layout = format::read_layout(&mut file).await?;
+    let candidates = if layout.format == Format::V1 {
+        vec![Format::V3 { base_seq: 0 }, Format::V2 { base_seq: 0 }, Format::V1]
+    } else {
+        vec![layout.format]
+    };
+
+    let mut best: Option<ScannedTable> = None;
+    for format in candidates {
+        let layout = TableLayout {
+            format,
+            ..layout
+        };
+        let table = scan(path, layout, dictionary.clone(), stride).await?;
+        if best.as_ref().is_none_or(|best| table.readable > best.readable) {
+            best = Some(ScannedTable {
+                file_len,
+                footer_len: file_len - layout.data_len,
+                ..table
+            });
+        }
+    }
+    Ok(best.expect("at least one format is tried"))
+}
+
+/// Reads the records of `path` until the first one that can't be read,
+/// indexing restart points at least `stride` records apart.
+async fn scan(
+    path: &Path,
+    layout: TableLayout,
+    dictionary: Option<Arc<Dictionary>>,
+    stride: usize,
+) -> Result<ScannedTable> {
+    let mut scan = TableScan::open_as(path, layout)
+        .await?
+        .with_dictionary(dictionary);
+    let mut table = ScannedTable {
+        layout,
+        file_len: 0,
+        footer_len: 0,
+        readable: 0,
+        records: 0,
+        max_seq: 0,
+        index: SparseIndex::new(),
+    };
+    let mut since_indexed = 0;
+    loop {
+        let offset = scan.offset();
+        let record = match scan.next().await {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("{} unreadable at {offset}: {e}", path.display());
+                break;
+            }
+        };
+        if scan.at_restart() && (table.index.is_empty() || since_indexed >= stride) {
+            table.index.insert(record.key, offset);
+            since_indexed = 0;
+        }
+        since_indexed += 1;
+        table.records += 1;
+        table.max_seq = table.max_seq.max(record.seq);
+    }
+    table.readable = scan.offset();
+    Ok(table)
+}
+
+/// Replaces the data file `name` with its readable records and a footer,
+/// moving the original to the quarantine directory.
+async fn rewrite_prefix(data_dir: &Path, name: &Path, table: &ScannedTable) -> Result<()> {
+    let part = data_dir.join(format!("{}.part", name.display()));
+    let mut writer = BufWriter::new(File::create(&part).await?);
+    let mut records = File::open(data_dir.join(name)).await?.take(table.readable);
+    tokio::io::copy(&mut records, &mut writer).await?;
+    format::write_footer(&mut writer, table.layout.format).await?;
+    writer.flush().await?;
+    writer.get_ref().sync_all().await?;
+    move_to_quarantine(data_dir, name).await?;
+    tokio::fs::rename(&part, data_dir.join(name)).await
+}
+
+async fn move_to_quarantine(data_dir: &Path, name: &Path) -> Result<()> {
+    let quarantine_dir = data_dir.join(QUARANTINE_DIR);
+    tokio::fs::create_dir_all(&quarantine_dir).await?;
+    let file_name = name.file_name().unwrap_or(name.as_os_str());
+    tokio::fs::rename(data_dir.join(name), quarantine_dir.join(file_name)).await
+}
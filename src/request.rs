@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+tokio::task_local! {
+    /// ID of the protocol command being run, see [`scope`].
+    static REQUEST_ID: u64;
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a new request ID, unique within the process.
+pub fn next_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Runs `future` as the request `id`, which [`current`] returns meanwhile,
+/// so that what it logs and replies can be matched with the command.
+pub async fn scope<F: Future>(id: u64, future: F) -> F::Output {
+    REQUEST_ID.scope(id, future).await
+}
+
+/// Returns the ID of the request being run, if any.
+pub fn current() -> Option<u64> {
+    REQUEST_ID.try_with(|id| *id).ok()
+}
@@ -0,0 +1,123 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::io::{Error, ErrorKind, Result};
+
+use crate::{
+    Manifest, disk,
+    wal::{self, SEGMENT_EXTENSION, Wal, WalCipher},
+};
+
+/// Restores into `data_dir` the database as it was right after the write
+/// of sequence number `sequence`: copies the backup in `backup_dir`, then
+/// writes the writes after it up to `sequence`, taken from the segments in
+/// `archive_dir` (see `Config::wal_archive_dir`), as the write-ahead log of
+/// the copy, replayed once it is opened. Returns the sequence number of the
+/// last write restored, which is that of the backup if none is.
+///
+/// Writes are restored whole: a batch is only restored if every write in it
+/// is at or before `sequence`. Bulk loads and ingested tables aren't in the
+/// archive, so those after the backup aren't restored. Sealed segments are
+/// opened with `cipher`, which seals the restored log too. `data_dir` must
+/// not already contain a database; the database must not be open.
+pub async fn restore_to(
+    backup_dir: &Path,
+    archive_dir: &Path,
+    data_dir: &Path,
+    sequence: u64,
+    cipher: Option<Arc<dyn WalCipher>>,
+) -> Result<u64> {
+    if tokio::fs::metadata(data_dir.join("MANIFEST")).await.is_ok() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already contains a database", data_dir.display()),
+        ));
+    }
+    let contents = tokio::fs::read_to_string(backup_dir.join("MANIFEST")).await?;
+    let manifest: Manifest = toml::from_str(&contents).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Unable to parse the MANIFEST of {}: {e}", backup_dir.display()),
+        )
+    })?;
+    let base_seq = manifest.last_record_seq;
+    if sequence < base_seq {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("The backup is already past sequence number {sequence}, at {base_seq}"),
+        ));
+    }
+
+    let mut segments = Vec::new();
+    let mut entries = tokio::fs::read_dir(archive_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+            segments.push(path);
+        }
+    }
+    // Named after the zero-padded sequence number of their first write.
+    segments.sort();
+
+    log::info!("Restoring {} into {}...", backup_dir.display(), data_dir.display());
+    tokio::fs::create_dir_all(data_dir).await?;
+    copy_tables(backup_dir, data_dir).await?;
+    let (mut log, logged) = Wal::open(data_dir, true, cipher.clone()).await?;
+    if !logged.is_empty() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already holds a write-ahead log", data_dir.display()),
+        ));
+    }
+    let mut restored = base_seq;
+    'segments: for segment in &segments {
+        for frame in wal::read_segment(segment, cipher.as_deref()).await? {
+            let (Some(first), Some(last)) = (frame.first(), frame.last()) else {
+                continue;
+            };
+            if last.seq <= restored {
+                continue;
+            }
+            if first.seq <= restored {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} holds a write across sequence number {restored}, where the \
+                         restore is at: the archive doesn't match the backup",
+                        segment.display()
+                    ),
+                ));
+            }
+            if last.seq > sequence {
+                break 'segments;
+            }
+            log.append(&frame)?;
+            restored = last.seq;
+        }
+    }
+    // Written last, so that a restore that fails doesn't leave a database.
+    tokio::fs::copy(backup_dir.join("MANIFEST"), data_dir.join("MANIFEST")).await?;
+    disk::sync_dir(data_dir).await?;
+    log::info!("Restored up to sequence number {restored}, from {base_seq} in the backup.");
+    Ok(restored)
+}
+
+/// Copies the files under `from` but its MANIFEST into `to`.
+async fn copy_tables(from: &Path, to: &Path) -> Result<()> {
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(from.join(&dir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = dir.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                tokio::fs::create_dir_all(to.join(&path)).await?;
+                dirs.push(path);
+            } else if path != Path::new("MANIFEST") {
+                tokio::fs::copy(from.join(&path), to.join(&path)).await?;
+            }
+        }
+    }
+    Ok(())
+}
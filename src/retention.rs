@@ -0,0 +1,43 @@
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::keyenc::KeyDecoder;
+
+/// Deletes the keys under `prefix` that are followed by a timestamp older
+/// than `max_age_ms`, as appended by `keyenc::KeyEncoder::timestamp`: e.g.
+/// `metrics/` followed by the encoded time of the sample. Keys under the
+/// prefix not followed by a timestamp are kept. See `Config::retention`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionRule {
+    pub prefix: String,
+    pub max_age_ms: u64,
+}
+
+impl RetentionRule {
+    /// Returns whether `key` is older than the rule allows at `now`, in
+    /// milliseconds since the UNIX epoch.
+    fn expires(&self, key: &str, now: u64) -> bool {
+        let Some(rest) = key.strip_prefix(self.prefix.as_str()) else {
+            return false;
+        };
+        match KeyDecoder::new(rest).timestamp() {
+            Ok(time) => {
+                let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                (now as u128).saturating_sub(millis) > self.max_age_ms as u128
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Returns whether `key` is past the retention of the rule with the longest
+/// prefix it starts with, so that a rule for `metrics/debug/` overrides one
+/// for `metrics/`.
+pub fn expires(rules: &[RetentionRule], key: &str, now: u64) -> bool {
+    rules
+        .iter()
+        .filter(|rule| key.starts_with(rule.prefix.as_str()))
+        .max_by_key(|rule| rule.prefix.len())
+        .is_some_and(|rule| rule.expires(key, now))
+}
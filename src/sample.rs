@@ -0,0 +1,118 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::io::Result;
+
+use crate::{
+    dictionary::Dictionary,
+    file_cache::FileCache,
+    memtable::MemTable,
+    sstable_set::{SSTableSet, TableScan},
+};
+
+/// Draws stored keys at random from the memtable and the tables, reading a
+/// single block of a table per key: a restart point from its sparse index
+/// and the records up to the next one.
+///
+/// Every block counts as many keys as the stride of its table against the
+/// memtable's, so keys of small blocks are somewhat more likely to be
+/// drawn. Keys are drawn with replacement, and may have been overwritten or
+/// deleted since.
+pub(crate) struct KeySampler<'a> {
+    memtable: Vec<&'a String>,
+    /// Data file, start and end offset of every block.
+    blocks: Vec<(PathBuf, u64, u64)>,
+    /// Number of keys the blocks up to and including each one count as.
+    weights: Vec<usize>,
+    dictionary: Option<Arc<Dictionary>>,
+    files: &'a FileCache,
+    random: Random,
+}
+
+impl<'a> KeySampler<'a> {
+    pub(crate) async fn new(
+        memtable: &'a MemTable,
+        sstable_set: &SSTableSet,
+        data_dir: &Path,
+        stride: usize,
+        files: &'a FileCache,
+    ) -> Result<Self> {
+        let mut blocks = Vec::new();
+        let mut weights = Vec::new();
+        let mut total = 0;
+        for table in &sstable_set.tables {
+            let contents = table.contents().await?;
+            let path = data_dir.join(&table.data_path);
+            let stride = table.stride.unwrap_or(stride).max(1);
+            let mut offsets = contents.index.values().copied().peekable();
+            while let Some(start) = offsets.next() {
+                let end = offsets.peek().copied().unwrap_or(contents.layout.data_len);
+                blocks.push((path.clone(), start, end));
+                total += stride;
+                weights.push(total);
+            }
+        }
+        Ok(Self {
+            memtable: memtable.keys().collect(),
+            blocks,
+            weights,
+            dictionary: sstable_set.dictionary.clone(),
+            files,
+            random: Random::new(),
+        })
+    }
+
+    /// Returns a stored key drawn at random, or `None` if there are none.
+    pub(crate) async fn draw(&mut self) -> Result<Option<String>> {
+        let total = self.memtable.len() + self.weights.last().copied().unwrap_or(0);
+        if total == 0 {
+            return Ok(None);
+        }
+        let i = self.random.below(total);
+        if let Some(key) = self.memtable.get(i) {
+            return Ok(Some(key.to_string()));
+        }
+
+        let weight = i - self.memtable.len();
+        let (path, start, end) = &self.blocks[self.weights.partition_point(|&w| w <= weight)];
+        // Closed before returning, as callers go on to look keys up.
+        let _files = self.files.reserve(1).await;
+        let mut scan = TableScan::open(path).await?.with_dictionary(self.dictionary.clone());
+        scan.seek(*start).await?;
+        let mut keys = Vec::new();
+        while scan.offset() < *end
+            && let Some(record) = scan.next().await?
+        {
+            keys.push(record.key);
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let j = self.random.below(keys.len());
+        Ok(Some(keys.swap_remove(j)))
+    }
+}
+
+/// Pseudo-random numbers from the standard library's randomly seeded hasher.
+struct Random {
+    state: RandomState,
+    counter: u64,
+}
+
+impl Random {
+    fn new() -> Self {
+        Self {
+            state: RandomState::new(),
+            counter: 0,
+        }
+    }
+
+    /// Returns a number in `0..n`, `n` being positive.
+    fn below(&mut self, n: usize) -> usize {
+        self.counter += 1;
+        (self.state.hash_one(self.counter) % n as u64) as usize
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, StreamExt, stream::BoxStream};
+use tokio::io::Result;
+
+use crate::{Collation, Controller, Value};
+
+/// Entries read at a time by [`Controller::scan_stream`].
+const PAGE_ENTRIES: usize = 1000;
+
+/// Entries of a scan, in collation order and each key at most once, as
+/// returned by [`Controller::scan_stream`]. The stream ends after the first
+/// error.
+///
+/// Combinators that keep these guarantees return an `OrderedStream`; it is
+/// also a plain [`Stream`] for everything else.
+pub struct OrderedStream<'a> {
+    entries: BoxStream<'a, Result<(String, Value)>>,
+    collation: Collation,
+}
+
+impl<'a> OrderedStream<'a> {
+    /// Reads the range `start` to `end` (exclusive) of `database`, whose
+    /// keys are in `collation`, a page at a time.
+    pub(crate) fn new(
+        database: &'a Controller,
+        start: &str,
+        end: Option<&str>,
+        collation: Collation,
+    ) -> Self {
+        struct Pages<'a> {
+            database: &'a Controller,
+            start: String,
+            end: Option<String>,
+            /// Entries left of the last page read.
+            buffered: VecDeque<(String, Value)>,
+            /// Where the next page starts: `Some(None)` before the first
+            /// one, `None` once the range is exhausted.
+            next: Option<Option<String>>,
+        }
+
+        let pages = Pages {
+            database,
+            start: start.to_string(),
+            end: end.map(str::to_string),
+            buffered: VecDeque::new(),
+            next: Some(None),
+        };
+        let entries = futures::stream::unfold(pages, |mut pages| async move {
+            loop {
+                if let Some(entry) = pages.buffered.pop_front() {
+                    return Some((Ok(entry), pages));
+                }
+                let after = pages.next.take()?;
+                let page = pages
+                    .database
+                    .scan_page(&pages.start, pages.end.as_deref(), after.as_deref(), PAGE_ENTRIES)
+                    .await;
+                match page {
+                    Ok(page) => {
+                        pages.buffered = page.entries.into();
+                        pages.next = page.next.map(Some);
+                    }
+                    Err(e) => return Some((Err(e), pages)),
+                }
+            }
+        });
+        Self {
+            entries: entries.boxed(),
+            collation,
+        }
+    }
+
+    /// Ends the stream at the first key that doesn't start with `prefix`,
+    /// as compared in the collation of the keys. Keys that do are adjacent
+    /// in scans, so none is missed if the scan started at or before them.
+    pub fn take_while_prefix(self, prefix: &str) -> Self {
+        let (collation, prefix) = (self.collation, prefix.to_string());
+        let entries = self.entries.take_while(move |entry| {
+            let keep = match entry {
+                Ok((key, _)) => collation.has_prefix(key, &prefix),
+                Err(_) => true,
+            };
+            async move { keep }
+        });
+        Self {
+            entries: entries.boxed(),
+            collation,
+        }
+    }
+
+    /// Ends the stream after `n` entries.
+    pub fn take(self, n: usize) -> Self {
+        Self {
+            entries: self.entries.take(n).boxed(),
+            collation: self.collation,
+        }
+    }
+
+    /// Groups the entries into vectors of `size` entries, the last one
+    /// possibly shorter. An error ends the stream after the entries read
+    /// before it.
+    pub fn chunks(self, size: usize) -> impl Stream<Item = Result<Vec<(String, Value)>>> + 'a {
+        assert!(size > 0, "chunks must hold at least one entry");
+        futures::stream::unfold(Some(self.entries), move |entries| async move {
+            let mut entries = entries?;
+            let mut chunk = Vec::with_capacity(size);
+            while chunk.len() < size {
+                match entries.next().await {
+                    Some(Ok(entry)) => chunk.push(entry),
+                    Some(Err(e)) if chunk.is_empty() => return Some((Err(e), None)),
+                    // Sent once the entries before it are.
+                    Some(Err(e)) => {
+                        let rest = futures::stream::once(async { Err(e) }).boxed();
+                        return Some((Ok(chunk), Some(rest)));
+                    }
+                    None if chunk.is_empty() => return None,
+                    None => return Some((Ok(chunk), None)),
+                }
+            }
+            Some((Ok(chunk), Some(entries)))
+        })
+    }
+}
+
+impl Stream for OrderedStream<'_> {
+    type Item = Result<(String, Value)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.entries.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,31 @@
+use std::{future::Future, time::Duration};
+
+use tokio::sync::watch;
+
+/// How often a disabled job checks whether it was enabled in the meantime.
+const DISABLED_POLL: Duration = Duration::from_secs(1);
+
+/// Runs `job` every `interval()` until `shutdown` changes. The interval is
+/// read again before every wait, so hot-tuned options apply from the next
+/// run; a zero interval disables the job until it is set again.
+pub(crate) async fn every<I, F, Fut>(
+    mut shutdown: watch::Receiver<bool>,
+    interval: I,
+    mut job: F,
+) where
+    I: Fn() -> Duration,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        let period = interval();
+        let wait = if period.is_zero() { DISABLED_POLL } else { period };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {},
+            _ = shutdown.changed() => return,
+        }
+        if !period.is_zero() && !interval().is_zero() {
+            job().await;
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::io::Result;
+
+use crate::{
+    Database, DatabaseImpl, MemValue, Value,
+    events::{EventKind, Notifier},
+};
+
+/// Runs scripts submitted with [`crate::Controller::eval`], such as a Lua or
+/// WASM interpreter. No engine is built in: embedders register one with
+/// [`crate::Controller::with_script_engine`].
+pub trait ScriptEngine: Send + Sync {
+    /// Runs `script` with the client supplied `args`, returning the values to
+    /// reply with. The whole script runs under the database write lock, so
+    /// its reads and writes are atomic with respect to other clients.
+    fn eval<'a>(
+        &'a self,
+        script: &'a str,
+        args: &'a [String],
+        db: ScriptContext<'a>,
+    ) -> BoxFuture<'a, Result<Vec<Value>>>;
+}
+
+/// The database as seen by a running script.
+pub struct ScriptContext<'a> {
+    db: &'a mut DatabaseImpl,
+    notifier: &'a Notifier,
+    /// Time the script started at, used for expiration.
+    now: u64,
+}
+
+impl<'a> ScriptContext<'a> {
+    pub(crate) fn new(db: &'a mut DatabaseImpl, notifier: &'a Notifier, now: u64) -> Self {
+        Self { db, notifier, now }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Value>> {
+        let now = self.now;
+        Ok(self
+            .db
+            .get_entry(key)
+            .await?
+            .filter(|entry| !entry.is_expired(now))
+            .and_then(MemValue::into_value))
+    }
+
+    pub async fn set(&mut self, key: String, value: Value) -> Result<()> {
+        let event = self.notifier.wants(EventKind::Set).then(|| (key.clone(), value.clone()));
+        self.db.set(key, value).await?;
+        self.notifier.publish_set(event);
+        Ok(())
+    }
+
+    /// Sets `key` to `value` for the given time-to-live.
+    pub async fn set_ex(&mut self, key: String, value: Value, ttl: Duration) -> Result<()> {
+        let event = self.notifier.wants(EventKind::Set).then(|| (key.clone(), value.clone()));
+        let expires_at = self.now + ttl.as_millis() as u64;
+        self.db.set_with_expiry(key, value, expires_at).await?;
+        self.notifier.publish_set(event);
+        Ok(())
+    }
+
+    pub async fn delete(&mut self, key: String) -> Result<()> {
+        let event_key = self.notifier.wants(EventKind::Delete).then(|| key.clone());
+        self.db.delete(key).await?;
+        self.notifier.publish(EventKind::Delete, event_key);
+        Ok(())
+    }
+}
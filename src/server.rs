@@ -0,0 +1,2251 @@
+//! The network front-end of the `my-database` binary, for applications that
+//! embed it: a line protocol served on a client and an admin listener, and
+//! health checks over HTTP, along with a read-only web console when built with
+//! the `web-console` feature.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Deserializer, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, Error, ErrorKind, ReadBuf, Result, sink,
+    },
+    net::{TcpListener, TcpStream},
+    sync::{
+        Notify,
+        broadcast::error::RecvError,
+        watch::{self, Receiver},
+    },
+    runtime::Handle,
+    task::JoinSet,
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+#[cfg(feature = "web-console")]
+mod web_console;
+
+use crate::{
+    Controller, Dictionary, EventFilter, EventKind, KeyTtl, Lease, OPTION_NAMES, Value, ValueType,
+    WriteBatch, request,
+};
+
+/// Listeners of a [`Server`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Addresses of the client listeners, e.g. `[::1]:2345` and
+    /// `127.0.0.1:2345`, each accepting on its own. A single address may be
+    /// given as a string.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<String>,
+    /// Listener on which every connection may run admin commands.
+    pub admin_bind: Option<String>,
+    /// Token unlocking admin commands on the client listener via `auth <token>`.
+    pub admin_token: Option<String>,
+    /// HTTP listener serving `/healthz` and `/readyz`, and the read-only
+    /// `/console` page and `/scan` export when built with the `web-console`
+    /// feature, which show keys and values to anyone who can connect.
+    pub http_bind: Option<String>,
+    /// Path of a UNIX domain socket served like `bind`, for local clients.
+    /// Who may connect is up to the permissions of the socket file and its
+    /// directory. A socket left behind by a server that didn't shut down
+    /// cleanly is replaced. UNIX only.
+    pub unix_socket: Option<PathBuf>,
+    /// Whether replies to TCP clients are sent right away rather than
+    /// batched by Nagle's algorithm, which delays short replies.
+    pub nodelay: bool,
+    /// Idle time after which TCP keepalive probes are sent on client
+    /// connections, and interval between them. Unset, keepalive is off.
+    pub keepalive_ms: Option<u64>,
+    /// Size of the kernel receive buffer of TCP client connections. Unset,
+    /// the system default is kept.
+    pub recv_buffer_bytes: Option<usize>,
+    /// Size of the kernel send buffer of TCP client connections. Unset, the
+    /// system default is kept.
+    pub send_buffer_bytes: Option<usize>,
+    /// Size from which replies to `get`, `mget` and `scan` are compressed, on
+    /// connections that asked for it with `hello 2 compress`.
+    pub compress_min_bytes: usize,
+    /// Largest request accepted, command line and bulk strings included.
+    /// Larger ones are skipped and answered with an error, keeping the
+    /// connection open.
+    pub max_request_bytes: usize,
+}
+
+impl ServerConfig {
+    /// Applies the socket options to the accepted connection `socket`.
+    fn configure(&self, socket: &TcpStream) -> Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(socket);
+        if let Some(keepalive_ms) = self.keepalive_ms {
+            let interval = Duration::from_millis(keepalive_ms);
+            let keepalive = TcpKeepalive::new().with_time(interval).with_interval(interval);
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(bytes) = self.recv_buffer_bytes {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.send_buffer_bytes {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1:2345".to_string()],
+            admin_bind: Some("127.0.0.1:2346".to_string()),
+            admin_token: None,
+            http_bind: Some("127.0.0.1:8080".to_string()),
+            unix_socket: None,
+            nodelay: true,
+            keepalive_ms: None,
+            recv_buffer_bytes: None,
+            send_buffer_bytes: None,
+            compress_min_bytes: 16 * 1024,
+            max_request_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Reads either a string or a list of strings.
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Name of the database connections start on.
+pub const DEFAULT_DATABASE: &str = "default";
+
+/// Runs an application specific command, such as an RPC, on the connections
+/// of a [`Server`]. No command is built in: embedders register one with
+/// [`Server::with_command`].
+pub trait CommandHandler: Send + Sync {
+    /// Runs the command with `args`, the words following its name, against
+    /// `database`, the one the connection selected. Keys in `args` aren't
+    /// prefixed with the namespace selected with `use`. Returns the reply,
+    /// which should end with a newline; errors are replied as
+    /// `(error) <message>`.
+    fn call<'a>(
+        &'a self,
+        args: &'a [&'a str],
+        database: &'a Controller,
+    ) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// Whether the command may only run on the admin listener, the local
+    /// console, or after `auth`.
+    fn admin(&self) -> bool {
+        false
+    }
+}
+
+/// Commands registered with [`Server::with_command`], by name.
+type Commands = HashMap<String, Arc<dyn CommandHandler>>;
+
+/// The databases a [`Server`] serves, by name. Connections start on
+/// [`DEFAULT_DATABASE`] and switch with `select <name>`.
+#[derive(Clone)]
+pub struct Databases {
+    by_name: BTreeMap<String, Arc<Controller>>,
+}
+
+impl Databases {
+    /// Serves `database` as [`DEFAULT_DATABASE`].
+    pub fn new(database: Arc<Controller>) -> Self {
+        Self {
+            by_name: BTreeMap::from([(DEFAULT_DATABASE.to_string(), database)]),
+        }
+    }
+
+    /// Also serves `database` as `name`, replacing any database of that name.
+    pub fn with(mut self, name: impl Into<String>, database: Arc<Controller>) -> Self {
+        self.by_name.insert(name.into(), database);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Controller>> {
+        self.by_name.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<Controller>)> {
+        self.by_name.iter().map(|(name, database)| (name.as_str(), database))
+    }
+
+    fn default_database(&self) -> &Arc<Controller> {
+        &self.by_name[DEFAULT_DATABASE]
+    }
+}
+
+impl From<Arc<Controller>> for Databases {
+    fn from(database: Arc<Controller>) -> Self {
+        Self::new(database)
+    }
+}
+
+/// Serves databases over the network. Binding and serving are separate steps
+/// so that health checks are answered while the databases recover: bind,
+/// open the databases, serve them, and once done shut the server down before
+/// the databases.
+pub struct Server {
+    config: Arc<ServerConfig>,
+    clients: Arc<Clients>,
+    databases: Arc<OnceLock<Arc<Databases>>>,
+    commands: Arc<Commands>,
+    /// Client and admin listeners, until [`Server::serve`] accepts on them.
+    pending: Vec<(Listener, bool)>,
+    local_addr: SocketAddr,
+    shutdown_tx: watch::Sender<()>,
+    listeners: JoinSet<()>,
+}
+
+impl Server {
+    /// Binds the listeners of `config` and starts answering health checks,
+    /// reporting the server not ready until [`Server::serve`] is called.
+    pub async fn bind(config: ServerConfig) -> Result<Self> {
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let clients = Arc::new(Clients::default());
+        let databases = Arc::new(OnceLock::new());
+        let mut listeners = JoinSet::new();
+
+        if let Some(http_bind) = &config.http_bind {
+            let listener = TcpListener::bind(http_bind).await?;
+            log::info!("Serving health checks on {}", http_bind);
+            let (databases, clients) = (databases.clone(), clients.clone());
+            listeners.spawn(async move {
+                let _ = serve_health(listener, databases, clients, shutdown_rx).await;
+            });
+        }
+
+        let Some((first, others)) = config.bind.split_first() else {
+            return Err(Error::new(ErrorKind::InvalidInput, "No address to listen on in bind"));
+        };
+        let listener = TcpListener::bind(first).await?;
+        let local_addr = listener.local_addr()?;
+        let mut pending = vec![(Listener::Tcp(listener), false)];
+        for bind in others {
+            pending.push((Listener::Tcp(TcpListener::bind(bind).await?), false));
+        }
+        if let Some(admin_bind) = &config.admin_bind {
+            pending.push((Listener::Tcp(TcpListener::bind(admin_bind).await?), true));
+        }
+        if let Some(path) = &config.unix_socket {
+            pending.push((Listener::bind_unix(path).await?, false));
+        }
+
+        Ok(Self {
+            config: Arc::new(config),
+            clients,
+            databases,
+            commands: Arc::default(),
+            pending,
+            local_addr,
+            shutdown_tx,
+            listeners,
+        })
+    }
+
+    /// Also runs `handler` for the command `name`, replacing any command
+    /// registered under that name. Built-in commands take precedence. Only
+    /// affects connections accepted by a later [`Server::serve`].
+    pub fn with_command(
+        mut self,
+        name: impl Into<String>,
+        handler: Arc<dyn CommandHandler>,
+    ) -> Self {
+        Arc::make_mut(&mut self.commands).insert(name.into(), handler);
+        self
+    }
+
+    /// Returns the address the first client listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Starts accepting connections, running their commands against
+    /// `databases`. Only the first call has any effect.
+    pub fn serve(&mut self, databases: impl Into<Databases>) {
+        let databases = Arc::new(databases.into());
+        if self.databases.set(databases.clone()).is_err() {
+            return;
+        }
+        for (listener, admin) in self.pending.drain(..) {
+            match listener.address() {
+                Ok(addr) if admin => log::info!("Listening for admin connections on {addr}"),
+                Ok(addr) => log::info!("Listening on {addr}"),
+                Err(_) => {}
+            }
+            let (databases, config) = (databases.clone(), self.config.clone());
+            let (clients, commands) = (self.clients.clone(), self.commands.clone());
+            let rx = self.shutdown_tx.subscribe();
+            self.listeners.spawn(async move {
+                let _ =
+                    accept_connections(listener, databases, config, clients, commands, admin, rx)
+                        .await;
+            });
+        }
+    }
+
+    /// Runs the commands read from `input`, writing their replies to
+    /// `output`, as the local console: admin commands are allowed and `drain`
+    /// doesn't close it. Returns on `exit` or at the end of the input. Fails
+    /// unless the server is serving.
+    pub async fn console<R, W>(&self, input: R, output: &mut W) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let databases = self.databases.get().ok_or_else(|| {
+            Error::new(ErrorKind::NotConnected, "The server isn't serving any database")
+        })?;
+        let mut session = Session::new(
+            true,
+            &self.config,
+            self.clients.clone(),
+            databases.clone(),
+            self.commands.clone(),
+        );
+        repl(&mut session, input, output).await
+    }
+
+    /// Stops accepting connections and closes the open ones, once done with
+    /// their current command. The databases are left open.
+    pub async fn shutdown(mut self) -> Result<()> {
+        let _ = self.shutdown_tx.send(());
+        while self.listeners.join_next().await.is_some() {}
+        log::info!("Closed network sockets.");
+        Ok(())
+    }
+}
+
+/// Answers `GET /healthz` (liveness) and `GET /readyz` (readiness) until shutdown.
+///
+/// The server is ready once recovery has finished and no database has been
+/// shut down, drained nor poisoned by a failed background flush. It is live
+/// while starting up, and afterwards as long as no database is shut down and
+/// the lock of every one can be taken promptly.
+async fn serve_health(
+    listener: TcpListener,
+    databases: Arc<OnceLock<Arc<Databases>>>,
+    clients: Arc<Clients>,
+    mut shutdown_rx: Receiver<()>,
+) -> Result<()> {
+    loop {
+        let (mut socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown_rx.changed() => return Ok(()),
+        };
+        let databases = databases.clone();
+        let draining = clients.is_draining();
+        tokio::spawn(async move {
+            let (read, mut write) = socket.split();
+            let mut lines = BufReader::new(read).lines();
+            let Ok(Some(request_line)) = lines.next_line().await else {
+                return;
+            };
+            // Read the headers; the request has no body.
+            let mut headers = Vec::new();
+            while let Ok(Some(header)) = lines.next_line().await {
+                if header.is_empty() {
+                    break;
+                }
+                headers.push(header);
+            }
+
+            #[cfg(feature = "web-console")]
+            {
+                let databases = databases.get().map(Arc::as_ref);
+                match web_console::serve(&request_line, &headers, databases, &mut write).await {
+                    Ok(false) => {}
+                    Ok(true) | Err(_) => return,
+                }
+            }
+
+            let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+            let healthy = match (path, databases.get()) {
+                ("/healthz", None) => Some(true),
+                ("/healthz", Some(dbs)) => {
+                    let mut live = true;
+                    for db in dbs.by_name.values() {
+                        live &= db.is_live(HEALTH_LOCK_TIMEOUT).await;
+                    }
+                    Some(live)
+                }
+                ("/readyz", dbs) => Some(
+                    !draining
+                        && dbs.is_some_and(|dbs| {
+                            dbs.by_name.values().all(|db| !db.is_shutdown() && !db.is_poisoned())
+                        }),
+                ),
+                _ => None,
+            };
+            let response: &[u8] = match healthy {
+                Some(true) => b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n",
+                Some(false) => b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 12\r\nConnection: close\r\n\r\nunavailable\n",
+                None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 10\r\nConnection: close\r\n\r\nnot found\n",
+            };
+            let _ = write.write_all(response).await;
+        });
+    }
+}
+
+/// Per-connection protocol state.
+struct Session {
+    /// Whether admin commands are allowed on this connection.
+    admin: bool,
+    admin_token: Option<String>,
+    /// Namespace selected with `use`, whose name and a `:` prefix every key.
+    namespace: Option<String>,
+    /// Writes queued since `multi`, applied together by `exec`.
+    transaction: Option<WriteBatch>,
+    /// Timeout of `get`, `scan` and `set` set with `timeout`, overriding the
+    /// database's `operation_timeout_ms`.
+    timeout: Option<Duration>,
+    clients: Arc<Clients>,
+    /// This connection, unless it is the local console.
+    client: Option<Arc<Client>>,
+    databases: Arc<Databases>,
+    /// Database selected with `select`, which commands run against.
+    database: Arc<Controller>,
+    commands: Arc<Commands>,
+    /// Protocol version agreed on with `hello`.
+    protocol: u32,
+    /// Whether large replies are compressed, as asked for with `hello`.
+    compress: bool,
+    compress_min_bytes: usize,
+    max_request_bytes: usize,
+}
+
+impl Session {
+    fn new(
+        admin: bool,
+        config: &ServerConfig,
+        clients: Arc<Clients>,
+        databases: Arc<Databases>,
+        commands: Arc<Commands>,
+    ) -> Self {
+        Self {
+            admin,
+            admin_token: config.admin_token.clone(),
+            namespace: None,
+            transaction: None,
+            timeout: None,
+            clients,
+            client: None,
+            database: databases.default_database().clone(),
+            databases,
+            commands,
+            protocol: 1,
+            compress: false,
+            compress_min_bytes: config.compress_min_bytes,
+            max_request_bytes: config.max_request_bytes,
+        }
+    }
+
+    /// Returns the stored key `key` refers to in the selected namespace.
+    fn key(&self, key: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{namespace}:{key}"),
+            None => key.to_string(),
+        }
+    }
+
+    /// Reverses [`Session::key`].
+    fn strip_namespace<'a>(&self, key: &'a str) -> &'a str {
+        match &self.namespace {
+            Some(namespace) => key
+                .strip_prefix(namespace.as_str())
+                .and_then(|key| key.strip_prefix(':'))
+                .unwrap_or(key),
+            None => key,
+        }
+    }
+
+    /// Returns the timeout of this connection's operations, if any.
+    fn timeout(&self, database: &Controller) -> Option<Duration> {
+        self.timeout.or_else(|| database.operation_timeout())
+    }
+
+    /// Returns what this connection's protocol supports, as replied to `hello`.
+    fn capabilities(&self) -> BTreeMap<String, String> {
+        let auth = if self.admin {
+            "not_required"
+        } else if self.admin_token.is_some() {
+            "required"
+        } else {
+            "unavailable"
+        };
+        [
+            ("proto", self.protocol.to_string()),
+            ("server", env!("CARGO_PKG_VERSION").to_string()),
+            ("values", "text,bulk".to_string()),
+            ("compression", if self.compress { "lz" } else { "none" }.to_string()),
+            ("auth", auth.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+
+    /// Returns `reply` as [`compress_reply`] frames if this connection asked
+    /// for compression and it takes at least `compress_min_bytes`.
+    fn compress(&self, reply: Vec<u8>) -> Vec<u8> {
+        if !self.compress || reply.len() < self.compress_min_bytes {
+            return reply;
+        }
+        compress_reply(&reply).unwrap_or(reply)
+    }
+
+    /// Returns a stored key greater than every key of the selected namespace,
+    /// to end scans of it, if a namespace is selected.
+    fn namespace_end(&self) -> Option<String> {
+        // `;` is the character following `:`.
+        self.namespace.as_ref().map(|namespace| format!("{namespace};"))
+    }
+}
+
+/// How long `/healthz` waits for the database lock before reporting a deadlock.
+const HEALTH_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Latest protocol version, which `hello` negotiates down to what a client
+/// asks for. Version 1 is text commands and replies, with `$<len>` bulk
+/// strings for values that aren't single-line text; clients that don't send
+/// `hello` get it. Version 2 adds compressed replies, on connections that
+/// ask for them with `hello 2 compress`, and so sends text values starting
+/// with `~` as bulk strings.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Most bytes of a reply a compressed frame holds: the most
+/// [`Dictionary::decompress`] accepts.
+const COMPRESSED_FRAME_BYTES: usize = 1 << 20;
+
+/// Number of entries `scan` returns when no count is given.
+const SCAN_LIMIT: usize = 100;
+
+/// Commands accepted between `multi` and `exec`; writes are queued.
+const TRANSACTION_COMMANDS: &[&str] =
+    &["set", "mset", "delete", "exec", "discard", "multi", "ping"];
+
+/// Commands that may only run on the admin listener, the local console, or
+/// after `auth`.
+const ADMIN_COMMANDS: &[&str] = &[
+    "flush", "compact", "dump", "stats", "analyze", "verify", "backup", "ingest", "config",
+    "words", "client", "drain", "manifest", "retention",
+];
+
+/// A client or admin listener of a [`Server`].
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+/// A listening UNIX domain socket, whose file is removed once dropped.
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+impl Listener {
+    #[cfg(unix)]
+    async fn bind_unix(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let stale = tokio::fs::symlink_metadata(path)
+            .await
+            .is_ok_and(|metadata| metadata.file_type().is_socket());
+        if stale {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use by another server", path.display()),
+                ));
+            }
+            tokio::fs::remove_file(path).await?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(Listener::Unix(UnixSocket {
+            listener,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    #[cfg(not(unix))]
+    async fn bind_unix(_path: &Path) -> Result<Self> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "UNIX domain sockets aren't supported on this platform",
+        ))
+    }
+
+    /// Returns the address or path the listener is bound to.
+    fn address(&self) -> Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(socket) => Ok(socket.path.display().to_string()),
+        }
+    }
+
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    async fn accept(
+        &self,
+        clients: &Clients,
+        config: &ServerConfig,
+    ) -> Result<(Box<dyn Connection>, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                if let Err(e) = config.configure(&socket) {
+                    log::warn!(peer:% = addr, error:% = e; "Unable to set socket options: {e}");
+                }
+                Ok((Box::new(socket), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => {
+                let (socket, _) = socket.listener.accept().await?;
+                let id = clients.unix_peers.fetch_add(1, Ordering::Relaxed) + 1;
+                Ok((Box::new(socket), Peer::Unix(id)))
+            }
+        }
+    }
+}
+
+/// The other end of a connection, as `client list` shows it and `client
+/// kill` takes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Peer {
+    Tcp(SocketAddr),
+    /// A connection to the UNIX domain socket, numbered in order of arrival
+    /// since its peers have no address.
+    Unix(u64),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix(id) => write!(f, "unix:{id}"),
+        }
+    }
+}
+
+impl FromStr for Peer {
+    type Err = ();
+
+    fn from_str(peer: &str) -> std::result::Result<Self, ()> {
+        match peer.strip_prefix("unix:") {
+            Some(id) => id.parse().map(Peer::Unix).map_err(|_| ()),
+            None => peer.parse().map(Peer::Tcp).map_err(|_| ()),
+        }
+    }
+}
+
+async fn accept_connections(
+    listener: Listener,
+    databases: Arc<Databases>,
+    config: Arc<ServerConfig>,
+    clients: Arc<Clients>,
+    commands: Arc<Commands>,
+    admin: bool,
+    shutdown_rx: Receiver<()>,
+) -> Result<()> {
+    let mut shutdown_rx_main = shutdown_rx.clone();
+    let mut draining_rx = clients.draining();
+    let mut connections = JoinSet::new();
+
+    tokio::select! {
+        Ok::<_, Error>(()) = async {
+            loop {
+                let (socket, conn) = listener.accept(&clients, &config).await?;
+
+                let client = clients.register(conn, admin);
+                let mut session = Session::new(
+                    admin,
+                    &config,
+                    clients.clone(),
+                    databases.clone(),
+                    commands.clone(),
+                );
+                session.client = Some(client.clone());
+                let registration = Registration {
+                    clients: clients.clone(),
+                    client: client.clone(),
+                };
+                let mut shutdown_rx_task = shutdown_rx.clone();
+                connections.spawn(async move {
+                    tokio::select! {
+                        _ = handle_connection(socket, conn, session) => {},
+                        _ = client.kill.notified() => {
+                            log::info!(peer:% = conn; "Client {conn} killed");
+                        }
+                        _ = shutdown_rx_task.changed() => {
+                            log::info!(peer:% = conn; "Socket {conn} shutdown requested");
+                        }
+                    }
+                    client.delete_ephemeral(client.take_ephemeral()).await;
+                    drop(registration);
+                });
+            }
+        } => {
+            Ok(())
+        },
+        _ = async { draining_rx.wait_for(|draining| *draining).await.is_ok() } => {
+            drop(listener);
+            log::info!("Stopped accepting connections to drain.");
+
+            while let Some(res) = connections.join_next().await {
+                if let Err(e) = res {
+                    log::warn!("Connection handler exited with error: {:?}", e)
+                }
+            }
+
+            Ok(())
+        },
+        _ = shutdown_rx_main.changed() => {
+            log::info!("Socket shutdown requested.");
+
+            while let Some(res) = connections.join_next().await {
+                if let Err(e) = res {
+                    log::warn!("Connection handler exited with error: {:?}", e)
+                }
+            }
+
+            Ok(())
+        },
+    }
+}
+
+async fn handle_connection(
+    socket: Box<dyn Connection>,
+    addr: Peer,
+    mut session: Session,
+) -> Result<()> {
+    let client = session.client.clone().expect("network sessions have a client");
+    let (read, write) = tokio::io::split(socket);
+    let read = BufReader::new(Metered::new(read, &client.bytes_in));
+    let mut write = Metered::new(write, &client.bytes_out);
+    log::info!(
+        peer:% = addr, admin = session.admin;
+        "Client connection from {addr}"
+    );
+    if let Err(e) = repl(&mut session, read, &mut write).await {
+        log::warn!(
+            peer:% = addr, error:% = e;
+            "Connection from {addr} failed: {e}"
+        );
+        return Err(e);
+    }
+    log::info!(
+        peer:% = addr;
+        "Closed connection from {addr}"
+    );
+    Ok::<_, Error>(())
+}
+
+async fn repl<R, W>(session: &mut Session, mut input: R, output: &mut W) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut draining = session.clients.draining();
+    loop {
+        if session.client.is_some() && *draining.borrow() {
+            output.write_all(b"(closing) server is draining\n").await?;
+            break;
+        }
+        output.write_all(b"> ").await?;
+        output.flush().await?;
+
+        let request = tokio::select! {
+            request = read_request(&mut input, session.max_request_bytes) => request?,
+            _ = until_draining(&mut draining, session) => continue,
+        };
+        let Some(mut request) = request else {
+            break;
+        };
+        if let Some(reason) = request.rejected {
+            log::debug!("Rejected request: {reason}");
+            output.write_all(format!("(error) {reason}\n").as_bytes()).await?;
+            continue;
+        }
+        let line = request.line.trim();
+        if let Some(client) = &session.client {
+            client.record(line);
+        }
+        if line == "exit" {
+            output.write_all(b"bye.\n").await?;
+            break;
+        }
+        let database = session.database.clone();
+        let request_id = request::next_id();
+        if log::log_enabled!(log::Level::Debug) {
+            let peer = session.client.as_ref().map(|client| client.addr.to_string());
+            let peer = peer.as_deref().unwrap_or("console");
+            let name = line.split_whitespace().next().unwrap_or_default();
+            log::debug!(peer, request_id; "Request {request_id} from {peer}: {name}");
+        }
+        if let Some(args) = line.strip_prefix("watch")
+            && (args.is_empty() || args.starts_with(' '))
+        {
+            let args: Vec<_> = args.split_whitespace().collect();
+            tokio::select! {
+                watched = watch(&database, &args, &mut input, output, session.max_request_bytes)
+                    => watched?,
+                _ = until_draining(&mut draining, session) => {}
+            }
+            continue;
+        }
+        let parsed = parse(line, &mut request.bulk, &database, session, output);
+        request::scope(request_id, parsed).await?;
+    }
+    Ok(())
+}
+
+/// Resolves once `drain` has been run, unless `session` is the local
+/// console, which stays open to stop the server.
+async fn until_draining(draining: &mut Receiver<bool>, session: &Session) {
+    if session.client.is_none() || draining.wait_for(|draining| *draining).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Connections to the client and admin listeners, for `client list`,
+/// `client kill` and `drain`.
+#[derive(Default)]
+struct Clients {
+    connected: std::sync::Mutex<BTreeMap<Peer, Arc<Client>>>,
+    /// Notified whenever a client disconnects or runs `drain`.
+    disconnected: Notify,
+    /// Set by `drain`: listeners stop accepting and idle connections close.
+    draining: watch::Sender<bool>,
+    /// Number of connections accepted on the UNIX domain socket so far.
+    unix_peers: AtomicU64,
+}
+
+impl Clients {
+    fn register(&self, addr: Peer, admin: bool) -> Arc<Client> {
+        let client = Arc::new(Client {
+            addr,
+            admin,
+            connected_at: Instant::now(),
+            commands: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            last_command: std::sync::Mutex::new(String::new()),
+            kill: Notify::new(),
+            draining: AtomicBool::new(false),
+            ephemeral: std::sync::Mutex::new(Some(Vec::new())),
+        });
+        self.connected.lock().unwrap().insert(addr, client.clone());
+        client
+    }
+
+    fn unregister(&self, addr: &Peer) {
+        self.connected.lock().unwrap().remove(addr);
+        self.disconnected.notify_waiters();
+    }
+
+    fn draining(&self) -> Receiver<bool> {
+        self.draining.subscribe()
+    }
+
+    fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Stops accepting connections and waits until every client has
+    /// disconnected, once done with its current command, but `client` and
+    /// those running `drain` too.
+    async fn drain(&self, client: Option<&Client>) {
+        if let Some(client) = client {
+            client.draining.store(true, Ordering::Relaxed);
+            self.disconnected.notify_waiters();
+        }
+        self.draining.send_replace(true);
+        loop {
+            let disconnected = self.disconnected.notified();
+            let remaining = self
+                .connected
+                .lock()
+                .unwrap()
+                .values()
+                .any(|client| !client.draining.load(Ordering::Relaxed));
+            if !remaining {
+                return;
+            }
+            disconnected.await;
+        }
+    }
+
+    /// Disconnects the client at `addr`, returning `false` if there is none.
+    fn kill(&self, addr: &Peer) -> bool {
+        match self.connected.lock().unwrap().remove(addr) {
+            Some(client) => {
+                client.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns a line describing every connected client.
+    fn list(&self) -> String {
+        let connected = self.connected.lock().unwrap();
+        connected.values().map(|client| format!("{client}\n")).collect()
+    }
+}
+
+/// Unregisters a connection once its handler is done, even if it panics,
+/// so that it leaves `client list` and no longer holds up `drain`. The
+/// ephemeral keys of a handler that panicked are deleted in the background.
+struct Registration {
+    clients: Arc<Clients>,
+    client: Arc<Client>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let ephemeral = self.client.take_ephemeral();
+        if !ephemeral.is_empty()
+            && let Ok(runtime) = Handle::try_current()
+        {
+            let client = self.client.clone();
+            runtime.spawn(async move { client.delete_ephemeral(ephemeral).await });
+        }
+        self.clients.unregister(&self.client.addr);
+    }
+}
+
+/// A network connection and what it has done so far.
+struct Client {
+    addr: Peer,
+    admin: bool,
+    connected_at: Instant,
+    commands: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Name of the last command, without its arguments: those may hold
+    /// values or the admin token.
+    last_command: std::sync::Mutex<String>,
+    /// Notified by `client kill` to close the connection.
+    kill: Notify,
+    /// Set once the client runs `drain`, which other clients running it
+    /// don't wait for.
+    draining: AtomicBool,
+    /// Keys set with `setephemeral`, deleted once the connection closes, be it
+    /// by the client, `client kill` or a shutdown. A crash leaves them behind.
+    /// `None` once the connection is closed and they are deleted.
+    ephemeral: std::sync::Mutex<Option<Vec<EphemeralKey>>>,
+}
+
+/// A key set with `setephemeral`, in the database it was set in.
+struct EphemeralKey {
+    database: Arc<Controller>,
+    key: String,
+    /// Returned by [`Controller::set_ephemeral`] for the write.
+    token: u64,
+}
+
+impl Client {
+    /// Counts the command line `line`.
+    fn record(&self, line: &str) {
+        let Some(name) = line.split_whitespace().next() else {
+            return;
+        };
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        *self.last_command.lock().unwrap() = name.to_string();
+    }
+
+    /// Records that `key` of `database` was set by `setephemeral`, in the
+    /// write `token` stands for. Returns `false`, recording nothing, if the
+    /// connection is already closed.
+    fn add_ephemeral(&self, database: &Arc<Controller>, key: String, token: u64) -> bool {
+        let mut ephemeral = self.ephemeral.lock().unwrap();
+        let Some(ephemeral) = ephemeral.as_mut() else {
+            return false;
+        };
+        ephemeral.retain(|entry| !(Arc::ptr_eq(&entry.database, database) && entry.key == key));
+        ephemeral.push(EphemeralKey {
+            database: database.clone(),
+            key,
+            token,
+        });
+        true
+    }
+
+    /// Closes the list of keys set by `setephemeral`, returning them.
+    fn take_ephemeral(&self) -> Vec<EphemeralKey> {
+        self.ephemeral.lock().unwrap().take().unwrap_or_default()
+    }
+
+    /// Deletes those of `ephemeral` that still hold the write of
+    /// `setephemeral`: keys written since, even to the same value, belong to
+    /// whoever wrote them.
+    async fn delete_ephemeral(&self, ephemeral: Vec<EphemeralKey>) {
+        for entry in ephemeral {
+            if let Err(e) = entry.database.delete_ephemeral(&entry.key, entry.token).await {
+                log::warn!(
+                    peer:% = self.addr, error:% = e;
+                    "Unable to delete ephemeral key {} of {}: {e}", entry.key, self.addr
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "addr={} admin={} age={}s commands={} in={} out={} last={}",
+            self.addr,
+            if self.admin { "yes" } else { "no" },
+            self.connected_at.elapsed().as_secs(),
+            self.commands.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.last_command.lock().unwrap(),
+        )
+    }
+}
+
+/// Adds the number of bytes read from or written to `inner` to `bytes`.
+struct Metered<'a, T> {
+    inner: T,
+    bytes: &'a AtomicU64,
+}
+
+impl<'a, T> Metered<'a, T> {
+    fn new(inner: T, bytes: &'a AtomicU64) -> Self {
+        Self { inner, bytes }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Metered<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        self.bytes.fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Metered<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.bytes.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A command line and the bulk strings it announced.
+struct Request {
+    line: String,
+    /// Bytes of every `$<len>` argument, by argument position.
+    bulk: HashMap<usize, Vec<u8>>,
+    /// Why the request was skipped without being read in full, if it was.
+    rejected: Option<String>,
+}
+
+/// Reads a request: a line of whitespace separated arguments, after which
+/// the bytes of every `$<len>` argument follow in order, so that a value
+/// holding newlines or invalid UTF-8 can be sent as `set key $<len>\r\n<bytes>`.
+/// Returns `None` at the end of the input.
+///
+/// A request of more than `max_bytes` is consumed without being kept, so
+/// that the next one can be read, and returned rejected. The bulk strings of
+/// a line that is itself too long can't be told apart from the next lines.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    input: &mut R,
+    max_bytes: usize,
+) -> Result<Option<Request>> {
+    let mut line = Vec::new();
+    if read_line_limited(input, &mut line, max_bytes).await? == 0 {
+        return Ok(None);
+    }
+    let rejected = || Some(format!("request exceeds {max_bytes} bytes"));
+    if line.len() > max_bytes {
+        return Ok(Some(Request {
+            line: String::new(),
+            bulk: HashMap::new(),
+            rejected: rejected(),
+        }));
+    }
+    let line = String::from_utf8_lossy(&line).into_owned();
+
+    let mut bulk = HashMap::new();
+    let mut size = line.len();
+    let mut too_large = false;
+    for (i, arg) in line.split_whitespace().enumerate() {
+        let Some(len) = bulk_len(arg) else {
+            continue;
+        };
+        size = size.saturating_add(len);
+        too_large |= size > max_bytes;
+        if too_large {
+            let discarded = tokio::io::copy(&mut (&mut *input).take(len as u64), &mut sink()).await?;
+            if discarded < len as u64 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            continue;
+        }
+        let mut bytes = vec![0; len];
+        input.read_exact(&mut bytes).await?;
+        bulk.insert(i, bytes);
+    }
+    Ok(Some(Request {
+        line,
+        bulk,
+        rejected: if too_large { rejected() } else { None },
+    }))
+}
+
+/// Reads a line into `line` like `read_until`, but keeps no more than
+/// `max_bytes + 1` bytes of it, discarding the rest, so that a line longer
+/// than `max_bytes` is left longer than that without taking up more memory.
+/// Returns the number of bytes read, discarded ones included.
+///
+/// Cancel safe: a line cut short by a cancelled read is completed by the
+/// next read into the same `line`.
+async fn read_line_limited<R: AsyncBufRead + Unpin>(
+    input: &mut R,
+    line: &mut Vec<u8>,
+    max_bytes: usize,
+) -> Result<usize> {
+    let mut read = 0;
+    loop {
+        let available = input.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(read);
+        }
+        let (used, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        let room = max_bytes.saturating_add(1).saturating_sub(line.len());
+        line.extend(&available[..used.min(room)]);
+        input.consume(used);
+        read += used;
+        if done {
+            return Ok(read);
+        }
+    }
+}
+
+/// Returns the length announced by a `$<len>` bulk string argument.
+fn bulk_len(arg: &str) -> Option<usize> {
+    arg.strip_prefix('$')?.parse().ok()
+}
+
+/// Streams keyspace events to the client until it sends `unwatch` or disconnects.
+///
+/// Events are written as `<kind> <key>` lines; `args` optionally restricts the
+/// stream to the given event kinds, and to the keys and new values passing
+/// the conditions of [`watch_filter`].
+async fn watch<R, W>(
+    database: &Controller,
+    args: &[&str],
+    input: &mut R,
+    output: &mut W,
+    max_line_bytes: usize,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Some(filter) = watch_filter(args) else {
+        output
+            .write_all(
+                b"(error) usage: watch [<kind> ...] [key <pattern>] [type <type>] \
+                  [min <value>] [max <value>]\n",
+            )
+            .await?;
+        return output.flush().await;
+    };
+    let mut events = database.subscribe_filtered(filter);
+    output.write_all(b"watching.\n").await?;
+    output.flush().await?;
+
+    // Kept across iterations: a cancelled read leaves what it read so far here.
+    let mut line = Vec::new();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    output.write_all(format!("{} {}\n", event.kind, event.key).as_bytes()).await?;
+                    output.flush().await?;
+                }
+                Err(RecvError::Lagged(n)) => {
+                    output.write_all(format!("lagged {n}\n").as_bytes()).await?;
+                    output.flush().await?;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            read = read_line_limited(input, &mut line, max_line_bytes) => {
+                if read? == 0 || line.trim_ascii() == b"unwatch" {
+                    return Ok(());
+                }
+                line.clear();
+            },
+        }
+    }
+}
+
+/// Parses the arguments of `watch`: event kinds, unknown ones being ignored,
+/// and the conditions of an [`EventFilter`], each followed by its argument:
+/// `key <pattern>`, `type <type>`, `min <value>` and `max <value>`. Returns
+/// `None` if a condition lacks its argument or names no type.
+fn watch_filter(args: &[&str]) -> Option<EventFilter> {
+    let mut filter = EventFilter::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "key" => filter.key_pattern = Some(args.next()?.to_string()),
+            "type" => filter.value.value_type = Some(ValueType::parse(args.next()?)?),
+            "min" => filter.value.min = Some(parse_value(args.next()?)),
+            "max" => filter.value.max = Some(parse_value(args.next()?)),
+            kind => filter.kinds.extend(EventKind::parse(kind)),
+        }
+    }
+    Some(filter)
+}
+
+async fn parse<W: AsyncWrite + Unpin>(
+    command: &str,
+    bulk: &mut HashMap<usize, Vec<u8>>,
+    database: &Controller,
+    session: &mut Session,
+    output: &mut W,
+) -> Result<()> {
+    let args: Vec<_> = command.split_whitespace().collect();
+
+    if let Some(name) = args.first()
+        && (ADMIN_COMMANDS.contains(name)
+            || session.commands.get(*name).is_some_and(|handler| handler.admin()))
+        && !session.admin
+    {
+        output
+            .write_all(b"(error) admin command; use the admin port or 'auth <token>'\n")
+            .await?;
+        return output.flush().await;
+    }
+
+    if session.transaction.is_some()
+        && let Some(name) = args.first()
+        && !TRANSACTION_COMMANDS.contains(name)
+    {
+        output
+            .write_all(format!("(error) '{name}' can't be used in a transaction\n").as_bytes())
+            .await?;
+        return output.flush().await;
+    }
+
+    match args.first() {
+        Some(&"multi") => {
+            let reply: &[u8] = match session.transaction {
+                Some(_) => b"(error) transactions can't be nested\n",
+                None => {
+                    session.transaction = Some(WriteBatch::new());
+                    b"OK\n"
+                }
+            };
+            output.write_all(reply).await?;
+            output.flush().await
+        }
+        Some(&"exec") => {
+            let reply = match session.transaction.take() {
+                Some(batch) => {
+                    let writes = batch.len();
+                    match database.write(batch).await {
+                        Ok(()) => format!("OK {writes} writes\n"),
+                        Err(e) => error_reply(&e),
+                    }
+                }
+                None => "(error) exec without multi\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"discard") => {
+            let reply: &[u8] = match session.transaction.take() {
+                Some(_) => b"OK\n",
+                None => b"(error) discard without multi\n",
+            };
+            output.write_all(reply).await?;
+            output.flush().await
+        }
+        Some(&"ping") => {
+            output.write_all(b"PONG\n").await?;
+            output.flush().await
+        }
+        Some(&"hello") => {
+            let negotiated = match args[1..] {
+                [] => Ok((session.protocol, session.compress)),
+                [version] | [version, "compress"] => match version.parse::<u32>() {
+                    Ok(version) if version >= 1 => {
+                        let version = version.min(PROTOCOL_VERSION);
+                        let compress = args.len() == 3;
+                        if compress && version < 2 {
+                            Err("(error) compression needs protocol version 2\n".to_string())
+                        } else {
+                            Ok((version, compress))
+                        }
+                    }
+                    _ => Err(format!(
+                        "(error) unsupported protocol version {version}; \
+                         versions 1 to {PROTOCOL_VERSION} are supported\n"
+                    )),
+                },
+                _ => Err("(error) usage: hello [version [compress]]\n".to_string()),
+            };
+            let reply = match negotiated {
+                Ok((version, compress)) => {
+                    session.protocol = version;
+                    session.compress = compress;
+                    encode_map(session.capabilities(), session.protocol)
+                }
+                Err(message) => message.into_bytes(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"auth") => {
+            let authorized = session.admin_token.is_some()
+                && args.get(1).copied() == session.admin_token.as_deref();
+            session.admin |= authorized;
+            let reply: &[u8] = if authorized {
+                b"OK\n"
+            } else {
+                b"(error) invalid token\n"
+            };
+            output.write_all(reply).await?;
+            output.flush().await
+        }
+        Some(&"use") => {
+            let reply: &[u8] = match args[1..] {
+                [] => {
+                    session.namespace = None;
+                    b"OK\n"
+                }
+                [namespace] if !namespace.contains(':') => {
+                    session.namespace = Some(namespace.to_string());
+                    b"OK\n"
+                }
+                _ => b"(error) usage: use [namespace], without ':'\n",
+            };
+            output.write_all(reply).await?;
+            output.flush().await
+        }
+        Some(&"select") => {
+            let reply = match args[1..] {
+                [name] => match session.databases.get(name) {
+                    Some(database) => {
+                        session.database = database.clone();
+                        "OK\n".to_string()
+                    }
+                    None => format!("(error) no database named {name}\n"),
+                },
+                _ => "(error) usage: select <database>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"timeout") => {
+            let reply: &[u8] = match args[1..] {
+                [] => {
+                    session.timeout = None;
+                    b"OK\n"
+                }
+                [millis] => match millis.parse::<u64>() {
+                    Ok(0) => {
+                        session.timeout = None;
+                        b"OK\n"
+                    }
+                    Ok(millis) => {
+                        session.timeout = Some(Duration::from_millis(millis));
+                        b"OK\n"
+                    }
+                    Err(_) => b"(error) usage: timeout [milliseconds]\n",
+                },
+                _ => b"(error) usage: timeout [milliseconds]\n",
+            };
+            output.write_all(reply).await?;
+            output.flush().await
+        }
+        Some(&"get") => {
+            let [_, key] = args[..] else {
+                output.write_all(b"(error) usage: get <key>\n").await?;
+                return output.flush().await;
+            };
+            let key = session.key(key);
+            let reply = match database.get_within(&key, session.timeout(database)).await {
+                Ok(Some(value)) => encode_value(value, session.protocol),
+                Ok(None) => b"(none)\n".to_vec(),
+                Err(e) => error_reply(&e).into_bytes(),
+            };
+            output.write_all(&session.compress(reply)).await?;
+            output.flush().await
+        }
+        Some(&"getlen") => {
+            let reply = match args[1..] {
+                [key] => match database.get_len(&session.key(key)).await {
+                    Ok(Some(len)) => format!("{len}\n"),
+                    Ok(None) => "(none)\n".to_string(),
+                    Err(e) => error_reply(&e),
+                },
+                _ => "(error) usage: getlen <key>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"getrange") => {
+            let reply = match args[1..] {
+                [key, offset, len] => match (offset.parse(), len.parse()) {
+                    (Ok(offset), Ok(len)) => {
+                        let key = session.key(key);
+                        match database.get_range_of_value(&key, offset, len).await {
+                            Ok(Some(bytes)) => match String::from_utf8(bytes) {
+                                Ok(text) => encode_value(Value::Str(text), session.protocol),
+                                Err(e) => bulk_string(e.as_bytes()),
+                            },
+                            Ok(None) => b"(none)\n".to_vec(),
+                            Err(e) => error_reply(&e).into_bytes(),
+                        }
+                    }
+                    _ => b"(error) offset and length must be non-negative integers\n".to_vec(),
+                },
+                _ => b"(error) usage: getrange <key> <offset> <len>\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"mget") if args.len() > 1 => {
+            let keys: Vec<_> = args[1..].iter().map(|key| session.key(key)).collect();
+            let keys: Vec<_> = keys.iter().map(String::as_str).collect();
+            let reply = match database.multi_get_within(&keys, session.timeout(database)).await {
+                Ok(values) => values
+                    .into_iter()
+                    .flat_map(|value| match value {
+                        Some(value) => encode_value(value, session.protocol),
+                        None => b"(none)\n".to_vec(),
+                    })
+                    .collect(),
+                Err(e) => error_reply(&e).into_bytes(),
+            };
+            output.write_all(&session.compress(reply)).await?;
+            output.flush().await
+        }
+        Some(&"mset") if args.len() > 1 && args.len() % 2 == 1 => {
+            let queued = session.transaction.is_some();
+            let mut batch = session.transaction.take().unwrap_or_default();
+            for i in (1..args.len()).step_by(2) {
+                let value = match bulk.remove(&(i + 1)) {
+                    Some(bytes) => bytes_value(bytes),
+                    None => parse_value(args[i + 1]),
+                };
+                batch.set(session.key(args[i]), value);
+            }
+            if queued {
+                session.transaction = Some(batch);
+                output.write_all(b"QUEUED\n").await?;
+                return output.flush().await;
+            }
+            let reply = match database.write(batch).await {
+                Ok(()) => "OK\n".to_string(),
+                Err(e) => error_reply(&e),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"mget" | &"mset") => {
+            output.write_all(b"(error) usage: mget <key>... | mset <key> <value> ...\n").await?;
+            output.flush().await
+        }
+        Some(&"scan") => {
+            let reply = match args[1..] {
+                [start, ref rest @ ..] if rest.len() <= 2 => {
+                    let limit = rest.get(1).and_then(|x| x.parse().ok()).unwrap_or(SCAN_LIMIT);
+                    let start = session.key(start);
+                    let end = match rest.first() {
+                        Some(end) => Some(session.key(end)),
+                        None => session.namespace_end(),
+                    };
+                    let timeout = session.timeout(database);
+                    match database.scan_within(&start, end.as_deref(), limit, timeout).await {
+                        Ok(entries) => {
+                            let mut reply = Vec::new();
+                            for (key, value) in entries {
+                                let key = session.strip_namespace(&key);
+                                reply.extend(format!("{key} ").as_bytes());
+                                reply.extend(encode_value(value, session.protocol));
+                            }
+                            reply
+                        }
+                        Err(e) => error_reply(&e).into_bytes(),
+                    }
+                }
+                _ => b"(error) usage: scan <start> [end] [count]\n".to_vec(),
+            };
+            output.write_all(&session.compress(reply)).await?;
+            output.flush().await
+        }
+        Some(&"set") => {
+            let Some(key) = args.get(1) else {
+                output.write_all(b"(error) usage: set <key> <value> [ex|px <time>]\n").await?;
+                return output.flush().await;
+            };
+            let key = session.key(key);
+            let value = match (bulk.remove(&2), args.get(2)) {
+                (Some(bytes), _) => bytes_value(bytes),
+                (None, Some(value)) => parse_value(value),
+                (None, None) => {
+                    output.write_all(b"(error) usage: set <key> <value> [ex|px <time>]\n").await?;
+                    return output.flush().await;
+                }
+            };
+
+            let ttl = match parse_expiry(args.get(3..).unwrap_or_default()) {
+                Ok(ttl) => ttl,
+                Err(message) => {
+                    output.write_all(format!("(error) {message}\n").as_bytes()).await?;
+                    return output.flush().await;
+                }
+            };
+            if let Some(batch) = &mut session.transaction {
+                match ttl {
+                    None => batch.set(key, value),
+                    Some(ttl) => batch.set_ex(key, value, ttl),
+                };
+                output.write_all(b"QUEUED\n").await?;
+                return output.flush().await;
+            }
+            if let Err(e) = database.set_within(key, value, ttl, session.timeout(database)).await {
+                output.write_all(error_reply(&e).as_bytes()).await?;
+                output.flush().await?;
+            }
+            Ok(())
+        }
+        Some(&"setephemeral") => {
+            let Some(client) = &session.client else {
+                output.write_all(b"(error) ephemeral keys need a client connection\n").await?;
+                return output.flush().await;
+            };
+            let Some(key) = args.get(1) else {
+                output.write_all(b"(error) usage: setephemeral <key> <value>\n").await?;
+                return output.flush().await;
+            };
+            let key = session.key(key);
+            let value = match (bulk.remove(&2), args.get(2)) {
+                (Some(bytes), _) => bytes_value(bytes),
+                (None, Some(value)) => parse_value(value),
+                (None, None) => {
+                    output.write_all(b"(error) usage: setephemeral <key> <value>\n").await?;
+                    return output.flush().await;
+                }
+            };
+            let timeout = session.timeout(database);
+            // Run apart from the connection, so that a write applied as the
+            // connection closes is still deleted.
+            let (client, database) = (client.clone(), session.database.clone());
+            let set = tokio::spawn(async move {
+                let token = database.set_ephemeral_within(key.clone(), value, timeout).await?;
+                if !client.add_ephemeral(&database, key.clone(), token) {
+                    database.delete_ephemeral(&key, token).await?;
+                }
+                Ok::<_, Error>(())
+            });
+            let set = set.await.unwrap_or_else(|e| Err(Error::other(e)));
+            if let Err(e) = set {
+                output.write_all(error_reply(&e).as_bytes()).await?;
+                output.flush().await?;
+            }
+            Ok(())
+        }
+        Some(&"eval") => {
+            let reply = match args.get(1) {
+                Some(script) => {
+                    let script = match bulk.remove(&1) {
+                        Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                        None => script.to_string(),
+                    };
+                    let script_args: Vec<_> = args[2..].iter().map(|arg| arg.to_string()).collect();
+                    match database.eval(&script, &script_args).await {
+                        Ok(values) => values
+                            .into_iter()
+                            .flat_map(|value| encode_value(value, session.protocol))
+                            .collect(),
+                        Err(e) => error_reply(&e).into_bytes(),
+                    }
+                }
+                None => b"(error) usage: eval <script> [args...]\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"incrby") => {
+            let reply = match args[1..] {
+                [key, delta] => match delta.parse() {
+                    Ok(delta) => match database.incr_by(&session.key(key), delta).await {
+                        Ok(n) => format!("{n}\n"),
+                        Err(e) => error_reply(&e),
+                    },
+                    Err(_) => "(error) increment is not an integer\n".to_string(),
+                },
+                _ => "(error) usage: incrby <key> <delta>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"lock") => {
+            let reply = match args[1..] {
+                [key, ttl] | [key, ttl, _] => {
+                    let wait = args.get(3).map_or(Ok(0), |wait| wait.parse());
+                    match (ttl.parse(), wait) {
+                        (Ok(ttl), Ok(wait)) if ttl > 0 => {
+                            let ttl = Duration::from_millis(ttl);
+                            let wait = Duration::from_millis(wait);
+                            match database.lock(&session.key(key), ttl, wait).await {
+                                Some(token) => format!("OK {token}\n"),
+                                None => "(none)\n".to_string(),
+                            }
+                        }
+                        _ => "(error) ttl and wait must be milliseconds, ttl above 0\n".to_string(),
+                    }
+                }
+                _ => "(error) usage: lock <key> <ttl_ms> [wait_ms]\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"unlock") => {
+            let reply: &[u8] = match args[1..] {
+                [key, token] => match token.parse() {
+                    Ok(token) if database.unlock(&session.key(key), token) => b"OK\n",
+                    Ok(_) => b"(error) lock not held with that token\n",
+                    Err(_) => b"(error) invalid token\n",
+                },
+                _ => b"(error) usage: unlock <key> <token>\n",
+            };
+            output.write_all(reply).await?;
+            output.flush().await
+        }
+        Some(&"lease") => {
+            let ttl = |ttl: &str| ttl.parse().ok().filter(|&ttl| ttl > 0).map(Duration::from_millis);
+            let held = |result: Result<bool>| match result {
+                Ok(true) => "OK\n".to_string(),
+                Ok(false) => "(error) lease not held with that token\n".to_string(),
+                Err(e) => error_reply(&e),
+            };
+            let reply = match args[1..] {
+                ["acquire", key, ttl_ms] => match ttl(ttl_ms) {
+                    Some(ttl) => match Lease::acquire(database, &session.key(key), ttl).await {
+                        Ok(Some(lease)) => format!("OK {}\n", lease.token),
+                        Ok(None) => "(none)\n".to_string(),
+                        Err(e) => error_reply(&e),
+                    },
+                    None => "(error) ttl must be milliseconds above 0\n".to_string(),
+                },
+                ["renew", key, token, ttl_ms] => match (token.parse(), ttl(ttl_ms)) {
+                    (Ok(token), Some(ttl)) => {
+                        let lease = Lease { key: session.key(key), token };
+                        held(lease.renew(database, ttl).await)
+                    }
+                    _ => "(error) invalid token or ttl\n".to_string(),
+                },
+                ["release", key, token] => match token.parse() {
+                    Ok(token) => {
+                        let lease = Lease { key: session.key(key), token };
+                        held(lease.release(database).await)
+                    }
+                    Err(_) => "(error) invalid token\n".to_string(),
+                },
+                _ => "(error) usage: lease acquire <key> <ttl_ms> | lease renew <key> <token> \
+                      <ttl_ms> | lease release <key> <token>\n"
+                    .to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&command @ ("sadd" | "srem")) => {
+            let reply = match args[1..] {
+                [key, ref members @ ..] if !members.is_empty() => {
+                    let key = session.key(key);
+                    let members: Vec<_> = members.iter().map(|m| m.to_string()).collect();
+                    let result = if command == "sadd" {
+                        database.sadd(&key, members).await
+                    } else {
+                        database.srem(&key, &members).await
+                    };
+                    match result {
+                        Ok(n) => format!("{n}\n"),
+                        Err(e) => error_reply(&e),
+                    }
+                }
+                _ => format!("(error) usage: {command} <key> <member>...\n"),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&command @ ("lpush" | "rpush")) => {
+            let reply = match args[1..] {
+                [key, ref items @ ..] if !items.is_empty() => {
+                    let key = session.key(key);
+                    let items: Vec<_> = items.iter().map(|item| item.to_string()).collect();
+                    let result = if command == "lpush" {
+                        database.lpush(&key, items).await
+                    } else {
+                        database.rpush(&key, items).await
+                    };
+                    match result {
+                        Ok(len) => format!("{len}\n").into_bytes(),
+                        Err(e) => error_reply(&e).into_bytes(),
+                    }
+                }
+                _ => format!("(error) usage: {command} <key> <item>...\n").into_bytes(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"lpop") => {
+            let reply = match args[1..] {
+                [key] => match database.lpop(&session.key(key)).await {
+                    Ok(Some(item)) => encode_value(Value::Str(item), session.protocol),
+                    Ok(None) => b"(none)\n".to_vec(),
+                    Err(e) => error_reply(&e).into_bytes(),
+                },
+                _ => b"(error) usage: lpop <key>\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"lrange") => {
+            let reply = match args[1..] {
+                [key, start, stop] => match (start.parse(), stop.parse()) {
+                    (Ok(start), Ok(stop)) => {
+                        match database.lrange(&session.key(key), start, stop).await {
+                            Ok(items) => encode_strings(items.len(), items, session.protocol),
+                            Err(e) => error_reply(&e).into_bytes(),
+                        }
+                    }
+                    _ => b"(error) positions must be integers\n".to_vec(),
+                },
+                _ => b"(error) usage: lrange <key> <start> <stop>\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"hset") => {
+            let reply = match args[1..] {
+                [key, ref pairs @ ..] if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                    let fields = pairs
+                        .chunks(2)
+                        .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                        .collect();
+                    match database.hset(&session.key(key), fields).await {
+                        Ok(added) => format!("{added}\n"),
+                        Err(e) => error_reply(&e),
+                    }
+                }
+                _ => "(error) usage: hset <key> <field> <value> [<field> <value>]...\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"hget") => {
+            let reply = match args[1..] {
+                [key, field] => match database.hget(&session.key(key), field).await {
+                    Ok(Some(value)) => encode_value(Value::Str(value), session.protocol),
+                    Ok(None) => b"(none)\n".to_vec(),
+                    Err(e) => error_reply(&e).into_bytes(),
+                },
+                _ => b"(error) usage: hget <key> <field>\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"hdel") => {
+            let reply = match args[1..] {
+                [key, ref fields @ ..] if !fields.is_empty() => {
+                    let fields: Vec<_> = fields.iter().map(|field| field.to_string()).collect();
+                    match database.hdel(&session.key(key), &fields).await {
+                        Ok(removed) => format!("{removed}\n"),
+                        Err(e) => error_reply(&e),
+                    }
+                }
+                _ => "(error) usage: hdel <key> <field>...\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"hgetall") => {
+            let reply = match args[1..] {
+                [key] => match database.hgetall(&session.key(key)).await {
+                    Ok(fields) => encode_map(fields, session.protocol),
+                    Err(e) => error_reply(&e).into_bytes(),
+                },
+                _ => b"(error) usage: hgetall <key>\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"smembers") => {
+            let reply = match args[1..] {
+                [key] => match database.smembers(&session.key(key)).await {
+                    Ok(members) => encode_strings(members.len(), members, session.protocol),
+                    Err(e) => error_reply(&e).into_bytes(),
+                },
+                _ => b"(error) usage: smembers <key>\n".to_vec(),
+            };
+            output.write_all(&reply).await?;
+            output.flush().await
+        }
+        Some(&"ttl") => {
+            let reply = match args[1..] {
+                [key] => match database.ttl(&session.key(key)).await {
+                    Ok(ttl) => {
+                        let ttl = match ttl {
+                            KeyTtl::NotFound => -2,
+                            KeyTtl::NoExpiry => -1,
+                            KeyTtl::Remaining(remaining) => {
+                                remaining.as_millis().div_ceil(1000) as i64
+                            }
+                        };
+                        format!("{ttl}\n")
+                    }
+                    Err(e) => error_reply(&e),
+                },
+                _ => "(error) usage: ttl <key>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"persist") => {
+            let reply = match args[1..] {
+                [key] => match database.persist(&session.key(key)).await {
+                    Ok(persisted) => format!("{}\n", persisted as u8),
+                    Err(e) => error_reply(&e),
+                },
+                _ => "(error) usage: persist <key>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"delete") => {
+            let [_, key] = args[..] else {
+                output.write_all(b"(error) usage: delete <key>\n").await?;
+                return output.flush().await;
+            };
+            let key = session.key(key);
+            if let Some(batch) = &mut session.transaction {
+                batch.delete(key);
+                output.write_all(b"QUEUED\n").await?;
+                return output.flush().await;
+            }
+            database.delete(key).await
+        }
+        Some(&"deleteif") => {
+            let reply = match args[1..] {
+                [key, _] => {
+                    let expected = match bulk.remove(&2) {
+                        Some(bytes) => bytes_value(bytes),
+                        None => parse_value(args[2]),
+                    };
+                    match database.delete_if(&session.key(key), &expected).await {
+                        Ok(deleted) => format!("{}\n", deleted as u8),
+                        Err(e) => error_reply(&e),
+                    }
+                }
+                _ => "(error) usage: deleteif <key> <expected>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"analyze") => {
+            let every = args.get(1).and_then(|x| x.parse().ok()).unwrap_or(1);
+            let reply = match database.analyze(every).await {
+                Ok(report) => report.to_string(),
+                Err(e) => error_reply(&e),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"verify") => {
+            let bytes_per_sec = args.get(1).and_then(|x| x.parse().ok());
+            let reply = match database.verify_checksums(bytes_per_sec).await {
+                Ok(report) => report.to_string(),
+                Err(e) => error_reply(&e),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"compact") => match args[1..] {
+            ["plan"] => {
+                let reply = match database.compaction_plan().await {
+                    Ok(plan) => plan.to_string(),
+                    Err(e) => error_reply(&e),
+                };
+                output.write_all(reply.as_bytes()).await?;
+                output.flush().await
+            }
+            [] => {
+                if let Err(e) = database.compact().await {
+                    output.write_all(error_reply(&e).as_bytes()).await?;
+                    output.flush().await?;
+                }
+                Ok(())
+            }
+            ref numbers => {
+                let Ok(numbers) = numbers
+                    .iter()
+                    .map(|number| number.parse::<u64>())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                else {
+                    output.write_all(b"(error) usage: compact [plan | <table>...]\n").await?;
+                    return output.flush().await;
+                };
+                if let Err(e) = database.compact_tables(&numbers).await {
+                    output.write_all(error_reply(&e).as_bytes()).await?;
+                    output.flush().await?;
+                }
+                Ok(())
+            }
+        },
+        Some(&"flush") => {
+            if let Err(e) = database.flush().await {
+                output.write_all(error_reply(&e).as_bytes()).await?;
+                output.flush().await?;
+            }
+            Ok(())
+        }
+        Some(&"dump") => database.dump().await,
+        Some(&"stats") => {
+            let stats = database.stats().await;
+            output.write_all(stats.to_string().as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"manifest") => {
+            let reply = match args[1..] {
+                ["rewrite"] => match database.rewrite_manifest().await {
+                    Ok(rewrite) => rewrite.to_string(),
+                    Err(e) => error_reply(&e),
+                },
+                _ => "(error) usage: manifest rewrite\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"retention") => {
+            let rules = match args[1..] {
+                [] => Ok(database.retention().await),
+                ["reload"] => database.reload_retention().await,
+                _ => {
+                    output.write_all(b"(error) usage: retention [reload]\n").await?;
+                    return output.flush().await;
+                }
+            };
+            let reply = match rules {
+                Ok(rules) if rules.is_empty() => "(none)\n".to_string(),
+                Ok(rules) => rules
+                    .iter()
+                    .map(|rule| format!("{:?} {}\n", rule.prefix, rule.max_age_ms))
+                    .collect(),
+                Err(e) => error_reply(&e),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"config") => {
+            let reply = match args[1..] {
+                ["set", name, value] => match database.set_option(name, value).await {
+                    Ok(()) => "OK\n".to_string(),
+                    Err(e) => error_reply(&e),
+                },
+                ["get", name] => match database.option(name) {
+                    Some(value) => format!("{value}\n"),
+                    None => format!("(error) unknown option {name}\n"),
+                },
+                ["get"] => OPTION_NAMES
+                    .iter()
+                    .map(|name| format!("{name}: {}\n", database.option(name).unwrap_or_default()))
+                    .collect(),
+                _ => "(error) usage: config get [name] | config set <name> <value>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"drain") => {
+            let started = Instant::now();
+            session.clients.drain(session.client.as_deref()).await;
+            log::info!("Connections drained, flushing the memtables.");
+            let mut flushed = Ok(());
+            for database in session.databases.by_name.values() {
+                flushed = flushed.and(database.flush().await);
+            }
+            let reply = match flushed {
+                Ok(()) => format!("OK drained in {:?}, safe to stop\n", started.elapsed()),
+                Err(e) => format!("(error) drained, but the flush failed: {e}\n"),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"client") => {
+            let reply = match args[1..] {
+                ["list"] => session.clients.list(),
+                ["kill", addr] => match addr.parse() {
+                    Ok(addr) if session.clients.kill(&addr) => "OK\n".to_string(),
+                    Ok(_) => format!("(error) no client connected from {addr}\n"),
+                    Err(_) => format!("(error) invalid address {addr}\n"),
+                },
+                _ => "(error) usage: client list | client kill <addr>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"backup") => match args[1..] {
+            ["verify", dir] => {
+                let reply = match crate::verify_backup(dir.as_ref()).await {
+                    Ok(report) if report.is_healthy() => format!("{report}OK\n"),
+                    Ok(report) => format!("{report}(error) backup {dir} is not usable\n"),
+                    Err(e) => error_reply(&e),
+                };
+                output.write_all(reply.as_bytes()).await?;
+                output.flush().await
+            }
+            [dir] => {
+                let reply = match database.backup(dir.as_ref()).await {
+                    Ok(()) => "OK\n".to_string(),
+                    Err(e) => error_reply(&e),
+                };
+                output.write_all(reply.as_bytes()).await?;
+                output.flush().await
+            }
+            _ => {
+                output.write_all(b"(error) usage: backup <dir> | backup verify <dir>\n").await?;
+                output.flush().await
+            }
+        },
+        Some(&"ingest") => {
+            let reply = match args.get(1) {
+                Some(path) => match database.ingest_sstable(path.as_ref()).await {
+                    Ok(records) => format!("OK {records} records\n"),
+                    Err(e) => error_reply(&e),
+                },
+                None => "(error) usage: ingest <path.db>\n".to_string(),
+            };
+            output.write_all(reply.as_bytes()).await?;
+            output.flush().await
+        }
+        Some(&"words") => load_words_into_db(database).await,
+        Some(name) => match session.commands.get(*name).cloned() {
+            Some(handler) => {
+                let reply = match handler.call(&args[1..], database).await {
+                    Ok(reply) => reply,
+                    Err(e) => error_reply(&e).into_bytes(),
+                };
+                output.write_all(&reply).await?;
+                output.flush().await
+            }
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+/// Formats the reply to the failed request `e`, giving the request ID that
+/// the server's logs about it carry.
+fn error_reply(e: &Error) -> String {
+    match request::current() {
+        Some(request_id) => {
+            log::debug!(request_id, error:% = e; "Request {request_id} failed: {e}");
+            format!("(error) {e} (request {request_id})\n")
+        }
+        None => format!("(error) {e}\n"),
+    }
+}
+
+/// Formats a value the way `parse_value` reads it back, ending with a
+/// newline, for a connection speaking `protocol`. Values that can't be
+/// written as a line (bytes, strings spanning several lines or starting with
+/// `$` or `*`, or from version 2 on `~`, which starts compressed frames) are
+/// written as a bulk string: `$<len>\r\n<bytes>\n`. Sets and lists are
+/// written as `*<len>\r\n` followed by their members, maps as their fields
+/// each followed by its value.
+fn encode_value(value: Value, protocol: u32) -> Vec<u8> {
+    let text = match value {
+        Value::Str(s) => s,
+        Value::Int64(i) => format!("i:{}", i),
+        Value::Float64(f) => format!("f:{}", f),
+        Value::Bytes(bytes) => return bulk_string(&bytes),
+        Value::Set(members) => return encode_strings(members.len(), members, protocol),
+        Value::List(items) => return encode_strings(items.len(), items, protocol),
+        Value::Map(fields) => return encode_map(fields, protocol),
+    };
+    if text.contains(['\r', '\n'])
+        || text.starts_with(['$', '*'])
+        || (protocol >= 2 && text.starts_with('~'))
+    {
+        return bulk_string(text.as_bytes());
+    }
+    let mut reply = text.into_bytes();
+    reply.push(b'\n');
+    reply
+}
+
+fn encode_strings(
+    count: usize,
+    strings: impl IntoIterator<Item = String>,
+    protocol: u32,
+) -> Vec<u8> {
+    let mut reply = format!("*{count}\r\n").into_bytes();
+    for s in strings {
+        reply.extend(encode_value(Value::Str(s), protocol));
+    }
+    reply
+}
+
+fn encode_map(fields: BTreeMap<String, String>, protocol: u32) -> Vec<u8> {
+    let count = fields.len() * 2;
+    let strings = fields.into_iter().flat_map(|(field, value)| [field, value]);
+    encode_strings(count, strings, protocol)
+}
+
+/// Compresses a reply into `~<len>\r\n<bytes>\n` frames, each holding up to
+/// [`COMPRESSED_FRAME_BYTES`] of it compressed by a [`Dictionary`] without
+/// dictionary bytes; the reply is the concatenation of the decompressed
+/// frames. Returns `None` if a part of the reply doesn't get shorter.
+fn compress_reply(reply: &[u8]) -> Option<Vec<u8>> {
+    let compressor = Dictionary::new(0, Vec::new());
+    let mut framed = Vec::new();
+    for part in reply.chunks(COMPRESSED_FRAME_BYTES) {
+        let compressed = compressor.compress(part)?;
+        framed.extend(format!("~{}\r\n", compressed.len()).as_bytes());
+        framed.extend(compressed);
+        framed.push(b'\n');
+    }
+    Some(framed)
+}
+
+fn bulk_string(bytes: &[u8]) -> Vec<u8> {
+    let mut reply = format!("${}\r\n", bytes.len()).into_bytes();
+    reply.extend(bytes);
+    reply.push(b'\n');
+    reply
+}
+
+/// Returns the value sent as a bulk string: a string if it is valid UTF-8.
+fn bytes_value(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(s) => Value::Str(s),
+        Err(e) => Value::Bytes(e.into_bytes()),
+    }
+}
+
+/// Parses the optional `EX <seconds>` / `PX <millis>` arguments of `set`.
+fn parse_expiry(args: &[&str]) -> std::result::Result<Option<Duration>, &'static str> {
+    match args {
+        [] => Ok(None),
+        [unit, amount] => {
+            let amount = amount
+                .parse::<u64>()
+                .ok()
+                .filter(|amount| *amount > 0)
+                .ok_or("invalid expire time")?;
+            match unit.to_ascii_lowercase().as_str() {
+                "ex" => Ok(Some(Duration::from_secs(amount))),
+                "px" => Ok(Some(Duration::from_millis(amount))),
+                _ => Err("syntax error"),
+            }
+        }
+        _ => Err("syntax error"),
+    }
+}
+
+fn parse_value(input: &str) -> Value {
+    if let Some(rest) = input.strip_prefix("i:") {
+        if let Ok(num) = rest.parse::<i64>() {
+            return Value::Int64(num);
+        }
+    } else if let Some(rest) = input.strip_prefix("f:")
+        && let Ok(num) = rest.parse::<f64>()
+    {
+        return Value::Float64(num);
+    }
+    Value::Str(input.to_string())
+}
+
+async fn load_words_into_db(database: &Controller) -> Result<()> {
+    let content = tokio::fs::read_to_string("words.txt").await?;
+    let words: BTreeSet<_> = content.lines().collect();
+    let entries = words.into_iter().map(|word| {
+        let reversed = Value::Str(word.chars().rev().collect());
+        (word.to_string(), reversed)
+    });
+    let loaded = database.bulk_load(futures::stream::iter(entries)).await?;
+    log::info!("Loaded {loaded} words.");
+
+    Ok(())
+}
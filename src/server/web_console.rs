@@ -0,0 +1,477 @@
+//! Read-only pages served by the HTTP listener: `/console`, showing the stats
+//! and tables of a database and looking up or scanning its keys, and
+//! `/scan`, streaming a range as CSV or newline-delimited JSON for tools to
+//! read. Nothing here writes; it is meant for debugging and analysis
+//! without a client at hand.
+
+use std::fmt::Write;
+
+use futures::StreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt, Result};
+
+use crate::{Controller, Value};
+
+use super::{DEFAULT_DATABASE, Databases};
+
+/// Entries listed by a scan when the form doesn't say.
+const DEFAULT_SCAN_LIMIT: usize = 20;
+/// Most entries a scan lists, whatever the form says.
+const MAX_SCAN_LIMIT: usize = 1000;
+/// Entries sent at a time by `/scan`, as one chunk.
+const STREAM_PAGE_ENTRIES: usize = 1000;
+
+/// Answers `request_line` if it is for one of these pages, returning
+/// whether it was. `headers` are the request headers, `databases` unset
+/// until the server starts serving.
+pub(super) async fn serve<W: AsyncWrite + Unpin>(
+    request_line: &str,
+    headers: &[String],
+    databases: Option<&Databases>,
+    output: &mut W,
+) -> Result<bool> {
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(false);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/console" && path != "/scan" {
+        return Ok(false);
+    }
+    let reply = |status, body| response(status, "text/plain", body);
+    if method != "GET" {
+        output.write_all(&reply("405 Method Not Allowed", "read-only\n")).await?;
+        return Ok(true);
+    }
+    let Some(databases) = databases else {
+        output.write_all(&reply("503 Service Unavailable", "starting\n")).await?;
+        return Ok(true);
+    };
+
+    let params = Params::parse(query);
+    let name = params.get("db").unwrap_or(DEFAULT_DATABASE);
+    let Some(database) = databases.get(name) else {
+        output.write_all(&reply("404 Not Found", "no such database\n")).await?;
+        return Ok(true);
+    };
+    if path == "/scan" {
+        let Some(format) = StreamFormat::negotiate(header(headers, "accept")) else {
+            let body = "supported: text/csv, application/x-ndjson\n";
+            output.write_all(&reply("406 Not Acceptable", body)).await?;
+            return Ok(true);
+        };
+        stream_scan(database, &params, format, output).await?;
+    } else {
+        let page = render(databases, name, database, &params).await;
+        output.write_all(&response("200 OK", "text/html; charset=utf-8", &page)).await?;
+    }
+    Ok(true)
+}
+
+/// Returns the value of the header `name`, which is lowercase.
+fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|header| {
+        let (field, value) = header.split_once(':')?;
+        field.trim().eq_ignore_ascii_case(name).then_some(value.trim())
+    })
+}
+
+/// Representation of the entries streamed by `/scan`, picked from the
+/// `Accept` header of the request.
+#[derive(Clone, Copy)]
+enum StreamFormat {
+    /// A `key,type,value` header line, then one line per entry.
+    Csv,
+    /// One `{"key":...,"type":...,"value":...}` object per line.
+    Ndjson,
+}
+
+impl StreamFormat {
+    /// Picks the first supported media type of `accept`, ignoring quality
+    /// values. Without an `Accept` header, entries are sent as NDJSON.
+    fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let Some(accept) = accept else {
+            return Some(StreamFormat::Ndjson);
+        };
+        accept.split(',').find_map(|media| {
+            let media = media.split(';').next().unwrap_or_default().trim();
+            match media.to_ascii_lowercase().as_str() {
+                "text/csv" | "text/*" => Some(StreamFormat::Csv),
+                "application/x-ndjson" | "application/*" | "*/*" => Some(StreamFormat::Ndjson),
+                _ => None,
+            }
+        })
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Csv => "text/csv; charset=utf-8",
+            StreamFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn write_entry(&self, out: &mut String, key: &str, value: &Value) {
+        let type_name = type_name(value);
+        match self {
+            StreamFormat::Csv => {
+                let _ = writeln!(out, "{},{type_name},{}", csv_field(key), csv_field(&text(value)));
+            }
+            StreamFormat::Ndjson => {
+                let _ = writeln!(
+                    out,
+                    "{{\"key\":{},\"type\":\"{type_name}\",\"value\":{}}}",
+                    json_string(key),
+                    json_value(value)
+                );
+            }
+        }
+    }
+}
+
+/// Streams the entries of the range given by `params` with chunked
+/// encoding, reading them a page at a time so that writes can go on while
+/// the client reads. An error past the headers ends the connection without
+/// the final chunk, which clients report as an incomplete response.
+async fn stream_scan<W: AsyncWrite + Unpin>(
+    database: &Controller,
+    params: &Params,
+    format: StreamFormat,
+    output: &mut W,
+) -> Result<()> {
+    let start = params.get("start").unwrap_or_default();
+    let end = params.get("end").filter(|end| !end.is_empty());
+    let limit = params.get("limit").and_then(|limit| limit.parse().ok());
+    let mut remaining = limit.unwrap_or(usize::MAX);
+    // The end is exclusive, an empty range has no entries.
+    if end.is_some_and(|end| start >= end) {
+        remaining = 0;
+    }
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        format.content_type()
+    );
+    output.write_all(head.as_bytes()).await?;
+    let mut chunk = String::new();
+    if let StreamFormat::Csv = format {
+        chunk.push_str("key,type,value\n");
+    }
+    let pages = database.scan_stream(start, end).await.take(remaining);
+    let mut pages = std::pin::pin!(pages.chunks(STREAM_PAGE_ENTRIES));
+    while let Some(page) = pages.next().await {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                log::warn!(error:% = e; "Scan over HTTP failed: {e}");
+                return output.flush().await;
+            }
+        };
+        for (key, value) in &page {
+            format.write_entry(&mut chunk, key, value);
+        }
+        write_chunk(output, chunk.as_bytes()).await?;
+        chunk.clear();
+    }
+    write_chunk(output, chunk.as_bytes()).await?;
+    output.write_all(b"0\r\n\r\n").await?;
+    output.flush().await
+}
+
+async fn write_chunk<W: AsyncWrite + Unpin>(output: &mut W, chunk: &[u8]) -> Result<()> {
+    // An empty chunk would end the response.
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    output.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+    output.write_all(chunk).await?;
+    output.write_all(b"\r\n").await
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes()
+}
+
+async fn render(
+    databases: &Databases,
+    name: &str,
+    database: &Controller,
+    params: &Params,
+) -> String {
+    let mut page = String::new();
+    let db = escape(name);
+    let _ = write!(
+        page,
+        "<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>logdb: {db}</title>\
+         <style>body{{font-family:monospace}}td,th{{padding:0 1em;text-align:left}}</style>\
+         </head><body>\n<h1>{db}</h1>\n<p>"
+    );
+    for other in databases.by_name.keys() {
+        let _ = write!(page, "<a href=\"/console?db={}\">{}</a> ", encode(other), escape(other));
+    }
+    page.push_str("</p>\n");
+
+    let stats = database.stats().await.to_string();
+    let _ = write!(page, "<h2>Stats</h2>\n<pre>{}</pre>\n", escape(&stats));
+
+    page.push_str("<h2>Tables</h2>\n");
+    match database.tables().await {
+        Ok(tables) => {
+            page.push_str(
+                "<table><tr><th>file</th><th>generation</th><th>compacted</th>\
+                 <th>age_ms</th><th>data_bytes</th><th>index_bytes</th></tr>\n",
+            );
+            for table in tables {
+                let _ = writeln!(
+                    page,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    escape(&table.data_path),
+                    table.generation,
+                    table.compacted,
+                    table.age_ms,
+                    table.data_bytes,
+                    table.index_bytes,
+                );
+            }
+            page.push_str("</table>\n");
+        }
+        Err(e) => {
+            let _ = writeln!(page, "<p>error: {}</p>", escape(&e.to_string()));
+        }
+    }
+
+    let key = params.get("key").unwrap_or_default();
+    let _ = write!(
+        page,
+        "<h2>Get</h2>\n<form action=\"/console\">\
+         <input type=\"hidden\" name=\"db\" value=\"{db}\">\
+         <input name=\"key\" value=\"{}\"> <button>get</button></form>\n",
+        escape(key)
+    );
+    if !key.is_empty() {
+        let result = match database.get(key).await {
+            Ok(Some(value)) => describe(&value),
+            Ok(None) => "(nil)".to_string(),
+            Err(e) => format!("error: {e}"),
+        };
+        let _ = writeln!(page, "<pre>{}</pre>", escape(&result));
+    }
+
+    let (start, end) = (params.get("start"), params.get("end").filter(|end| !end.is_empty()));
+    let limit = params
+        .get("limit")
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_SCAN_LIMIT)
+        .min(MAX_SCAN_LIMIT);
+    let _ = write!(
+        page,
+        "<h2>Scan</h2>\n<form action=\"/console\">\
+         <input type=\"hidden\" name=\"db\" value=\"{db}\">\
+         start <input name=\"start\" value=\"{}\"> end <input name=\"end\" value=\"{}\"> \
+         limit <input name=\"limit\" value=\"{limit}\" size=\"5\"> <button>scan</button></form>\n",
+        escape(start.unwrap_or_default()),
+        escape(end.unwrap_or_default()),
+    );
+    // The end is exclusive, an empty range lists nothing.
+    if let Some(start) = start
+        && end.is_none_or(|end| start < end)
+    {
+        match database.scan(start, end, limit).await {
+            Ok(entries) => {
+                page.push_str("<table><tr><th>key</th><th>value</th></tr>\n");
+                for (key, value) in &entries {
+                    let _ = writeln!(
+                        page,
+                        "<tr><td>{}</td><td>{}</td></tr>",
+                        escape(key),
+                        escape(&describe(value))
+                    );
+                }
+                let _ = writeln!(page, "</table>\n<p>{} entries</p>", entries.len());
+            }
+            Err(e) => {
+                let _ = writeln!(page, "<p>error: {}</p>", escape(&e.to_string()));
+            }
+        }
+    }
+    page.push_str("</body></html>\n");
+    page
+}
+
+/// Shows `value` the way the client protocol does, collections aside.
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Str(s) => s.clone(),
+        Value::Int64(i) => format!("i:{i}"),
+        Value::Float64(f) => format!("f:{f}"),
+        Value::Bytes(bytes) => {
+            format!("({} bytes) {}", bytes.len(), String::from_utf8_lossy(bytes))
+        }
+        Value::Set(members) => format!("{members:?}"),
+        Value::List(items) => format!("{items:?}"),
+        Value::Map(fields) => format!("{fields:?}"),
+    }
+}
+
+/// Type of `value`, named as in `KeyspaceReport`.
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Str(_) => "str",
+        Value::Int64(_) => "int64",
+        Value::Float64(_) => "float64",
+        Value::Bytes(_) => "bytes",
+        Value::Set(_) => "set",
+        Value::List(_) => "list",
+        Value::Map(_) => "map",
+    }
+}
+
+/// Shows `value` as text, without a type prefix: bytes in hex and
+/// collections as JSON.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Str(s) => s.clone(),
+        Value::Int64(i) => i.to_string(),
+        Value::Float64(f) => f.to_string(),
+        Value::Bytes(bytes) => bytes.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        }),
+        value => json_value(value),
+    }
+}
+
+/// Encodes `value` as JSON: numbers as numbers (non-finite floats as
+/// strings), bytes as a hex string, sets and lists as arrays and maps as
+/// objects.
+fn json_value(value: &Value) -> String {
+    let array = |items: &mut dyn Iterator<Item = &String>| {
+        let items: Vec<_> = items.map(|item| json_string(item)).collect();
+        format!("[{}]", items.join(","))
+    };
+    match value {
+        Value::Str(s) => json_string(s),
+        Value::Int64(i) => i.to_string(),
+        Value::Float64(f) if f.is_finite() => f.to_string(),
+        Value::Float64(f) => json_string(&f.to_string()),
+        Value::Bytes(_) => json_string(&text(value)),
+        Value::Set(members) => array(&mut members.iter()),
+        Value::List(items) => array(&mut items.iter()),
+        Value::Map(fields) => {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(field, value)| format!("{}:{}", json_string(field), json_string(value)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quotes `field` for CSV if it contains a separator, a quote or a line
+/// break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes `text` for use in a query string.
+fn encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
+
+/// Form fields of a query string.
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn parse(query: &str) -> Self {
+        let fields = query
+            .split('&')
+            .filter(|field| !field.is_empty())
+            .map(|field| {
+                let (name, value) = field.split_once('=').unwrap_or((field, ""));
+                (decode(name), decode(value))
+            })
+            .collect();
+        Self(fields)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(field, _)| field == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Decodes a form field, in which spaces are sent as `+`. Malformed escapes
+/// are kept as they are.
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        match (bytes[i], escape) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(hex)) => {
+                let hex = std::str::from_utf8(hex).expect("hex digits are ASCII");
+                decoded.push(u8::from_str_radix(hex, 16).expect("checked hex digits"));
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
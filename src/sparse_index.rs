@@ -1,19 +1,23 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt};
 
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, Result};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind, Result};
 
 pub type SparseIndex = BTreeMap<String, u64>;
 
-#[derive(Debug)]
+/// Where the record of a key is in a data file, if the table holds it.
+/// Offsets are always within the records of the file: indexes are checked
+/// against the length of the records when they are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScanRange {
+    /// The key is indexed; its record starts at `offset`.
     Exact { offset: u64 },
-    FromBegin { end: u64 },
+    /// The record is somewhere in `start..end`, starting at a restart point.
     Range { start: u64, end: u64 },
-    ToEnd { start: u64 },
 }
 
-/// Inspects a sparse index for a key.
-pub fn bounds(index: &SparseIndex, key: &str) -> ScanRange {
+/// Inspects a sparse index for a key, in a data file whose records take
+/// `data_len` bytes.
+pub fn bounds(index: &SparseIndex, key: &str, data_len: u64) -> ScanRange {
     let upper = index.range(key.to_string()..).next();
     let lower = index.range(..=key.to_string()).next_back();
 
@@ -23,60 +27,191 @@ pub fn bounds(index: &SparseIndex, key: &str) -> ScanRange {
                 offset: lower_offset,
             }
         }
-        (Some((_, &lower_offset)), Some((_, &upper_offset))) => ScanRange::Range {
-            start: lower_offset,
-            end: upper_offset,
+        (lower, upper) => ScanRange::Range {
+            start: lower.map_or(0, |(_, &offset)| offset),
+            end: upper.map_or(data_len, |(_, &offset)| offset),
         },
-        (Some((_, &lower_offset)), None) => ScanRange::ToEnd {
-            start: lower_offset,
-        },
-        (None, Some((_, &upper_offset))) => ScanRange::FromBegin { end: upper_offset },
-        _ => panic!("Illegal state: no `upper` nor `lower` bound found."),
     }
 }
 
-/// Writes a sparse index to the given writer.
+/// Length of the trailer ending index files:
+/// `[data_len (u64)][entries (u64)][crc32 (u32)][magic]`, `data_len` being
+/// the length of the records of the data file and the checksum covering
+/// everything before it. Files written before it was added end with the
+/// last entry.
+const TRAILER_LEN: usize = 8 + 8 + 4 + 8;
+const MAGIC: &[u8; 8] = b"LOGDBIDX";
+
+/// Why an index file can't be read. Returned as the inner error of an
+/// [`ErrorKind::InvalidData`] error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexCorruption {
+    /// The entry starting at `offset` is cut short.
+    Truncated { offset: u64 },
+    /// The key of the entry at `offset` isn't valid UTF-8.
+    InvalidKey { offset: u64 },
+    /// The entry at `offset` doesn't sort after the previous one.
+    OutOfOrder { offset: u64 },
+    /// The entries don't match the checksum of the trailer.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The trailer counts a different number of entries.
+    CountMismatch { expected: u64, actual: u64 },
+    /// The index was written for a data file whose records take `expected`
+    /// bytes, rather than `actual`.
+    LengthMismatch { expected: u64, actual: u64 },
+    /// The entry at `offset` points past the records of the data file.
+    PastEnd { offset: u64 },
+}
+
+impl fmt::Display for IndexCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { offset } => write!(f, "entry at byte {offset} is truncated"),
+            Self::InvalidKey { offset } => write!(f, "key at byte {offset} is not UTF-8"),
+            Self::OutOfOrder { offset } => write!(f, "entry at byte {offset} is out of order"),
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum {actual:08x} doesn't match {expected:08x}")
+            }
+            Self::CountMismatch { expected, actual } => {
+                write!(f, "{actual} entries instead of {expected}")
+            }
+            Self::LengthMismatch { expected, actual } => {
+                write!(f, "indexes {expected} bytes of records, the data file has {actual}")
+            }
+            Self::PastEnd { offset } => {
+                write!(f, "entry at byte {offset} points past the records")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IndexCorruption {}
+
+impl From<IndexCorruption> for Error {
+    fn from(corruption: IndexCorruption) -> Self {
+        Error::new(ErrorKind::InvalidData, corruption)
+    }
+}
+
+/// Returns the corruption `error` reports, if it was returned by [`read_from`]
+/// for a corrupt index.
+pub fn corruption(error: &Error) -> Option<&IndexCorruption> {
+    error.get_ref()?.downcast_ref()
+}
+
+/// Returns the stride at which records of `record_bytes` on average are
+/// about `scan_bytes` apart, or `stride` if either is unknown (`0`).
+pub fn adaptive_stride(stride: usize, scan_bytes: u64, record_bytes: u64) -> usize {
+    if scan_bytes == 0 || record_bytes == 0 {
+        return stride;
+    }
+    usize::try_from(scan_bytes / record_bytes).unwrap_or(usize::MAX).max(1)
+}
+
+/// Writes the sparse index of a data file whose records take `data_len`
+/// bytes to the given writer, followed by a trailer with `data_len`, the
+/// number of entries and their checksum.
 /// Each entry: [key_len (u16)][key bytes][offset (u64)]
-pub async fn write_to<W>(index: &SparseIndex, writer: &mut W) -> Result<()>
+pub async fn write_to<W>(index: &SparseIndex, data_len: u64, writer: &mut W) -> Result<()>
 where
     W: AsyncWrite + Unpin,
 {
+    let mut entries = Vec::new();
     for (key, &offset) in index {
         let key_bytes = key.as_bytes();
         let key_len = key_bytes.len() as u16;
 
-        writer.write_all(&key_len.to_be_bytes()).await?;
-        writer.write_all(key_bytes).await?;
-        writer.write_all(&offset.to_be_bytes()).await?;
+        entries.extend_from_slice(&key_len.to_be_bytes());
+        entries.extend_from_slice(key_bytes);
+        entries.extend_from_slice(&offset.to_be_bytes());
     }
+    entries.extend_from_slice(&data_len.to_be_bytes());
+    entries.extend_from_slice(&(index.len() as u64).to_be_bytes());
+    writer.write_all(&entries).await?;
+    writer.write_all(&crc32fast::hash(&entries).to_be_bytes()).await?;
+    writer.write_all(MAGIC).await?;
     writer.flush().await?;
     Ok(())
 }
 
-/// Reads a sparse index from the given reader.
+/// Reads the sparse index of a data file whose records take `data_len`
+/// bytes from the given reader, checking it against its trailer if it has
+/// one. Fails with an [`IndexCorruption`] if an entry is cut short, has a
+/// key that isn't UTF-8, is out of order or points past `data_len`, or the
+/// trailer doesn't match the entries or `data_len`.
 /// Each entry: [key_len (u16)][key bytes][offset (u64)]
-pub async fn read_from<R>(mut reader: R) -> Result<SparseIndex>
+pub async fn read_from<R>(mut reader: R, data_len: u64) -> Result<SparseIndex>
 where
     R: AsyncReadExt + Unpin,
 {
-    let mut index = BTreeMap::new();
-    let mut len_buf = [0u8; 2];
-    let mut offset_buf = [0u8; 8];
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents).await?;
+
+    let mut expected_count = None;
+    let mut entries = &contents[..];
+    if let Some(split) = contents.len().checked_sub(TRAILER_LEN)
+        && &contents[split + 20..] == MAGIC
+    {
+        let trailer = &contents[split..];
+        let expected = u32::from_be_bytes(trailer[16..20].try_into().unwrap());
+        let actual = crc32fast::hash(&contents[..split + 16]);
+        if actual != expected {
+            return Err(IndexCorruption::ChecksumMismatch { expected, actual }.into());
+        }
+        let indexed_len = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+        if indexed_len != data_len {
+            return Err(IndexCorruption::LengthMismatch {
+                expected: indexed_len,
+                actual: data_len,
+            }
+            .into());
+        }
+        expected_count = Some(u64::from_be_bytes(trailer[8..16].try_into().unwrap()));
+        entries = &contents[..split];
+    }
 
-    loop {
-        if reader.read_exact(&mut len_buf).await.is_err() {
-            break;
+    let index = parse_entries(entries, data_len)?;
+    if let Some(expected) = expected_count
+        && expected != index.len() as u64
+    {
+        return Err(IndexCorruption::CountMismatch {
+            expected,
+            actual: index.len() as u64,
         }
-        let key_len = u16::from_be_bytes(len_buf) as usize;
+        .into());
+    }
+    Ok(index)
+}
 
-        let mut key_buf = vec![0u8; key_len];
-        reader.read_exact(&mut key_buf).await?;
+fn parse_entries(
+    mut entries: &[u8],
+    data_len: u64,
+) -> std::result::Result<SparseIndex, IndexCorruption> {
+    let mut index = BTreeMap::new();
+    let mut position = 0;
+    let mut last_key: Option<String> = None;
 
-        reader.read_exact(&mut offset_buf).await?;
-        let offset = u64::from_be_bytes(offset_buf);
+    while !entries.is_empty() {
+        let offset = position as u64;
+        let truncated = IndexCorruption::Truncated { offset };
+        let (len_buf, rest) = entries.split_first_chunk::<2>().ok_or(truncated.clone())?;
+        let key_len = u16::from_be_bytes(*len_buf) as usize;
+        let (key_buf, rest) = rest.split_at_checked(key_len).ok_or(truncated.clone())?;
+        let (offset_buf, rest) = rest.split_first_chunk::<8>().ok_or(truncated)?;
 
-        let key = String::from_utf8(key_buf).expect("Invalid UTF-8 in key");
-        index.insert(key, offset);
+        let key = String::from_utf8(key_buf.to_vec())
+            .map_err(|_| IndexCorruption::InvalidKey { offset })?;
+        if last_key.as_ref().is_some_and(|last| *last >= key) {
+            return Err(IndexCorruption::OutOfOrder { offset });
+        }
+        let record_offset = u64::from_be_bytes(*offset_buf);
+        if record_offset >= data_len {
+            return Err(IndexCorruption::PastEnd { offset });
+        }
+        index.insert(key.clone(), record_offset);
+        last_key = Some(key);
+        position += 2 + key_len + 8;
+        entries = rest;
     }
 
     Ok(index)
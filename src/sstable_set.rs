@@ -1,33 +1,191 @@
-use std::io::SeekFrom;
-use std::path::Path;
+use std::io::{Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+
+use tokio::fs::File;
+use tokio::sync::OnceCell;
 use tokio::io::{
-    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader, Error, ErrorKind, Result,
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader, Error, ErrorKind, Result, Take,
 };
 
-use crate::record::{MemValue, Record};
+use crate::collation::Collation;
+use crate::layout::Layout;
+use crate::dictionary::Dictionary;
+use crate::format::{self, Format, TableLayout};
+use crate::record::{self, FrameCheck, MemValue, Record};
+use crate::repair;
 use crate::sparse_index::ScanRange;
 use crate::version;
 use crate::{
     Manifest,
+    manifest::SSTableEntry,
     sparse_index::{self, SparseIndex},
 };
 
+/// Bytes a scan with read-ahead reads past its last seek before it is taken
+/// to be reading sequentially.
+const SEQUENTIAL_SCAN_BYTES: u64 = 64 * 1024;
+
+/// Largest range of records a lookup reads at once rather than record by
+/// record.
+const LOOKUP_WINDOW_BYTES: u64 = 1024 * 1024;
+
+/// Directory of the data directory corrupt tables are moved to.
+pub const QUARANTINE_DIR: &str = "corrupt";
+
+/// What opening the database does with a table that can't be loaded, see
+/// `Config::corrupt_tables`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorruptTablePolicy {
+    /// Fail to open.
+    #[default]
+    Fail,
+    /// Leave the table out of reads but keep it in the MANIFEST, so that it
+    /// is loaded again once repaired.
+    Skip,
+    /// Move the table's files to the `corrupt/` directory and drop it from
+    /// the MANIFEST.
+    Quarantine,
+}
+
+impl FromStr for CorruptTablePolicy {
+    type Err = ();
+
+    fn from_str(name: &str) -> std::result::Result<Self, ()> {
+        match name {
+            "fail" => Ok(CorruptTablePolicy::Fail),
+            "skip" => Ok(CorruptTablePolicy::Skip),
+            "quarantine" => Ok(CorruptTablePolicy::Quarantine),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SSTable {
-    pub index: SparseIndex,
     pub index_path: String,
     pub data_path: String,
+    /// Recency of the table, see `SSTableEntry::generation`.
+    pub generation: usize,
+    /// Written by the last compaction, see `SSTableEntry::compacted`.
+    pub compacted: bool,
+    /// Time the table was written at, see `SSTableEntry::created_at`.
+    pub created_at: u64,
+    /// Records per index entry, see `SSTableEntry::stride`.
+    pub stride: Option<usize>,
+    contents: OnceCell<TableContents>,
+    /// Where `contents` is read from on first use, for tables opened without
+    /// `Config::load_indexes_eagerly`.
+    loader: Option<Arc<ContentsLoader>>,
+}
+
+/// What reading a table takes besides its data file.
+#[derive(Debug)]
+pub struct TableContents {
+    pub index: SparseIndex,
+    pub layout: TableLayout,
+}
+
+#[derive(Debug)]
+struct ContentsLoader {
+    data_dir: PathBuf,
+    dictionary: Option<Arc<Dictionary>>,
+    sparse_stride: usize,
+}
+
+impl SSTable {
+    pub fn new(
+        data_path: String,
+        index_path: String,
+        index: SparseIndex,
+        layout: TableLayout,
+        generation: usize,
+        compacted: bool,
+        created_at: u64,
+    ) -> Self {
+        Self {
+            index_path,
+            data_path,
+            generation,
+            compacted,
+            created_at,
+            stride: None,
+            contents: OnceCell::new_with(Some(TableContents { index, layout })),
+            loader: None,
+        }
+    }
+
+    /// Records that the table's index has an entry every `stride` records.
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        self.stride = Some(stride);
+        self
+    }
+
+    /// Returns the index and layout of the table, reading them first if the
+    /// table was opened lazily.
+    pub async fn contents(&self) -> Result<&TableContents> {
+        self.contents
+            .get_or_try_init(|| async {
+                let loader = self.loader.as_ref().expect("unloaded tables have a loader");
+                load_contents(
+                    &loader.data_dir,
+                    Path::new(&self.data_path),
+                    Path::new(&self.index_path),
+                    loader.dictionary.clone(),
+                    loader.sparse_stride,
+                )
+                .await
+            })
+            .await
+    }
+
+    pub async fn index(&self) -> Result<&SparseIndex> {
+        Ok(&self.contents().await?.index)
+    }
+
+    pub async fn layout(&self) -> Result<TableLayout> {
+        Ok(self.contents().await?.layout)
+    }
 }
 
 #[derive(Debug)]
 pub struct SSTableSet {
+    /// Highest file number allocated, see `Manifest::last_sequence`.
     pub last_sequence: usize,
+    /// Sequence number of the most recent write.
+    pub last_record_seq: u64,
+    /// Tables ordered newest first, i.e. by decreasing generation.
     pub tables: Vec<SSTable>,
+    /// Dictionary the compressed values of every table were compressed with.
+    pub dictionary: Option<Arc<Dictionary>>,
+    /// Collation the keys of every table are stored with.
+    pub collation: Collation,
+    /// Newest format tables were written in, see `Manifest::table_format`.
+    pub table_format: Option<u32>,
+    /// Layout new tables are written in.
+    pub layout: Layout,
+    /// Tables that failed to load under [`CorruptTablePolicy::Skip`]. They
+    /// aren't read but stay in the MANIFEST.
+    pub skipped: Vec<SSTableEntry>,
 }
 
 impl SSTableSet {
-    pub async fn build(manifest: &Manifest, data_dir: Option<&Path>) -> Result<SSTableSet> {
+    /// Loads the tables of `manifest`, handling those that fail to load as
+    /// `policy` says. Missing or empty indexes are rebuilt from their data
+    /// file, indexing every `sparse_stride`-th record. Unless `eager`, indexes
+    /// are only read on first use instead, where failing to load a table
+    /// fails the read.
+    pub async fn build(
+        manifest: &Manifest,
+        data_dir: Option<&Path>,
+        policy: CorruptTablePolicy,
+        sparse_stride: usize,
+        eager: bool,
+    ) -> Result<SSTableSet> {
         let data_dir = data_dir.unwrap_or(Path::new("."));
         if manifest.version != version::VERSION {
             panic!(
@@ -37,148 +195,463 @@ impl SSTableSet {
             );
         }
 
+        let dictionary = match &manifest.dictionary {
+            Some(path) => {
+                log::info!("Loading dictionary from: {}...", data_dir.join(path).display());
+                Some(Arc::new(Dictionary::load(&data_dir.join(path)).await?))
+            }
+            None => None,
+        };
+
+        let count = manifest.sstables.len();
         let indexes: Vec<_> = manifest
             .sstables
             .iter()
-            .map(|entry| {
+            .enumerate()
+            .map(|(i, entry)| {
                 let data_path = entry.data_path.clone();
                 let index_path = entry.index_path.clone();
+                let generation = entry.generation.unwrap_or(count - i);
+                let compacted = entry.compacted;
+                let created_at = entry.created_at;
+                let stride = entry.stride;
+                // A missing index is rebuilt at the stride it was written with.
+                let sparse_stride = stride.unwrap_or(sparse_stride);
+                let dictionary = dictionary.clone();
+
+                let loader = (!eager).then(|| {
+                    Arc::new(ContentsLoader {
+                        data_dir: data_dir.to_path_buf(),
+                        dictionary: dictionary.clone(),
+                        sparse_stride,
+                    })
+                });
 
                 async move {
-                    log::info!(
-                        "Loading sparse index from: {}...",
-                        data_dir.join(&index_path).to_str().unwrap()
-                    );
-                    let reader =
-                        BufReader::new(tokio::fs::File::open(data_dir.join(&index_path)).await?);
-                    let index = sparse_index::read_from(reader).await?;
-                    if index.len() == 0 {
-                        return Err(Error::new(ErrorKind::InvalidData, "Index can't be empty"));
-                    }
-                    log::info!("Done!");
-                    let data_path = data_path.into_os_string().into_string().map_err(|_| {
-                        tokio::io::Error::new(
-                            tokio::io::ErrorKind::InvalidData,
-                            "Non-UTF-8 file path in manifest",
-                        )
-                    })?;
-                    let index_path: String =
-                        index_path.into_os_string().into_string().map_err(|_| {
-                            tokio::io::Error::new(
-                                tokio::io::ErrorKind::InvalidData,
-                                "Non-UTF-8 file path in manifest",
-                            )
-                        })?;
+                    let contents = match loader {
+                        Some(_) => OnceCell::new(),
+                        None => {
+                            let contents = load_contents(
+                                data_dir,
+                                &data_path,
+                                &index_path,
+                                dictionary,
+                                sparse_stride,
+                            );
+                            OnceCell::new_with(Some(contents.await?))
+                        }
+                    };
+                    let created_at = match created_at {
+                        Some(created_at) => created_at,
+                        None => modified_millis(&data_dir.join(&data_path)).await?,
+                    };
+                    let utf8 = |path: PathBuf| {
+                        path.into_os_string().into_string().map_err(|_| {
+                            Error::new(ErrorKind::InvalidData, "Non-UTF-8 file path in manifest")
+                        })
+                    };
                     Ok(SSTable {
-                        index,
-                        data_path,
-                        index_path,
+                        data_path: utf8(data_path)?,
+                        index_path: utf8(index_path)?,
+                        generation,
+                        compacted,
+                        created_at,
+                        stride,
+                        contents,
+                        loader,
                     })
                 }
             })
             .collect();
 
         let results = futures::future::join_all(indexes).await;
-        let tables: Result<Vec<_>> = results.into_iter().collect();
+        let mut tables = Vec::with_capacity(count);
+        let mut skipped = Vec::new();
+        for (entry, result) in manifest.sstables.iter().zip(results) {
+            let e = match result {
+                Ok(table) => {
+                    tables.push(table);
+                    continue;
+                }
+                Err(e) => e,
+            };
+            let table = entry.data_path.display();
+            match policy {
+                CorruptTablePolicy::Fail => return Err(e),
+                CorruptTablePolicy::Skip => {
+                    log::error!("Skipping table {table}, which failed to load: {e}");
+                    skipped.push(entry.clone());
+                }
+                CorruptTablePolicy::Quarantine => {
+                    log::error!("Quarantining table {table}, which failed to load: {e}");
+                    quarantine(data_dir, [&entry.data_path, &entry.index_path]).await?;
+                }
+            }
+        }
+        tables.sort_by_key(|table| std::cmp::Reverse(table.generation));
 
+        // Dictionaries used to be numbered apart from tables.
+        let dictionary_number = dictionary.as_ref().map_or(0, |dictionary| dictionary.id as usize);
         let sstable_set = SSTableSet {
-            last_sequence: manifest.last_sequence,
-            tables: tables?,
+            last_sequence: manifest.last_sequence.max(dictionary_number),
+            last_record_seq: manifest.last_record_seq,
+            tables,
+            dictionary,
+            collation: manifest.collation,
+            table_format: manifest.table_format,
+            layout: manifest.layout.clone(),
+            skipped,
         };
         Ok(sstable_set)
     }
+
+    /// Returns the number of sorted runs: every flushed or ingested table is
+    /// one, and the tables of the last compaction together make one more.
+    pub fn sorted_runs(&self) -> usize {
+        let compacted = self.tables.iter().any(|table| table.compacted);
+        self.flushed_tables() + compacted as usize
+    }
+
+    /// Returns the number of tables not written by the last compaction.
+    pub fn flushed_tables(&self) -> usize {
+        self.tables.iter().filter(|table| !table.compacted).count()
+    }
+
+    /// Returns the age in milliseconds of the oldest table not written by the
+    /// last compaction, or `None` if there is none.
+    pub fn oldest_flushed_age(&self, now: u64) -> Option<u64> {
+        self.tables
+            .iter()
+            .filter(|table| !table.compacted)
+            .map(|table| now.saturating_sub(table.created_at))
+            .max()
+    }
+}
+
+/// Moves the files of a table, its data and index paths relative to
+/// `data_dir`, to the quarantine directory.
+pub async fn quarantine(data_dir: &Path, paths: [&Path; 2]) -> Result<()> {
+    let quarantine_dir = data_dir.join(QUARANTINE_DIR);
+    tokio::fs::create_dir_all(&quarantine_dir).await?;
+    for path in paths {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        match tokio::fs::rename(data_dir.join(path), quarantine_dir.join(name)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the index of a table, rebuilding it if missing or empty, and the
+/// layout of its data file.
+async fn load_contents(
+    data_dir: &Path,
+    data_path: &Path,
+    index_path: &Path,
+    dictionary: Option<Arc<Dictionary>>,
+    sparse_stride: usize,
+) -> Result<TableContents> {
+    log::info!(
+        "Loading sparse index from: {}...",
+        data_dir.join(index_path).display()
+    );
+    let layout = format::read_layout(&mut File::open(data_dir.join(data_path)).await?).await?;
+    let index = match File::open(data_dir.join(index_path)).await {
+        Ok(file) => match sparse_index::read_from(BufReader::new(file), layout.data_len).await {
+            Ok(index) => index,
+            Err(e) => match sparse_index::corruption(&e) {
+                // The index only summarizes the data file, so it can be rebuilt.
+                Some(corruption) => {
+                    log::warn!("Index {} is corrupt: {corruption}.", index_path.display());
+                    SparseIndex::new()
+                }
+                None => return Err(e),
+            },
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => SparseIndex::new(),
+        Err(e) => return Err(e),
+    };
+    let index = if index.is_empty() {
+        log::warn!(
+            "Index {} is missing, empty or corrupt, rebuilding it.",
+            index_path.display()
+        );
+        repair::rebuild_index(
+            &data_dir.join(data_path),
+            &data_dir.join(index_path),
+            dictionary,
+            sparse_stride,
+        )
+        .await?
+    } else {
+        index
+    };
+    if index.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "Index can't be empty"));
+    }
+    log::info!("Done!");
+    Ok(TableContents { index, layout })
+}
+
+/// Returns the modification time of the file at `path` in milliseconds since
+/// the Unix epoch.
+pub(crate) async fn modified_millis(path: &Path) -> Result<u64> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    Ok(modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64))
 }
 
 pub async fn seek_and_read<R>(
     file: &mut R,
     key: &str,
     scan_range: ScanRange,
+    layout: TableLayout,
+    dictionary: Option<&Dictionary>,
 ) -> Result<Option<MemValue>>
 where
     R: AsyncRead + AsyncSeek + Unpin,
 {
     match scan_range {
         ScanRange::Exact { offset } => {
-            let record = read_exact(file, offset).await?;
+            let record = read_exact(file, offset, layout, dictionary).await?;
             if record.key != key {
-                return Err(Error::new(
-                    ErrorKind::Other,
+                return Err(Error::other(format!(
                     "Exact key read doesn't match expected key: read_key={}",
-                ));
+                    record.key
+                )));
             }
             Ok(Some(record.value))
         }
-        ScanRange::FromBegin { end } => scan_file_for_key(file, key, None, Some(end)).await,
-        ScanRange::ToEnd { start } => scan_file_for_key(file, key, Some(start), None).await,
         ScanRange::Range { start, end } => {
-            scan_file_for_key(file, key, Some(start), Some(end)).await
+            scan_file_for_key(file, key, start, end, layout, dictionary).await
+        }
+    }
+}
+
+/// Sequential reader over the records of one data file.
+#[derive(Debug)]
+pub struct TableScan {
+    reader: Take<BufReader<File>>,
+    layout: TableLayout,
+    last_key: String,
+    last_shared: usize,
+    offset: u64,
+    dictionary: Option<Arc<Dictionary>>,
+    readahead: usize,
+    since_seek: u64,
+}
+
+impl TableScan {
+    /// Opens `path`, reading its footer to learn where the records end.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).await?;
+        let layout = format::read_layout(&mut file).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        Ok(Self::from_file(file, layout))
+    }
+
+    /// Opens `path` assuming its records are framed as `layout` says, e.g.
+    /// for a file that lost its footer.
+    pub async fn open_as(path: &Path, layout: TableLayout) -> Result<Self> {
+        Ok(Self::from_file(File::open(path).await?, layout))
+    }
+
+    /// Reads the records of `file`, positioned at its start.
+    fn from_file(file: File, layout: TableLayout) -> Self {
+        Self {
+            reader: BufReader::new(file).take(layout.data_len),
+            layout,
+            last_key: String::new(),
+            last_shared: 0,
+            offset: 0,
+            dictionary: None,
+            readahead: 0,
+            since_seek: 0,
+        }
+    }
+
+    /// Sets the dictionary compressed values are decompressed with.
+    pub fn with_dictionary(mut self, dictionary: Option<Arc<Dictionary>>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Switches to reads of `bytes` at a time, and hints the OS to read ahead,
+    /// once the scan has read [`SEQUENTIAL_SCAN_BYTES`] past its last seek
+    /// (`0` keeps reads small).
+    pub fn with_readahead(mut self, bytes: usize) -> Self {
+        self.readahead = bytes;
+        self
+    }
+
+    pub fn layout(&self) -> TableLayout {
+        self.layout
+    }
+
+    /// Returns the offset just past the last record read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Moves to the record at `offset`, which must be a restart point such as
+    /// an offset from the sparse index.
+    pub async fn seek(&mut self, offset: u64) -> Result<()> {
+        self.reader.get_mut().seek(SeekFrom::Start(offset)).await?;
+        self.reader.set_limit(self.layout.data_len.saturating_sub(offset));
+        self.offset = offset;
+        self.since_seek = 0;
+        self.last_key.clear();
+        self.last_shared = 0;
+        Ok(())
+    }
+
+    /// Returns `true` if the last record read can be decoded without the
+    /// records before it, as required for records the sparse index points at.
+    pub fn at_restart(&self) -> bool {
+        self.last_shared == 0
+    }
+
+    /// Reads the next record, returning `None` once the end of the records is
+    /// reached.
+    pub async fn next(&mut self) -> Result<Option<Record>> {
+        let read = Record::read_framed(
+            &mut self.reader,
+            self.layout.format,
+            &self.last_key,
+            self.dictionary.as_deref(),
+        );
+        match read.await {
+            Ok((record, header)) => {
+                self.offset += header.frame_len();
+                self.since_seek += header.frame_len();
+                self.last_shared = header.shared;
+                self.last_key.clone_from(&record.key);
+                if self.readahead > 0 && self.since_seek >= SEQUENTIAL_SCAN_BYTES {
+                    self.read_ahead().await?;
+                }
+                Ok(Some(record))
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
         }
     }
+
+    /// Replaces the reader with one making reads of `readahead` bytes from the
+    /// current offset, which is a record boundary. Only done once per scan.
+    async fn read_ahead(&mut self) -> Result<()> {
+        // The clone shares the file position, which the old reader's buffer
+        // has moved past the current offset.
+        let mut file = self.reader.get_ref().get_ref().try_clone().await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        crate::disk::advise_sequential(&file, self.offset);
+        let limit = self.layout.data_len.saturating_sub(self.offset);
+        self.reader = BufReader::with_capacity(self.readahead, file).take(limit);
+        self.readahead = 0;
+        Ok(())
+    }
+
+    /// Checks the next record frame; see [`Record::verify_next`].
+    pub async fn verify_next(&mut self) -> Result<Option<FrameCheck>> {
+        Record::verify_next(&mut self.reader, self.layout.format).await
+    }
 }
 
-async fn read_exact<R>(reader: &mut R, offset: u64) -> tokio::io::Result<Record>
+async fn read_exact<R>(
+    reader: &mut R,
+    offset: u64,
+    layout: TableLayout,
+    dictionary: Option<&Dictionary>,
+) -> tokio::io::Result<Record>
 where
     R: AsyncRead + AsyncSeek + Unpin,
 {
     reader.seek(std::io::SeekFrom::Start(offset)).await?;
-    Record::read_from(reader).await
+    Record::read_from(reader, layout.format, "", dictionary).await
 }
 
+/// Scans the records between the offsets `start` and `end` for `key`. `start`
+/// must be a restart point, and the record at `end`, if any, must sort after
+/// `key`, as with the bounds of [`ScanRange::Range`].
+///
+/// Ranges of up to [`LOOKUP_WINDOW_BYTES`] are read at once, so that skipping
+/// a record doesn't take a seek and a read of its own.
 async fn scan_file_for_key<R>(
     reader: &mut R,
     key: &str,
-    start: Option<u64>,
-    end: Option<u64>,
+    start: u64,
+    end: u64,
+    layout: TableLayout,
+    dictionary: Option<&Dictionary>,
 ) -> Result<Option<MemValue>>
 where
     R: AsyncRead + AsyncSeek + Unpin,
 {
-    assert!(
-        start.is_some() || end.is_some(),
-        "At least one of `start` or `end` must be provided"
-    );
+    let end = end.min(layout.data_len);
+    reader.seek(SeekFrom::Start(start)).await?;
+    let len = end.saturating_sub(start);
+    if len > LOOKUP_WINDOW_BYTES {
+        return scan_records_for_key(reader, key, len, layout.format, dictionary).await;
+    }
+    let mut window = vec![0; len as usize];
+    reader.read_exact(&mut window).await?;
+    let mut window = Cursor::new(window);
+    scan_records_for_key(&mut window, key, len, layout.format, dictionary).await
+}
 
-    let mut len_buf = [0u8; 2]; // shared buffer for {key,val}_len
+/// Scans the `len` bytes of records from the current position of `reader`,
+/// which must be a restart point, for `key`.
+async fn scan_records_for_key<R>(
+    reader: &mut R,
+    key: &str,
+    len: u64,
+    format: Format,
+    dictionary: Option<&Dictionary>,
+) -> Result<Option<MemValue>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
     let mut key_buf = Vec::with_capacity(256);
-    let mut type_tag_buf = [0u8; 1];
-    let mut offset = start.unwrap_or(0);
-    let end_offset = end.unwrap_or(u64::MAX);
-
-    reader.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut offset = 0;
 
     loop {
-        if offset > end_offset {
+        if offset >= len {
             return Ok(None);
         }
 
-        if let Err(e) = reader.read_exact(&mut len_buf).await {
-            if e.kind() == ErrorKind::UnexpectedEof {
-                return Ok(None);
-            }
-            return Err(e);
-        }
-
-        let key_len = u16::from_be_bytes(len_buf) as usize;
-
-        reader.read_exact(&mut len_buf).await?;
-        let val_len = u16::from_be_bytes(len_buf) as usize;
-
-        reader.read_exact(&mut type_tag_buf).await?;
+        let header = match record::read_header(reader, format).await {
+            Ok(header) => header,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
 
-        key_buf.resize(key_len, 0);
-        reader.read_exact(&mut key_buf).await?;
-        let read_key = String::from_utf8(std::mem::take(&mut key_buf))
-            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        // `key_buf` holds the previous key, of which `shared` bytes are reused.
+        if header.shared > key_buf.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Shared key prefix is longer than the previous key",
+            ));
+        }
+        key_buf.truncate(header.shared);
+        key_buf.resize(header.shared + header.key_len, 0);
+        reader.read_exact(&mut key_buf[header.shared..]).await?;
 
-        if read_key == key {
-            let mut val_buf = vec![0u8; val_len];
+        if key_buf == key.as_bytes() {
+            let mut val_buf = vec![0u8; header.val_len];
             reader.read_exact(&mut val_buf).await?;
-            let value = MemValue::deserialize(type_tag_buf[0], &val_buf);
-            return value.map(|x| Some(x));
+            if !header.checksum_ok(&key_buf[header.shared..], &val_buf) {
+                return Err(record::checksum_mismatch());
+            }
+            let value = record::decode_value(header.tag, &val_buf, dictionary);
+            return value.map(Some);
         }
 
-        reader.seek(SeekFrom::Current(val_len as i64)).await?;
+        reader.seek(SeekFrom::Current(header.val_len as i64)).await?;
 
-        offset += (2 + 2 + key_len + val_len) as u64;
+        offset += header.frame_len();
     }
 }
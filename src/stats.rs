@@ -0,0 +1,141 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWriteExt, ErrorKind, Result};
+
+use crate::disk;
+
+/// File of the data directory [`Totals`] are saved in.
+pub const TOTALS_FILE: &str = "STATS";
+
+/// Point-in-time snapshot of database counters.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub memtable_entries: usize,
+    /// Size of the records the memtable will be flushed as, in bytes.
+    pub memtable_bytes: usize,
+    pub tables: usize,
+    /// Tables left out of reads for failing to load, see
+    /// `CorruptTablePolicy::Skip`.
+    pub skipped_tables: usize,
+    /// Time since each table was written, newest table first, in milliseconds.
+    pub table_ages_ms: Vec<u64>,
+    pub last_sequence: usize,
+    /// Sequence number of the last write, as given to
+    /// `DatabaseImpl::restore_to`.
+    pub last_record_seq: u64,
+    pub expiring_keys: usize,
+    /// Size of the live keys and values counted against `cache_max_bytes`
+    /// (`0` outside of cache mode).
+    pub cache_bytes: u64,
+    /// Keys evicted in cache mode since the database was opened.
+    pub evictions: u64,
+    /// Values fetched by the `CacheLoader` on a miss since the database was
+    /// opened.
+    pub cache_loads: u64,
+    /// Size of the data and index files, in bytes.
+    pub disk_bytes: u64,
+    /// Data files currently held open by the file cache.
+    pub open_files: usize,
+    /// Number of background flushes that failed. Any failure poisons the
+    /// controller.
+    pub flush_failures: u64,
+    pub last_flush_error: Option<String>,
+    /// Writes delayed for reaching `write_slowdown_tables`.
+    pub write_slowdowns: u64,
+    /// Writes stalled for reaching `write_stop_tables`.
+    pub write_stalls: u64,
+    /// Compactions refused since the database was opened for lack of free
+    /// disk space to write their outputs.
+    pub compactions_refused: u64,
+    /// Counters kept since the database was created.
+    pub totals: Totals,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "memtable_entries: {}", self.memtable_entries)?;
+        writeln!(f, "memtable_bytes: {}", self.memtable_bytes)?;
+        writeln!(f, "tables: {}", self.tables)?;
+        writeln!(f, "skipped_tables: {}", self.skipped_tables)?;
+        let ages: Vec<_> = self.table_ages_ms.iter().map(u64::to_string).collect();
+        writeln!(f, "table_ages_ms: {}", ages.join(","))?;
+        writeln!(f, "last_sequence: {}", self.last_sequence)?;
+        writeln!(f, "last_record_seq: {}", self.last_record_seq)?;
+        writeln!(f, "expiring_keys: {}", self.expiring_keys)?;
+        writeln!(f, "cache_bytes: {}", self.cache_bytes)?;
+        writeln!(f, "evictions: {}", self.evictions)?;
+        writeln!(f, "cache_loads: {}", self.cache_loads)?;
+        writeln!(f, "disk_bytes: {}", self.disk_bytes)?;
+        writeln!(f, "open_files: {}", self.open_files)?;
+        writeln!(f, "flush_failures: {}", self.flush_failures)?;
+        writeln!(f, "write_slowdowns: {}", self.write_slowdowns)?;
+        writeln!(f, "write_stalls: {}", self.write_stalls)?;
+        writeln!(f, "compactions_refused: {}", self.compactions_refused)?;
+        writeln!(f, "total_keys_written: {}", self.totals.keys_written)?;
+        writeln!(f, "total_bytes_written: {}", self.totals.bytes_written)?;
+        writeln!(f, "total_compactions: {}", self.totals.compactions)?;
+        if let Some(error) = &self.last_flush_error {
+            writeln!(f, "last_flush_error: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// One table of the database, as listed by `DatabaseImpl::tables`.
+#[derive(Debug, Clone, Default)]
+pub struct TableSummary {
+    /// Data file, relative to the data directory.
+    pub data_path: String,
+    pub generation: usize,
+    /// Written by a compaction rather than a flush or an ingest.
+    pub compacted: bool,
+    /// Time since the table was written, in milliseconds.
+    pub age_ms: u64,
+    pub data_bytes: u64,
+    /// Size of the index file, `0` while it hasn't been rebuilt yet (see
+    /// `Config::load_indexes_eagerly`).
+    pub index_bytes: u64,
+}
+
+/// Counters kept across restarts: saved to [`TOTALS_FILE`] when the
+/// database is shut down cleanly and loaded when it is opened. What was
+/// counted since the last clean shutdown is lost on a crash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Totals {
+    /// Keys set or deleted, expired keys included.
+    pub keys_written: u64,
+    /// Bytes of records written to tables by flushes and compactions.
+    pub bytes_written: u64,
+    /// Compactions completed.
+    pub compactions: u64,
+}
+
+impl Totals {
+    /// Reads the totals saved in `data_dir`. Counting starts over from zero
+    /// if there are none or they can't be parsed.
+    pub async fn load(data_dir: &Path) -> Result<Self> {
+        let contents = match tokio::fs::read_to_string(data_dir.join(TOTALS_FILE)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        Ok(toml::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!(error:% = e; "Unable to parse {TOTALS_FILE}, counting from zero: {e}");
+            Self::default()
+        }))
+    }
+
+    /// Atomically replaces the totals saved in `data_dir`.
+    pub async fn save(&self, data_dir: &Path) -> Result<()> {
+        let serialized = toml::to_string(self).expect("totals serialize");
+        let part = data_dir.join(format!("{TOTALS_FILE}.part"));
+        let mut file = tokio::fs::File::create(&part).await?;
+        file.write_all(serialized.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&part, data_dir.join(TOTALS_FILE)).await?;
+        disk::sync_dir(data_dir).await
+    }
+}
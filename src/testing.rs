@@ -0,0 +1,233 @@
+//! Helpers for integration tests against the real engine, enabled by the
+//! `testing` feature.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::io::{ErrorKind, Result};
+
+use crate::{Config, Controller, DatabaseImpl, Value, WalCipher};
+
+/// Distinguishes the directories of the databases of one process.
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A database in a directory of its own under the system's temporary
+/// directory, removed when the `TempDatabase` is closed.
+///
+/// Dereferences to its [`Controller`]. Tests must end with
+/// [`TempDatabase::close`], which shuts the database down before removing
+/// the directory. Dropping it without closing it, as a failing test does,
+/// leaves the directory behind: the background workers still hold the
+/// database, its lock and its files, and shutting them down is
+/// asynchronous.
+pub struct TempDatabase {
+    database: Option<Controller>,
+    config: Config,
+    wal_cipher: Option<Arc<dyn WalCipher>>,
+}
+
+impl TempDatabase {
+    /// Opens an empty database with [`TempDatabase::config`].
+    pub async fn new() -> Result<Self> {
+        Self::with_config(Self::config()).await
+    }
+
+    /// Opens an empty database with `config`, whose `data_dir` is replaced
+    /// by a new temporary directory.
+    pub async fn with_config(config: Config) -> Result<Self> {
+        Self::with_wal_cipher(config, None).await
+    }
+
+    /// Same as [`TempDatabase::with_config`], sealing the write-ahead log
+    /// with `cipher`, as are the databases it is reopened as.
+    pub async fn with_wal_cipher(
+        mut config: Config,
+        wal_cipher: Option<Arc<dyn WalCipher>>,
+    ) -> Result<Self> {
+        config.data_dir = create_dir()?;
+        match open(&config, wal_cipher.clone()).await {
+            Ok(database) => Ok(Self {
+                database: Some(database),
+                config,
+                wal_cipher,
+            }),
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&config.data_dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Opens the database in `data_dir`, e.g. one restored there, with
+    /// [`TempDatabase::config`] and `wal_cipher`. The directory is removed
+    /// once closed, but left as it is if the open fails.
+    pub async fn open_in(data_dir: &Path, wal_cipher: Option<Arc<dyn WalCipher>>) -> Result<Self> {
+        let config = Config {
+            data_dir: data_dir.to_path_buf(),
+            ..Self::config()
+        };
+        Ok(Self {
+            database: Some(open(&config, wal_cipher.clone()).await?),
+            config,
+            wal_cipher,
+        })
+    }
+
+    /// Returns the defaults of a [`TempDatabase`]: those of [`Config`],
+    /// without the background jobs that only slow tests down or make them
+    /// flaky (periodic compaction checks) and deleting replaced files right
+    /// away rather than keeping them in the trash.
+    pub fn config() -> Config {
+        Config {
+            compaction_check_interval_ms: 0,
+            trash_retention_ms: 0,
+            ..Config::default()
+        }
+    }
+
+    /// Returns the data directory.
+    pub fn path(&self) -> &Path {
+        &self.config.data_dir
+    }
+
+    /// Shuts the database down and opens it again from its directory, to
+    /// check what outlives a restart.
+    pub async fn reopen(&mut self) -> Result<()> {
+        if let Some(database) = self.database.take() {
+            database.shutdown().await?;
+        }
+        self.database = Some(open(&self.config, self.wal_cipher.clone()).await?);
+        Ok(())
+    }
+
+    /// Opens a copy of the files of the database, as a crash would leave
+    /// them, once `damage` has changed the copy, e.g. to cut a file short as
+    /// a crash in the middle of a write would. The database itself is left
+    /// running.
+    pub async fn open_crashed(&self, damage: impl FnOnce(&Path)) -> Result<Self> {
+        let mut config = self.config.clone();
+        config.data_dir = create_dir()?;
+        let opened = match copy_dir(self.path(), &config.data_dir) {
+            Ok(()) => {
+                damage(&config.data_dir);
+                open(&config, self.wal_cipher.clone()).await
+            }
+            Err(e) => Err(e),
+        };
+        match opened {
+            Ok(database) => Ok(Self {
+                database: Some(database),
+                config,
+                wal_cipher: self.wal_cipher.clone(),
+            }),
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&config.data_dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Shuts the database down and removes its directory.
+    pub async fn close(mut self) -> Result<()> {
+        if let Some(database) = self.database.take() {
+            database.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = Controller;
+
+    fn deref(&self) -> &Controller {
+        self.database.as_ref().expect("the database is open until closed")
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        let dir = &self.config.data_dir;
+        if self.database.is_some() {
+            if !std::thread::panicking() {
+                log::warn!("TempDatabase dropped without close, leaving {}", dir.display());
+            }
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(dir) {
+            log::warn!("Unable to remove {}: {e}", dir.display());
+        }
+    }
+}
+
+/// A directory of its own under the system's temporary directory, for what
+/// a test keeps beside its databases, e.g. a backup or an archive. Created
+/// empty and removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates the directory.
+    pub fn new() -> Result<Self> {
+        Ok(Self { path: create_dir()? })
+    }
+
+    /// Returns the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            log::warn!("Unable to remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Returns the string value `s`, which is what most tests write.
+pub fn str_value(s: &str) -> Value {
+    Value::Str(s.to_string())
+}
+
+async fn open(config: &Config, wal_cipher: Option<Arc<dyn WalCipher>>) -> Result<Controller> {
+    let flush_threshold = config.flush_threshold;
+    let database = DatabaseImpl::build_with_wal_cipher(config.clone(), wal_cipher).await?;
+    Ok(Controller::new(database, flush_threshold))
+}
+
+/// Creates a directory no other database of this or another process uses.
+fn create_dir() -> Result<PathBuf> {
+    loop {
+        let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("logdb-test-{}-{n}", std::process::id()));
+        match std::fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            // Left behind by an earlier process with the same ID.
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copies the files under `from` into the directory `to`.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
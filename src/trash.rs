@@ -0,0 +1,92 @@
+use std::path::Path;
+
+use tokio::io::{Error, ErrorKind, Result};
+
+use crate::manifest::Manifest;
+
+/// Directory of the data directory files replaced by a compaction are moved
+/// to, until `Config::trash_retention_ms` has passed.
+pub const TRASH_DIR: &str = "trash";
+
+/// Moves the file `path` of `data_dir` to the trash, its name prefixed with
+/// `now`, the time it was discarded at. A missing file is ignored. The table
+/// subdirectory it was in is removed once empty.
+pub async fn discard(data_dir: &Path, path: &Path, now: u64) -> Result<()> {
+    let trash_dir = data_dir.join(TRASH_DIR);
+    tokio::fs::create_dir_all(&trash_dir).await?;
+    let Some(name) = path.file_name() else {
+        return Ok(());
+    };
+    let trashed = trash_dir.join(format!("{now}-{}", name.to_string_lossy()));
+    match tokio::fs::rename(data_dir.join(path), trashed).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        // Fails as long as other files are left in it.
+        let _ = tokio::fs::remove_dir(data_dir.join(dir)).await;
+    }
+    Ok(())
+}
+
+/// Deletes the files discarded at least `retention_ms` before `now`,
+/// returning how many. Nothing is deleted unless MANIFEST can be read and
+/// every file it refers to exists: should it be wrong about which files are
+/// live, the trash holds what it replaced.
+pub async fn purge(data_dir: &Path, now: u64, retention_ms: u64) -> Result<usize> {
+    let mut entries = match tokio::fs::read_dir(data_dir.join(TRASH_DIR)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut due = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let discarded_at = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(time, _)| time.parse::<u64>().ok());
+        if discarded_at.is_some_and(|time| time.saturating_add(retention_ms) <= now) {
+            due.push(entry.path());
+        }
+    }
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    check_manifest(data_dir).await?;
+    for path in &due {
+        log::info!("Deleting {} from the trash.", path.display());
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(due.len())
+}
+
+/// Fails unless MANIFEST parses and the data files and dictionary it refers
+/// to exist. Indexes may be missing, see `Config::load_indexes_eagerly`.
+async fn check_manifest(data_dir: &Path) -> Result<()> {
+    let contents = tokio::fs::read_to_string(data_dir.join("MANIFEST")).await?;
+    let manifest = toml::from_str::<Manifest>(&contents).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "Unable to parse MANIFEST, keeping the trash",
+        )
+    })?;
+    let referenced = manifest
+        .sstables
+        .iter()
+        .map(|entry| &entry.data_path)
+        .chain(&manifest.dictionary);
+    for path in referenced {
+        if tokio::fs::metadata(data_dir.join(path)).await.is_err() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "MANIFEST refers to the missing {}, keeping the trash",
+                    path.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
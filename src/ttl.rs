@@ -0,0 +1,116 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
+    time::Duration,
+};
+
+use tokio::io::Result;
+
+use crate::sstable_set::{SSTableSet, TableScan};
+
+/// Time-to-live of a key, as reported by `ttl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyTtl {
+    NotFound,
+    NoExpiry,
+    Remaining(Duration),
+}
+
+/// Secondary index of keys ordered by expiration time.
+///
+/// Keeps a `(expires_at, key)` ordered set alongside a `key -> expires_at` map,
+/// so that both "what expires next" and "forget this key" are cheap.
+#[derive(Debug, Default)]
+pub struct TtlIndex {
+    by_deadline: BTreeSet<(u64, String)>,
+    deadlines: HashMap<String, u64>,
+}
+
+impl TtlIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// Returns the expiration time of `key`, if it is tracked.
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.deadlines.get(key).copied()
+    }
+
+    /// Tracks `key` as expiring at `expires_at`, replacing any previous deadline.
+    pub fn insert(&mut self, key: String, expires_at: u64) {
+        self.remove(&key);
+        self.by_deadline.insert((expires_at, key.clone()));
+        self.deadlines.insert(key, expires_at);
+    }
+
+    /// Stops tracking `key`.
+    pub fn remove(&mut self, key: &str) {
+        if let Some(expires_at) = self.deadlines.remove(key) {
+            self.by_deadline.remove(&(expires_at, key.to_string()));
+        }
+    }
+
+    /// Returns the earliest tracked expiration time.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.by_deadline.first().map(|(expires_at, _)| *expires_at)
+    }
+
+    /// Removes and returns every key whose deadline is at or before `now`.
+    pub fn pop_expired(&mut self, now: u64) -> Vec<String> {
+        let mut expired = Vec::new();
+        while let Some((expires_at, _)) = self.by_deadline.first() {
+            if *expires_at > now {
+                break;
+            }
+            let (_, key) = self.by_deadline.pop_first().unwrap();
+            self.deadlines.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+}
+
+/// Rebuilds a `TtlIndex` by scanning every SSTable, newest first.
+///
+/// Only the newest version of each key is considered, so a key that was later
+/// overwritten without a TTL (or deleted) is not tracked. Unreadable records
+/// end the scan of their table with a warning rather than failing the open.
+pub async fn load(sstable_set: &SSTableSet, data_dir: &Path) -> Result<TtlIndex> {
+    let mut index = TtlIndex::new();
+    let mut seen = HashSet::new();
+
+    for table in &sstable_set.tables {
+        let mut scan = TableScan::open(&data_dir.join(&table.data_path))
+            .await?
+            .with_dictionary(sstable_set.dictionary.clone());
+        loop {
+            let record = match scan.next().await {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!(
+                        "Unable to read expiration times past a bad record in {}: {e}",
+                        table.data_path
+                    );
+                    break;
+                }
+            };
+            if !seen.insert(record.key.clone()) {
+                continue;
+            }
+            if let Some(expires_at) = record.value.expires_at() {
+                index.insert(sstable_set.collation.decode(&record.key).to_string(), expires_at);
+            }
+        }
+    }
+
+    Ok(index)
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{ErrorKind, Result};
+
+use crate::{rate_limit::RateLimiter, sstable_set::TableScan};
+
+#[derive(Debug, Clone)]
+pub struct CorruptBlock {
+    /// Data file the corruption was found in.
+    pub table: String,
+    /// Offset of the first byte of the corrupt record.
+    pub offset: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumReport {
+    pub tables: usize,
+    pub records: u64,
+    pub bytes: u64,
+    pub corrupt: Vec<CorruptBlock>,
+}
+
+impl ChecksumReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+impl fmt::Display for ChecksumReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "checked {} tables, {} records, {} bytes: {} corrupt",
+            self.tables,
+            self.records,
+            self.bytes,
+            self.corrupt.len()
+        )?;
+        for block in &self.corrupt {
+            writeln!(f, "  {} @ {}: {}", block.table, block.offset, block.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Streams every record of the given data files and checks its checksum.
+///
+/// A record whose checksum doesn't match is reported and skipped; a record
+/// whose header can't be read ends the scan of that file, since the following
+/// record boundaries are unknown. Files deleted during the scan (e.g. by a
+/// concurrent compaction) are skipped.
+pub async fn verify_files(paths: &[PathBuf], bytes_per_sec: Option<u64>) -> Result<ChecksumReport> {
+    let mut report = ChecksumReport::default();
+    let limiter = RateLimiter::new(bytes_per_sec.unwrap_or(0));
+
+    for path in paths {
+        verify_from(path, 0, u64::MAX, &limiter, &mut report).await?;
+    }
+
+    Ok(report)
+}
+
+/// Where the incremental scrubber carries on from: a data file, and the
+/// offset of the next record to check in it.
+#[derive(Debug, Clone)]
+pub struct ScrubCursor {
+    path: PathBuf,
+    offset: u64,
+}
+
+/// Checks about `budget` bytes of records of the given data files, as
+/// [`verify_files`] does, starting at `cursor` and moving it past them.
+///
+/// Files are visited in path order, so that a pass over them carries on
+/// across the tables a compaction replaced: a file no longer there is
+/// skipped, and the next one taken from its start. Once the last file is
+/// done the cursor is reset, and the next slice starts a new pass.
+pub async fn verify_slice(
+    paths: &[PathBuf],
+    cursor: &mut Option<ScrubCursor>,
+    budget: u64,
+    bytes_per_sec: Option<u64>,
+) -> Result<ChecksumReport> {
+    let mut report = ChecksumReport::default();
+    let limiter = RateLimiter::new(bytes_per_sec.unwrap_or(0));
+    let mut paths: Vec<_> = paths.iter().collect();
+    paths.sort();
+
+    let first = match cursor.as_ref() {
+        Some(cursor) => paths.partition_point(|path| **path < cursor.path),
+        None => 0,
+    };
+    for path in &paths[first..] {
+        let offset = match cursor.take() {
+            Some(cursor) if cursor.path == **path => cursor.offset,
+            _ => 0,
+        };
+        let remaining = budget.saturating_sub(report.bytes);
+        let stopped = match remaining {
+            0 => Some(offset),
+            _ => verify_from(path, offset, remaining, &limiter, &mut report).await?,
+        };
+        if let Some(offset) = stopped {
+            *cursor = Some(ScrubCursor {
+                path: path.to_path_buf(),
+                offset,
+            });
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Checks the records of `path` from `offset`, which must be the start of a
+/// record, until `budget` bytes are checked. Returns the offset to carry on
+/// from, or `None` once the file is done with.
+async fn verify_from(
+    path: &Path,
+    mut offset: u64,
+    budget: u64,
+    limiter: &RateLimiter,
+    report: &mut ChecksumReport,
+) -> Result<Option<u64>> {
+    let table = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut scan = match TableScan::open(path).await {
+        Ok(scan) => scan,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if offset > 0 {
+        scan.seek(offset).await?;
+    }
+    report.tables += 1;
+
+    let mut checked = 0;
+    while checked < budget {
+        match scan.verify_next().await {
+            Ok(Some(frame)) => {
+                if !frame.checksum_ok {
+                    report.corrupt.push(CorruptBlock {
+                        table: table.clone(),
+                        offset,
+                        error: "checksum mismatch".to_string(),
+                    });
+                }
+                report.records += 1;
+                report.bytes += frame.len;
+                offset += frame.len;
+                checked += frame.len;
+                limiter.consume(frame.len).await;
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                report.corrupt.push(CorruptBlock {
+                    table,
+                    offset,
+                    error: e.to_string(),
+                });
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(offset))
+}
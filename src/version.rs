@@ -1 +1,88 @@
-pub const VERSION: &str = "0.1.0";
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::sstable_set::SSTableSet;
+
+pub const VERSION: &str = "0.2.0";
+
+/// The files making up the database at one point in time: the data and
+/// index files of every table, newest first, and the dictionary. Readers
+/// that let go of the database lock hold on to a version, which keeps its
+/// files from being moved to the trash by a compaction.
+#[derive(Debug, Default)]
+pub(crate) struct Version {
+    files: Vec<PathBuf>,
+}
+
+impl Version {
+    pub(crate) fn new(sstable_set: &SSTableSet) -> Self {
+        let files = sstable_set
+            .tables
+            .iter()
+            .flat_map(|table| [&table.data_path, &table.index_path])
+            .map(PathBuf::from)
+            .chain(
+                sstable_set
+                    .dictionary
+                    .iter()
+                    .map(|dictionary| dictionary.file_name().into()),
+            )
+            .collect();
+        Self { files }
+    }
+
+    /// Returns the files of the version, relative to the data directory.
+    pub(crate) fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+}
+
+/// The current version and those still held by readers, telling which
+/// files are live: referenced by any of them.
+#[derive(Debug, Default)]
+pub(crate) struct VersionSet {
+    current: Mutex<Arc<Version>>,
+    /// Versions handed out by [`VersionSet::current`]; dropped ones are
+    /// pruned as new ones are handed out.
+    held: Mutex<Vec<Weak<Version>>>,
+}
+
+impl VersionSet {
+    pub(crate) fn new(sstable_set: &SSTableSet) -> Self {
+        Self {
+            current: Mutex::new(Arc::new(Version::new(sstable_set))),
+            held: Mutex::default(),
+        }
+    }
+
+    /// Returns the current version, keeping its files live until every
+    /// clone of it is dropped.
+    pub(crate) fn current(&self) -> Arc<Version> {
+        let current = self.current.lock().unwrap().clone();
+        let mut held = self.held.lock().unwrap();
+        held.retain(|version| version.strong_count() > 0);
+        if !held.iter().any(|version| version.as_ptr() == Arc::as_ptr(&current)) {
+            held.push(Arc::downgrade(&current));
+        }
+        current
+    }
+
+    /// Makes `version` the current one, as the set of tables changed.
+    pub(crate) fn install(&self, version: Version) {
+        *self.current.lock().unwrap() = Arc::new(version);
+    }
+
+    /// Returns every file referenced by the current version or one still
+    /// held by a reader.
+    pub(crate) fn live_files(&self) -> BTreeSet<PathBuf> {
+        let mut live: BTreeSet<PathBuf> =
+            self.current.lock().unwrap().files.iter().cloned().collect();
+        for version in self.held.lock().unwrap().iter().filter_map(Weak::upgrade) {
+            live.extend(version.files.iter().cloned());
+        }
+        live
+    }
+}
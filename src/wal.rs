@@ -0,0 +1,350 @@
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    runtime::{Handle, RuntimeFlavor},
+};
+
+use crate::{
+    disk,
+    format::Format,
+    record::{self, Record},
+};
+
+pub const WAL_FILE: &str = "WAL";
+
+/// Extension of the segments of the log archived by [`Wal::archive`].
+pub const SEGMENT_EXTENSION: &str = "wal";
+
+/// Encoding of the records of a frame. Each v2 record stands on its own
+/// and carries its sequence number in full, relative to `0`, so that frames
+/// need no footer or base of their own.
+const FORMAT: Format = Format::V2 { base_seq: 0 };
+
+/// Size of the header of a frame:
+/// `[payload_len (u32)][payload crc32 (u32)][crc32 of the previous 8 bytes (u32)]`.
+const FRAME_HEADER_LEN: usize = 12;
+
+/// Set in the `payload_len` of a frame whose payload is sealed by a
+/// [`WalCipher`].
+const SEALED: u32 = 1 << 31;
+
+/// Encrypts the frames of the write-ahead log, where writes land before
+/// they reach a table, independently of anything else on disk; see
+/// `DatabaseImpl::build_with_wal_cipher`. Implementations hold their own
+/// key and authenticate what they seal, picking a fresh nonce for every
+/// frame and storing it in the sealed bytes.
+///
+/// Checksums cover the sealed bytes, so that a torn or corrupt frame is
+/// told apart from one sealed with another key: only the latter reaches
+/// [`WalCipher::open`], whose failure fails the open of the database.
+pub trait WalCipher: Debug + Send + Sync {
+    /// Encrypts and authenticates the records of one write.
+    fn seal(&self, payload: &[u8]) -> Result<Vec<u8>>;
+
+    /// Returns the records sealed by [`WalCipher::seal`], failing if they
+    /// were sealed with another key or altered since.
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The write-ahead log: the records of the memtable, in the order they were
+/// written, appended before the memtable takes them and emptied once they
+/// are flushed to a table.
+///
+/// The log is a sequence of frames, each holding the records of one write
+/// (a single key, or every key of a batch) and checksummed as a whole, so
+/// that a crash keeps either all of a write or none of it.
+///
+/// Appends block on the file rather than going through the runtime, as the
+/// memtable is written to under the database lock without awaiting; on a
+/// multi-threaded runtime they leave the worker thread to other tasks while
+/// they do.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    /// Length of the frames appended so far, where a failed append is cut
+    /// back to.
+    len: u64,
+    /// Syncs the file after every append.
+    sync: bool,
+    /// Sequence number of the first record logged since the log was last
+    /// emptied, naming the segment it is archived as.
+    first_seq: Option<u64>,
+    /// Seals the frames appended, if set.
+    cipher: Option<Arc<dyn WalCipher>>,
+}
+
+impl Wal {
+    /// Opens the log in `data_dir`, creating it if missing, and returns the
+    /// records it holds, oldest first.
+    ///
+    /// Only the last frame may be bad, as left by a crash in the middle of
+    /// its append: cut short, failing the checksum of its header or payload
+    /// or followed by nothing but zeros, it is cut off with a warning. A bad
+    /// frame followed by more data is corruption, and fails the open with
+    /// `InvalidData` rather than drop the writes after it. So does a sealed
+    /// frame without `cipher`, or that `cipher` fails to open; frames logged
+    /// before `cipher` was set are read as they are.
+    pub async fn open(
+        data_dir: &Path,
+        sync: bool,
+        cipher: Option<Arc<dyn WalCipher>>,
+    ) -> Result<(Self, Vec<Record>)> {
+        let path = data_dir.join(WAL_FILE);
+        let (bytes, created) = match tokio::fs::read(&path).await {
+            Ok(bytes) => (bytes, false),
+            Err(e) if e.kind() == ErrorKind::NotFound => (Vec::new(), true),
+            Err(e) => return Err(e),
+        };
+
+        let (frames, len) = read_frames(&path, &bytes, cipher.as_deref()).await?;
+        if len < bytes.len() {
+            log::warn!(
+                "Truncating {} at byte {len} of {}: its last write is incomplete",
+                path.display(),
+                bytes.len()
+            );
+        }
+        let records: Vec<_> = frames.into_iter().flatten().collect();
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if len < bytes.len() {
+            blocking(|| {
+                file.set_len(len as u64)?;
+                file.sync_all()
+            })?;
+        }
+        if created {
+            disk::sync_dir(data_dir).await?;
+        }
+        let wal = Self {
+            path,
+            file,
+            len: len as u64,
+            sync,
+            first_seq: records.first().map(|record| record.seq),
+            cipher,
+        };
+        Ok((wal, records))
+    }
+
+    /// Appends `records`, under their stored keys, as one frame, sealed if
+    /// the log has a cipher. Fails with `InvalidInput`, appending nothing,
+    /// if a record is too long to read back. A failed append is cut back off
+    /// the log, so that it doesn't end the log for the frames after it.
+    pub fn append(&mut self, records: &[Record]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut payload = Vec::new();
+        for record in records {
+            record::check_lengths(&record.key, record.value.len(), FORMAT)?;
+            record.encode_into(&mut payload, FORMAT, "", None);
+        }
+        let mut flags = 0;
+        if let Some(cipher) = &self.cipher {
+            payload = cipher.seal(&payload)?;
+            flags = SEALED;
+        }
+        let payload_len = u32::try_from(payload.len())
+            .ok()
+            .filter(|len| len & SEALED == 0)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Write too large to log"))?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend((payload_len | flags).to_be_bytes());
+        frame.extend(crc32fast::hash(&payload).to_be_bytes());
+        frame.extend(crc32fast::hash(&frame).to_be_bytes());
+        frame.extend(payload);
+
+        let written = blocking(|| {
+            self.file.write_all(&frame)?;
+            if self.sync {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            if let Err(e) = blocking(|| self.file.set_len(self.len)) {
+                log::error!("Unable to cut a failed append off the write-ahead log: {e}");
+            }
+            return Err(e);
+        }
+        self.len += frame.len() as u64;
+        self.first_seq.get_or_insert(records[0].seq);
+        Ok(())
+    }
+
+    /// Copies the frames logged since the log was last emptied to
+    /// `archive_dir`, as a segment named after the sequence number of their
+    /// first record, before they are flushed and the log emptied. The copy is
+    /// synced and renamed into place, so that segments are never seen
+    /// incomplete. It replaces the segment of an earlier attempt that the
+    /// log still holds, e.g. from a flush that failed. Sealed frames are
+    /// copied as they are.
+    pub async fn archive(&self, archive_dir: &Path) -> Result<()> {
+        let Some(first_seq) = self.first_seq else {
+            return Ok(());
+        };
+        let mut bytes = tokio::fs::read(&self.path).await?;
+        bytes.truncate(self.len as usize);
+        tokio::fs::create_dir_all(archive_dir).await?;
+        let segment = archive_dir.join(format!("{first_seq:020}.{SEGMENT_EXTENSION}"));
+        let part = segment.with_extension("part");
+        let mut file = tokio::fs::File::create(&part).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&part, &segment).await?;
+        disk::sync_dir(archive_dir).await?;
+        log::info!(
+            "Archived {} bytes of the write-ahead log to {}.",
+            bytes.len(),
+            segment.display()
+        );
+        Ok(())
+    }
+
+    /// Empties the log, once the records it holds are flushed. Synced
+    /// whatever `sync` is: records left behind by a crash would otherwise
+    /// come back on open over newer versions flushed since.
+    pub fn truncate(&mut self) -> Result<()> {
+        blocking(|| {
+            self.file.set_len(0)?;
+            self.file.sync_data()
+        })?;
+        self.len = 0;
+        self.first_seq = None;
+        Ok(())
+    }
+
+    /// Removes the log in `data_dir`, if any.
+    pub async fn remove(data_dir: &Path) -> Result<()> {
+        match tokio::fs::remove_file(data_dir.join(WAL_FILE)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the frames of a segment archived by [`Wal::archive`], oldest
+/// first, each holding the records of one write. Segments are written
+/// whole, so one cut short is corrupt too. Sealed frames are opened with
+/// `cipher`, as [`Wal::open`] does.
+pub async fn read_segment(
+    path: &Path,
+    cipher: Option<&dyn WalCipher>,
+) -> Result<Vec<Vec<Record>>> {
+    let bytes = tokio::fs::read(path).await?;
+    match read_frames(path, &bytes, cipher).await? {
+        (frames, len) if len == bytes.len() => Ok(frames),
+        (_, len) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is cut short at byte {len} of {}", path.display(), bytes.len()),
+        )),
+    }
+}
+
+/// Reads the frames of the log `path` holds as `bytes`, returning them
+/// and the length of those read, short of the bytes of an incomplete last
+/// frame.
+async fn read_frames(
+    path: &Path,
+    bytes: &[u8],
+    cipher: Option<&dyn WalCipher>,
+) -> Result<(Vec<Vec<Record>>, usize)> {
+    let mut frames = Vec::new();
+    let mut len = 0;
+    while len < bytes.len() {
+        let frame = read_frame(&bytes[len..], cipher).await.map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is corrupt at byte {len} of {}: {e}", path.display(), bytes.len()),
+            )
+        })?;
+        let Some((frame_len, records)) = frame else {
+            break;
+        };
+        frames.push(records);
+        len += frame_len;
+    }
+    Ok((frames, len))
+}
+
+/// Reads the frame at the start of `bytes`, returning its length and
+/// records, or `None` if it is the incomplete last frame of the log. Fails
+/// if it is bad otherwise.
+async fn read_frame(
+    bytes: &[u8],
+    cipher: Option<&dyn WalCipher>,
+) -> Result<Option<(usize, Vec<Record>)>> {
+    // Zeros are what a crash leaves once the file has grown but before the
+    // frame has landed.
+    if bytes.len() < FRAME_HEADER_LEN || bytes.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    let (header, rest) = bytes.split_at(FRAME_HEADER_LEN);
+    let field = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap());
+    if crc32fast::hash(&header[..8]) != field(8) {
+        // The length it holds can't be trusted: the header is torn if no
+        // frame starts anywhere after it.
+        if !(1..bytes.len()).any(|start| starts_with_frame(&bytes[start..])) {
+            return Ok(None);
+        }
+        return Err(Error::other("frame header checksum mismatch"));
+    }
+    let payload_len = (field(0) & !SEALED) as usize;
+    if rest.len() < payload_len {
+        return Ok(None);
+    }
+    let payload = &rest[..payload_len];
+    if crc32fast::hash(payload) != field(4) {
+        if rest.len() == payload_len {
+            return Ok(None);
+        }
+        return Err(Error::other("frame checksum mismatch"));
+    }
+    let opened;
+    let mut payload = match (field(0) & SEALED != 0, cipher) {
+        (false, _) => payload,
+        (true, Some(cipher)) => {
+            opened = cipher.open(payload)?;
+            opened.as_slice()
+        }
+        (true, None) => return Err(Error::other("frame is encrypted, but no cipher is set")),
+    };
+
+    let mut records = Vec::new();
+    while !payload.is_empty() {
+        records.push(Record::read_from(&mut payload, FORMAT, "", None).await?);
+    }
+    Ok(Some((FRAME_HEADER_LEN + payload_len, records)))
+}
+
+/// Returns whether `bytes` start with a whole frame, its header and payload
+/// matching their checksums.
+fn starts_with_frame(bytes: &[u8]) -> bool {
+    let Some((header, rest)) = bytes.split_at_checked(FRAME_HEADER_LEN) else {
+        return false;
+    };
+    let field = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap());
+    let payload_len = (field(0) & !SEALED) as usize;
+    crc32fast::hash(&header[..8]) == field(8)
+        && rest.get(..payload_len).is_some_and(|payload| crc32fast::hash(payload) == field(4))
+}
+
+/// Runs the blocking `io`, letting the runtime move the other tasks of the
+/// worker thread elsewhere in the meantime if it has other workers.
+fn blocking<T>(io: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(io)
+        }
+        _ => io(),
+    }
+}
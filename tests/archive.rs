@@ -0,0 +1,83 @@
+use std::io::ErrorKind;
+
+use my_database::{Config, DatabaseImpl, TempDatabase, TempDir, WriteBatch, str_value as value};
+
+#[tokio::test]
+async fn archived_writes_are_restored_over_a_backup_up_to_a_sequence_number() {
+    let dir = TempDir::new().unwrap();
+    let (archive, backup) = (dir.path().join("archive"), dir.path().join("backup"));
+    let config = Config {
+        wal_archive_dir: Some(archive.clone()),
+        ..TempDatabase::config()
+    };
+    let db = TempDatabase::with_config(config).await.unwrap();
+    db.set("a".to_string(), value("1")).await.unwrap();
+    db.backup(&backup).await.unwrap();
+    let base = db.stats().await.last_record_seq;
+
+    db.set("b".to_string(), value("1")).await.unwrap();
+    let after_b = db.stats().await.last_record_seq;
+    let mut batch = WriteBatch::new();
+    batch.set("c".to_string(), value("1"));
+    batch.set("d".to_string(), value("1"));
+    db.write(batch).await.unwrap();
+    let after_batch = db.stats().await.last_record_seq;
+    db.flush().await.unwrap();
+    db.set("a".to_string(), value("2")).await.unwrap();
+    db.delete("b".to_string()).await.unwrap();
+    db.flush().await.unwrap();
+    let last = db.stats().await.last_record_seq;
+
+    // Up to the end of the archive, across segments.
+    let restored = dir.path().join("restored-all");
+    let to = DatabaseImpl::restore_to(&backup, &archive, &restored, last, None).await.unwrap();
+    assert_eq!(to, last);
+    let copy = TempDatabase::open_in(&restored, None).await.unwrap();
+    assert_eq!(copy.get("a").await.unwrap(), Some(value("2")));
+    assert_eq!(copy.get("b").await.unwrap(), None);
+    assert_eq!(copy.get("d").await.unwrap(), Some(value("1")));
+    copy.close().await.unwrap();
+
+    // Within the batch, which is left out whole.
+    let restored = dir.path().join("restored-batch");
+    let sequence = after_batch - 1;
+    let to = DatabaseImpl::restore_to(&backup, &archive, &restored, sequence, None).await.unwrap();
+    assert_eq!(to, after_b);
+    let copy = TempDatabase::open_in(&restored, None).await.unwrap();
+    assert_eq!(copy.get("a").await.unwrap(), Some(value("1")));
+    assert_eq!(copy.get("b").await.unwrap(), Some(value("1")));
+    assert_eq!(copy.get("c").await.unwrap(), None);
+    copy.close().await.unwrap();
+
+    // Before the backup.
+    let restored = dir.path().join("restored-early");
+    let restore = DatabaseImpl::restore_to(&backup, &archive, &restored, base - 1, None);
+    let e = restore.await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_flush_keeps_the_log_when_it_cannot_be_archived() {
+    // A file where the archive directory should be.
+    let dir = TempDir::new().unwrap();
+    let archive = dir.path().join("archive");
+    std::fs::write(&archive, b"").unwrap();
+    let config = Config {
+        wal_archive_dir: Some(archive.clone()),
+        ..TempDatabase::config()
+    };
+    let db = TempDatabase::with_config(config).await.unwrap();
+    db.set("a".to_string(), value("1")).await.unwrap();
+    assert!(db.flush().await.is_err());
+    assert_eq!(db.stats().await.tables, 0);
+    let crashed = db.open_crashed(|_| {}).await.unwrap();
+    assert_eq!(crashed.get("a").await.unwrap(), Some(value("1")));
+
+    std::fs::remove_file(&archive).unwrap();
+    crashed.close().await.unwrap();
+    db.flush().await.unwrap();
+    assert!(archive.join(format!("{:020}.wal", 1)).exists());
+    db.close().await.unwrap();
+}
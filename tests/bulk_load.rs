@@ -0,0 +1,34 @@
+use std::{io::ErrorKind, path::Path};
+
+use my_database::{TempDatabase, str_value as value};
+
+/// Returns the `.part` files under `dir`.
+fn part_files(dir: &Path) -> Vec<String> {
+    let mut parts = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            parts.extend(part_files(&path));
+        } else if path.extension().is_some_and(|ext| ext == "part") {
+            parts.push(path.display().to_string());
+        }
+    }
+    parts
+}
+
+#[tokio::test]
+async fn a_failed_load_leaves_neither_files_nor_lost_keys_behind() {
+    let db = TempDatabase::new().await.unwrap();
+    let token = db.set_ephemeral("b".to_string(), value("up")).await.unwrap();
+    let entries = ["a", "b", "a"].map(|key| (key.to_string(), value("loaded")));
+    let e = db.bulk_load(futures::stream::iter(entries)).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(part_files(db.path()), Vec::<String>::new());
+    assert_eq!(db.get("a").await.unwrap(), None);
+    // Still the write of `set_ephemeral`, which the load didn't replace.
+    assert!(db.delete_ephemeral("b", token).await.unwrap());
+
+    let entries = ["a", "b"].map(|key| (key.to_string(), value("loaded")));
+    assert_eq!(db.bulk_load(futures::stream::iter(entries)).await.unwrap(), 2);
+    db.close().await.unwrap();
+}
@@ -0,0 +1,35 @@
+use my_database::{TempDatabase, str_value as value};
+
+#[tokio::test]
+async fn ephemeral_keys_are_deleted_with_their_token() {
+    let db = TempDatabase::new().await.unwrap();
+    let token = db.set_ephemeral("node/1".to_string(), value("up")).await.unwrap();
+    assert!(db.delete_ephemeral("node/1", token).await.unwrap());
+    assert_eq!(db.get("node/1").await.unwrap(), None);
+    assert!(!db.delete_ephemeral("node/1", token).await.unwrap());
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn ephemeral_keys_written_since_are_left_alone() {
+    let mut db = TempDatabase::new().await.unwrap();
+    let token = db.set_ephemeral("node/1".to_string(), value("up")).await.unwrap();
+    // Another client writing the very same value now owns the key.
+    db.set("node/1".to_string(), value("up")).await.unwrap();
+    assert!(!db.delete_ephemeral("node/1", token).await.unwrap());
+    assert_eq!(db.get("node/1").await.unwrap(), Some(value("up")));
+
+    // Neither does a newer ephemeral write go with the older token.
+    let older = db.set_ephemeral("node/2".to_string(), value("up")).await.unwrap();
+    let newer = db.set_ephemeral("node/2".to_string(), value("up")).await.unwrap();
+    assert!(!db.delete_ephemeral("node/2", older).await.unwrap());
+    db.flush().await.unwrap();
+    assert!(db.delete_ephemeral("node/2", newer).await.unwrap());
+    assert_eq!(db.get("node/2").await.unwrap(), None);
+
+    // Tokens don't outlive a restart.
+    let token = db.set_ephemeral("node/3".to_string(), value("up")).await.unwrap();
+    db.reopen().await.unwrap();
+    assert!(!db.delete_ephemeral("node/3", token).await.unwrap());
+    db.close().await.unwrap();
+}
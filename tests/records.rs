@@ -0,0 +1,79 @@
+use std::io::ErrorKind;
+
+use my_database::{TempDatabase, Value, WriteBatch};
+
+/// Longest value a v2 or v3 record holds, see `record::MAX_VALUE_LEN`.
+const MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+
+#[tokio::test]
+async fn values_longer_than_u16_are_read_back_from_tables() {
+    let mut db = TempDatabase::new().await.unwrap();
+    let value = Value::Str("x".repeat(70_000));
+    db.set("big".to_string(), value.clone()).await.unwrap();
+    db.set("small".to_string(), Value::Int64(1)).await.unwrap();
+    db.flush().await.unwrap();
+    assert_eq!(db.get("big").await.unwrap(), Some(value.clone()));
+
+    db.reopen().await.unwrap();
+    assert_eq!(db.get("big").await.unwrap(), Some(value));
+    assert_eq!(db.get("small").await.unwrap(), Some(Value::Int64(1)));
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn values_over_the_limit_are_refused_before_anything_is_written() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("key".to_string(), Value::Int64(1)).await.unwrap();
+
+    let too_long = Value::Str("x".repeat(MAX_VALUE_LEN + 1));
+    let e = db.set("key".to_string(), too_long.clone()).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(db.get("key").await.unwrap(), Some(Value::Int64(1)));
+
+    let mut batch = WriteBatch::new();
+    batch.set("other".to_string(), Value::Int64(2));
+    batch.set("key".to_string(), too_long);
+    let e = db.write(batch).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(db.get("other").await.unwrap(), None);
+    assert_eq!(db.get("key").await.unwrap(), Some(Value::Int64(1)));
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn keys_over_the_limit_are_refused() {
+    let db = TempDatabase::new().await.unwrap();
+    let key = "k".repeat(u16::MAX as usize + 1);
+    let e = db.set(key.clone(), Value::Int64(1)).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(db.get(&key).await.unwrap(), None);
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn values_too_long_to_decompress_are_stored_uncompressed() {
+    let config = my_database::Config {
+        dictionary_max_bytes: 4096,
+        ..TempDatabase::config()
+    };
+    let mut db = TempDatabase::with_config(config).await.unwrap();
+    for table in 0..2 {
+        for i in 0..100 {
+            let value = Value::Str(format!("{{\"user\": {i}, \"table\": {table}}}"));
+            db.set(format!("user/{i:03}"), value).await.unwrap();
+        }
+        db.flush().await.unwrap();
+    }
+    // Trains the dictionary that later tables are compressed with.
+    db.compact().await.unwrap();
+
+    let value = Value::Str("{\"user\": 1, \"table\": 1}".repeat(100_000));
+    db.set("big".to_string(), value.clone()).await.unwrap();
+    db.flush().await.unwrap();
+    assert_eq!(db.get("big").await.unwrap(), Some(value.clone()));
+
+    db.reopen().await.unwrap();
+    assert_eq!(db.get("big").await.unwrap(), Some(value));
+    assert!(db.get("user/042").await.unwrap().is_some());
+    db.close().await.unwrap();
+}
@@ -0,0 +1,252 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use my_database::{
+    Config, DatabaseImpl, KeyTtl, TempDatabase, TempDir, WalCipher, WriteBatch,
+    str_value as value,
+};
+
+/// Cuts the last `n` bytes off the write-ahead log in `data_dir`.
+fn cut_wal(data_dir: &Path, n: u64) {
+    let file = std::fs::OpenOptions::new().write(true).open(data_dir.join("WAL")).unwrap();
+    let len = file.metadata().unwrap().len();
+    file.set_len(len - n).unwrap();
+}
+
+fn wal_len(data_dir: &Path) -> u64 {
+    std::fs::metadata(data_dir.join("WAL")).unwrap().len()
+}
+
+// Multi-threaded, for appends to leave the worker thread while they block.
+#[tokio::test(flavor = "multi_thread")]
+async fn unflushed_writes_are_replayed_after_a_crash() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("flushed".to_string(), value("old")).await.unwrap();
+    db.set("deleted".to_string(), value("old")).await.unwrap();
+    db.flush().await.unwrap();
+    assert_eq!(wal_len(db.path()), 0);
+
+    db.set("flushed".to_string(), value("new")).await.unwrap();
+    db.delete("deleted".to_string()).await.unwrap();
+    db.set_ex("expiring".to_string(), value("soon"), Duration::from_secs(3600)).await.unwrap();
+    let crashed = db.open_crashed(|_| {}).await.unwrap();
+    assert_eq!(crashed.get("flushed").await.unwrap(), Some(value("new")));
+    assert_eq!(crashed.get("deleted").await.unwrap(), None);
+    assert_eq!(crashed.get("expiring").await.unwrap(), Some(value("soon")));
+    assert!(matches!(crashed.ttl("expiring").await.unwrap(), KeyTtl::Remaining(_)));
+
+    // Replayed writes are logged again until flushed.
+    crashed.set("after".to_string(), value("crash")).await.unwrap();
+    let again = crashed.open_crashed(|_| {}).await.unwrap();
+    assert_eq!(again.get("flushed").await.unwrap(), Some(value("new")));
+    assert_eq!(again.get("after").await.unwrap(), Some(value("crash")));
+    again.close().await.unwrap();
+    crashed.close().await.unwrap();
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_torn_last_write_is_dropped() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("first".to_string(), value("kept")).await.unwrap();
+    db.set("second".to_string(), value("torn")).await.unwrap();
+    let crashed = db.open_crashed(|dir| cut_wal(dir, 3)).await.unwrap();
+    assert_eq!(crashed.get("first").await.unwrap(), Some(value("kept")));
+    assert_eq!(crashed.get("second").await.unwrap(), None);
+
+    // The torn write is cut off, so later ones aren't lost behind it.
+    crashed.set("third".to_string(), value("kept")).await.unwrap();
+    let again = crashed.open_crashed(|_| {}).await.unwrap();
+    assert_eq!(again.get("first").await.unwrap(), Some(value("kept")));
+    assert_eq!(again.get("third").await.unwrap(), Some(value("kept")));
+    again.close().await.unwrap();
+    crashed.close().await.unwrap();
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn batches_are_replayed_whole_or_not_at_all() {
+    let db = TempDatabase::new().await.unwrap();
+    let mut batch = WriteBatch::new();
+    batch.set("a".to_string(), value("1"));
+    batch.set("b".to_string(), value("2"));
+    batch.delete("c".to_string());
+    db.write(batch).await.unwrap();
+
+    let whole = db.open_crashed(|_| {}).await.unwrap();
+    assert_eq!(whole.get("a").await.unwrap(), Some(value("1")));
+    assert_eq!(whole.get("b").await.unwrap(), Some(value("2")));
+    whole.close().await.unwrap();
+
+    // Torn within its last record, the batch loses its first ones too.
+    let torn = db.open_crashed(|dir| cut_wal(dir, 1)).await.unwrap();
+    assert_eq!(torn.get("a").await.unwrap(), None);
+    assert_eq!(torn.get("b").await.unwrap(), None);
+    torn.close().await.unwrap();
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn corruption_before_the_last_write_fails_the_open() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("first".to_string(), value("corrupt")).await.unwrap();
+    db.set("second".to_string(), value("intact")).await.unwrap();
+    let flip_byte = |dir: &Path| {
+        let path = dir.join("WAL");
+        let mut bytes = std::fs::read(&path).unwrap();
+        // Inside the first record.
+        bytes[16] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+    };
+    let Err(e) = db.open_crashed(flip_byte).await else {
+        panic!("opened a database with a corrupt write-ahead log");
+    };
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_torn_header_is_only_dropped_from_the_last_write() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("first".to_string(), value("kept")).await.unwrap();
+    let first_len = wal_len(db.path()) as usize;
+    db.set("second".to_string(), value("torn")).await.unwrap();
+    let flip_header_byte = |at: usize| {
+        move |dir: &Path| {
+            let path = dir.join("WAL");
+            let mut bytes = std::fs::read(&path).unwrap();
+            // Within the length of the frame.
+            bytes[at + 2] ^= 0xff;
+            std::fs::write(&path, bytes).unwrap();
+        }
+    };
+
+    let crashed = db.open_crashed(flip_header_byte(first_len)).await.unwrap();
+    assert_eq!(crashed.get("first").await.unwrap(), Some(value("kept")));
+    assert_eq!(crashed.get("second").await.unwrap(), None);
+    crashed.close().await.unwrap();
+
+    let Err(e) = db.open_crashed(flip_header_byte(0)).await else {
+        panic!("opened a database with a corrupt write-ahead log");
+    };
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn refused_writes_are_not_logged() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("kept".to_string(), value("small")).await.unwrap();
+    let len = wal_len(db.path());
+    let mut batch = WriteBatch::new();
+    batch.set("refused".to_string(), value("small"));
+    batch.set("x".repeat(usize::from(u16::MAX) + 1), value("small"));
+    let e = db.write(batch).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(wal_len(db.path()), len);
+
+    let crashed = db.open_crashed(|_| {}).await.unwrap();
+    assert_eq!(crashed.get("kept").await.unwrap(), Some(value("small")));
+    assert_eq!(crashed.get("refused").await.unwrap(), None);
+    crashed.close().await.unwrap();
+    db.close().await.unwrap();
+}
+
+/// Stand-in for a real cipher: XORs with its key, which it appends so that
+/// another key fails to open what it sealed.
+#[derive(Debug)]
+struct XorCipher(u8);
+
+impl WalCipher for XorCipher {
+    fn seal(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(payload.iter().map(|b| b ^ self.0).chain([self.0]).collect())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        match sealed.split_last() {
+            Some((&key, sealed)) if key == self.0 => Ok(sealed.iter().map(|b| b ^ key).collect()),
+            _ => Err(Error::new(ErrorKind::InvalidData, "sealed with another key")),
+        }
+    }
+}
+
+/// Copies the files of the unflushed database in `from` to `to`.
+fn copy_files(from: &Path, to: &Path) {
+    std::fs::create_dir(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn sealed_logs_are_only_replayed_with_their_cipher() {
+    let cipher: Arc<dyn WalCipher> = Arc::new(XorCipher(0x5a));
+    let db = TempDatabase::with_wal_cipher(TempDatabase::config(), Some(cipher.clone()))
+        .await
+        .unwrap();
+    db.set("secret".to_string(), value("plaintext")).await.unwrap();
+    let wal = std::fs::read(db.path().join("WAL")).unwrap();
+    assert!(!wal.windows(9).any(|window| window == b"plaintext"));
+
+    let dir = TempDir::new().unwrap();
+    let copy = dir.path().join("copy");
+    let crashed = db.open_crashed(|data_dir| copy_files(data_dir, &copy)).await.unwrap();
+    assert_eq!(crashed.get("secret").await.unwrap(), Some(value("plaintext")));
+    crashed.close().await.unwrap();
+
+    for cipher in [None, Some(Arc::new(XorCipher(1)) as Arc<dyn WalCipher>)] {
+        let e = TempDatabase::open_in(&copy, cipher).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn logs_written_before_a_cipher_is_set_are_still_replayed() {
+    let db = TempDatabase::new().await.unwrap();
+    db.set("plain".to_string(), value("1")).await.unwrap();
+    let dir = TempDir::new().unwrap();
+    let copy = dir.path().join("copy");
+    let crashed = db.open_crashed(|data_dir| copy_files(data_dir, &copy)).await.unwrap();
+    crashed.close().await.unwrap();
+
+    let sealed = TempDatabase::open_in(&copy, Some(Arc::new(XorCipher(0x5a)))).await.unwrap();
+    assert_eq!(sealed.get("plain").await.unwrap(), Some(value("1")));
+    sealed.close().await.unwrap();
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn sealed_archives_are_restored_with_their_cipher() {
+    let dir = TempDir::new().unwrap();
+    let (archive, backup) = (dir.path().join("archive"), dir.path().join("backup"));
+    let cipher: Arc<dyn WalCipher> = Arc::new(XorCipher(0x5a));
+    let config = Config {
+        wal_archive_dir: Some(archive.clone()),
+        ..TempDatabase::config()
+    };
+    let db = TempDatabase::with_wal_cipher(config, Some(cipher.clone())).await.unwrap();
+    db.backup(&backup).await.unwrap();
+    db.set("secret".to_string(), value("plaintext")).await.unwrap();
+    db.flush().await.unwrap();
+    let last = db.stats().await.last_record_seq;
+
+    let unsealed = dir.path().join("unsealed");
+    let restore = DatabaseImpl::restore_to(&backup, &archive, &unsealed, last, None);
+    assert_eq!(restore.await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let restored = dir.path().join("restored");
+    let cipher_again = Some(cipher.clone());
+    let restore = DatabaseImpl::restore_to(&backup, &archive, &restored, last, cipher_again);
+    assert_eq!(restore.await.unwrap(), last);
+    assert!(TempDatabase::open_in(&restored, None).await.is_err());
+    let copy = TempDatabase::open_in(&restored, Some(cipher)).await.unwrap();
+    assert_eq!(copy.get("secret").await.unwrap(), Some(value("plaintext")));
+    copy.close().await.unwrap();
+    db.close().await.unwrap();
+}